@@ -0,0 +1,36 @@
+#!/usr/bin/env bash
+# Compare --backend sync and --backend async on a real library.
+# Usage: scripts/bench-backends.sh <chd-dir> [parallel-readers]
+# Needs a binary built with `--features async` (set BIN=...).
+set -euo pipefail
+
+BIN="${BIN:-target/release/chd2iso-fuse}"
+SRC="${1:?usage: $0 <chd-dir> [parallel-readers]}"
+JOBS="${2:-4}"
+
+MNT="$(mktemp -d)"
+trap 'fusermount -u "$MNT" 2>/dev/null || true; rmdir "$MNT"' EXIT
+
+for backend in sync async; do
+  "$BIN" --source "$SRC" --mount "$MNT" --backend "$backend" &
+  pid=$!
+  for _ in $(seq 50); do mountpoint -q "$MNT" && break; sleep 0.1; done
+
+  mapfile -t files < <(find "$MNT" -maxdepth 1 -type f -name '*.iso' | head -n "$JOBS")
+  if [[ ${#files[@]} -eq 0 ]]; then
+    echo "no .iso files exposed from $SRC" >&2
+    exit 1
+  fi
+
+  start=$(date +%s.%N)
+  for f in "${files[@]}"; do
+    dd if="$f" of=/dev/null bs=1M count=256 status=none &
+  done
+  wait $(jobs -p | grep -v "^$pid$")
+  end=$(date +%s.%N)
+
+  printf '%-6s %d readers x 256 MiB: %.2fs\n' "$backend" "${#files[@]}" "$(echo "$end - $start" | bc)"
+
+  fusermount -u "$MNT"
+  wait "$pid" || true
+done
@@ -0,0 +1,280 @@
+//! CHDs packed in `.zip` and `.7z` archives (`archive` feature), as many
+//! collections ship a `Game.zip` holding one CHD. A member is addressed as a
+//! path below its archive, `Game.zip/Game.chd`. Stored (uncompressed) zip
+//! members are read in place through [`ArchiveVfs`]; compressed ones, and
+//! every 7z member, are extracted once into `--spill-dir` at index time,
+//! like `.iso.gz` inputs.
+
+use anyhow::{anyhow, bail, Context, Result};
+use sevenz_rust::{Password, SevenZReader};
+use std::{
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+};
+use tracing::{info, warn};
+use zip::{CompressionMethod, ZipArchive};
+
+use crate::{
+    chd_image::ChdFactory,
+    provider::{spill_key, ProbeContext, Probed, ProviderFactory},
+    sha1,
+    vfs::{self, RangedRead, ScanOptions, SourceMeta, SourceVfs},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Format {
+    Zip,
+    SevenZ,
+}
+
+impl Format {
+    fn detect(path: &Path) -> Option<Self> {
+        let ext = path.extension()?.to_str()?.to_ascii_lowercase();
+        match ext.as_str() {
+            "zip" => Some(Format::Zip),
+            "7z" => Some(Format::SevenZ),
+            _ => None,
+        }
+    }
+}
+
+/// A file inside an archive.
+struct Member {
+    name: String,
+    size: u64,
+    /// Offset of the data in the archive, if stored uncompressed
+    stored_at: Option<u64>,
+}
+
+fn members(archive: &Path) -> Result<Vec<Member>> {
+    let format = Format::detect(archive).ok_or_else(|| anyhow!("{archive:?}: not an archive"))?;
+    let reader = vfs::reader(archive)?;
+
+    match format {
+        Format::Zip => {
+            let mut zip =
+                ZipArchive::new(reader).with_context(|| format!("reading {archive:?}"))?;
+            let mut out = Vec::new();
+            for i in 0..zip.len() {
+                let f = zip.by_index_raw(i)?;
+                if f.is_dir() {
+                    continue;
+                }
+                out.push(Member {
+                    name: f.name().to_string(),
+                    size: f.size(),
+                    stored_at: (f.compression() == CompressionMethod::Stored)
+                        .then(|| f.data_start()),
+                });
+            }
+            Ok(out)
+        }
+        Format::SevenZ => {
+            let len = vfs::stat(archive)?.size;
+            let sz = SevenZReader::new(reader, len, Password::empty())
+                .with_context(|| format!("reading {archive:?}"))?;
+            Ok(sz
+                .archive()
+                .files
+                .iter()
+                .filter(|e| !e.is_directory() && e.has_stream())
+                .map(|e| Member {
+                    name: e.name().to_string(),
+                    size: e.size(),
+                    stored_at: None,
+                })
+                .collect())
+        }
+    }
+}
+
+/// `path` split into its archive and member name, if it lies below an
+/// archive file.
+pub fn split(path: &Path) -> Option<(&Path, &str)> {
+    let archive = path
+        .ancestors()
+        .skip(1)
+        .find(|a| Format::detect(a).is_some() && !a.is_dir())?;
+    Some((archive, path.strip_prefix(archive).ok()?.to_str()?))
+}
+
+fn member(archive: &Path, name: &str) -> Result<Member> {
+    members(archive)?
+        .into_iter()
+        .find(|m| m.name == name)
+        .ok_or_else(|| anyhow!("{archive:?} has no {name}"))
+}
+
+/// Extract `m` into `spill_dir`, once per archive version, keeping its file
+/// name so it probes under the same title.
+fn extract(archive: &Path, m: &Member, spill_dir: &Path) -> Result<PathBuf> {
+    let base = Path::new(&m.name)
+        .file_name()
+        .ok_or_else(|| anyhow!("{archive:?}: bad member name {}", m.name))?;
+    let dir = spill_dir.join(format!(
+        "{}-{}",
+        spill_key(archive)?,
+        &sha1::hex(&sha1::digest(m.name.as_bytes()))[..8]
+    ));
+    let path = dir.join(base);
+    if path.is_file() {
+        return Ok(path);
+    }
+
+    fs::create_dir_all(&dir).with_context(|| format!("creating spill dir {dir:?}"))?;
+    info!("extracting {} from {:?} -> {:?}", m.name, archive, path);
+    let tmp = path.with_extension("part");
+    let mut out = File::create(&tmp)?;
+    let reader = vfs::reader(archive)?;
+
+    match Format::detect(archive) {
+        Some(Format::Zip) => {
+            let mut zip = ZipArchive::new(reader)?;
+            io::copy(&mut zip.by_name(&m.name)?, &mut out)
+                .with_context(|| format!("extracting {} from {archive:?}", m.name))?;
+        }
+        Some(Format::SevenZ) => {
+            let len = vfs::stat(archive)?.size;
+            let mut found = false;
+            SevenZReader::new(reader, len, Password::empty())?.for_each_entries(|e, r| {
+                if e.name() == m.name {
+                    io::copy(r, &mut out)?;
+                    found = true;
+                    return Ok(false);
+                }
+                io::copy(r, &mut io::sink())?;
+                Ok(true)
+            })?;
+            if !found {
+                bail!("{archive:?} has no {}", m.name);
+            }
+        }
+        None => bail!("{archive:?}: not an archive"),
+    }
+
+    out.sync_all()?;
+    fs::rename(&tmp, &path)?;
+    Ok(path)
+}
+
+/// Registry entry for archives: the CHD inside is probed like a loose one.
+pub struct ArchiveFactory;
+
+impl ProviderFactory for ArchiveFactory {
+    fn matches(&self, path: &Path) -> bool {
+        Format::detect(path).is_some()
+    }
+
+    fn probe(&self, path: &Path, ctx: &ProbeContext) -> Result<Option<Probed>> {
+        let chds: Vec<Member> = members(path)?
+            .into_iter()
+            .filter(|m| ChdFactory.matches(Path::new(&m.name)))
+            .collect();
+        let Some(first) = chds.first() else {
+            return Ok(None);
+        };
+        if chds.len() > 1 {
+            warn!(
+                "{:?} holds {} CHDs; indexing only {}",
+                path,
+                chds.len(),
+                first.name
+            );
+        }
+
+        let inner = match first.stored_at {
+            Some(_) => path.join(&first.name),
+            None => extract(path, first, ctx.spill_dir)?,
+        };
+        ChdFactory.probe(&inner, ctx)
+    }
+}
+
+/// Stored members, read in place.
+pub struct ArchiveVfs;
+
+impl SourceVfs for ArchiveVfs {
+    fn list(&self, root: &Path, opts: &ScanOptions) -> Result<Vec<PathBuf>> {
+        let (archive, prefix) =
+            split(root).ok_or_else(|| anyhow!("{root:?}: not in an archive"))?;
+        let prefix = format!("{}/", prefix.trim_end_matches('/'));
+        Ok(members(archive)?
+            .into_iter()
+            .filter(|m| m.name.starts_with(&prefix))
+            .filter(|m| {
+                !(opts.skip_hidden
+                    && m.name
+                        .rsplit('/')
+                        .next()
+                        .is_some_and(|n| n.starts_with('.')))
+            })
+            .map(|m| archive.join(m.name))
+            .filter(|p| (opts.wanted)(p))
+            .collect())
+    }
+
+    fn stat(&self, path: &Path) -> Result<SourceMeta> {
+        let (archive, name) = split(path).ok_or_else(|| anyhow!("{path:?}: not in an archive"))?;
+        let m = member(archive, name)?;
+        Ok(SourceMeta {
+            is_dir: false,
+            size: m.size,
+            ..vfs::stat(archive)?
+        })
+    }
+
+    fn open(&self, path: &Path) -> Result<Box<dyn RangedRead>> {
+        let (archive, name) = split(path).ok_or_else(|| anyhow!("{path:?}: not in an archive"))?;
+        let m = member(archive, name)?;
+        let Some(start) = m.stored_at else {
+            bail!("{path:?} is compressed; only stored members are read in place");
+        };
+        Ok(Box::new(Slice {
+            inner: vfs::open(archive)?,
+            start,
+            len: m.size,
+        }))
+    }
+}
+
+/// `len` bytes of `inner` from `start`.
+#[derive(Debug)]
+struct Slice {
+    inner: Box<dyn RangedRead>,
+    start: u64,
+    len: u64,
+}
+
+impl RangedRead for Slice {
+    fn size(&self) -> u64 {
+        self.len
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        if offset >= self.len {
+            return Ok(0);
+        }
+        let want = (buf.len() as u64).min(self.len - offset) as usize;
+        self.inner.read_at(&mut buf[..want], self.start + offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_member_paths() {
+        let p = Path::new("/nonexistent/Game (USA).zip/CHD/Game (USA).chd");
+        assert_eq!(
+            split(p),
+            Some((
+                Path::new("/nonexistent/Game (USA).zip"),
+                "CHD/Game (USA).chd"
+            ))
+        );
+        assert_eq!(split(Path::new("/nonexistent/Game.zip")), None);
+        assert_eq!(split(Path::new("/nonexistent/Game.chd")), None);
+    }
+}
@@ -0,0 +1,510 @@
+//! `--backend async`: the same filesystem served through fuse3 on a tokio
+//! runtime. Requests are handled concurrently; provider reads (which block on
+//! file I/O and decompression) run on tokio's blocking pool, so a slow hunk
+//! decode does not stall lookups or reads of other files.
+
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use fuse3::raw::prelude::*;
+use fuse3::{Errno, MountOptions};
+use futures_util::stream::{self, Iter};
+use std::{ffi::OsStr, num::NonZeroU32, sync::Arc, vec::IntoIter};
+
+use crate::fusedev;
+use crate::handles::Handle;
+use crate::health;
+use crate::overlay::Overlay;
+use crate::provider::BackingProvider;
+use crate::vfs;
+use crate::{negative_attr, FsState, Lookup, Xattr, SMB_TTL, TTL};
+
+struct AsyncFs(Arc<FsState>);
+
+/// fuse_open_out flag: bypass the page cache for this handle.
+const FOPEN_DIRECT_IO: u32 = 1;
+/// fuse_open_out flag: keep the page cache from earlier opens.
+const FOPEN_KEEP_CACHE: u32 = 1 << 1;
+
+/// fuser attributes (shared with the sync backend) -> fuse3 attributes.
+fn convert_attr(a: fuser::FileAttr) -> FileAttr {
+    FileAttr {
+        ino: a.ino.0,
+        size: a.size,
+        blocks: a.blocks,
+        atime: a.atime.into(),
+        mtime: a.mtime.into(),
+        ctime: a.ctime.into(),
+        kind: convert_kind(a.kind),
+        perm: a.perm,
+        nlink: a.nlink,
+        uid: a.uid,
+        gid: a.gid,
+        rdev: a.rdev,
+        blksize: a.blksize,
+    }
+}
+
+impl AsyncFs {
+    /// errno for a failed source access: `ENODEV` if the drive is gone.
+    fn io_errno(&self) -> Errno {
+        self.0.health.source_error();
+        Errno::from(if self.0.source_lost() {
+            libc::ENODEV
+        } else {
+            libc::EIO
+        })
+    }
+}
+
+fn convert_kind(kind: fuser::FileType) -> FileType {
+    match kind {
+        fuser::FileType::Directory => FileType::Directory,
+        fuser::FileType::Symlink => FileType::Symlink,
+        _ => FileType::RegularFile,
+    }
+}
+
+impl Filesystem for AsyncFs {
+    type DirEntryStream<'a>
+        = Iter<IntoIter<fuse3::Result<DirectoryEntry>>>
+    where
+        Self: 'a;
+
+    type DirEntryPlusStream<'a>
+        = Iter<IntoIter<fuse3::Result<DirectoryEntryPlus>>>
+    where
+        Self: 'a;
+
+    async fn init(&self, _req: Request) -> fuse3::Result<ReplyInit> {
+        Ok(ReplyInit {
+            max_write: NonZeroU32::new(128 * 1024).unwrap(),
+        })
+    }
+
+    async fn destroy(&self, _req: Request) {}
+
+    async fn lookup(&self, req: Request, parent: u64, name: &OsStr) -> fuse3::Result<ReplyEntry> {
+        self.0.visible_to(req.uid).map_err(Errno::from)?;
+        if let Some(attr) = self.0.control_lookup(parent, name) {
+            return Ok(ReplyEntry {
+                ttl: TTL,
+                attr: convert_attr(attr),
+                generation: self.0.generation,
+            });
+        }
+        self.0.online().map_err(Errno::from)?;
+        let found = self.0.index().lookup(parent, &name.to_string_lossy());
+
+        let (ttl, attr) = match found {
+            Some(Lookup::Dir(ino)) => (self.0.ttl(ino), self.0.dir_attr(ino)),
+            Some(Lookup::Link(ino)) => (self.0.ttl(ino), self.0.attr(ino).map_err(Errno::from)?),
+            Some(Lookup::Entry(e)) if self.0.visible(&e) => {
+                (self.0.ttl(e.ino), self.0.file_attr(&e))
+            }
+            Some(Lookup::Entry(_)) => return Err(Errno::new_not_exist()),
+            None => match self.0.overlay_lookup(parent, name) {
+                Some(attr) => (TTL, attr),
+                None if self.0.args().smb_mode => (SMB_TTL, negative_attr()),
+                None => return Err(Errno::new_not_exist()),
+            },
+        };
+
+        Ok(ReplyEntry {
+            ttl,
+            attr: convert_attr(attr),
+            generation: self.0.generation,
+        })
+    }
+
+    async fn getattr(
+        &self,
+        _req: Request,
+        inode: u64,
+        _fh: Option<u64>,
+        _flags: u32,
+    ) -> fuse3::Result<ReplyAttr> {
+        let attr = self.0.attr(inode).map_err(Errno::from)?;
+        Ok(ReplyAttr {
+            ttl: self.0.ttl(inode),
+            attr: convert_attr(attr),
+        })
+    }
+
+    async fn readlink(&self, _req: Request, inode: u64) -> fuse3::Result<ReplyData> {
+        let target = self.0.readlink(inode).map_err(Errno::from)?;
+        Ok(ReplyData {
+            data: Bytes::from(target.into_bytes()),
+        })
+    }
+
+    async fn open(&self, req: Request, inode: u64, flags: u32) -> fuse3::Result<ReplyOpen> {
+        self.0.visible_to(req.uid).map_err(Errno::from)?;
+        if Overlay::owns(inode) {
+            let fh = self
+                .0
+                .overlay_open(inode, flags as i32)
+                .map_err(Errno::from)?;
+            return Ok(ReplyOpen { fh, flags: 0 });
+        }
+
+        if flags as i32 & libc::O_ACCMODE != libc::O_RDONLY {
+            return Err(Errno::from(libc::EROFS));
+        }
+        if inode == health::FILE_INO {
+            // Direct I/O: the report changes size between reads.
+            return Ok(ReplyOpen {
+                fh: 0,
+                flags: FOPEN_DIRECT_IO,
+            });
+        }
+        self.0.online().map_err(Errno::from)?;
+
+        let e = self
+            .0
+            .entry_by_ino(inode)
+            .ok_or_else(Errno::new_not_exist)?;
+
+        match vfs::check_open(&e.chd_path) {
+            Ok(()) => {}
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                return Err(Errno::from(self.0.source_gone(&e.chd_path)));
+            }
+            Err(_) => return Err(self.io_errno()),
+        }
+
+        let fh = self.0.open_handle(&e, flags as i32, req.uid, req.pid);
+        let flags = if self.0.args().smb_mode {
+            FOPEN_KEEP_CACHE
+        } else {
+            0
+        };
+
+        Ok(ReplyOpen { fh, flags })
+    }
+
+    async fn setattr(
+        &self,
+        _req: Request,
+        inode: u64,
+        _fh: Option<u64>,
+        set_attr: SetAttr,
+    ) -> fuse3::Result<ReplyAttr> {
+        let attr = self
+            .0
+            .overlay_setattr(inode, set_attr.size)
+            .map_err(Errno::from)?;
+        Ok(ReplyAttr {
+            ttl: TTL,
+            attr: convert_attr(attr),
+        })
+    }
+
+    async fn mknod(
+        &self,
+        _req: Request,
+        _parent: u64,
+        _name: &OsStr,
+        _mode: u32,
+        _rdev: u32,
+    ) -> fuse3::Result<ReplyEntry> {
+        Err(Errno::from(libc::EROFS))
+    }
+
+    async fn mkdir(
+        &self,
+        _req: Request,
+        _parent: u64,
+        _name: &OsStr,
+        _mode: u32,
+        _umask: u32,
+    ) -> fuse3::Result<ReplyEntry> {
+        Err(Errno::from(libc::EROFS))
+    }
+
+    async fn unlink(&self, _req: Request, parent: u64, name: &OsStr) -> fuse3::Result<()> {
+        self.0.unlink_file(parent, name).map_err(Errno::from)
+    }
+
+    async fn rmdir(&self, _req: Request, parent: u64, name: &OsStr) -> fuse3::Result<()> {
+        self.0.deny_delete(parent, name).map_err(Errno::from)
+    }
+
+    async fn rename(
+        &self,
+        _req: Request,
+        parent: u64,
+        name: &OsStr,
+        new_parent: u64,
+        new_name: &OsStr,
+    ) -> fuse3::Result<()> {
+        self.0
+            .overlay_rename((parent, name), (new_parent, new_name))
+            .map_err(Errno::from)
+    }
+
+    async fn write(
+        &self,
+        _req: Request,
+        inode: u64,
+        fh: u64,
+        offset: u64,
+        data: &[u8],
+        _write_flags: u32,
+        _flags: u32,
+    ) -> fuse3::Result<ReplyWrite> {
+        if !Overlay::owns(inode) {
+            return Err(Errno::from(libc::EROFS));
+        }
+        let written = self
+            .0
+            .overlay_write(inode, fh, offset, data)
+            .map_err(Errno::from)?;
+        Ok(ReplyWrite { written })
+    }
+
+    async fn create(
+        &self,
+        _req: Request,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        flags: u32,
+    ) -> fuse3::Result<ReplyCreated> {
+        let (attr, fh) = self
+            .0
+            .overlay_create(parent, name, mode, flags as i32)
+            .map_err(Errno::from)?;
+        Ok(ReplyCreated {
+            ttl: TTL,
+            attr: convert_attr(attr),
+            generation: self.0.generation,
+            fh,
+            flags: 0,
+        })
+    }
+
+    async fn release(
+        &self,
+        _req: Request,
+        _inode: u64,
+        fh: u64,
+        _flags: u32,
+        _lock_owner: u64,
+        _flush: bool,
+    ) -> fuse3::Result<()> {
+        self.0.release_handle(fh);
+
+        Ok(())
+    }
+
+    async fn read(
+        &self,
+        _req: Request,
+        inode: u64,
+        fh: u64,
+        offset: u64,
+        size: u32,
+    ) -> fuse3::Result<ReplyData> {
+        if Overlay::owns(inode) {
+            let buf = self
+                .0
+                .overlay_read(inode, fh, offset, size)
+                .map_err(Errno::from)?;
+            return Ok(ReplyData {
+                data: Bytes::from(buf),
+            });
+        }
+
+        if inode == health::FILE_INO {
+            return Ok(ReplyData {
+                data: Bytes::from(self.0.health_read(offset, size)),
+            });
+        }
+        self.0.online().map_err(Errno::from)?;
+        let ent = self
+            .0
+            .entry_by_ino(inode)
+            .ok_or_else(Errno::new_not_exist)?;
+        self.0.note_read_size(size);
+
+        let handle = self.0.handles.get(fh, inode).map_err(Errno::from)?;
+        if self.0.check_quarantine(&handle).map_err(Errno::from)? {
+            return Ok(ReplyData { data: Bytes::new() });
+        }
+
+        let provider = self.0.hot.provider(inode).unwrap_or(ent.provider);
+        let reader = Arc::clone(&handle);
+        let data =
+            tokio::task::spawn_blocking(move || read_blocking(&reader, &provider, offset, size))
+                .await
+                .map_err(|_| Errno::from(libc::EIO))?;
+
+        match data {
+            Ok(buf) => {
+                self.0.count_read(buf.len());
+                let delay = self.0.throttle_delay(handle.throttle.as_deref(), buf.len());
+                if !delay.is_zero() {
+                    tokio::time::sleep(delay).await;
+                }
+                Ok(ReplyData {
+                    data: Bytes::from(buf),
+                })
+            }
+            Err(e) => {
+                self.0.read_failed(&handle, &e);
+                Err(self.io_errno())
+            }
+        }
+    }
+
+    async fn getxattr(
+        &self,
+        _req: Request,
+        inode: u64,
+        name: &OsStr,
+        size: u32,
+    ) -> fuse3::Result<ReplyXAttr> {
+        xattr_reply(self.0.xattr(inode, Some(name), size))
+    }
+
+    async fn listxattr(&self, _req: Request, inode: u64, size: u32) -> fuse3::Result<ReplyXAttr> {
+        xattr_reply(self.0.xattr(inode, None, size))
+    }
+
+    async fn readdir<'a>(
+        &'a self,
+        req: Request,
+        parent: u64,
+        _fh: u64,
+        offset: i64,
+    ) -> fuse3::Result<ReplyDirectory<Self::DirEntryStream<'a>>> {
+        self.0.visible_to(req.uid).map_err(Errno::from)?;
+        if parent != health::DIR_INO {
+            self.0.online().map_err(Errno::from)?;
+        }
+        let index = self.0.index();
+
+        if !index.is_dir(parent) && parent != health::DIR_INO {
+            return Err(Errno::from(libc::ENOTDIR));
+        }
+
+        let up = index.dir(parent).map_or(1, |d| d.parent);
+        let overlay = self.0.overlay_children(&index, parent);
+        let control = self.0.control_children(parent);
+        let children = [
+            (parent, FileType::Directory, "."),
+            (up, FileType::Directory, ".."),
+        ]
+        .into_iter()
+        .chain(
+            index
+                .dirs
+                .iter()
+                .filter(|d| d.parent == parent)
+                .map(|d| (d.ino, FileType::Directory, d.name.as_str())),
+        )
+        .chain(
+            index
+                .links
+                .iter()
+                .filter(|l| l.parent == parent)
+                .map(|l| (l.ino, FileType::Symlink, l.name.as_str())),
+        )
+        .chain(
+            index
+                .entries
+                .iter()
+                .filter(|e| e.parent == parent && self.0.visible(e))
+                .map(|e| (e.ino, FileType::RegularFile, e.name.as_str())),
+        )
+        .chain(
+            overlay
+                .iter()
+                .map(|(i, n)| (*i, FileType::RegularFile, n.as_str())),
+        )
+        .chain(
+            control
+                .iter()
+                .map(|&(i, kind, n)| (i, convert_kind(kind), n)),
+        );
+
+        let entries: Vec<_> = children
+            .enumerate()
+            .skip(offset as usize)
+            .map(|(i, (inode, kind, name))| {
+                Ok(DirectoryEntry {
+                    inode,
+                    kind,
+                    name: name.into(),
+                    offset: i as i64 + 1,
+                })
+            })
+            .collect();
+
+        Ok(ReplyDirectory {
+            entries: stream::iter(entries),
+        })
+    }
+}
+
+fn xattr_reply(x: Result<Xattr, i32>) -> fuse3::Result<ReplyXAttr> {
+    match x {
+        Ok(Xattr::Size(n)) => Ok(ReplyXAttr::Size(n)),
+        Ok(Xattr::Data(v)) => Ok(ReplyXAttr::Data(Bytes::from(v))),
+        Err(e) => Err(Errno::from(e)),
+    }
+}
+
+fn read_blocking(
+    handle: &Arc<Handle>,
+    provider: &Arc<dyn BackingProvider>,
+    offset: u64,
+    size: u32,
+) -> Result<Vec<u8>> {
+    let len = (size as u64).min(provider.size().saturating_sub(offset));
+    let mut buf = vec![0u8; len as usize];
+    let n = handle.read(provider, offset, &mut buf)?;
+    buf.truncate(n);
+    Ok(buf)
+}
+
+/// Mount every filesystem with fuse3 on one shared runtime (and blocking
+/// pool) and serve until all are unmounted.
+pub fn mount(mounts: Vec<Arc<FsState>>) -> Result<()> {
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?;
+
+    rt.block_on(async move {
+        let mut sessions = Vec::new();
+
+        for fs in mounts {
+            let mut opts = MountOptions::default();
+            opts.fs_name("chd2iso")
+                .read_only(fs.read_only_mount())
+                .default_permissions(true);
+
+            let allow_other = fs.args().allow_other.resolve(fs.args().mountpoint());
+            if allow_other {
+                opts.allow_other(true);
+            }
+
+            let mountpoint = fs.args().mountpoint().to_path_buf();
+            sessions.push(
+                Session::new(opts)
+                    .mount_with_unprivileged(AsyncFs(fs), &mountpoint)
+                    .await
+                    .map_err(|e| {
+                        let hint = fusedev::allow_other_hint(allow_other);
+                        std::io::Error::new(e.kind(), format!("{e}{hint}"))
+                    })?,
+            );
+        }
+
+        for s in sessions {
+            s.await?;
+        }
+        Ok::<_, std::io::Error>(())
+    })
+    .map_err(|e| anyhow!("mount failed: {e}{}", fusedev::mount_hint(&e)))
+}
@@ -0,0 +1,127 @@
+//! Source-file attributes behind getattr and lookup. Each source is stat'ed
+//! when the index is built and then only once its entry is stale: older
+//! than `--attr-refresh`, or reported changed by the inotify watch.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime},
+};
+use tracing::warn;
+
+use crate::vfs;
+
+/// What attributes take from the source file.
+#[derive(Clone, Copy, Debug)]
+pub struct SourceStat {
+    pub mtime: SystemTime,
+    pub ctime: SystemTime,
+    pub uid: u32,
+    pub gid: u32,
+    /// Allocated 512-byte blocks
+    pub blocks: u64,
+}
+
+impl SourceStat {
+    /// None for a source without inode details (a remote one).
+    fn of(path: &Path) -> Option<Self> {
+        let meta = vfs::stat(path).inspect_err(|e| warn!("{:#}", e)).ok()?;
+        let local = meta.local?;
+        Some(Self {
+            mtime: SystemTime::UNIX_EPOCH + Duration::from_secs(meta.mtime.unwrap_or(0) as u64),
+            ctime: SystemTime::UNIX_EPOCH + Duration::from_secs(local.ctime as u64),
+            uid: local.uid,
+            gid: local.gid,
+            blocks: local.blocks,
+        })
+    }
+}
+
+struct Cached {
+    /// None if the stat failed
+    stat: Option<SourceStat>,
+    taken: Instant,
+    changed: bool,
+}
+
+impl Cached {
+    fn take(path: &Path) -> Self {
+        Self {
+            stat: SourceStat::of(path),
+            taken: Instant::now(),
+            changed: false,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct AttrCache(Mutex<HashMap<PathBuf, Cached>>);
+
+impl AttrCache {
+    fn map(&self) -> std::sync::MutexGuard<'_, HashMap<PathBuf, Cached>> {
+        self.0.lock().expect("attr cache mutex poisoned")
+    }
+
+    /// Stat every one of `paths` afresh and forget all others.
+    pub fn reset<'a>(&self, paths: impl IntoIterator<Item = &'a Path>) {
+        let fresh: HashMap<PathBuf, Cached> = paths
+            .into_iter()
+            .map(|p| (p.to_path_buf(), Cached::take(p)))
+            .collect();
+        *self.map() = fresh;
+    }
+
+    /// Attributes of `path`, stat'ed again first if it changed or was last
+    /// stat'ed more than `refresh` ago (zero: only on change).
+    pub fn get(&self, path: &Path, refresh: Duration) -> Option<SourceStat> {
+        if let Some(c) = self.map().get(path) {
+            let expired = !refresh.is_zero() && c.taken.elapsed() >= refresh;
+            if !c.changed && !expired {
+                return c.stat;
+            }
+        }
+
+        // Stat without the lock; a slow network source must not stall
+        // lookups of other files.
+        let fresh = Cached::take(path);
+        let stat = fresh.stat;
+        self.map().insert(path.to_path_buf(), fresh);
+        stat
+    }
+
+    /// Mark `path` changed, if it is a known source.
+    pub fn invalidate(&self, path: &Path) {
+        if let Some(c) = self.map().get_mut(path) {
+            c.changed = true;
+        }
+    }
+
+    pub fn invalidate_all(&self) {
+        for c in self.map().values_mut() {
+            c.changed = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn serves_cached_stat_until_invalidated() {
+        let path = std::env::temp_dir().join(format!("chd2iso-attrs-{}", std::process::id()));
+        fs::write(&path, b"x").unwrap();
+
+        let cache = AttrCache::default();
+        cache.reset([path.as_path()]);
+        fs::remove_file(&path).unwrap();
+
+        assert!(cache.get(&path, Duration::ZERO).is_some());
+        assert!(cache.get(&path, Duration::from_secs(3600)).is_some());
+
+        cache.invalidate(&path);
+        assert!(cache.get(&path, Duration::ZERO).is_none());
+    }
+}
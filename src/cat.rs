@@ -0,0 +1,191 @@
+//! `chd2iso-fuse cat FILE`: write the image a source is exposed as to
+//! stdout, for `isoinfo`, `dd` or a checksum tool without mounting. The
+//! source is probed by the same providers as in a mount with default
+//! settings, so a CD CHD gives the 2048-byte ISO view and a DVD CHD its
+//! data as is.
+
+use anyhow::{bail, Context, Result};
+use std::{
+    env,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use crate::{
+    chd_image::{AudioCdMode, Form2Policy, FrameCache, TailPolicy},
+    progress::{Progress, ProgressMode},
+    provider::{BackingProvider, ExtensionMap, ProbeContext, Probed, Registry},
+};
+
+/// Bytes read from the provider per write.
+const CHUNK: usize = 1 << 20;
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct CatArgs {
+    /// CHD (or .cso/.zso/.iso.gz) to read
+    #[arg(value_name = "FILE")]
+    file: PathBuf,
+
+    /// First byte of the image to write
+    #[arg(long = "offset", value_name = "BYTES", default_value_t = 0)]
+    offset: u64,
+
+    /// Bytes to write (default: to the end of the image)
+    #[arg(long = "length", value_name = "BYTES")]
+    length: Option<u64>,
+
+    /// Progress on stderr: "none", "bar" or "json" (one object per line)
+    #[arg(long = "progress", value_name = "MODE", default_value = "none")]
+    progress: ProgressMode,
+}
+
+pub fn run(args: &CatArgs) -> Result<()> {
+    let registry = Registry::with_builtin();
+    let frame_cache = Arc::new(FrameCache::new(256, 64 << 20));
+    let probed = probe(&registry, &args.file, &frame_cache)?;
+
+    let size = probed.provider.size();
+    let total = args
+        .length
+        .map_or(size, |l| l.min(size))
+        .min(size.saturating_sub(args.offset));
+    let progress = Progress::new(args.progress, 1, total);
+    let name = args.file.display().to_string();
+    let advance = |n| progress.advance(&name, n);
+
+    let mut out = io::stdout().lock();
+    let res = copy_range(
+        &*probed.provider,
+        args.offset,
+        args.length,
+        &mut out,
+        &advance,
+    );
+    progress.done();
+    match res {
+        // `cat ... | head` is fine.
+        Err(e)
+            if e.downcast_ref::<io::Error>()
+                .is_some_and(|e| e.kind() == io::ErrorKind::BrokenPipe) =>
+        {
+            Ok(())
+        }
+        res => res.map(|_| ()),
+    }
+}
+
+/// Probe `file` as a mount with default settings would, for its main
+/// image.
+pub fn probe(registry: &Registry, file: &Path, frame_cache: &Arc<FrameCache>) -> Result<Probed> {
+    if !registry.matches(file) {
+        bail!("{file:?} is not a CHD, CSO, ZSO or .iso.gz");
+    }
+
+    let spill_dir = env::temp_dir();
+    let ctx = ProbeContext {
+        form2: Form2Policy::Hide,
+        audio_tracks: false,
+        pregap_audio: false,
+        audio_byteswap: false,
+        subchannel: false,
+        audio_cd: AudioCdMode::Hide,
+        toc: false,
+        full_disc: false,
+        merged_iso: false,
+        spill_dir: &spill_dir,
+        frame_cache,
+        serials: false,
+        consoles: false,
+        tail: TailPolicy::Eio,
+        strict: false,
+        extensions: &ExtensionMap::default(),
+    };
+    registry
+        .probe(file, &ctx)
+        .with_context(|| format!("opening {file:?}"))?
+        .with_context(|| format!("{file:?} has no data track to expose"))
+}
+
+/// Write `length` bytes of `provider` from `offset` (to its end if None)
+/// into `out`, telling `advance` of each chunk; returns the bytes written.
+pub fn copy_range(
+    provider: &dyn BackingProvider,
+    offset: u64,
+    length: Option<u64>,
+    out: &mut impl Write,
+    advance: &dyn Fn(u64),
+) -> Result<u64> {
+    let size = provider.size();
+    if offset > size {
+        bail!("offset {offset} is past the end of the image ({size} bytes)");
+    }
+    let end = length.map_or(size, |l| offset.saturating_add(l).min(size));
+
+    let mut buf = vec![0u8; CHUNK.min((end - offset) as usize)];
+    let mut pos = offset;
+    while pos < end {
+        let want = ((end - pos) as usize).min(buf.len());
+        let n = provider
+            .read_at(pos, &mut buf[..want])
+            .with_context(|| format!("reading at byte {pos}"))?;
+        if n == 0 {
+            bail!("image ended early at byte {pos} of {size}");
+        }
+        out.write_all(&buf[..n])?;
+        advance(n as u64);
+        pos += n as u64;
+    }
+    out.flush()?;
+    Ok(pos - offset)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::provider::ImageKind;
+
+    #[derive(Debug)]
+    struct Mem(Vec<u8>);
+
+    impl BackingProvider for Mem {
+        fn size(&self) -> u64 {
+            self.0.len() as u64
+        }
+
+        fn kind(&self) -> ImageKind {
+            ImageKind::Dvd
+        }
+
+        fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize> {
+            let data = self.0.get(offset as usize..).unwrap_or_default();
+            // Short reads, as decoders return at hunk boundaries.
+            let n = data.len().min(buf.len()).min(3);
+            buf[..n].copy_from_slice(&data[..n]);
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn copies_the_requested_range() {
+        let image = Mem(b"0123456789".to_vec());
+        let mut out = Vec::new();
+        assert_eq!(copy_range(&image, 0, None, &mut out, &|_| {}).unwrap(), 10);
+        assert_eq!(out, b"0123456789");
+
+        out.clear();
+        assert_eq!(
+            copy_range(&image, 2, Some(5), &mut out, &|_| {}).unwrap(),
+            5
+        );
+        assert_eq!(out, b"23456");
+
+        out.clear();
+        assert_eq!(
+            copy_range(&image, 8, Some(100), &mut out, &|_| {}).unwrap(),
+            2
+        );
+        assert_eq!(copy_range(&image, 10, None, &mut out, &|_| {}).unwrap(), 0);
+        assert!(copy_range(&image, 11, None, &mut out, &|_| {}).is_err());
+    }
+}
@@ -0,0 +1,2274 @@
+//! CHD probing and the CHD-backed providers: 2048-byte passthrough for DVD
+//! (and unrecognized) CHDs, the user-data view of a CD's first data track,
+//! and raw views of its audio tracks and subcode.
+
+use anyhow::{anyhow, bail, Context, Result};
+use chd::metadata::{KnownMetadata, Metadata, MetadataTag};
+use chd::Chd;
+use clap::ValueEnum;
+use lru::LruCache;
+use serde::Deserialize;
+use std::{
+    cell::RefCell,
+    hash::{DefaultHasher, Hash, Hasher},
+    io::{Read, Seek},
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::Instant,
+};
+use tracing::info;
+
+use crate::inflight::Inflight;
+use crate::iso;
+use crate::latency;
+use crate::mapping;
+use crate::provider::{
+    BackingProvider, ImageKind, ProbeContext, Probed, ProviderFactory, SheetProvider,
+};
+use crate::shared_cache;
+use crate::sheet;
+use crate::vfs::{self, SourceMeta, SourceReader};
+
+pub const CD_FRAME_2352: usize = 2352;
+/// A 2352-byte frame followed by 96 bytes of subcode, as `chdman createcd`
+/// stores it.
+pub const CD_FRAME_2448: usize = 2448;
+const SUBCODE_BYTES: usize = CD_FRAME_2448 - CD_FRAME_2352;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CdPayloadKind {
+    Mode1_2048,
+    Mode2Form1_2048,
+    Mode2Form2_2324,
+    /// Subheader, data and EDC of every Mode 2 sector, Form 1 or 2
+    Mode2_2336,
+    /// Whole 2352-byte CDDA frames
+    Audio2352,
+    /// The 96 subcode bytes after each frame
+    Subcode96,
+}
+
+impl CdPayloadKind {
+    /// User-data bytes per sector.
+    pub const fn sector_size(self) -> usize {
+        match self {
+            CdPayloadKind::Mode1_2048 | CdPayloadKind::Mode2Form1_2048 => 2048,
+            CdPayloadKind::Mode2Form2_2324 => 2324,
+            CdPayloadKind::Mode2_2336 => 2336,
+            CdPayloadKind::Audio2352 => CD_FRAME_2352,
+            CdPayloadKind::Subcode96 => SUBCODE_BYTES,
+        }
+    }
+
+    /// Offset of the user data within a 2352-byte frame.
+    pub const fn payload_offset(self) -> usize {
+        match self {
+            CdPayloadKind::Mode1_2048 => 16,
+            CdPayloadKind::Mode2Form1_2048 => 24,
+            CdPayloadKind::Mode2Form2_2324 => 24,
+            CdPayloadKind::Mode2_2336 => 16,
+            CdPayloadKind::Audio2352 => 0,
+            CdPayloadKind::Subcode96 => CD_FRAME_2352,
+        }
+    }
+}
+
+/// `--audio-cd`: what to do with CHDs whose tracks are all audio.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AudioCdMode {
+    /// Skip them (logged at info level)
+    Hide,
+    /// Name.cue plus raw "Name (Track NN).bin" files
+    Bin,
+    /// Name.cue plus "Name (Track NN).wav" files
+    Wav,
+}
+
+/// `--form2-policy`: what to expose of Mode 2 Form 2 data (video, XA
+/// audio), which has no place in a 2048-byte ISO.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Form2Policy {
+    /// Leave it out
+    Hide,
+    /// "Name (Form2).bin" of 2324-byte sectors
+    Bin,
+    /// "Name (XA).bin" of 2336-byte sectors (subheader included), readable
+    /// whichever form each sector is
+    XaView,
+}
+
+impl Form2Policy {
+    /// Payload a Form 2 track or sector is exposed as, None to skip it.
+    fn payload(self) -> Option<CdPayloadKind> {
+        match self {
+            Form2Policy::Hide => None,
+            Form2Policy::Bin => Some(CdPayloadKind::Mode2Form2_2324),
+            Form2Policy::XaView => Some(CdPayloadKind::Mode2_2336),
+        }
+    }
+}
+
+/// `--tail-policy`: reads of hunks past the end of a CHD, which imperfect
+/// rips claim to have.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TailPolicy {
+    /// Fail with EIO
+    Eio,
+    /// Read zeros
+    Zero,
+    /// End the read short there, as at end of file
+    Truncate,
+}
+
+/// Independently locked parts of the frame cache.
+const CACHE_SHARDS: usize = 16;
+
+/// Consecutive frames of one provider land in the same shard, so eviction
+/// within a sequential read stays least-recently-used.
+const SHARD_RUN: u64 = 64;
+
+struct Shard {
+    lru: LruCache<(u64, u64), Vec<u8>>,
+}
+
+impl Shard {
+    /// Evict the least-recently-used frame, returning its size.
+    fn pop_lru(&mut self) -> Option<usize> {
+        self.lru.pop_lru().map(|(_k, v)| v.len())
+    }
+}
+
+/// Frame cache shared by every CD provider, bounded by entry count and an
+/// approximate byte budget. Keys are (provider cache id, frame index). Split
+/// into shards so concurrent readers of different files rarely contend.
+pub struct FrameCache {
+    shards: Vec<Mutex<Shard>>,
+    approx_bytes: AtomicUsize,
+    max_bytes: AtomicUsize,
+    /// Lower byte budget imposed by `--max-memory`
+    cap_bytes: AtomicUsize,
+}
+
+/// Per-shard entry capacity for a total of `entries`.
+fn shard_cap(entries: usize) -> NonZeroUsize {
+    let entries = if entries == 0 { 64 } else { entries };
+    NonZeroUsize::new(entries.div_ceil(CACHE_SHARDS)).expect("non-zero")
+}
+
+impl FrameCache {
+    pub fn new(entries: usize, max_bytes: usize) -> Self {
+        let cap = shard_cap(entries);
+
+        Self {
+            shards: (0..CACHE_SHARDS)
+                .map(|_| {
+                    Mutex::new(Shard {
+                        lru: LruCache::new(cap),
+                    })
+                })
+                .collect(),
+            approx_bytes: AtomicUsize::new(0),
+            max_bytes: AtomicUsize::new(max_bytes),
+            cap_bytes: AtomicUsize::new(usize::MAX),
+        }
+    }
+
+    fn shard(&self, (id, frame): (u64, u64)) -> &Mutex<Shard> {
+        let h = id.wrapping_mul(0x9E37_79B9_7F4A_7C15) ^ (frame / SHARD_RUN);
+        &self.shards[(h % CACHE_SHARDS as u64) as usize]
+    }
+
+    fn lock(shard: &Mutex<Shard>) -> std::sync::MutexGuard<'_, Shard> {
+        shard.lock().expect("frame_cache mutex poisoned")
+    }
+
+    fn over_budget(&self) -> bool {
+        let budget = self.max_bytes().min(self.cap_bytes.load(Ordering::Relaxed));
+        self.approx_bytes.load(Ordering::Relaxed) > budget
+    }
+
+    /// Byte budget as configured, whatever the cap.
+    pub fn max_bytes(&self) -> usize {
+        self.max_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Hold the cache to `bytes` below its configured budget, evicting
+    /// down to it now (None: lift the cap).
+    pub fn cap(&self, bytes: Option<usize>) {
+        self.cap_bytes
+            .store(bytes.unwrap_or(usize::MAX), Ordering::Relaxed);
+        self.shrink_all();
+    }
+
+    /// Evict from every shard in turn until the byte budget is met.
+    fn shrink_all(&self) {
+        for shard in &self.shards {
+            let mut shard = Self::lock(shard);
+            while self.over_budget() {
+                match shard.pop_lru() {
+                    Some(n) => {
+                        self.approx_bytes.fetch_sub(n, Ordering::Relaxed);
+                    }
+                    None => break,
+                }
+            }
+        }
+    }
+
+    /// (frames, approximate bytes) held right now.
+    pub fn usage(&self) -> (usize, usize) {
+        let frames = self.shards.iter().map(|s| Self::lock(s).lru.len()).sum();
+        (frames, self.approx_bytes.load(Ordering::Relaxed))
+    }
+
+    /// Drop every frame; returns how many there were.
+    pub fn clear(&self) -> usize {
+        let mut frames = 0;
+        for shard in &self.shards {
+            let mut shard = Self::lock(shard);
+            while let Some(n) = shard.pop_lru() {
+                self.approx_bytes.fetch_sub(n, Ordering::Relaxed);
+                frames += 1;
+            }
+        }
+        frames
+    }
+
+    /// Change both limits in place, evicting least-recently-used frames
+    /// until the cache fits.
+    pub fn resize(&self, entries: usize, max_bytes: usize) {
+        let cap = shard_cap(entries);
+        self.max_bytes.store(max_bytes, Ordering::Relaxed);
+
+        for shard in &self.shards {
+            let mut shard = Self::lock(shard);
+            while shard.lru.len() > cap.get() {
+                let n = shard.pop_lru().expect("non-empty");
+                self.approx_bytes.fetch_sub(n, Ordering::Relaxed);
+            }
+            shard.lru.resize(cap);
+        }
+
+        self.shrink_all();
+    }
+
+    fn get(&self, key: (u64, u64)) -> Option<Vec<u8>> {
+        Self::lock(self.shard(key)).lru.get(&key).cloned()
+    }
+
+    fn put(&self, key: (u64, u64), frame: Vec<u8>) {
+        let mut shard = Self::lock(self.shard(key));
+
+        self.approx_bytes.fetch_add(frame.len(), Ordering::Relaxed);
+        if let Some((_k, v)) = shard.lru.push(key, frame) {
+            self.approx_bytes.fetch_sub(v.len(), Ordering::Relaxed);
+        }
+
+        // Prefer evicting locally; only sweep other shards if this one
+        // cannot cover the overshoot on its own.
+        while self.over_budget() && shard.lru.len() > 1 {
+            let n = shard.pop_lru().expect("non-empty");
+            self.approx_bytes.fetch_sub(n, Ordering::Relaxed);
+        }
+        drop(shard);
+
+        if self.over_budget() {
+            self.shrink_all();
+        }
+    }
+}
+
+/// A cache id no other provider has.
+fn next_cache_id() -> u64 {
+    static NEXT: AtomicU64 = AtomicU64::new(1);
+    NEXT.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Key of a CHD's frames in the shared frame cache, from what identifies its
+/// content: the SHA-1 of its data where the header has one, else its path,
+/// size and mtime. Frames thus survive a re-index of an unchanged file and
+/// are shared by copies of it, but a replaced file never aliases the old
+/// one's. Falls back to a fresh id when the source cannot be stat'ed or has
+/// no mtime.
+fn content_cache_id(path: &Path, data_sha1: Option<[u8; 20]>) -> u64 {
+    let mut h = DefaultHasher::new();
+    match data_sha1 {
+        Some(digest) => ("sha1", digest).hash(&mut h),
+        None => match vfs::stat(path) {
+            Ok(SourceMeta {
+                size,
+                mtime: Some(mtime),
+                ..
+            }) => ("stat", path, size, mtime).hash(&mut h),
+            _ => return next_cache_id(),
+        },
+    }
+    h.finish()
+}
+
+/// Frames must not straddle hunks: chdman only writes CD hunks of whole
+/// frames, and nothing says where the rest of a split frame would be.
+fn check_frame_layout(hunk_size: usize, frame_stride: usize) -> Result<()> {
+    if frame_stride == 0 || hunk_size == 0 || hunk_size % frame_stride != 0 {
+        return Err(anyhow!(
+            "hunk size {hunk_size} is not a multiple of the {frame_stride}-byte CD frame; the CHD is malformed"
+        ));
+    }
+    Ok(())
+}
+
+/// `len` bytes at `at` of a decoded hunk or frame: a corrupt CHD can decode
+/// short, and that should fail the one read (EIO) rather than panic.
+fn bytes_at(buf: &[u8], at: usize, len: usize) -> Result<&[u8]> {
+    at.checked_add(len)
+        .and_then(|end| buf.get(at..end))
+        .ok_or_else(|| anyhow!("decoded {} bytes, short of {at}+{len}", buf.len()))
+}
+
+fn bytes_at_mut(buf: &mut [u8], at: usize, len: usize) -> Result<&mut [u8]> {
+    let have = buf.len();
+    at.checked_add(len)
+        .and_then(|end| buf.get_mut(at..end))
+        .ok_or_else(|| anyhow!("decoded {have} bytes, short of {at}+{len}"))
+}
+
+/// `mapping::hunk_of`, failing past the CHD's hunk range.
+fn hunk_of(n: u64, per_hunk: u64) -> Result<(u32, u64)> {
+    mapping::hunk_of(n, per_hunk)
+        .ok_or_else(|| anyhow!("position {n} is beyond the CHD's hunk range"))
+}
+
+/// Decoded hunks shared between concurrent reads of one file.
+type HunkFlights = Inflight<u32, Arc<Vec<u8>>>;
+
+/// 2048-byte passthrough of the decompressed CHD stream.
+pub struct PassthroughProvider {
+    path: PathBuf,
+    size: u64,
+    kind: ImageKind,
+    hunk_size: u64,
+    hunk_count: u32,
+    tail: TailPolicy,
+    /// Id in `--shared-cache`, if the CHD has a data SHA-1
+    shared_id: Option<u64>,
+    flights: HunkFlights,
+}
+
+impl std::fmt::Debug for PassthroughProvider {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PassthroughProvider")
+            .field("path", &self.path)
+            .field("size", &self.size)
+            .field("kind", &self.kind)
+            .finish()
+    }
+}
+
+impl BackingProvider for PassthroughProvider {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn kind(&self) -> ImageKind {
+        self.kind
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        if offset >= self.size || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let end = offset.saturating_add(buf.len() as u64).min(self.size);
+        let mut reader: Option<HunkReader> = None;
+        let mut pos = offset;
+
+        while pos < end {
+            let (hunk, in_hunk) = hunk_of(pos, self.hunk_size)?;
+            if hunk >= self.hunk_count {
+                match self.tail {
+                    TailPolicy::Eio => bail!(
+                        "{:?}: offset {} is in hunk {}, past the {} stored",
+                        self.path,
+                        pos,
+                        hunk,
+                        self.hunk_count
+                    ),
+                    TailPolicy::Truncate => break,
+                    TailPolicy::Zero => {
+                        let take = (self.hunk_size - in_hunk).min(end - pos);
+                        let out = (pos - offset) as usize;
+                        buf[out..out + take as usize].fill(0);
+                        pos += take;
+                        continue;
+                    }
+                }
+            }
+            let in_hunk = in_hunk as usize;
+
+            let data = self.flights.run(hunk, || {
+                let r = match &mut reader {
+                    Some(r) => r,
+                    None => reader.insert(HunkReader::open(&self.path)?),
+                };
+                shared_cache::hunk(self.shared_id, hunk, || r.decode_hunk(hunk)).map(Arc::new)
+            })?;
+
+            let take = (self.hunk_size as usize - in_hunk).min((end - pos) as usize);
+            let out = (pos - offset) as usize;
+            let src = bytes_at(&data, in_hunk, take)
+                .with_context(|| format!("{:?}: hunk {hunk}", self.path));
+            buf[out..out + take].copy_from_slice(src?);
+            recycle_hunk(data);
+            pos += take as u64;
+        }
+
+        Ok((pos - offset) as usize)
+    }
+}
+
+/// User-data view of one CD track: `size` bytes of `payload_kind` sectors
+/// starting at frame `first_data_lba`.
+pub struct CdProvider {
+    path: PathBuf,
+    cache_id: u64,
+    /// Id in `--shared-cache`, if the CHD has a data SHA-1
+    shared_id: Option<u64>,
+    first_data_lba: u64,
+    payload_kind: CdPayloadKind,
+    fixup: FrameFixup,
+    size: u64,
+    hunk_size: usize,
+    hunk_count: u32,
+    tail: TailPolicy,
+    /// Bytes per frame in the hunk: `CD_FRAME_2352` or `CD_FRAME_2448`
+    frame_stride: usize,
+    frame_cache: Arc<FrameCache>,
+    flights: HunkFlights,
+}
+
+/// Rewrites a frame's payload after it leaves the cache.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum FrameFixup {
+    None,
+    /// Swap the bytes of each 16-bit CDDA sample
+    SwapSamples,
+    /// Raw interleaved subcode (`RW_RAW`) -> P..W channels of 12 bytes each
+    DeinterleaveSubcode,
+}
+
+impl std::fmt::Debug for CdProvider {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CdProvider")
+            .field("path", &self.path)
+            .field("first_data_lba", &self.first_data_lba)
+            .field("payload_kind", &self.payload_kind)
+            .field("size", &self.size)
+            .finish()
+    }
+}
+
+impl CdProvider {
+    /// Frame `frame_index`; none if it lies past the stored hunks and
+    /// `--tail-policy truncate` ends the read there.
+    fn get_cd_frame(&self, frame_index: u64) -> Result<Option<Vec<u8>>> {
+        let cached = self.frame_cache.get((self.cache_id, frame_index));
+        latency::frame_cache(cached.is_some());
+        if let Some(buf) = cached {
+            return Ok(Some(buf));
+        }
+
+        check_frame_layout(self.hunk_size, self.frame_stride)?;
+        let (hunk_index, frame_off) =
+            mapping::frame_in_hunk(frame_index, self.frame_stride, self.hunk_size)
+                .ok_or_else(|| anyhow!("frame {frame_index} is beyond the CHD's hunk range"))?;
+        if hunk_index >= self.hunk_count {
+            return match self.tail {
+                TailPolicy::Eio => Err(anyhow!(
+                    "{:?}: frame {} is in hunk {}, past the {} stored",
+                    self.path,
+                    frame_index,
+                    hunk_index,
+                    self.hunk_count
+                )),
+                TailPolicy::Zero => Ok(Some(vec![0; self.frame_stride])),
+                TailPolicy::Truncate => Ok(None),
+            };
+        }
+
+        let hunk_buf = self.flights.run(hunk_index, || {
+            shared_cache::hunk(self.shared_id, hunk_index, || {
+                HunkReader::open(&self.path)?.decode_hunk(hunk_index)
+            })
+            .map(Arc::new)
+        })?;
+
+        let owned = bytes_at(&hunk_buf, frame_off, self.frame_stride)
+            .with_context(|| format!("{:?}: hunk {hunk_index}", self.path))
+            .map(<[u8]>::to_vec);
+        recycle_hunk(hunk_buf);
+        let owned = owned?;
+
+        self.frame_cache
+            .put((self.cache_id, frame_index), owned.clone());
+
+        Ok(Some(owned))
+    }
+}
+
+impl BackingProvider for CdProvider {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn kind(&self) -> ImageKind {
+        match self.payload_kind {
+            CdPayloadKind::Audio2352 => ImageKind::Audio,
+            _ => ImageKind::Cd,
+        }
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        let per_sector = self.payload_kind.sector_size();
+        let payload_start = self.payload_kind.payload_offset();
+
+        if offset >= self.size || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let end = offset.saturating_add(buf.len() as u64).min(self.size);
+        let mut pos = offset;
+
+        while pos < end {
+            let at = mapping::cd_byte(pos, self.first_data_lba, self.payload_kind);
+            let Some(mut sec) = self.get_cd_frame(at.frame)? else {
+                break;
+            };
+
+            let payload = bytes_at_mut(&mut sec, payload_start, per_sector)
+                .with_context(|| format!("{:?}: frame {}", self.path, at.frame))?;
+            match self.fixup {
+                FrameFixup::None => {}
+                FrameFixup::SwapSamples => payload.chunks_exact_mut(2).for_each(|s| s.swap(0, 1)),
+                FrameFixup::DeinterleaveSubcode => deinterleave_subcode(payload),
+            }
+            let take = at.left.min((end - pos) as usize);
+            let out = (pos - offset) as usize;
+            buf[out..out + take].copy_from_slice(bytes_at(&sec, at.byte, take)?);
+            pos += take as u64;
+        }
+
+        Ok((pos - offset) as usize)
+    }
+}
+
+/// A CD audio track as a RIFF/WAVE file: 44-byte header, then the
+/// little-endian 16-bit stereo 44.1 kHz samples.
+#[derive(Debug)]
+pub struct WavProvider {
+    header: [u8; 44],
+    pcm: CdProvider,
+}
+
+impl WavProvider {
+    fn new(pcm: CdProvider) -> Self {
+        let data_len = u32::try_from(pcm.size()).unwrap_or(u32::MAX - 36);
+        let mut header = [0u8; 44];
+
+        header[0..4].copy_from_slice(b"RIFF");
+        header[4..8].copy_from_slice(&(36 + data_len).to_le_bytes());
+        header[8..16].copy_from_slice(b"WAVEfmt ");
+        header[16..20].copy_from_slice(&16u32.to_le_bytes());
+        header[20..22].copy_from_slice(&1u16.to_le_bytes()); // PCM
+        header[22..24].copy_from_slice(&2u16.to_le_bytes()); // channels
+        header[24..28].copy_from_slice(&44_100u32.to_le_bytes());
+        header[28..32].copy_from_slice(&176_400u32.to_le_bytes()); // bytes/s
+        header[32..34].copy_from_slice(&4u16.to_le_bytes()); // block align
+        header[34..36].copy_from_slice(&16u16.to_le_bytes()); // bits/sample
+        header[36..40].copy_from_slice(b"data");
+        header[40..44].copy_from_slice(&data_len.to_le_bytes());
+
+        Self { header, pcm }
+    }
+}
+
+impl BackingProvider for WavProvider {
+    fn size(&self) -> u64 {
+        self.header.len() as u64 + self.pcm.size()
+    }
+
+    fn kind(&self) -> ImageKind {
+        ImageKind::Audio
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        let hlen = self.header.len() as u64;
+        if offset >= hlen {
+            return self.pcm.read_at(offset - hlen, buf);
+        }
+
+        let n = buf.len().min((hlen - offset) as usize);
+        buf[..n].copy_from_slice(&self.header[offset as usize..offset as usize + n]);
+        if n == buf.len() {
+            return Ok(n);
+        }
+        Ok(n + self.pcm.read_at(0, &mut buf[n..])?)
+    }
+}
+
+/// The user data of several data tracks back to back (`--merged-iso`), for
+/// tools that expect one image of a multi-track data disc.
+#[derive(Debug)]
+pub struct MergedDataProvider {
+    /// Each track and where it starts in the merged image
+    tracks: Vec<(u64, CdProvider)>,
+    size: u64,
+}
+
+impl MergedDataProvider {
+    fn new(tracks: Vec<CdProvider>) -> Self {
+        let mut size = 0;
+        let tracks = tracks
+            .into_iter()
+            .map(|t| {
+                let at = size;
+                size += t.size;
+                (at, t)
+            })
+            .collect();
+        Self { tracks, size }
+    }
+}
+
+impl BackingProvider for MergedDataProvider {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn kind(&self) -> ImageKind {
+        ImageKind::Cd
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        if offset >= self.size || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let end = offset.saturating_add(buf.len() as u64).min(self.size);
+        let mut i = self.tracks.partition_point(|(at, _)| *at <= offset) - 1;
+        let mut pos = offset;
+
+        while pos < end {
+            let (at, track) = &self.tracks[i];
+            let take = ((at + track.size).min(end) - pos) as usize;
+            let out = (pos - offset) as usize;
+            let n = track.read_at(pos - at, &mut buf[out..out + take])?;
+            pos += n as u64;
+            if n < take {
+                break;
+            }
+            i += 1;
+        }
+
+        Ok((pos - offset) as usize)
+    }
+}
+
+/// Every track of a CD as raw 2352-byte frames, back to back as `chdman
+/// extractcd` writes them: stored pregaps kept, the padding chdman adds
+/// after each track left out.
+#[derive(Debug)]
+pub struct FullDiscProvider {
+    frames: CdProvider,
+    spans: Vec<DiscSpan>,
+    /// Audio frames to little-endian (no `--audio-byteswap`)
+    swap_audio: bool,
+    size: u64,
+}
+
+/// One track's frames in a `FullDiscProvider`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct DiscSpan {
+    /// First frame in the exposed file
+    out: u64,
+    /// First frame in the CHD
+    chd: u64,
+    frames: u64,
+    audio: bool,
+}
+
+impl FullDiscProvider {
+    fn new(frames: CdProvider, tracks: &[TrackInfo], swap_audio: bool) -> Self {
+        let spans = disc_spans(tracks);
+        let size = spans.last().map_or(0, |s| s.out + s.frames) * CD_FRAME_2352 as u64;
+        Self {
+            frames,
+            spans,
+            swap_audio,
+            size,
+        }
+    }
+}
+
+/// Where each track's frames, stored pregap included, land in the full-disc
+/// file.
+fn disc_spans(tracks: &[TrackInfo]) -> Vec<DiscSpan> {
+    let mut out = 0;
+    tracks
+        .iter()
+        .zip(track_extents(tracks))
+        .map(|(t, (start, frames))| {
+            let pregap = t.stored_pregap() as u64;
+            let span = DiscSpan {
+                out,
+                chd: start - pregap,
+                frames: frames + pregap,
+                audio: t.kind == TrackKind::Audio,
+            };
+            out += span.frames;
+            span
+        })
+        .collect()
+}
+
+impl BackingProvider for FullDiscProvider {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn kind(&self) -> ImageKind {
+        ImageKind::Disc
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        if offset >= self.size || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let end = offset.saturating_add(buf.len() as u64).min(self.size);
+        let mut pos = offset;
+
+        while pos < end {
+            let frame = pos / CD_FRAME_2352 as u64;
+            let in_frame = (pos % CD_FRAME_2352 as u64) as usize;
+            let span = self.spans[self.spans.partition_point(|s| s.out + s.frames <= frame)];
+            let Some(mut sec) = self.frames.get_cd_frame(span.chd + frame - span.out)? else {
+                break;
+            };
+
+            let sec = bytes_at_mut(&mut sec, 0, CD_FRAME_2352)?;
+            if span.audio && self.swap_audio {
+                sec.chunks_exact_mut(2).for_each(|s| s.swap(0, 1));
+            }
+            let take = (CD_FRAME_2352 - in_frame).min((end - pos) as usize);
+            let out = (pos - offset) as usize;
+            buf[out..out + take].copy_from_slice(&sec[in_frame..in_frame + take]);
+            pos += take as u64;
+        }
+
+        Ok((pos - offset) as usize)
+    }
+}
+
+/// Registry entry for `*.chd`.
+pub struct ChdFactory;
+
+impl ProviderFactory for ChdFactory {
+    fn matches(&self, path: &Path) -> bool {
+        path.extension()
+            .and_then(|s| s.to_str())
+            .map(|s| s.eq_ignore_ascii_case("chd"))
+            == Some(true)
+    }
+
+    fn probe(&self, chd_path: &Path, ctx: &ProbeContext) -> Result<Option<Probed>> {
+        let mut chd = Chd::open(vfs::reader(chd_path)?, None)?;
+
+        let hdr = chd.header();
+        let sha1 = hdr.sha1();
+        let data_sha1 = hdr.raw_sha1().or(sha1);
+        let hunk_size = hdr.hunk_size();
+        let unit_bytes = hdr.unit_bytes() as usize;
+        let logical_bytes = hdr.logical_bytes();
+        let hunk_count = hdr.hunk_count();
+        let shared_id = shared_cache::content_id(data_sha1, hunk_size, hdr.unit_bytes());
+
+        let stem = chd_path
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .unwrap_or("unknown");
+
+        if hunk_size == 0 {
+            return Err(anyhow!("invalid hunk size"));
+        }
+        // Hunks are otherwise first decoded when read.
+        if ctx.strict && hunk_count > 0 {
+            let (mut cmp, mut buf) = (Vec::new(), chd.get_hunksized_buffer());
+            let decoded = chd
+                .hunk(0)
+                .and_then(|mut hk| hk.read_hunk_in(&mut cmp, &mut buf));
+            if let Err(e) = decoded {
+                ctx.ambiguous(
+                    chd_path,
+                    format!("hunk 0 does not decode ({e}); unsupported codec?"),
+                )?;
+            }
+        }
+
+        let passthrough = |kind, size| PassthroughProvider {
+            path: chd_path.to_path_buf(),
+            size,
+            kind,
+            hunk_size: hunk_size as u64,
+            hunk_count,
+            tail: ctx.tail,
+            shared_id,
+            flights: HunkFlights::default(),
+        };
+
+        if unit_bytes == 2048 {
+            let iso_size = logical_bytes;
+            let name = format!("{stem}.{}", ctx.extensions.dvd);
+            return Ok(Some(Probed {
+                name,
+                provider: Arc::new(passthrough(ImageKind::Dvd, iso_size)),
+                sha1,
+                extras: Vec::new(),
+            }));
+        }
+
+        if unit_bytes == CD_FRAME_2352 || unit_bytes == CD_FRAME_2448 {
+            check_frame_layout(hunk_size as usize, unit_bytes)?;
+            let total_frames = logical_bytes / unit_bytes as u64;
+
+            let mut rf = vfs::reader(chd_path)?;
+            let mut tracks = parse_cd_toc_from_metadata(&mut chd, &mut rf)?;
+            if logical_bytes % unit_bytes as u64 != 0 {
+                ctx.ambiguous(
+                    chd_path,
+                    format!(
+                        "logical size {logical_bytes} is not a whole number of {unit_bytes}-byte frames"
+                    ),
+                )?;
+            }
+            for (number, claimed, kept) in clamp_track_frames(&mut tracks, total_frames) {
+                ctx.ambiguous(
+                    chd_path,
+                    format!(
+                        "track {number} claims {claimed} frames but only {kept} fit in the {logical_bytes} logical bytes stored; exposing {kept} (the rip may be short)"
+                    ),
+                )?;
+            }
+
+            if tracks.is_empty() {
+                ctx.ambiguous(
+                    chd_path,
+                    "no CD track metadata; mapping data and audio regions",
+                )?;
+                tracks = region_map(chd_path, total_frames, unit_bytes, hunk_size as usize)?;
+            }
+
+            // chdman gives every track of a disc the same subcode type.
+            let sub_type = tracks
+                .iter()
+                .map(|t| t.subtype)
+                .find(|s| *s != SubType::None);
+
+            // Every view of the disc reads the same frames.
+            let cache_id = content_cache_id(chd_path, data_sha1);
+            let cd_provider =
+                |first_data_lba, payload_kind: CdPayloadKind, frames: u64| CdProvider {
+                    path: chd_path.to_path_buf(),
+                    cache_id,
+                    shared_id,
+                    first_data_lba,
+                    payload_kind,
+                    fixup: match payload_kind {
+                        // CHDs store CDDA big-endian; .bin/.cue rips are little-endian.
+                        CdPayloadKind::Audio2352 if !ctx.audio_byteswap => FrameFixup::SwapSamples,
+                        CdPayloadKind::Subcode96 if sub_type == Some(SubType::RwRaw) => {
+                            FrameFixup::DeinterleaveSubcode
+                        }
+                        _ => FrameFixup::None,
+                    },
+                    size: mapping::cd_size(frames, payload_kind),
+                    hunk_size: hunk_size as usize,
+                    hunk_count,
+                    tail: ctx.tail,
+                    frame_stride: unit_bytes,
+                    frame_cache: Arc::clone(ctx.frame_cache),
+                    flights: HunkFlights::default(),
+                };
+            // "`name`.bin" of every track and its "`name`.cue".
+            let full_disc = |name: &str, tracks: &[TrackInfo]| {
+                let file = format!("{name}.bin");
+                let cue = sheet::disc_cue(&file, tracks);
+                let frames = cd_provider(0, CdPayloadKind::Audio2352, total_frames);
+                let files: [(String, Arc<dyn BackingProvider>); 2] = [
+                    (
+                        file,
+                        Arc::new(FullDiscProvider::new(frames, tracks, !ctx.audio_byteswap)),
+                    ),
+                    (format!("{name}.cue"), Arc::new(SheetProvider::new(cue))),
+                ];
+                files
+            };
+            let full = format!("{stem} (Full)");
+
+            if !tracks.is_empty() && tracks.iter().all(|t| t.kind == TrackKind::Audio) {
+                let ext = match ctx.audio_cd {
+                    AudioCdMode::Hide => {
+                        info!(
+                            "{:?}: audio-only CD, not exposed (see --audio-cd)",
+                            chd_path
+                        );
+                        return Ok(None);
+                    }
+                    AudioCdMode::Bin => "bin",
+                    AudioCdMode::Wav => "wav",
+                };
+
+                let mut files = Vec::new();
+                let mut extras: Vec<(String, Arc<dyn BackingProvider>)> = Vec::new();
+                for (t, (start, frames)) in tracks.iter().zip(track_extents(&tracks)) {
+                    // Keep a stored pregap; the cue sheet marks it as INDEX 00.
+                    let pregap = t.stored_pregap() as u64;
+                    let name = format!("{stem} (Track {:02}).{ext}", t.number);
+                    let pcm =
+                        cd_provider(start - pregap, CdPayloadKind::Audio2352, frames + pregap);
+                    let provider: Arc<dyn BackingProvider> = match ctx.audio_cd {
+                        // WAVE is little-endian whatever --audio-byteswap says.
+                        AudioCdMode::Wav => Arc::new(WavProvider::new(CdProvider {
+                            fixup: FrameFixup::SwapSamples,
+                            ..pcm
+                        })),
+                        _ => Arc::new(pcm),
+                    };
+                    files.push(name.clone());
+                    extras.push((name, provider));
+                }
+
+                let layout = sheet::layout(&tracks, &files);
+                if ctx.toc {
+                    extras.push((
+                        format!("{stem}.toc"),
+                        Arc::new(SheetProvider::new(sheet::toc(&layout))),
+                    ));
+                }
+
+                if ctx.full_disc {
+                    extras.extend(full_disc(&full, &tracks));
+                }
+
+                let cue = sheet::cue(&layout, ctx.audio_cd == AudioCdMode::Wav);
+                return Ok(Some(Probed {
+                    name: format!("{stem}.cue"),
+                    provider: Arc::new(SheetProvider::new(cue)),
+                    sha1,
+                    extras,
+                }));
+            }
+
+            let mut extras: Vec<(String, Arc<dyn BackingProvider>)> = Vec::new();
+            if ctx.audio_tracks {
+                for (t, (start, frames)) in tracks.iter().zip(track_extents(&tracks)) {
+                    if t.kind == TrackKind::Audio {
+                        // `--pregap-audio`: from INDEX 00, as the audio CD views.
+                        let pregap = match ctx.pregap_audio {
+                            true => t.stored_pregap() as u64,
+                            false => 0,
+                        };
+                        extras.push((
+                            format!("{stem} (Track {:02}).bin", t.number),
+                            Arc::new(cd_provider(
+                                start - pregap,
+                                CdPayloadKind::Audio2352,
+                                frames + pregap,
+                            )),
+                        ));
+                    }
+                }
+            }
+
+            let (first_lba, payload, track_frames) = match first_data_track(&tracks, ctx.form2) {
+                Some(toc) => toc,
+                // CD-i and some Neo Geo CD discs: raw Mode 2 tracks with no
+                // one user data size, served as the raw disc with its cue.
+                None if tracks.iter().any(|t| t.kind == TrackKind::Mode2Raw) => {
+                    info!(
+                        "{:?}: raw Mode 2 data track, exposing the disc as {stem}.bin/.cue",
+                        chd_path
+                    );
+                    let [bin, (name, cue)] = full_disc(stem, &tracks);
+                    extras.insert(0, bin);
+                    return Ok(Some(Probed {
+                        name,
+                        provider: cue,
+                        sha1,
+                        extras,
+                    }));
+                }
+                None => {
+                    ctx.ambiguous(
+                        chd_path,
+                        match tracks.is_empty() {
+                            true => "no CD track metadata; scanning for the first data sector",
+                            false => "no exposable data track in the CD metadata; scanning for the first data sector",
+                        },
+                    )?;
+                    match quick_scan_first_data(
+                        chd_path,
+                        total_frames,
+                        unit_bytes,
+                        hunk_size as usize,
+                        ctx.form2,
+                    )? {
+                        Scan::Data(first_lba, payload) => (first_lba, payload, None),
+                        // Without a TOC there are no tracks to expose it by.
+                        Scan::Audio => {
+                            info!(
+                                "{:?}: no data sector, only audio, at the start of the disc; not exposed",
+                                chd_path
+                            );
+                            return Ok(None);
+                        }
+                        Scan::Nothing => (0, CdPayloadKind::Mode1_2048, None),
+                    }
+                }
+            };
+
+            let name = match payload {
+                CdPayloadKind::Mode1_2048 | CdPayloadKind::Mode2Form1_2048 => {
+                    format!("{stem}.{}", ctx.extensions.cd_data)
+                }
+                CdPayloadKind::Mode2Form2_2324 => {
+                    format!("{stem} (Form2).{}", ctx.extensions.form2)
+                }
+                CdPayloadKind::Mode2_2336 => format!("{stem} (XA).{}", ctx.extensions.xa),
+                CdPayloadKind::Audio2352 | CdPayloadKind::Subcode96 => {
+                    unreachable!("never a data track")
+                }
+            };
+
+            let frames = data_frames(track_frames, first_lba, total_frames);
+            let provider = cd_provider(first_lba, payload, frames);
+
+            // Mednafen and Beetle load these only as a cue sheet of the whole disc.
+            let system = iso::system_header(&provider).filter(|c| c.wants_cue());
+            if let Some(system) = system.filter(|_| !ctx.full_disc) {
+                info!(
+                    "{:?}: {} disc, also exposing \"{stem} (Full).cue\"",
+                    chd_path,
+                    system.dir()
+                );
+            }
+            if ctx.full_disc || system.is_some() {
+                // Without a TOC, the whole disc is one track of the scanned mode.
+                let whole = [TrackInfo {
+                    number: 1,
+                    kind: match payload {
+                        CdPayloadKind::Mode1_2048 => TrackKind::Mode1,
+                        _ => TrackKind::Mode2Raw,
+                    },
+                    frames: u32::try_from(total_frames).unwrap_or(u32::MAX),
+                    pregap: 0,
+                    pregap_stored: false,
+                    subtype: SubType::None,
+                    postgap: 0,
+                }];
+                extras.extend(full_disc(
+                    &full,
+                    match tracks.is_empty() {
+                        true => &whole,
+                        false => &tracks,
+                    },
+                ));
+            }
+
+            if ctx.merged_iso {
+                let data = merged_data_tracks(&tracks);
+                if data.len() > 1 {
+                    let data = data
+                        .into_iter()
+                        .map(|(start, payload, frames)| cd_provider(start, payload, frames))
+                        .collect();
+                    extras.push((
+                        format!("{stem} (Merged).{}", ctx.extensions.cd_data),
+                        Arc::new(MergedDataProvider::new(data)),
+                    ));
+                }
+            }
+
+            // Covers the same sectors as the exposed image.
+            if ctx.subchannel && unit_bytes == CD_FRAME_2448 && sub_type.is_some() {
+                extras.push((
+                    format!("{stem}.sub"),
+                    Arc::new(cd_provider(first_lba, CdPayloadKind::Subcode96, frames)),
+                ));
+            }
+
+            return Ok(Some(Probed {
+                name,
+                provider: Arc::new(provider),
+                sha1,
+                extras,
+            }));
+        }
+
+        let name = format!("{stem}.{}", ctx.extensions.hdd);
+        Ok(Some(Probed {
+            name,
+            provider: Arc::new(passthrough(ImageKind::Raw, logical_bytes)),
+            sha1,
+            extras: Vec::new(),
+        }))
+    }
+}
+
+/// Read the CD TOC from CHD metadata (CHTR/CHT2), sorted by track number.
+/// Empty when the CHD carries none.
+pub fn parse_cd_toc_from_metadata<R: Read + Seek>(
+    chd: &mut Chd<R>,
+    file: &mut R,
+) -> Result<Vec<TrackInfo>> {
+    let mut tracks: Vec<TrackInfo> = Vec::new();
+
+    let it = chd.metadata_refs();
+    for mref in it {
+        let md: Metadata = mref.read(file)?;
+        let tag = md.metatag;
+
+        if tag != KnownMetadata::CdRomTrack.metatag() && tag != KnownMetadata::CdRomTrack2.metatag()
+        {
+            continue;
+        }
+
+        let s = String::from_utf8_lossy(&md.value).to_string();
+        if let Some(ti) = parse_track_line(&s) {
+            tracks.push(ti);
+        }
+    }
+
+    tracks.sort_by_key(|t| t.number);
+    Ok(tracks)
+}
+
+/// chdman pads every track to a multiple of this many frames.
+const CD_TRACK_PADDING: u64 = 4;
+
+/// (first frame, frame count) of each track's own data in the CHD frame
+/// stream. Tracks are stored back to back, each padded to
+/// `CD_TRACK_PADDING`; a pregap counts towards `FRAMES` only when its data
+/// is stored (`PGTYPE:V...`), and postgaps are never stored.
+pub fn track_extents(tracks: &[TrackInfo]) -> Vec<(u64, u64)> {
+    let mut pos = 0u64;
+
+    tracks
+        .iter()
+        .map(|t| {
+            let frames = t.frames as u64;
+            let stored_pregap = t.stored_pregap() as u64;
+            let extent = (pos + stored_pregap, frames.saturating_sub(stored_pregap));
+            pos += frames.next_multiple_of(CD_TRACK_PADDING);
+            extent
+        })
+        .collect()
+}
+
+/// Cut each track's `FRAMES` to what the CHD stores, `total_frames` in all:
+/// some CHDs claim more, and reads past the stored data fail. Returns
+/// (track, claimed, kept) for every track cut short.
+pub fn clamp_track_frames(tracks: &mut [TrackInfo], total_frames: u64) -> Vec<(u32, u32, u32)> {
+    let mut pos = 0u64;
+    let mut cut = Vec::new();
+
+    for t in tracks.iter_mut() {
+        let room = total_frames.saturating_sub(pos).min(u32::MAX as u64) as u32;
+        if t.frames > room {
+            cut.push((t.number, t.frames, room));
+            t.frames = room;
+        }
+        pos += (t.frames as u64).next_multiple_of(CD_TRACK_PADDING);
+    }
+    cut
+}
+
+/// The first track with exposable user data: (first frame, payload kind,
+/// frames in track).
+pub fn first_data_track(
+    tracks: &[TrackInfo],
+    form2: Form2Policy,
+) -> Option<(u64, CdPayloadKind, Option<u64>)> {
+    tracks
+        .iter()
+        .zip(track_extents(tracks))
+        .find_map(|(t, (start, frames))| {
+            let payload = match t.kind {
+                TrackKind::Mode1 => CdPayloadKind::Mode1_2048,
+                TrackKind::Mode2Form1 => CdPayloadKind::Mode2Form1_2048,
+                TrackKind::Mode2Form2 => form2.payload()?,
+                _ => return None,
+            };
+            Some((start, payload, Some(frames)))
+        })
+}
+
+/// Frames of the data track at `first_lba`: its own count, or without a TOC
+/// the rest of the disc, none if the scan landed past the stored frames.
+fn data_frames(track_frames: Option<u64>, first_lba: u64, total_frames: u64) -> u64 {
+    track_frames.unwrap_or_else(|| total_frames.saturating_sub(first_lba))
+}
+
+/// (first frame, payload, frames) of every track with 2048-byte user data,
+/// pregaps left out: the parts of `--merged-iso`.
+fn merged_data_tracks(tracks: &[TrackInfo]) -> Vec<(u64, CdPayloadKind, u64)> {
+    tracks
+        .iter()
+        .zip(track_extents(tracks))
+        .filter_map(|(t, (start, frames))| match t.kind {
+            TrackKind::Mode1 => Some((start, CdPayloadKind::Mode1_2048, frames)),
+            TrackKind::Mode2Form1 => Some((start, CdPayloadKind::Mode2Form1_2048, frames)),
+            _ => None,
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone)]
+pub struct TrackInfo {
+    pub number: u32,
+    pub kind: TrackKind,
+    pub frames: u32,
+    pub pregap: u32,
+    /// `PGTYPE:V...`: the pregap's frames are stored in the CHD
+    pub pregap_stored: bool,
+    pub subtype: SubType,
+    /// Never stored in the CHD, so it does not affect `track_extents`
+    pub postgap: u32,
+}
+
+impl TrackInfo {
+    /// Pregap frames present in the CHD (counted in `frames`).
+    pub fn stored_pregap(&self) -> u32 {
+        if self.pregap_stored {
+            self.pregap
+        } else {
+            0
+        }
+    }
+}
+
+/// `SUBTYPE:` of a track: how its subcode is stored, if at all.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubType {
+    None,
+    /// Deinterleaved ("cooked") P..W channels
+    Rw,
+    /// Raw, interleaved as read from the disc
+    RwRaw,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrackKind {
+    Audio,
+    Mode1,
+    Mode2Form1,
+    Mode2Form2,
+    Mode2Raw,
+}
+
+pub fn parse_track_line(s: &str) -> Option<TrackInfo> {
+    let mut number = None;
+    let mut frames = 0u32;
+    let mut pregap = 0u32;
+    let mut postgap = 0u32;
+    let mut pregap_stored = false;
+    let mut subtype = SubType::None;
+    let mut kind = None::<TrackKind>;
+
+    for tok in s.split(|c: char| c.is_whitespace() || c == ',') {
+        if tok.is_empty() {
+            continue;
+        }
+
+        if let Some((k, v)) = tok.split_once(':') {
+            match k {
+                "TRACK" => number = v.parse().ok(),
+                "FRAMES" => frames = v.parse().unwrap_or(0),
+                "PREGAP" => pregap = v.parse().unwrap_or(0),
+                "POSTGAP" => postgap = v.parse().unwrap_or(0),
+                "PGTYPE" => pregap_stored = v.starts_with('V'),
+                "SUBTYPE" => {
+                    subtype = match v {
+                        "RW" => SubType::Rw,
+                        "RW_RAW" => SubType::RwRaw,
+                        _ => SubType::None,
+                    }
+                }
+                "TYPE" => {
+                    kind = Some(match v {
+                        "MODE1" | "MODE1_RAW" | "MODE1/2048" | "MODE1/2352" => TrackKind::Mode1,
+                        "MODE2/2048" | "MODE2_FORM1" => TrackKind::Mode2Form1,
+                        "MODE2/2324" | "MODE2_FORM2" => TrackKind::Mode2Form2,
+                        "MODE2/2352" | "MODE2_RAW" | "MODE2" | "MODE2_FORM_MIX" | "MODE2/2336"
+                        | "CDI/2352" => TrackKind::Mode2Raw,
+                        "AUDIO" => TrackKind::Audio,
+                        other => {
+                            if other.starts_with("MODE1") {
+                                TrackKind::Mode1
+                            } else if other.starts_with("MODE2") && other.contains("2048") {
+                                TrackKind::Mode2Form1
+                            } else if other.starts_with("MODE2") && other.contains("2324") {
+                                TrackKind::Mode2Form2
+                            } else if other.starts_with("MODE2") || other.starts_with("CDI") {
+                                TrackKind::Mode2Raw
+                            } else {
+                                TrackKind::Audio
+                            }
+                        }
+                    })
+                }
+                _ => {}
+            }
+        }
+    }
+
+    Some(TrackInfo {
+        number: number?,
+        kind: kind?,
+        frames,
+        pregap,
+        pregap_stored,
+        subtype,
+        postgap,
+    })
+}
+
+/// Raw subcode carries one bit of each channel P..W per byte (P in bit 7);
+/// regroup it into eight 12-byte channels, as `.sub` files hold it.
+fn deinterleave_subcode(raw: &mut [u8]) {
+    let mut out = [0u8; SUBCODE_BYTES];
+
+    for (i, &b) in raw.iter().enumerate() {
+        for ch in 0..8 {
+            if b & (0x80 >> ch) != 0 {
+                out[ch * 12 + i / 8] |= 0x80 >> (i % 8);
+            }
+        }
+    }
+
+    raw.copy_from_slice(&out);
+}
+
+/// Frames the fallback scan looks at, from the start of the disc.
+const SCAN_FRAMES: u64 = 2000;
+
+/// Hunks the fallback scan decodes at once.
+const SCAN_WORKERS: usize = 4;
+
+/// Audible frames without a data sector (8 s) after which the scan takes a
+/// disc for an audio CD and stops.
+const AUDIO_BAIL_FRAMES: u64 = 600;
+
+/// Sync pattern opening every raw data sector.
+const SECTOR_SYNC: [u8; 12] = [
+    0x00, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x00,
+];
+
+/// What the fallback scan found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scan {
+    /// First data sector and how it is exposed
+    Data(u64, CdPayloadKind),
+    /// Only audio up to `AUDIO_BAIL_FRAMES` or the end of the scan
+    Audio,
+    /// Neither, e.g. silence throughout
+    Nothing,
+}
+
+/// Fallback when metadata is missing: decode the first `SCAN_FRAMES` frames
+/// hunk by hunk on up to `SCAN_WORKERS` threads, each with its own handle
+/// on the CHD at `path`, and find the first valid data sector.
+pub fn quick_scan_first_data(
+    path: &Path,
+    total_frames: u64,
+    frame_stride: usize,
+    hunk_size: usize,
+    form2: Form2Policy,
+) -> Result<Scan> {
+    check_frame_layout(hunk_size, frame_stride)?;
+    let per_hunk = (hunk_size / frame_stride) as u64;
+    let scan_limit = total_frames.min(SCAN_FRAMES);
+    let hunks = scan_limit.div_ceil(per_hunk);
+    let workers = thread::available_parallelism()
+        .map_or(1, |n| n.get())
+        .min(SCAN_WORKERS)
+        .min(hunks as usize)
+        .max(1);
+
+    let next = AtomicU64::new(0);
+    let found = Mutex::new(None::<(u64, CdPayloadKind)>);
+    let audible = AtomicU64::new(0);
+    let first_found = || found.lock().expect("scan mutex poisoned").map(|(f, _)| f);
+
+    let results: Vec<Result<()>> = thread::scope(|s| {
+        let handles: Vec<_> = (0..workers)
+            .map(|_| {
+                s.spawn(|| -> Result<()> {
+                    let mut reader = HunkReader::open(path)?;
+                    loop {
+                        let hunk = next.fetch_add(1, Ordering::Relaxed);
+                        let first = hunk * per_hunk;
+                        // Hunks past a data sector found earlier cannot hold
+                        // the first one.
+                        if hunk >= hunks || first_found().is_some_and(|f| f < first) {
+                            return Ok(());
+                        }
+                        if first_found().is_none()
+                            && audible.load(Ordering::Relaxed) >= AUDIO_BAIL_FRAMES
+                        {
+                            return Ok(());
+                        }
+
+                        let buf = reader.decode_hunk(hunk as u32)?;
+                        for frame in first..(first + per_hunk).min(scan_limit) {
+                            let base = (frame - first) as usize * frame_stride;
+                            let sec = bytes_at(&buf, base, CD_FRAME_2352)?;
+                            if !is_data_sector(sec) {
+                                if sec.iter().any(|&b| b != 0) {
+                                    audible.fetch_add(1, Ordering::Relaxed);
+                                }
+                                continue;
+                            }
+                            if let Some(payload) = sector_payload(sec, form2) {
+                                let mut found = found.lock().expect("scan mutex poisoned");
+                                if found.is_none_or(|(f, _)| frame < f) {
+                                    *found = Some((frame, payload));
+                                }
+                                break;
+                            }
+                        }
+                        recycle_hunk(Arc::new(buf));
+                    }
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|h| {
+                h.join()
+                    .unwrap_or_else(|_| Err(anyhow!("scan thread panicked")))
+            })
+            .collect()
+    });
+
+    if let Some((frame, payload)) = *found.lock().expect("scan mutex poisoned") {
+        return Ok(Scan::Data(frame, payload));
+    }
+    results.into_iter().collect::<Result<()>>()?;
+    Ok(match audible.into_inner() {
+        0 => Scan::Nothing,
+        _ => Scan::Audio,
+    })
+}
+
+/// A raw data sector: the sync pattern, then a BCD minute:second:frame
+/// address in range and a mode byte of 1 or 2.
+fn is_data_sector(sec: &[u8]) -> bool {
+    let bcd = |b: u8, below: u8| b >> 4 < 10 && b & 0x0F < 10 && (b >> 4) * 10 + (b & 0x0F) < below;
+    sec[..12] == SECTOR_SYNC
+        && bcd(sec[12], 100)
+        && bcd(sec[13], 60)
+        && bcd(sec[14], 75)
+        && matches!(sec[15], 0x01 | 0x02)
+}
+
+/// Frames between the samples of a region map (4 s).
+const REGION_SAMPLE_FRAMES: u64 = 300;
+
+/// A sampled frame, as the region map sees it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Sector {
+    Mode1,
+    /// Mode 2, all Form 2 so far
+    Mode2 {
+        form2: bool,
+    },
+    Audio,
+    Silence,
+}
+
+impl Sector {
+    fn of(sec: &[u8]) -> Self {
+        if is_data_sector(sec) {
+            return match sec[0x0F] {
+                0x01 => Self::Mode1,
+                _ => Self::Mode2 {
+                    form2: sec[0x12] & SUBMODE_FORM2 != 0,
+                },
+            };
+        }
+        match sec.iter().any(|&b| b != 0) {
+            true => Self::Audio,
+            false => Self::Silence,
+        }
+    }
+
+    /// Sectors of one region: Mode 1, Mode 2 of either form, or anything
+    /// without a data header.
+    fn same_region(self, other: Self) -> bool {
+        use Sector::*;
+        matches!(
+            (self, other),
+            (Mode1, Mode1) | (Mode2 { .. }, Mode2 { .. }) | (Audio | Silence, Audio | Silence)
+        )
+    }
+
+    /// `self` widened by another sector of its region.
+    fn absorb(self, other: Self) -> Self {
+        match (self, other) {
+            (Self::Mode2 { form2: a }, Self::Mode2 { form2: b }) => Self::Mode2 { form2: a && b },
+            (Self::Silence, s) | (s, Self::Silence) => s,
+            (s, _) => s,
+        }
+    }
+}
+
+/// Decodes single frames of a CD CHD, keeping the last hunk.
+struct FrameSampler {
+    reader: HunkReader,
+    frame_stride: usize,
+    hunk_size: usize,
+    hunk: Option<(u32, Vec<u8>)>,
+}
+
+impl FrameSampler {
+    fn open(path: &Path, frame_stride: usize, hunk_size: usize) -> Result<Self> {
+        Ok(Self {
+            reader: HunkReader::open(path)?,
+            frame_stride,
+            hunk_size,
+            hunk: None,
+        })
+    }
+
+    fn at(&mut self, frame: u64) -> Result<Sector> {
+        let (n, base) = mapping::frame_in_hunk(frame, self.frame_stride, self.hunk_size)
+            .ok_or_else(|| anyhow!("frame {frame} is beyond the CHD's hunk range"))?;
+        if self.hunk.as_ref().is_none_or(|(h, _)| *h != n) {
+            self.hunk = Some((n, self.reader.decode_hunk(n)?));
+        }
+        let (_, buf) = self.hunk.as_ref().expect("hunk just decoded");
+        Ok(Sector::of(bytes_at(buf, base, CD_FRAME_2352)?))
+    }
+}
+
+/// Track layout of a CD CHD without track metadata: sample every
+/// `REGION_SAMPLE_FRAMES`th frame on up to `SCAN_WORKERS` threads, then
+/// bisect between samples of different regions for the exact boundary. A
+/// track per run of Mode 1, Mode 2 or audio frames; silence joins the track
+/// before it. Empty if the disc is silent throughout.
+pub fn region_map(
+    path: &Path,
+    total_frames: u64,
+    frame_stride: usize,
+    hunk_size: usize,
+) -> Result<Vec<TrackInfo>> {
+    let points: Vec<u64> = (0..total_frames)
+        .step_by(REGION_SAMPLE_FRAMES as usize)
+        .collect();
+    let workers = thread::available_parallelism()
+        .map_or(1, |n| n.get())
+        .clamp(1, SCAN_WORKERS);
+
+    let next = AtomicUsize::new(0);
+    let sampled = Mutex::new(vec![Sector::Silence; points.len()]);
+    thread::scope(|s| {
+        let handles: Vec<_> = (0..workers)
+            .map(|_| {
+                s.spawn(|| -> Result<()> {
+                    let mut sampler = FrameSampler::open(path, frame_stride, hunk_size)?;
+                    while let Some(&frame) = points.get(next.fetch_add(1, Ordering::Relaxed)) {
+                        let sector = sampler.at(frame)?;
+                        let i = (frame / REGION_SAMPLE_FRAMES) as usize;
+                        sampled.lock().expect("region map mutex poisoned")[i] = sector;
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+        handles.into_iter().try_for_each(|h| {
+            h.join()
+                .unwrap_or_else(|_| Err(anyhow!("scan thread panicked")))
+        })
+    })?;
+
+    let samples = sampled.into_inner().expect("region map mutex poisoned");
+    let mut sampler = FrameSampler::open(path, frame_stride, hunk_size)?;
+    regions_to_tracks(&points, &samples, total_frames, |f| sampler.at(f))
+}
+
+/// Tracks from `samples` taken at frames `points`, finding each boundary
+/// with `probe`.
+fn regions_to_tracks(
+    points: &[u64],
+    samples: &[Sector],
+    total_frames: u64,
+    mut probe: impl FnMut(u64) -> Result<Sector>,
+) -> Result<Vec<TrackInfo>> {
+    let mut regions: Vec<(u64, Sector)> = Vec::new();
+    for (i, (&at, &sector)) in points.iter().zip(samples).enumerate() {
+        match regions.last_mut() {
+            Some((_, kind)) if kind.same_region(sector) => *kind = kind.absorb(sector),
+            Some((_, kind)) => {
+                let kind = *kind;
+                let (mut lo, mut hi) = (points[i - 1], at);
+                while hi - lo > 1 {
+                    let mid = lo + (hi - lo) / 2;
+                    match probe(mid)?.same_region(kind) {
+                        true => lo = mid,
+                        false => hi = mid,
+                    }
+                }
+                regions.push((hi, sector));
+            }
+            None => regions.push((0, sector)),
+        }
+    }
+
+    // Silence runs on the track before it, or starts the first.
+    let mut merged: Vec<(u64, Sector)> = Vec::new();
+    for (start, kind) in regions {
+        match merged.last_mut() {
+            Some((_, last)) if kind == Sector::Silence || *last == kind => {}
+            Some((_, last)) if *last == Sector::Silence => *last = kind,
+            _ => merged.push((start, kind)),
+        }
+    }
+    if merged.iter().all(|(_, k)| *k == Sector::Silence) {
+        return Ok(Vec::new());
+    }
+
+    let ends = merged.iter().skip(1).map(|(s, _)| *s).chain([total_frames]);
+    Ok(merged
+        .iter()
+        .zip(ends)
+        .enumerate()
+        .map(|(i, (&(start, kind), end))| TrackInfo {
+            number: i as u32 + 1,
+            kind: match kind {
+                Sector::Mode1 => TrackKind::Mode1,
+                Sector::Mode2 { form2: false } => TrackKind::Mode2Form1,
+                Sector::Mode2 { form2: true } => TrackKind::Mode2Form2,
+                Sector::Audio | Sector::Silence => TrackKind::Audio,
+            },
+            frames: u32::try_from(end - start).unwrap_or(u32::MAX),
+            pregap: 0,
+            pregap_stored: false,
+            subtype: SubType::None,
+            postgap: 0,
+        })
+        .collect())
+}
+
+/// XA subheader submode bit of a Form 2 sector.
+const SUBMODE_FORM2: u8 = 0x20;
+
+/// What a raw data sector found by the scan is exposed as, by its mode byte
+/// and, for Mode 2, the submode of its XA subheader; None to scan on.
+fn sector_payload(sec: &[u8], form2: Form2Policy) -> Option<CdPayloadKind> {
+    match sec[0x0F] {
+        0x01 => Some(CdPayloadKind::Mode1_2048),
+        0x02 if sec[0x12] & SUBMODE_FORM2 != 0 => form2.payload(),
+        0x02 => Some(CdPayloadKind::Mode2Form1_2048),
+        _ => None,
+    }
+}
+
+/// Hunk buffers a thread keeps for its next decode.
+const SPARE_HUNKS: usize = 2;
+
+/// Bytes of spare hunk buffers across threads.
+static SPARE_BYTES: AtomicUsize = AtomicUsize::new(0);
+/// Cleared by `--max-memory` to stop keeping spares.
+static KEEP_SPARES: AtomicBool = AtomicBool::new(true);
+
+pub fn spare_hunk_bytes() -> usize {
+    SPARE_BYTES.load(Ordering::Relaxed)
+}
+
+/// Whether threads keep decoded hunk buffers for reuse; spares kept already
+/// go as they are used.
+pub fn keep_spare_hunks(keep: bool) {
+    KEEP_SPARES.store(keep, Ordering::Relaxed);
+}
+
+/// Decode buffers reused across a thread's reads, so a long stream does not
+/// allocate a hunk (up to megabytes for DVDs) per request.
+#[derive(Default)]
+struct HunkBuffers {
+    /// Compressed hunk data
+    cmp: Vec<u8>,
+    /// Decoded hunks no longer shared, any size
+    spare: Vec<Vec<u8>>,
+}
+
+impl Drop for HunkBuffers {
+    fn drop(&mut self) {
+        let bytes = self.spare.iter().map(Vec::len).sum();
+        SPARE_BYTES.fetch_sub(bytes, Ordering::Relaxed);
+    }
+}
+
+thread_local! {
+    static HUNK_BUFFERS: RefCell<HunkBuffers> = RefCell::default();
+}
+
+/// Give a decoded hunk back to this thread's buffers once nothing else
+/// (a concurrent read of the same hunk) holds it.
+fn recycle_hunk(hunk: Arc<Vec<u8>>) {
+    if !KEEP_SPARES.load(Ordering::Relaxed) {
+        return;
+    }
+    if let Ok(buf) = Arc::try_unwrap(hunk) {
+        HUNK_BUFFERS.with_borrow_mut(|b| {
+            if b.spare.len() < SPARE_HUNKS {
+                SPARE_BYTES.fetch_add(buf.len(), Ordering::Relaxed);
+                b.spare.push(buf);
+            }
+        });
+    }
+}
+
+/// Open CHD handle that decodes hunks on demand.
+pub struct HunkReader {
+    chd: Chd<SourceReader>,
+}
+
+impl HunkReader {
+    pub fn open(path: &Path) -> Result<Self> {
+        let chd = Chd::open(vfs::reader(path)?, None)?;
+
+        Ok(Self { chd })
+    }
+
+    /// Decode hunk `n`, into a spare buffer of this thread where there is
+    /// one of the right size.
+    pub fn decode_hunk(&mut self, n: u32) -> Result<Vec<u8>> {
+        let hunk_size = self.chd.header().hunk_size() as usize;
+        HUNK_BUFFERS.with_borrow_mut(|b| {
+            let mut out = match b.spare.iter().position(|s| s.len() == hunk_size) {
+                Some(i) => {
+                    SPARE_BYTES.fetch_sub(hunk_size, Ordering::Relaxed);
+                    b.spare.swap_remove(i)
+                }
+                None => self.chd.get_hunksized_buffer(),
+            };
+            let start = Instant::now();
+            let mut hk = self.chd.hunk(n)?;
+            hk.read_hunk_in(&mut b.cmp, &mut out)?;
+            latency::hunk_decoded(n, start.elapsed());
+            Ok(out)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_mode1_track_line() {
+        let line = "TRACK:1 TYPE:MODE1 SUBTYPE:NONE FRAMES:26888 PREGAP:0 PGTYPE:MODE1 PGSUB:RW_RAW POSTGAP:0";
+        let ti = parse_track_line(line).expect("should parse MODE1 track");
+
+        assert_eq!(ti.number, 1);
+        assert_eq!(ti.kind, TrackKind::Mode1);
+        assert_eq!(ti.frames, 26888);
+        assert_eq!(ti.pregap, 0);
+        assert_eq!(ti.postgap, 0);
+    }
+
+    #[test]
+    fn parse_mode2_2048_track_line() {
+        let line = "TRACK:2 TYPE:MODE2/2048 FRAMES:1234 PREGAP:5 POSTGAP:6";
+        let ti = parse_track_line(line).expect("should parse MODE2/2048 track");
+
+        assert_eq!(ti.number, 2);
+        assert_eq!(ti.kind, TrackKind::Mode2Form1);
+        assert_eq!(ti.frames, 1234);
+        assert_eq!(ti.pregap, 5);
+        assert_eq!(ti.postgap, 6);
+    }
+
+    #[test]
+    fn parse_mode2_2324_track_line() {
+        let line = "TRACK:3 TYPE:MODE2/2324 FRAMES:567 PREGAP:0 POSTGAP:0";
+        let ti = parse_track_line(line).expect("should parse MODE2/2324 track");
+
+        assert_eq!(ti.number, 3);
+        assert_eq!(ti.kind, TrackKind::Mode2Form2);
+        assert_eq!(ti.frames, 567);
+    }
+
+    #[test]
+    fn parse_malformed_track_line() {
+        let line = "TRACK:4 FRAMES:100";
+        assert!(parse_track_line(line).is_none());
+    }
+
+    #[test]
+    fn frame_cache_resize_evicts_oldest() {
+        let cache = FrameCache::new(8, 1 << 20);
+        for i in 0..4 {
+            cache.put((0, i), vec![0u8; 100]);
+        }
+
+        cache.resize(8, 250);
+        assert!(cache.get((0, 0)).is_none());
+        assert!(cache.get((0, 1)).is_none());
+        assert!(cache.get((0, 3)).is_some());
+
+        cache.resize(1, 250);
+        assert!(cache.get((0, 2)).is_none());
+        assert!(cache.get((0, 3)).is_some());
+    }
+
+    #[test]
+    fn frame_cache_budget_spans_shards() {
+        let cache = FrameCache::new(1024, 1000);
+        for id in 0..100 {
+            cache.put((id, id * SHARD_RUN), vec![0u8; 100]);
+        }
+
+        assert!(cache.approx_bytes.load(Ordering::Relaxed) <= 1000);
+        assert!(cache.get((99, 99 * SHARD_RUN)).is_some());
+    }
+
+    #[test]
+    fn track_extents_follow_chd_layout() {
+        let tracks: Vec<TrackInfo> = [
+            "TRACK:1 TYPE:MODE1 FRAMES:1001 PREGAP:0 PGTYPE:MODE1 POSTGAP:0",
+            "TRACK:2 TYPE:AUDIO FRAMES:302 PREGAP:150 PGTYPE:VAUDIO POSTGAP:0",
+            "TRACK:3 TYPE:AUDIO FRAMES:100 PREGAP:150 PGTYPE:AUDIO POSTGAP:0",
+        ]
+        .iter()
+        .map(|l| parse_track_line(l).unwrap())
+        .collect();
+
+        // Tracks are padded to 4 frames; only track 2's pregap is stored.
+        assert_eq!(
+            track_extents(&tracks),
+            vec![(0, 1001), (1154, 152), (1308, 100)]
+        );
+        assert_eq!(
+            first_data_track(&tracks[1..], Form2Policy::Hide),
+            None,
+            "audio tracks carry no user data"
+        );
+
+        // The full-disc file drops the padding but keeps stored pregaps.
+        let span = |out, chd, frames, audio| DiscSpan {
+            out,
+            chd,
+            frames,
+            audio,
+        };
+        assert_eq!(
+            disc_spans(&tracks),
+            vec![
+                span(0, 0, 1001, false),
+                span(1001, 1004, 302, true),
+                span(1303, 1308, 100, true)
+            ]
+        );
+    }
+
+    #[test]
+    fn recycles_unshared_hunks() {
+        let shared = Arc::new(vec![0u8; 16]);
+        let waiter = Arc::clone(&shared);
+        recycle_hunk(shared);
+        assert!(HUNK_BUFFERS.with_borrow(|b| b.spare.is_empty()));
+
+        recycle_hunk(waiter);
+        for _ in 0..SPARE_HUNKS {
+            recycle_hunk(Arc::new(vec![0u8; 16]));
+        }
+        assert_eq!(HUNK_BUFFERS.with_borrow(|b| b.spare.len()), SPARE_HUNKS);
+    }
+
+    #[test]
+    fn cache_ids_follow_content() {
+        let path = Path::new("/srv/a.chd");
+        assert_eq!(
+            content_cache_id(path, Some([1; 20])),
+            content_cache_id(Path::new("/srv/copy of a.chd"), Some([1; 20]))
+        );
+        assert_ne!(
+            content_cache_id(path, Some([1; 20])),
+            content_cache_id(path, Some([2; 20]))
+        );
+
+        // No digest and nothing to stat: never shared.
+        let missing = Path::new("/nonexistent.chd");
+        assert_ne!(
+            content_cache_id(missing, None),
+            content_cache_id(missing, None)
+        );
+
+        let file = std::env::temp_dir().join(format!("chd2iso-cacheid-{}", std::process::id()));
+        std::fs::write(&file, b"one").unwrap();
+        let first = content_cache_id(&file, None);
+        assert_eq!(content_cache_id(&file, None), first);
+        std::fs::write(&file, b"three").unwrap();
+        assert_ne!(content_cache_id(&file, None), first);
+        std::fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn tail_policy_past_the_stored_hunks() {
+        let provider = |tail| CdProvider {
+            path: PathBuf::from("/nonexistent.chd"),
+            cache_id: next_cache_id(),
+            shared_id: None,
+            first_data_lba: 0,
+            payload_kind: CdPayloadKind::Mode1_2048,
+            fixup: FrameFixup::None,
+            size: 4 * 2048,
+            hunk_size: 8 * CD_FRAME_2448,
+            hunk_count: 0,
+            tail,
+            frame_stride: CD_FRAME_2448,
+            frame_cache: Arc::new(FrameCache::new(8, 1 << 20)),
+            flights: HunkFlights::default(),
+        };
+
+        let mut buf = [0xffu8; 4096];
+        assert!(provider(TailPolicy::Eio).read_at(0, &mut buf).is_err());
+        assert_eq!(
+            provider(TailPolicy::Truncate).read_at(0, &mut buf).unwrap(),
+            0
+        );
+        assert_eq!(
+            provider(TailPolicy::Zero).read_at(0, &mut buf).unwrap(),
+            4096
+        );
+        assert!(buf.iter().all(|&b| b == 0));
+    }
+
+    /// An uncompressed v5 CHD of `data` in `hunk_bytes` hunks, each stored
+    /// at the offset its map entry names.
+    fn write_chd(path: &Path, data: &[u8], hunk_bytes: u32, unit_bytes: u32) {
+        let hunks = data.len().div_ceil(hunk_bytes as usize);
+        let mut out = Vec::new();
+        out.extend_from_slice(b"MComprHD");
+        out.extend_from_slice(&124u32.to_be_bytes());
+        out.extend_from_slice(&5u32.to_be_bytes());
+        out.extend_from_slice(&[0; 16]);
+        out.extend_from_slice(&(data.len() as u64).to_be_bytes());
+        out.extend_from_slice(&124u64.to_be_bytes());
+        out.extend_from_slice(&0u64.to_be_bytes());
+        out.extend_from_slice(&hunk_bytes.to_be_bytes());
+        out.extend_from_slice(&unit_bytes.to_be_bytes());
+        out.extend_from_slice(&crate::sha1::digest(data));
+        out.extend_from_slice(&crate::sha1::digest(data));
+        out.extend_from_slice(&[0; 20]);
+        for n in 0..hunks {
+            out.extend_from_slice(&(n as u32 + 1).to_be_bytes());
+        }
+        for hunk in data.chunks(hunk_bytes as usize) {
+            out.resize(out.len().next_multiple_of(hunk_bytes as usize), 0);
+            out.extend_from_slice(hunk);
+        }
+        out.resize(out.len().next_multiple_of(hunk_bytes as usize), 0);
+        std::fs::write(path, out).unwrap();
+    }
+
+    #[test]
+    fn shared_hunks_follow_hunk_size() {
+        let dir = std::env::temp_dir().join(format!("chd2iso-shared-ids-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        shared_cache::configure(&dir.join("hunks.sock"), 1).unwrap();
+
+        let frame_cache = Arc::new(FrameCache::new(8, 1 << 20));
+        let ctx = ProbeContext {
+            form2: Form2Policy::Hide,
+            audio_tracks: false,
+            pregap_audio: false,
+            audio_byteswap: false,
+            subchannel: false,
+            audio_cd: AudioCdMode::Hide,
+            toc: false,
+            full_disc: false,
+            merged_iso: false,
+            spill_dir: &dir,
+            frame_cache: &frame_cache,
+            serials: false,
+            consoles: false,
+            tail: TailPolicy::Eio,
+            strict: false,
+            extensions: &crate::provider::ExtensionMap::default(),
+        };
+        // Same data, so the same data SHA-1, in 4 and 8 KiB hunks.
+        let data: Vec<u8> = (0..16384u32).map(|i| (i / 7) as u8).collect();
+        let mut images = Vec::new();
+        for hunk_bytes in [4096, 8192] {
+            let path = dir.join(format!("{hunk_bytes}.chd"));
+            write_chd(&path, &data, hunk_bytes, 2048);
+            images.push(ChdFactory.probe(&path, &ctx).unwrap().unwrap().provider);
+        }
+
+        // Hunk 0 of the first is shared before the second reads its own.
+        let mut buf = vec![0u8; 4096];
+        for (image, at) in images.iter().zip([0, 4096]) {
+            assert_eq!(image.read_at(at, &mut buf).unwrap(), 4096);
+            assert_eq!(buf, data[at as usize..at as usize + 4096]);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_frames_straddling_hunks() {
+        assert!(check_frame_layout(8 * CD_FRAME_2448, CD_FRAME_2448).is_ok());
+        let err = check_frame_layout(8 * CD_FRAME_2448, CD_FRAME_2352).unwrap_err();
+        assert!(err.to_string().contains("not a multiple"), "{err}");
+        assert!(check_frame_layout(CD_FRAME_2352, CD_FRAME_2448).is_err());
+
+        // Past the stored hunks too, rather than a frame from the wrong place.
+        let provider = CdProvider {
+            path: PathBuf::from("/nonexistent.chd"),
+            cache_id: next_cache_id(),
+            shared_id: None,
+            first_data_lba: 0,
+            payload_kind: CdPayloadKind::Mode1_2048,
+            fixup: FrameFixup::None,
+            size: 4 * 2048,
+            hunk_size: 8 * CD_FRAME_2448,
+            hunk_count: 0,
+            tail: TailPolicy::Zero,
+            frame_stride: CD_FRAME_2352,
+            frame_cache: Arc::new(FrameCache::new(8, 1 << 20)),
+            flights: HunkFlights::default(),
+        };
+        assert!(provider.read_at(0, &mut [0u8; 2048]).is_err());
+        assert!(quick_scan_first_data(
+            Path::new("/nonexistent.chd"),
+            100,
+            CD_FRAME_2352,
+            8 * CD_FRAME_2448,
+            Form2Policy::Hide
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn short_frames_fail_the_read() {
+        assert_eq!(bytes_at(&[1, 2, 3], 1, 2).unwrap(), [2, 3]);
+        assert!(bytes_at(&[1, 2, 3], 2, 2).is_err());
+        assert!(bytes_at(&[], usize::MAX, 2).is_err());
+
+        // Frames decoded short by a corrupt hunk, at every length.
+        let tracks =
+            [parse_track_line("TRACK:1 TYPE:MODE1_RAW FRAMES:2 PREGAP:0 POSTGAP:0").unwrap()];
+        for len in (0..CD_FRAME_2352).step_by(97) {
+            let frames = CdProvider {
+                path: PathBuf::from("/nonexistent.chd"),
+                cache_id: next_cache_id(),
+                shared_id: None,
+                first_data_lba: 0,
+                payload_kind: CdPayloadKind::Mode2_2336,
+                fixup: FrameFixup::None,
+                size: 2 * 2336,
+                hunk_size: 8 * CD_FRAME_2352,
+                hunk_count: 1,
+                tail: TailPolicy::Eio,
+                frame_stride: CD_FRAME_2352,
+                frame_cache: Arc::new(FrameCache::new(8, 1 << 20)),
+                flights: HunkFlights::default(),
+            };
+            frames.frame_cache.put((frames.cache_id, 0), vec![0; len]);
+            for offset in [0, 1000, 2335] {
+                assert!(frames.read_at(offset, &mut [0u8; 64]).is_err(), "{len}");
+            }
+            let disc = FullDiscProvider::new(frames, &tracks, false);
+            assert!(disc.read_at(2000, &mut [0u8; 64]).is_err(), "{len}");
+        }
+    }
+
+    #[test]
+    fn clamps_frames_to_stored_data() {
+        let mut tracks: Vec<TrackInfo> = [
+            "TRACK:1 TYPE:MODE1 FRAMES:1001 PREGAP:0 PGTYPE:MODE1 POSTGAP:0",
+            "TRACK:2 TYPE:AUDIO FRAMES:300 PREGAP:0 PGTYPE:AUDIO POSTGAP:0",
+            "TRACK:3 TYPE:AUDIO FRAMES:100 PREGAP:0 PGTYPE:AUDIO POSTGAP:0",
+        ]
+        .iter()
+        .map(|l| parse_track_line(l).unwrap())
+        .collect();
+
+        assert_eq!(clamp_track_frames(&mut tracks.clone(), 1404), vec![]);
+        assert_eq!(
+            clamp_track_frames(&mut tracks, 1200),
+            vec![(2, 300, 196), (3, 100, 0)]
+        );
+        assert_eq!(
+            track_extents(&tracks),
+            vec![(0, 1001), (1004, 196), (1200, 0)]
+        );
+    }
+
+    #[test]
+    fn data_frames_past_the_stored_frames() {
+        assert_eq!(data_frames(Some(500), 2000, 1000), 500);
+        assert_eq!(data_frames(None, 16, 1000), 984);
+        assert_eq!(data_frames(None, 2000, 1000), 0);
+    }
+
+    #[test]
+    fn deinterleaves_raw_subcode() {
+        // P set on every byte, Q only on the first: P channel all ones, Q
+        // channel a single leading bit.
+        let mut raw = [0x80u8; SUBCODE_BYTES];
+        raw[0] |= 0x40;
+        deinterleave_subcode(&mut raw);
+
+        assert_eq!(raw[..12], [0xff; 12]);
+        assert_eq!(raw[12], 0x80);
+        assert!(raw[13..].iter().all(|&b| b == 0));
+
+        let line = "TRACK:1 TYPE:MODE2_RAW SUBTYPE:RW_RAW FRAMES:300";
+        assert_eq!(parse_track_line(line).unwrap().subtype, SubType::RwRaw);
+    }
+
+    #[test]
+    fn hunk_mapping_past_4gib() {
+        // Byte 5 GiB of a DVD CHD with 4 KiB hunks.
+        assert_eq!(hunk_of(5 << 30, 4096).unwrap(), (1_310_720, 0));
+        assert_eq!(hunk_of((5 << 30) + 7, 4096).unwrap(), (1_310_720, 7));
+        assert!(hunk_of(u64::MAX, 1).is_err());
+    }
+
+    #[test]
+    fn raw_data_tracks_are_not_audio() {
+        for (ty, kind) in [
+            ("MODE1_RAW", TrackKind::Mode1),
+            ("MODE2_FORM_MIX", TrackKind::Mode2Raw),
+            ("CDI/2352", TrackKind::Mode2Raw),
+            ("MODE2", TrackKind::Mode2Raw),
+        ] {
+            let line = format!("TRACK:1 TYPE:{ty} SUBTYPE:NONE FRAMES:1000");
+            assert_eq!(parse_track_line(&line).unwrap().kind, kind, "{ty}");
+        }
+
+        // Not a track an ISO can be cut from: the disc goes out raw.
+        let cdi = parse_track_line("TRACK:1 TYPE:CDI/2352 SUBTYPE:NONE FRAMES:1000").unwrap();
+        assert!(first_data_track(&[cdi], Form2Policy::XaView).is_none());
+    }
+
+    #[test]
+    fn merged_iso_takes_every_2048_byte_data_track() {
+        let tracks: Vec<TrackInfo> = [
+            "TRACK:1 TYPE:MODE1 SUBTYPE:NONE FRAMES:1000",
+            "TRACK:2 TYPE:AUDIO SUBTYPE:NONE FRAMES:500",
+            "TRACK:3 TYPE:MODE2_FORM1 SUBTYPE:NONE FRAMES:300 PREGAP:150 PGTYPE:VMODE2_FORM1",
+            "TRACK:4 TYPE:MODE2_RAW SUBTYPE:NONE FRAMES:100",
+        ]
+        .iter()
+        .map(|l| parse_track_line(l).unwrap())
+        .collect();
+
+        assert_eq!(
+            merged_data_tracks(&tracks),
+            [
+                (0, CdPayloadKind::Mode1_2048, 1000),
+                (1650, CdPayloadKind::Mode2Form1_2048, 150),
+            ]
+        );
+    }
+
+    #[test]
+    fn scan_wants_sync_and_address() {
+        let mut sec = [0u8; CD_FRAME_2352];
+        sec[..12].copy_from_slice(&SECTOR_SYNC);
+        sec[12..16].copy_from_slice(&[0x00, 0x02, 0x16, 0x01]);
+        assert!(is_data_sector(&sec));
+
+        // Audio that happens to start like a sync pattern.
+        sec[13] = 0x60;
+        assert!(!is_data_sector(&sec));
+        sec[13] = 0x0A;
+        assert!(!is_data_sector(&sec));
+        sec[13] = 0x02;
+        sec[15] = 0x03;
+        assert!(!is_data_sector(&sec));
+        sec[15] = 0x02;
+        sec[5] = 0xFE;
+        assert!(!is_data_sector(&sec));
+    }
+
+    #[test]
+    fn region_map_finds_track_boundaries() {
+        // Silent lead-in, Mode 1 to 1000, a silent gap, audio from 1150 and
+        // silence again from 2500.
+        let disc = |f: u64| match f {
+            0..100 => Sector::Silence,
+            100..1000 => Sector::Mode1,
+            1000..1150 => Sector::Silence,
+            1150..2500 => Sector::Audio,
+            _ => Sector::Silence,
+        };
+        let points: Vec<u64> = (0..3000).step_by(REGION_SAMPLE_FRAMES as usize).collect();
+        let samples: Vec<Sector> = points.iter().map(|&f| disc(f)).collect();
+        let tracks = regions_to_tracks(&points, &samples, 3000, |f| Ok(disc(f))).unwrap();
+
+        let layout: Vec<_> = tracks
+            .iter()
+            .map(|t| (t.number, t.kind, t.frames))
+            .collect();
+        assert_eq!(
+            layout,
+            [(1, TrackKind::Mode1, 1000), (2, TrackKind::Audio, 2000)]
+        );
+
+        let silent = [Sector::Silence; 10];
+        assert!(
+            regions_to_tracks(&points, &silent, 3000, |_| Ok(Sector::Silence))
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn scan_tells_form1_from_form2() {
+        let mut sec = [0u8; CD_FRAME_2352];
+        sec[0x0F] = 0x02;
+        assert_eq!(
+            sector_payload(&sec, Form2Policy::Bin),
+            Some(CdPayloadKind::Mode2Form1_2048)
+        );
+
+        sec[0x12] = SUBMODE_FORM2;
+        assert_eq!(sector_payload(&sec, Form2Policy::Hide), None);
+        assert_eq!(
+            sector_payload(&sec, Form2Policy::Bin),
+            Some(CdPayloadKind::Mode2Form2_2324)
+        );
+        assert_eq!(
+            sector_payload(&sec, Form2Policy::XaView),
+            Some(CdPayloadKind::Mode2_2336)
+        );
+
+        sec[0x0F] = 0x01;
+        assert_eq!(
+            sector_payload(&sec, Form2Policy::Hide),
+            Some(CdPayloadKind::Mode1_2048)
+        );
+    }
+}
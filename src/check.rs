@@ -0,0 +1,250 @@
+//! `chd2iso-fuse check DIR`: integrity check of a library, like `chdman
+//! verify`. Every hunk is decoded, so a broken hunk map or codec stream shows
+//! up as a failed hunk, and the decoded data is hashed and compared with the
+//! header's raw SHA-1, then (v4+) the overall SHA-1 that also covers the
+//! checksummed metadata.
+
+use anyhow::{anyhow, bail, Context, Result};
+use chd::Chd;
+use std::{
+    cell::Cell,
+    fs,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+};
+
+use crate::{
+    progress::{Progress, ProgressMode},
+    sha1::{self, Sha1},
+    vfs::{self, SourceReader},
+};
+
+/// Metadata flag: the entry counts towards the overall SHA-1.
+const MDFLAGS_CHECKSUM: u8 = 0x01;
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct CheckArgs {
+    /// Directory to scan for *.chd files (recursively), or a single CHD
+    #[arg(value_name = "DIR")]
+    dir: PathBuf,
+
+    /// Images verified at once (default: one per CPU)
+    #[arg(short = 'j', long = "jobs", value_name = "N")]
+    jobs: Option<usize>,
+
+    /// Progress on stderr: "none", "bar" or "json" (one object per line)
+    #[arg(long = "progress", value_name = "MODE", default_value = "none")]
+    progress: ProgressMode,
+}
+
+pub enum Outcome {
+    Verified,
+    /// v1/v2 images store no SHA-1; their hunks still decoded
+    NoDigest,
+}
+
+/// Verify every CHD under `args.dir`, printing one line per image and a
+/// summary; fails if any image did.
+pub fn run(args: &CheckArgs) -> Result<()> {
+    let mut files = Vec::new();
+    if args.dir.is_dir() {
+        collect(&args.dir, &is_chd, &mut files)?;
+    } else {
+        files.push(args.dir.clone());
+    }
+    files.sort();
+
+    let jobs = args
+        .jobs
+        .unwrap_or_else(|| thread::available_parallelism().map_or(1, |n| n.get()))
+        .clamp(1, files.len().max(1));
+
+    // Progress counts CHD bytes on disk, each file advancing hunk by hunk.
+    let sizes: Vec<u64> = files
+        .iter()
+        .map(|p| fs::metadata(p).map_or(0, |m| m.len()))
+        .collect();
+    let progress = Progress::new(args.progress, files.len(), sizes.iter().sum());
+
+    let next = AtomicUsize::new(0);
+    let failed = AtomicUsize::new(0);
+    let no_digest = AtomicUsize::new(0);
+    thread::scope(|s| {
+        for _ in 0..jobs {
+            s.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                let Some(path) = files.get(i) else {
+                    break;
+                };
+                let name = path.strip_prefix(&args.dir).unwrap_or(path);
+                let name = match name.as_os_str().is_empty() {
+                    true => path.display().to_string(),
+                    false => name.display().to_string(),
+                };
+
+                let reported = Cell::new(0u64);
+                let on_hunk = |n: u32, count: u32, _| {
+                    let now = sizes[i] * (u64::from(n) + 1) / u64::from(count.max(1));
+                    progress.advance(&name, now.saturating_sub(reported.replace(now)));
+                };
+                let outcome = verify(path, &on_hunk);
+                progress.advance(&name, sizes[i].saturating_sub(reported.get()));
+
+                match outcome {
+                    Ok(Outcome::Verified) => {
+                        progress.finish_file(&name, "ok", None, &format!("ok        {name}"))
+                    }
+                    Ok(Outcome::NoDigest) => {
+                        no_digest.fetch_add(1, Ordering::Relaxed);
+                        progress.finish_file(&name, "no_sha1", None, &format!("no sha1   {name}"));
+                    }
+                    Err(e) => {
+                        failed.fetch_add(1, Ordering::Relaxed);
+                        let e = format!("{e:#}");
+                        progress.finish_file(
+                            &name,
+                            "failed",
+                            Some(&e),
+                            &format!("FAILED    {name}: {e}"),
+                        );
+                    }
+                }
+            });
+        }
+    });
+
+    progress.done();
+    let failed = failed.into_inner();
+    println!(
+        "{} checked: {} failed, {} without a stored SHA-1",
+        files.len(),
+        failed,
+        no_digest.into_inner()
+    );
+    if failed > 0 {
+        return Err(anyhow!("{failed} of {} images failed", files.len()));
+    }
+    Ok(())
+}
+
+/// Files under `dir` that `keep` accepts; symlinked directories are not
+/// followed.
+pub fn collect(dir: &Path, keep: &dyn Fn(&Path) -> bool, out: &mut Vec<PathBuf>) -> Result<()> {
+    for ent in fs::read_dir(dir).with_context(|| format!("reading {dir:?}"))? {
+        let ent = ent?;
+        let path = ent.path();
+        if ent.file_type()?.is_dir() {
+            collect(&path, keep, out)?;
+        } else if keep(&path) {
+            out.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn is_chd(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case("chd"))
+}
+
+/// Verify one CHD, calling `on_hunk` with the number, count and size of
+/// each hunk before it is decoded.
+pub fn verify(path: &Path, on_hunk: &dyn Fn(u32, u32, usize)) -> Result<Outcome> {
+    let mut chd = Chd::open(vfs::reader(path)?, None)?;
+
+    let hdr = chd.header();
+    // v3 has a single SHA-1, over the data alone.
+    let (raw, overall) = match (hdr.raw_sha1(), hdr.sha1()) {
+        (Some(raw), overall) => (Some(raw), overall),
+        (None, sha1) => (sha1, None),
+    };
+    let hunk_size = hdr.hunk_size() as u64;
+    let logical_bytes = hdr.logical_bytes();
+    let hunk_count = hdr.hunk_count();
+
+    let mut data = Sha1::default();
+    let mut out = chd.get_hunksized_buffer();
+    let mut cmp_buf = Vec::new();
+    for n in 0..hunk_count {
+        let start = n as u64 * hunk_size;
+        if start >= logical_bytes {
+            break;
+        }
+        on_hunk(n, hunk_count, hunk_size as usize);
+        chd.hunk(n)
+            .and_then(|mut hunk| hunk.read_hunk_in(&mut cmp_buf, &mut out))
+            .with_context(|| format!("hunk {n}"))?;
+        data.update(&out[..(logical_bytes - start).min(hunk_size) as usize]);
+    }
+
+    let Some(raw) = raw else {
+        return Ok(Outcome::NoDigest);
+    };
+    let actual = data.finish();
+    if actual != raw {
+        bail!(
+            "data SHA-1 {} does not match the header's {}",
+            sha1::hex(&actual),
+            sha1::hex(&raw)
+        );
+    }
+
+    if let Some(overall) = overall {
+        let actual = overall_sha1(&mut chd, path, &raw)?;
+        if actual != overall {
+            bail!(
+                "overall SHA-1 {} does not match the header's {} (metadata changed?)",
+                sha1::hex(&actual),
+                sha1::hex(&overall)
+            );
+        }
+    }
+    Ok(Outcome::Verified)
+}
+
+/// chdman's overall SHA-1: the raw SHA-1 followed by the sorted (tag,
+/// SHA-1) pairs of the checksummed metadata entries.
+fn overall_sha1(chd: &mut Chd<SourceReader>, path: &Path, raw: &[u8; 20]) -> Result<[u8; 20]> {
+    let mut rf = vfs::reader(path)?;
+    let mut entries = Vec::new();
+    for mref in chd.metadata_refs() {
+        let md = mref.read(&mut rf)?;
+        if md.flags & MDFLAGS_CHECKSUM == 0 {
+            continue;
+        }
+        let mut entry = [0u8; 24];
+        entry[..4].copy_from_slice(&md.metatag.to_be_bytes());
+        entry[4..].copy_from_slice(&sha1::digest(&md.value));
+        entries.push(entry);
+    }
+    entries.sort();
+
+    let mut all = Sha1::default();
+    all.update(raw);
+    for entry in &entries {
+        all.update(entry);
+    }
+    Ok(all.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn collects_chds_recursively() {
+        let root = std::env::temp_dir().join(format!("chd2iso-check-{}", std::process::id()));
+        fs::create_dir_all(root.join("ps2")).unwrap();
+        for f in ["A.chd", "ps2/B.CHD", "ps2/B.cue", "notes.txt"] {
+            fs::write(root.join(f), b"").unwrap();
+        }
+
+        let mut files = Vec::new();
+        collect(&root, &is_chd, &mut files).unwrap();
+        files.sort();
+        assert_eq!(files, vec![root.join("A.chd"), root.join("ps2/B.CHD")]);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
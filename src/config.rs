@@ -0,0 +1,122 @@
+//! `--config FILE`: TOML settings file.
+//!
+//! ```toml
+//! log_level = "info"
+//! cache_bytes = 536870912
+//!
+//! [[mount]]
+//! source = "/srv/roms/ps2/chd"
+//! mount = "/srv/roms/ps2/iso"
+//! compressed_view = "zso"
+//!
+//! [[mount]]
+//! source = "/srv/roms/psx/chd"
+//! mount = "/srv/roms/psx/iso"
+//! form2_policy = "bin"
+//! ```
+//!
+//! Each `[[mount]]` table starts from the command-line settings and overrides
+//! the keys it sets. The top-level keys override the matching process-wide
+//! flags; backend and scheduling always come from the command line.
+//!
+//! SIGHUP re-reads the file. Cache sizes, log level and per-mount index and
+//! throughput settings are applied in place; `allow_other` and adding or
+//! removing `[[mount]]` tables only take effect after a restart.
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::{fs, path::Path, path::PathBuf};
+
+use crate::chd_image::{AudioCdMode, Form2Policy, TailPolicy};
+use crate::cso::CsoFormat;
+use crate::fusedev::AllowOther;
+use crate::jobs::Task;
+use crate::provider::ExtensionMap;
+use crate::{BlocksReport, ChecksumFiles, Layout, MtimePolicy, Quarantine};
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfigFile {
+    pub cache_hunks: Option<usize>,
+    pub cache_bytes: Option<usize>,
+    /// MiB, as `--max-memory`
+    pub max_memory: Option<u64>,
+    /// `tracing` filter directive, e.g. `info` or `chd2iso_fuse=debug`
+    pub log_level: Option<String>,
+    pub mount: Vec<MountSection>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MountSection {
+    pub source: Option<PathBuf>,
+    pub source_list: Option<PathBuf>,
+    pub mount: PathBuf,
+    pub allow_other: Option<AllowOther>,
+    pub visible_to: Option<Vec<u32>>,
+    pub audit_log: Option<PathBuf>,
+    pub only: Option<Vec<String>>,
+    pub nfs_export: Option<bool>,
+    pub smb_mode: Option<bool>,
+    pub form2_policy: Option<Form2Policy>,
+    /// Deprecated: `form2_policy = "bin"`
+    pub cd_allow_form2: Option<bool>,
+    pub audio_tracks: Option<bool>,
+    pub pregap_audio: Option<bool>,
+    pub audio_byteswap: Option<bool>,
+    pub export_subchannel: Option<bool>,
+    pub audio_cd: Option<AudioCdMode>,
+    pub tail_policy: Option<TailPolicy>,
+    pub strict: Option<bool>,
+    pub probe_isolation: Option<u64>,
+    pub toc: Option<bool>,
+    pub full_disc_image: Option<bool>,
+    pub merged_iso: Option<bool>,
+    pub extension_map: Option<ExtensionMap>,
+    pub follow_symlinks: Option<bool>,
+    pub skip_hidden: Option<bool>,
+    pub dedupe: Option<bool>,
+    pub expose_sources: Option<bool>,
+    pub title_db: Option<PathBuf>,
+    pub title_region_dirs: Option<bool>,
+    pub console_dirs: Option<bool>,
+    pub sidecar_dir: Option<PathBuf>,
+    pub layout: Option<Layout>,
+    pub checksum_files: Option<ChecksumFiles>,
+    pub name_from_label: Option<bool>,
+    pub blocks_report: Option<BlocksReport>,
+    pub mtime: Option<MtimePolicy>,
+    pub reproducible: Option<bool>,
+    pub attr_refresh: Option<u64>,
+    pub deny_delete_silently: Option<bool>,
+    pub overlay: Option<PathBuf>,
+    pub health: Option<bool>,
+    pub error_budget: Option<u64>,
+    pub quarantine: Option<Quarantine>,
+    pub export_index: Option<PathBuf>,
+    pub import_index: Option<PathBuf>,
+    pub compressed_view: Option<CsoFormat>,
+    pub spill_dir: Option<PathBuf>,
+    pub header_cache: Option<PathBuf>,
+    pub pin_mib: Option<u64>,
+    pub warmup: Option<u64>,
+    pub maintenance: Option<Vec<Task>>,
+    pub maintenance_workers: Option<usize>,
+    pub maintenance_throughput: Option<f64>,
+    pub max_background: Option<u16>,
+    pub congestion_threshold: Option<u16>,
+    pub drop_source_cache: Option<bool>,
+    pub max_throughput: Option<f64>,
+    pub max_handle_throughput: Option<f64>,
+    pub slow_read_ms: Option<u64>,
+    pub trace_reads_every: Option<u64>,
+    pub trace_reads_slower_ms: Option<u64>,
+    pub trace_reads_file: Option<String>,
+    pub hot: Option<Vec<String>>,
+    pub hot_mib: Option<u64>,
+}
+
+pub fn load(path: &Path) -> Result<ConfigFile> {
+    let text = fs::read_to_string(path).with_context(|| format!("reading {path:?}"))?;
+    toml::from_str(&text).with_context(|| format!("parsing {path:?}"))
+}
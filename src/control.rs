@@ -0,0 +1,516 @@
+//! `--control-socket PATH`: JSON-RPC 2.0 on a Unix socket, one request
+//! object per line and one response line per request (none for
+//! notifications), for web frontends and scripts that cannot reach D-Bus.
+//! Methods take their arguments by name; `mount` may be left out when the
+//! process serves a single mount.
+//!
+//! | method             | params                     | result                 |
+//! |--------------------|----------------------------|------------------------|
+//! | `list_mounts`      |                            | mountpoints            |
+//! | `list_entries`     | `mount`                    | paths in the mount     |
+//! | `entries`          | `mount`                    | every `EntryInfo`      |
+//! | `entry`            | `mount`, `path`            | an `EntryInfo`         |
+//! | `stats`            | `mount`                    | counters, as D-Bus     |
+//! | `degraded_sources` | `mount`                    | `{source, error}` list |
+//! | `errors`           | `mount`                    | `SourceErrors` list    |
+//! | `reload`           | `mount`                    | entry count            |
+//! | `evict`            |                            | frames dropped         |
+//! | `degrade`          | `mount`, `path`, `reason`? | whether it changed     |
+//! | `restore`          | `mount`, `path`            | whether it changed     |
+//! | `read_trace`       | `mount`                    | `TraceRule` or null    |
+//! | `trace_reads`      | `mount`, rule fields       | the `TraceRule`        |
+//! | `stop_trace`       | `mount`                    | whether it changed     |
+//! | `hot`              | `mount`                    | `HotTitle` list        |
+//! | `pin`              | `mount`, `path`            | whether it changed     |
+//! | `unpin`            | `mount`, `path`            | whether it changed     |
+//! | `handles`          | `mount`                    | `OpenHandle` list      |
+//!
+//! The rule fields of `trace_reads` are `every`, `slower_ms` and `file`, as
+//! `--trace-reads-every`, `--trace-reads-slower-ms` and `--trace-reads-file`;
+//! left out, they do not filter.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    io::{BufRead, BufReader, Write},
+    os::unix::{
+        fs::{FileTypeExt, PermissionsExt},
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+};
+use tracing::{debug, info, warn};
+
+use crate::{latency::TraceRule, sha1, virtual_path, FsState, IndexEntry};
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+/// Server error: the method was valid but failed
+const FAILED: i64 = -32000;
+/// Server error: the method exists but not over this transport
+const NOT_ALLOWED: i64 = -32001;
+
+#[derive(Debug, Deserialize)]
+struct Request {
+    #[serde(default)]
+    jsonrpc: String,
+    /// Absent for a notification, which gets no response
+    #[serde(default)]
+    id: Option<Id>,
+    method: String,
+    #[serde(default)]
+    params: Params,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(untagged)]
+enum Id {
+    Num(i64),
+    Str(String),
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct Params {
+    mount: Option<String>,
+    path: Option<String>,
+    reason: Option<String>,
+    every: Option<u64>,
+    slower_ms: Option<u64>,
+    file: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Response {
+    jsonrpc: &'static str,
+    id: Option<Id>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<Reply>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<RpcError>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+enum Reply {
+    Paths(Vec<String>),
+    Entry(EntryInfo),
+    Entries(Vec<EntryInfo>),
+    Errors(Vec<SourceErrors>),
+    Stats(BTreeMap<String, u64>),
+    Trace(Option<TraceRule>),
+    Hot(Vec<HotTitle>),
+    Handles(Vec<OpenHandle>),
+    Degraded(Vec<DegradedSource>),
+    Count(u64),
+    Changed(bool),
+}
+
+/// `entry`: what the mount knows about one file.
+#[derive(Debug, Serialize)]
+struct EntryInfo {
+    path: String,
+    source: String,
+    size: u64,
+    /// Provider kind, e.g. `dvd`, `cd` or `sheet`
+    kind: String,
+    sha1: Option<String>,
+    label: Option<String>,
+    original_name: Option<String>,
+    /// Why the source is degraded, if it is
+    degraded: Option<String>,
+}
+
+/// `errors`: failed reads of one source since the last re-index.
+#[derive(Debug, Serialize)]
+struct SourceErrors {
+    source: String,
+    failures: u64,
+    last_error: String,
+    degraded: Option<String>,
+}
+
+/// `hot`: a pinned title and whether it is in memory yet.
+#[derive(Debug, Serialize)]
+struct HotTitle {
+    path: String,
+    size: u64,
+    loaded: bool,
+}
+
+/// `handles`: an open file and what it read, by `--fair-reads` class.
+#[derive(Debug, Serialize)]
+struct OpenHandle {
+    fh: u64,
+    source: String,
+    interactive_bytes: u64,
+    bulk_bytes: u64,
+    queued_ms: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct DegradedSource {
+    source: String,
+    error: String,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+/// Listen on `path` (replacing a stale socket there) and serve `mounts`
+/// from a background thread, a thread per client.
+pub fn serve(path: &Path, mounts: Vec<Arc<FsState>>) -> Result<()> {
+    if fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket()) {
+        fs::remove_file(path).with_context(|| format!("removing stale socket {path:?}"))?;
+    }
+    let listener = UnixListener::bind(path).with_context(|| format!("binding {path:?}"))?;
+    // Reload, degrade and evict are for the mount's owner only.
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    info!("control socket: listening on {:?}", path);
+
+    let mounts = Arc::new(mounts);
+    thread::Builder::new()
+        .name("control".into())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let mounts = Arc::clone(&mounts);
+                        let _ = thread::Builder::new()
+                            .name("control-client".into())
+                            .spawn(move || client(stream, &mounts));
+                    }
+                    Err(e) => warn!("control socket: accept failed: {}", e),
+                }
+            }
+        })?;
+    Ok(())
+}
+
+fn client(stream: UnixStream, mounts: &[Arc<FsState>]) {
+    let Ok(mut out) = stream.try_clone() else {
+        return;
+    };
+    for line in BufReader::new(stream).lines() {
+        let Ok(line) = line else {
+            return;
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        let Some(mut bytes) = respond(mounts, &line, &|_| true) else {
+            continue;
+        };
+        bytes.push(b'\n');
+        if out.write_all(&bytes).is_err() {
+            return;
+        }
+    }
+}
+
+/// The serialized response to one request; None for a notification. Also
+/// serves `--web-ui`.
+/// Methods `allowed` rejects fail with `NOT_ALLOWED`.
+pub fn respond(
+    mounts: &[Arc<FsState>],
+    request: &str,
+    allowed: &dyn Fn(&str) -> bool,
+) -> Option<Vec<u8>> {
+    let response = handle(mounts, request, allowed)?;
+    Some(serde_json::to_vec(&response).expect("response serializes"))
+}
+
+/// The response to one request line; None for a notification.
+fn handle(mounts: &[Arc<FsState>], line: &str, allowed: &dyn Fn(&str) -> bool) -> Option<Response> {
+    let (id, outcome) = match serde_json::from_str::<Request>(line) {
+        Err(e) => (None, Err(RpcError::new(PARSE_ERROR, e.to_string()))),
+        Ok(req) if req.jsonrpc != "2.0" => (
+            req.id,
+            Err(RpcError::new(INVALID_REQUEST, "jsonrpc must be \"2.0\"")),
+        ),
+        Ok(req) if !allowed(&req.method) => (
+            req.id,
+            Err(RpcError::new(
+                NOT_ALLOWED,
+                format!("{:?} is not available here", req.method),
+            )),
+        ),
+        Ok(req) => {
+            debug!("control socket: {} {:?}", req.method, req.params);
+            let outcome = call(mounts, &req.method, &req.params);
+            (Some(req.id?), outcome)
+        }
+    };
+
+    let (result, error) = match outcome {
+        Ok(reply) => (Some(reply), None),
+        Err(e) => (None, Some(e)),
+    };
+    Some(Response {
+        jsonrpc: "2.0",
+        id,
+        result,
+        error,
+    })
+}
+
+fn call(mounts: &[Arc<FsState>], method: &str, params: &Params) -> Result<Reply, RpcError> {
+    match method {
+        "list_mounts" => Ok(Reply::Paths(
+            mounts
+                .iter()
+                .map(|fs| fs.args().mountpoint().display().to_string())
+                .collect(),
+        )),
+        "list_entries" => {
+            let index = mount(mounts, params)?.index();
+            Ok(Reply::Paths(
+                index
+                    .entries
+                    .iter()
+                    .map(|e| virtual_path(&e.dir, &e.name))
+                    .collect(),
+            ))
+        }
+        "entries" => {
+            let fs = mount(mounts, params)?;
+            let degraded: HashMap<PathBuf, String> = fs.errors.degraded().into_iter().collect();
+            Ok(Reply::Entries(
+                fs.index()
+                    .entries
+                    .iter()
+                    .map(|e| entry_info(e, &degraded))
+                    .collect(),
+            ))
+        }
+        "entry" => {
+            let fs = mount(mounts, params)?;
+            let e = entry(fs, params)?;
+            let degraded: HashMap<PathBuf, String> = fs.errors.degraded().into_iter().collect();
+            Ok(Reply::Entry(entry_info(&e, &degraded)))
+        }
+        "stats" => Ok(Reply::Stats(mount(mounts, params)?.stats())),
+        "degraded_sources" => Ok(Reply::Degraded(
+            mount(mounts, params)?
+                .errors
+                .degraded()
+                .into_iter()
+                .map(|(path, error)| DegradedSource {
+                    source: path.display().to_string(),
+                    error,
+                })
+                .collect(),
+        )),
+        "errors" => Ok(Reply::Errors(
+            mount(mounts, params)?
+                .errors
+                .failures()
+                .into_iter()
+                .map(|f| SourceErrors {
+                    source: f.path.display().to_string(),
+                    failures: f.count,
+                    last_error: f.last,
+                    degraded: f.degraded,
+                })
+                .collect(),
+        )),
+        "reload" => {
+            let fs = mount(mounts, params)?;
+            fs.build_index()
+                .map_err(|e| RpcError::new(FAILED, format!("re-index failed: {e:#}")))?;
+            info!("control socket: re-indexed {:?}", fs.args().mountpoint());
+            Ok(Reply::Count(fs.index().entries.len() as u64))
+        }
+        // The frame cache is shared by every mount.
+        "evict" => Ok(Reply::Count(match mounts.first() {
+            Some(fs) => fs.frame_cache.clear() as u64,
+            None => 0,
+        })),
+        "degrade" => {
+            let fs = mount(mounts, params)?;
+            let e = entry(fs, params)?;
+            let reason = params.reason.as_deref().unwrap_or("degraded by hand");
+            let changed = fs.errors.degrade(&e.chd_path, reason);
+            if changed {
+                info!("control socket: degraded {:?}: {}", e.chd_path, reason);
+                fs.invalidate_source(&e.chd_path);
+            }
+            Ok(Reply::Changed(changed))
+        }
+        "restore" => {
+            let fs = mount(mounts, params)?;
+            let e = entry(fs, params)?;
+            let changed = fs.errors.restore(&e.chd_path);
+            if changed {
+                info!("control socket: restored {:?}", e.chd_path);
+                fs.invalidate_source(&e.chd_path);
+            }
+            Ok(Reply::Changed(changed))
+        }
+        "read_trace" => Ok(Reply::Trace(mount(mounts, params)?.read_trace.rule())),
+        "trace_reads" => {
+            let fs = mount(mounts, params)?;
+            let rule = TraceRule {
+                every: params.every.unwrap_or(1).max(1),
+                slower_ms: params.slower_ms.unwrap_or(0),
+                file: params.file.clone(),
+            };
+            info!(
+                "control socket: tracing reads of {:?}: {:?}",
+                fs.args().mountpoint(),
+                rule
+            );
+            fs.read_trace.set(Some(rule.clone()));
+            Ok(Reply::Trace(Some(rule)))
+        }
+        "stop_trace" => {
+            let fs = mount(mounts, params)?;
+            let changed = fs.read_trace.rule().is_some();
+            fs.read_trace.set(None);
+            Ok(Reply::Changed(changed))
+        }
+        "hot" => Ok(Reply::Hot(
+            mount(mounts, params)?
+                .hot
+                .list()
+                .into_iter()
+                .map(|p| HotTitle {
+                    path: p.path,
+                    size: p.size,
+                    loaded: p.loaded,
+                })
+                .collect(),
+        )),
+        "pin" => {
+            let fs = mount(mounts, params)?;
+            let e = entry(fs, params)?;
+            let changed = fs
+                .pin_title(&virtual_path(&e.dir, &e.name))
+                .map_err(|e| RpcError::new(FAILED, format!("{e:#}")))?;
+            Ok(Reply::Changed(changed))
+        }
+        "unpin" => {
+            // By path alone: the title may have left the index.
+            let fs = mount(mounts, params)?;
+            let path = params
+                .path
+                .as_deref()
+                .ok_or_else(|| RpcError::new(INVALID_PARAMS, "path is required"))?;
+            let changed = fs.hot.unpin(path.trim_matches('/'));
+            if changed {
+                info!("control socket: {:?} no longer hot", path);
+            }
+            Ok(Reply::Changed(changed))
+        }
+        "handles" => Ok(Reply::Handles(
+            mount(mounts, params)?
+                .handles
+                .list()
+                .into_iter()
+                .map(|(fh, h)| {
+                    let (interactive_bytes, bulk_bytes, queued) = h.fair.get();
+                    OpenHandle {
+                        fh,
+                        source: h.chd_path.display().to_string(),
+                        interactive_bytes,
+                        bulk_bytes,
+                        queued_ms: queued.as_millis() as u64,
+                    }
+                })
+                .collect(),
+        )),
+        _ => Err(RpcError::new(
+            METHOD_NOT_FOUND,
+            format!("no method {method:?}"),
+        )),
+    }
+}
+
+fn entry_info(e: &IndexEntry, degraded: &HashMap<PathBuf, String>) -> EntryInfo {
+    EntryInfo {
+        path: virtual_path(&e.dir, &e.name),
+        source: e.chd_path.display().to_string(),
+        size: e.provider.size(),
+        kind: format!("{:?}", e.provider.kind()).to_lowercase(),
+        sha1: e.sha1.as_ref().map(sha1::hex),
+        label: e.label.clone(),
+        original_name: e.original_name.clone(),
+        degraded: degraded.get(&e.chd_path).cloned(),
+    }
+}
+
+/// The mount `params` names, or the only one.
+fn mount<'a>(mounts: &'a [Arc<FsState>], params: &Params) -> Result<&'a Arc<FsState>, RpcError> {
+    match (&params.mount, mounts) {
+        (None, [fs]) => Ok(fs),
+        (None, _) => Err(RpcError::new(INVALID_PARAMS, "mount is required")),
+        (Some(m), _) => mounts
+            .iter()
+            .find(|fs| fs.args().mountpoint() == Path::new(m))
+            .ok_or_else(|| RpcError::new(INVALID_PARAMS, format!("not served here: {m}"))),
+    }
+}
+
+/// The entry at `params.path` in `fs`.
+fn entry(fs: &FsState, params: &Params) -> Result<IndexEntry, RpcError> {
+    let path = params
+        .path
+        .as_deref()
+        .ok_or_else(|| RpcError::new(INVALID_PARAMS, "path is required"))?;
+    let path = path.trim_matches('/');
+    fs.index()
+        .entries
+        .iter()
+        .find(|e| virtual_path(&e.dir, &e.name) == path)
+        .cloned()
+        .ok_or_else(|| RpcError::new(INVALID_PARAMS, format!("no entry {path:?}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unknown_methods_and_mounts_are_rejected() {
+        let params = Params::default();
+        assert!(matches!(
+            call(&[], "list_mounts", &params),
+            Ok(Reply::Paths(p)) if p.is_empty()
+        ));
+        assert!(matches!(call(&[], "evict", &params), Ok(Reply::Count(0))));
+        assert_eq!(
+            call(&[], "format_disk", &params).unwrap_err().code,
+            METHOD_NOT_FOUND
+        );
+        assert_eq!(
+            call(&[], "stats", &params).unwrap_err(),
+            RpcError::new(INVALID_PARAMS, "mount is required")
+        );
+
+        let params = Params {
+            mount: Some("/srv/iso".into()),
+            ..Params::default()
+        };
+        assert_eq!(
+            call(&[], "reload", &params).unwrap_err(),
+            RpcError::new(INVALID_PARAMS, "not served here: /srv/iso")
+        );
+    }
+}
@@ -0,0 +1,60 @@
+//! CRC-32 (ISO-HDLC, as zip and gzip use it), for the `crc` attribute DATs
+//! give each ROM in `hash` output.
+
+const TABLE: [u32; 256] = table();
+
+const fn table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut c = i as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 != 0 {
+                0xEDB8_8320 ^ (c >> 1)
+            } else {
+                c >> 1
+            };
+            k += 1;
+        }
+        table[i] = c;
+        i += 1;
+    }
+    table
+}
+
+#[derive(Default)]
+pub struct Crc32(u32);
+
+impl Crc32 {
+    pub fn update(&mut self, data: &[u8]) {
+        let mut c = !self.0;
+        for &b in data {
+            c = TABLE[((c ^ u32::from(b)) & 0xFF) as usize] ^ (c >> 8);
+        }
+        self.0 = !c;
+    }
+
+    pub fn finish(self) -> u32 {
+        self.0
+    }
+}
+
+pub fn hex(crc: u32) -> String {
+    format!("{crc:08x}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_checksums() {
+        assert_eq!(Crc32::default().finish(), 0);
+
+        let mut h = Crc32::default();
+        h.update(b"1234");
+        h.update(b"56789");
+        assert_eq!(hex(h.finish()), "cbf43926");
+    }
+}
@@ -0,0 +1,397 @@
+//! CSO/ZSO container views over a 2048-byte ISO stream.
+//!
+//! Both formats share the CISO v1 layout: a 24-byte header, a table of
+//! `blocks + 1` little-endian u32 block offsets (shifted right by `align`,
+//! high bit = block stored uncompressed), then the block data. CSO blocks are
+//! raw deflate, ZSO blocks are raw LZ4.
+//!
+//! The block table can only be known after compressing every block once, so
+//! [`CsoLayout::build`] does a single pass and keeps just the offsets; block
+//! payloads are recompressed on demand (compression is deterministic) and
+//! cached by the caller.
+
+use anyhow::{anyhow, Result};
+use clap::ValueEnum;
+use flate2::{write::DeflateEncoder, Compression};
+use lru::LruCache;
+use serde::Deserialize;
+use std::{
+    fmt,
+    io::{Read, Write},
+    num::NonZeroUsize,
+    sync::{Arc, Mutex},
+};
+use tracing::{error, info};
+
+use crate::provider::{BackingProvider, ImageKind, ProviderReader};
+
+pub const CSO_BLOCK_SIZE: u32 = 2048;
+const HEADER_SIZE: u64 = 24;
+const PLAIN_FLAG: u32 = 0x8000_0000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CsoFormat {
+    /// Deflate-compressed CISO (broadest loader support)
+    Cso,
+    /// LZ4-compressed ZISO (cheaper to decode on the PS2)
+    Zso,
+}
+
+impl CsoFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            CsoFormat::Cso => "cso",
+            CsoFormat::Zso => "zso",
+        }
+    }
+
+    fn magic(self) -> &'static [u8; 4] {
+        match self {
+            CsoFormat::Cso => b"CISO",
+            CsoFormat::Zso => b"ZISO",
+        }
+    }
+}
+
+/// Compress one block; `None` means it does not shrink and is stored plain.
+pub fn compress_block(format: CsoFormat, block: &[u8]) -> Result<Option<Vec<u8>>> {
+    let packed = match format {
+        CsoFormat::Cso => {
+            let mut enc = DeflateEncoder::new(Vec::new(), Compression::best());
+            enc.write_all(block)?;
+            enc.finish()?
+        }
+        CsoFormat::Zso => lz4_flex::block::compress(block),
+    };
+
+    Ok((packed.len() < block.len()).then_some(packed))
+}
+
+#[derive(Debug)]
+pub struct CsoLayout {
+    pub format: CsoFormat,
+    pub total_bytes: u64,
+    pub align: u8,
+    /// `blocks + 1` raw table words, exactly as serialized
+    pub table: Vec<u32>,
+}
+
+impl CsoLayout {
+    /// Compress every block of `iso` once to compute the block table.
+    pub fn build<R: Read>(format: CsoFormat, total_bytes: u64, mut iso: R) -> Result<Self> {
+        let blocks = total_bytes.div_ceil(CSO_BLOCK_SIZE as u64);
+        let align = align_for(total_bytes, blocks);
+
+        let slots = usize::try_from(blocks)
+            .ok()
+            .and_then(|b| b.checked_add(1))
+            .ok_or_else(|| anyhow!("ISO too large for a CSO block table"))?;
+        let mut table = Vec::with_capacity(slots);
+        let mut pos = data_start(blocks, align);
+        let mut buf = vec![0u8; CSO_BLOCK_SIZE as usize];
+
+        for b in 0..blocks {
+            let len = block_len(total_bytes, b);
+            iso.read_exact(&mut buf[..len])?;
+
+            let (stored, flag) = match compress_block(format, &buf[..len])? {
+                Some(packed) => (packed.len() as u64, 0),
+                None => (len as u64, PLAIN_FLAG),
+            };
+
+            let word = u32::try_from(pos >> align)
+                .map_err(|_| anyhow!("CSO offset overflow at block {b}"))?;
+            table.push(word | flag);
+
+            pos = align_up(pos + stored, align);
+        }
+
+        let word = u32::try_from(pos >> align).map_err(|_| anyhow!("CSO offset overflow"))?;
+        table.push(word);
+
+        Ok(Self {
+            format,
+            total_bytes,
+            align,
+            table,
+        })
+    }
+
+    pub fn blocks(&self) -> u64 {
+        self.table.len() as u64 - 1
+    }
+
+    pub fn file_size(&self) -> u64 {
+        (self.table[self.table.len() - 1] as u64 & !(PLAIN_FLAG as u64)) << self.align
+    }
+
+    fn block_start(&self, b: u64) -> u64 {
+        ((self.table[b as usize] & !PLAIN_FLAG) as u64) << self.align
+    }
+
+    fn header_and_table(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(HEADER_SIZE as usize + self.table.len() * 4);
+        out.extend_from_slice(self.format.magic());
+        out.extend_from_slice(&(HEADER_SIZE as u32).to_le_bytes());
+        out.extend_from_slice(&self.total_bytes.to_le_bytes());
+        out.extend_from_slice(&CSO_BLOCK_SIZE.to_le_bytes());
+        out.push(1); // version
+        out.push(self.align);
+        out.extend_from_slice(&[0, 0]);
+
+        for w in &self.table {
+            out.extend_from_slice(&w.to_le_bytes());
+        }
+
+        out
+    }
+
+    /// Serve `[offset, offset + size)` of the container. `stored_block(b)`
+    /// must return block `b` exactly as laid out by [`CsoLayout::build`].
+    pub fn read_at<F>(&self, offset: u64, size: u64, mut stored_block: F) -> Result<Vec<u8>>
+    where
+        F: FnMut(u64) -> Result<Vec<u8>>,
+    {
+        let file_size = self.file_size();
+        if offset >= file_size || size == 0 {
+            return Ok(Vec::new());
+        }
+
+        let end = offset.saturating_add(size).min(file_size);
+        let mut out = Vec::with_capacity((end - offset) as usize);
+
+        let head = self.header_and_table();
+        if offset < head.len() as u64 {
+            let stop = end.min(head.len() as u64);
+            out.extend_from_slice(&head[offset as usize..stop as usize]);
+        }
+
+        let mut pos = offset + out.len() as u64;
+        if pos >= end {
+            return Ok(out);
+        }
+
+        // First block whose stored span (including padding) contains `pos`.
+        let (mut lo, mut hi) = (0u64, self.blocks());
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            if self.block_start(mid + 1) <= pos {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        let mut b = lo;
+
+        while pos < end && b < self.blocks() {
+            let start = self.block_start(b);
+            let next = self.block_start(b + 1);
+
+            if pos < start {
+                // Alignment padding between the block table and block 0.
+                let pad = (start.min(end) - pos) as usize;
+                out.resize(out.len() + pad, 0);
+                pos += pad as u64;
+                continue;
+            }
+
+            let data = stored_block(b)?;
+            let data_end = start + data.len() as u64;
+
+            if pos < data_end {
+                let stop = end.min(data_end);
+                out.extend_from_slice(&data[(pos - start) as usize..(stop - start) as usize]);
+                pos = stop;
+            }
+
+            if pos < end && pos < next {
+                let pad = (next.min(end) - pos) as usize;
+                out.resize(out.len() + pad, 0);
+                pos += pad as u64;
+            }
+
+            b += 1;
+        }
+
+        Ok(out)
+    }
+}
+
+/// CSO/ZSO container synthesized over another provider's ISO stream. The
+/// block table is built on first use; stored blocks are kept in a small LRU.
+pub struct CsoViewProvider {
+    inner: Arc<dyn BackingProvider>,
+    format: CsoFormat,
+    layout: Mutex<Option<Arc<CsoLayout>>>,
+    blocks: Mutex<LruCache<u64, Vec<u8>>>,
+}
+
+impl fmt::Debug for CsoViewProvider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CsoViewProvider")
+            .field("inner", &self.inner)
+            .field("format", &self.format)
+            .finish()
+    }
+}
+
+impl CsoViewProvider {
+    pub fn new(inner: Arc<dyn BackingProvider>, format: CsoFormat) -> Self {
+        Self {
+            inner,
+            format,
+            layout: Mutex::new(None),
+            blocks: Mutex::new(LruCache::new(NonZeroUsize::new(1024).unwrap())),
+        }
+    }
+
+    fn layout(&self) -> Result<Arc<CsoLayout>> {
+        let mut slot = self.layout.lock().expect("cso layout mutex poisoned");
+        if let Some(l) = &*slot {
+            return Ok(Arc::clone(l));
+        }
+
+        let iso_size = self.inner.size();
+        info!(
+            "building {:?} block table for {:?}",
+            self.format, self.inner
+        );
+        let layout = Arc::new(CsoLayout::build(
+            self.format,
+            iso_size,
+            ProviderReader::new(&*self.inner),
+        )?);
+        info!("{iso_size} -> {} bytes", layout.file_size());
+
+        *slot = Some(Arc::clone(&layout));
+        Ok(layout)
+    }
+
+    /// Block `b` exactly as laid out in the block table.
+    fn stored_block(&self, layout: &CsoLayout, b: u64) -> Result<Vec<u8>> {
+        if let Some(buf) = self
+            .blocks
+            .lock()
+            .expect("cso_blocks mutex poisoned")
+            .get(&b)
+        {
+            return Ok(buf.clone());
+        }
+
+        let mut raw = vec![0u8; block_len(layout.total_bytes, b)];
+        let n = self.inner.read_at(b * CSO_BLOCK_SIZE as u64, &mut raw)?;
+        if n < raw.len() {
+            return Err(anyhow!("short read of block {b} ({n} < {})", raw.len()));
+        }
+        let stored = compress_block(layout.format, &raw)?.unwrap_or(raw);
+
+        self.blocks
+            .lock()
+            .expect("cso_blocks mutex poisoned")
+            .put(b, stored.clone());
+
+        Ok(stored)
+    }
+}
+
+impl BackingProvider for CsoViewProvider {
+    /// Container size; builds the block table on first call. A failed build
+    /// is logged and reported as an empty file (reads then return EIO).
+    fn size(&self) -> u64 {
+        match self.layout() {
+            Ok(l) => l.file_size(),
+            Err(e) => {
+                error!("building compressed view of {:?}: {}", self.inner, e);
+                0
+            }
+        }
+    }
+
+    fn kind(&self) -> ImageKind {
+        ImageKind::View
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        let layout = self.layout()?;
+        let data = layout.read_at(offset, buf.len() as u64, |b| self.stored_block(&layout, b))?;
+
+        buf[..data.len()].copy_from_slice(&data);
+        Ok(data.len())
+    }
+}
+
+/// Bytes of block `b` in an ISO of `total_bytes` (the last block may be short).
+pub fn block_len(total_bytes: u64, b: u64) -> usize {
+    let start = b * CSO_BLOCK_SIZE as u64;
+    (total_bytes - start).min(CSO_BLOCK_SIZE as u64) as usize
+}
+
+/// Smallest alignment shift that keeps every worst-case offset below the
+/// plain-flag bit.
+fn align_for(total_bytes: u64, blocks: u64) -> u8 {
+    let mut align = 0u8;
+    loop {
+        let worst = data_start(blocks, align) + total_bytes + blocks * ((1u64 << align) - 1);
+        if (worst >> align) < PLAIN_FLAG as u64 {
+            return align;
+        }
+        align += 1;
+    }
+}
+
+fn data_start(blocks: u64, align: u8) -> u64 {
+    align_up(HEADER_SIZE + (blocks + 1) * 4, align)
+}
+
+fn align_up(v: u64, align: u8) -> u64 {
+    let a = 1u64 << align;
+    v.div_ceil(a) * a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn align_grows_only_for_huge_images() {
+        assert_eq!(align_for(700 * 1024 * 1024, 358_400), 0);
+        let eight_gib = 8u64 << 30;
+        assert!(align_for(eight_gib, eight_gib / 2048) >= 2);
+    }
+
+    #[test]
+    fn header_is_24_bytes_with_magic_and_version() {
+        let layout = CsoLayout {
+            format: CsoFormat::Zso,
+            total_bytes: 4096,
+            align: 0,
+            table: vec![36, 2084, 4132],
+        };
+
+        let head = layout.header_and_table();
+        assert_eq!(&head[0..4], b"ZISO");
+        assert_eq!(u32::from_le_bytes(head[4..8].try_into().unwrap()), 24);
+        assert_eq!(u64::from_le_bytes(head[8..16].try_into().unwrap()), 4096);
+        assert_eq!(head[20], 1);
+        assert_eq!(head.len(), 24 + 3 * 4);
+        assert_eq!(layout.file_size(), 4132);
+    }
+
+    #[test]
+    fn offsets_past_4gib_stay_in_u64() {
+        let eight_gib = 8u64 << 30;
+        let last = eight_gib / CSO_BLOCK_SIZE as u64 - 1;
+        assert_eq!(block_len(eight_gib, last), CSO_BLOCK_SIZE as usize);
+        assert_eq!(block_len(eight_gib + 100, last + 1), 100);
+
+        let align = align_for(eight_gib, last + 1);
+        let layout = CsoLayout {
+            format: CsoFormat::Cso,
+            total_bytes: eight_gib,
+            align,
+            table: vec![0, ((eight_gib + (1 << 20)) >> align) as u32],
+        };
+        assert!(layout.file_size() > u32::MAX as u64);
+    }
+}
@@ -0,0 +1,103 @@
+//! `--dbus`: the `org.chd2iso.Fuse` service, so desktop frontends can list,
+//! re-index and unmount libraries without shelling out. One object,
+//! `/org/chd2iso/Fuse`, serves every mount of the process; its methods take
+//! the mountpoint they act on.
+
+use anyhow::Result;
+use std::{collections::HashMap, path::Path, sync::Arc};
+use tracing::info;
+use zbus::{
+    blocking::{connection, Connection},
+    fdo, interface,
+};
+
+use crate::{fusedev, DbusBus, FsState};
+
+const NAME: &str = "org.chd2iso.Fuse";
+const PATH: &str = "/org/chd2iso/Fuse";
+
+struct Service {
+    mounts: Vec<Arc<FsState>>,
+}
+
+impl Service {
+    fn mount(&self, mountpoint: &str) -> fdo::Result<&Arc<FsState>> {
+        self.mounts
+            .iter()
+            .find(|fs| fs.args().mountpoint() == Path::new(mountpoint))
+            .ok_or_else(|| fdo::Error::InvalidArgs(format!("not served here: {mountpoint}")))
+    }
+}
+
+#[interface(name = "org.chd2iso.Fuse")]
+impl Service {
+    fn list_mounts(&self) -> Vec<String> {
+        self.mounts
+            .iter()
+            .map(|fs| fs.args().mountpoint().display().to_string())
+            .collect()
+    }
+
+    /// Paths of the exposed files, relative to the mountpoint.
+    fn list_entries(&self, mount: &str) -> fdo::Result<Vec<String>> {
+        let index = self.mount(mount)?.index();
+        Ok(index
+            .entries
+            .iter()
+            .map(|e| match e.dir.as_str() {
+                "" => e.name.clone(),
+                dir => format!("{dir}/{}", e.name),
+            })
+            .collect())
+    }
+
+    /// Re-index `mount`, as SIGHUP does without the config reload; returns
+    /// the new entry count.
+    fn reindex(&self, mount: &str) -> fdo::Result<u32> {
+        let fs = self.mount(mount)?;
+        fs.build_index()
+            .map_err(|e| fdo::Error::Failed(format!("re-index failed: {e:#}")))?;
+        info!("D-Bus: re-indexed {:?}", mount);
+        Ok(fs.index().entries.len() as u32)
+    }
+
+    /// Counters for `mount`; the frame cache figures are process-wide.
+    fn stats(&self, mount: &str) -> fdo::Result<HashMap<String, u64>> {
+        Ok(self.mount(mount)?.stats().into_iter().collect())
+    }
+
+    /// Sources of `mount` past their error budget, with the error that
+    /// degraded each; cleared by the next re-index.
+    fn degraded_sources(&self, mount: &str) -> fdo::Result<Vec<(String, String)>> {
+        Ok(self
+            .mount(mount)?
+            .errors
+            .degraded()
+            .into_iter()
+            .map(|(path, reason)| (path.display().to_string(), reason))
+            .collect())
+    }
+
+    fn unmount(&self, mount: &str) -> fdo::Result<()> {
+        let fs = self.mount(mount)?;
+        fusedev::unmount(fs.args().mountpoint(), false).map_err(fdo::Error::Failed)?;
+        info!("D-Bus: unmounted {:?}", mount);
+        Ok(())
+    }
+}
+
+/// Claim `org.chd2iso.Fuse` on `bus` and serve `mounts`. The service lives
+/// as long as the returned connection.
+pub fn serve(bus: DbusBus, mounts: Vec<Arc<FsState>>) -> Result<Connection> {
+    let builder = match bus {
+        DbusBus::Session => connection::Builder::session()?,
+        DbusBus::System => connection::Builder::system()?,
+    };
+
+    let conn = builder
+        .name(NAME)?
+        .serve_at(PATH, Service { mounts })?
+        .build()?;
+    info!("D-Bus: serving {} at {} on the {:?} bus", NAME, PATH, bus);
+    Ok(conn)
+}
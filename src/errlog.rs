@@ -0,0 +1,234 @@
+//! Logging of failed source reads. A frontend scanning a corrupt image can
+//! fail thousands of reads in a row, so each file's errors are deduplicated:
+//! a message that repeats the previous one for that file (numbers aside, as
+//! they are mostly block offsets) is only counted, and the count is logged
+//! as "repeated N times" at most every `SUMMARY_EVERY`. With an error budget
+//! (`--error-budget`) a file that fails that many reads is marked degraded
+//! until the next re-index; `--quarantine` decides how it is served then.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tracing::{error, warn};
+
+const SUMMARY_EVERY: Duration = Duration::from_secs(10);
+
+#[derive(Default)]
+struct FileErrors {
+    failures: u64,
+    /// Shape of the last message logged
+    last: String,
+    /// The last message itself
+    last_error: String,
+    /// Repeats of `last` not logged yet
+    repeats: u64,
+    logged_at: Option<Instant>,
+    /// The error that used up the budget
+    degraded: Option<String>,
+}
+
+impl FileErrors {
+    /// Lines to log for a failure with `msg` at `now`.
+    fn note(&mut self, msg: &str, now: Instant) -> Vec<String> {
+        let shape = shape(msg);
+        let mut out = Vec::new();
+
+        if shape == self.last {
+            self.repeats += 1;
+            let due = self
+                .logged_at
+                .is_none_or(|t| now.duration_since(t) >= SUMMARY_EVERY);
+            if due {
+                out.push(format!("{msg} (repeated {} times)", self.repeats));
+                self.repeats = 0;
+                self.logged_at = Some(now);
+            }
+            return out;
+        }
+
+        if self.repeats > 0 {
+            out.push(format!("previous error repeated {} times", self.repeats));
+        }
+        out.push(msg.to_string());
+        self.last = shape;
+        self.repeats = 0;
+        self.logged_at = Some(now);
+        out
+    }
+}
+
+/// `msg` with every run of digits folded to `#`.
+fn shape(msg: &str) -> String {
+    let mut out = String::with_capacity(msg.len());
+    for c in msg.chars() {
+        if !c.is_ascii_digit() {
+            out.push(c);
+        } else if !out.ends_with('#') {
+            out.push('#');
+        }
+    }
+    out
+}
+
+/// Failed reads of one source since the last re-index.
+pub struct Failures {
+    pub path: PathBuf,
+    pub count: u64,
+    pub last: String,
+    pub degraded: Option<String>,
+}
+
+#[derive(Default)]
+pub struct ErrorLog(Mutex<HashMap<PathBuf, FileErrors>>);
+
+impl ErrorLog {
+    fn files(&self) -> std::sync::MutexGuard<'_, HashMap<PathBuf, FileErrors>> {
+        self.0.lock().expect("error log mutex poisoned")
+    }
+
+    /// Record a failed read of `path`, logging `msg` unless it repeats the
+    /// previous error. Returns true if this failure used up `budget` (0:
+    /// unlimited) and so degraded the file.
+    pub fn failed(&self, path: &Path, msg: &str, budget: u64) -> bool {
+        let (lines, degraded) = {
+            let mut files = self.files();
+            let f = files.entry(path.to_path_buf()).or_default();
+            f.failures += 1;
+            f.last_error = msg.to_string();
+            let degraded = budget > 0 && f.degraded.is_none() && f.failures >= budget;
+            if degraded {
+                f.degraded = Some(msg.to_string());
+            }
+            (f.note(msg, Instant::now()), degraded)
+        };
+
+        for line in lines {
+            error!("read error on {:?}: {}", path, line);
+        }
+        if degraded {
+            warn!(
+                "{:?}: {} failed reads, degraded until the next re-index",
+                path, budget
+            );
+        }
+        degraded
+    }
+
+    pub fn is_degraded(&self, path: &Path) -> bool {
+        self.files().get(path).is_some_and(|f| f.degraded.is_some())
+    }
+
+    /// Degraded files with the error that degraded them, by path.
+    pub fn degraded(&self) -> Vec<(PathBuf, String)> {
+        let mut out: Vec<(PathBuf, String)> = self
+            .files()
+            .iter()
+            .filter_map(|(p, f)| Some((p.clone(), f.degraded.clone()?)))
+            .collect();
+        out.sort();
+        out
+    }
+
+    /// Every source with failed reads (or degraded by hand), by path.
+    pub fn failures(&self) -> Vec<Failures> {
+        let mut out: Vec<Failures> = self
+            .files()
+            .iter()
+            .map(|(p, f)| Failures {
+                path: p.clone(),
+                count: f.failures,
+                last: f.last_error.clone(),
+                degraded: f.degraded.clone(),
+            })
+            .collect();
+        out.sort_by(|a, b| a.path.cmp(&b.path));
+        out
+    }
+
+    /// Degrade `path` by hand, as if it had used up its budget with
+    /// `reason`; false if it already was.
+    pub fn degrade(&self, path: &Path, reason: &str) -> bool {
+        let mut files = self.files();
+        let f = files.entry(path.to_path_buf()).or_default();
+        if f.degraded.is_some() {
+            return false;
+        }
+        f.degraded = Some(reason.to_string());
+        true
+    }
+
+    /// Serve `path` normally again, with a fresh budget; false if it was
+    /// not degraded.
+    pub fn restore(&self, path: &Path) -> bool {
+        self.files()
+            .remove(path)
+            .is_some_and(|f| f.degraded.is_some())
+    }
+
+    /// Forget all errors (on re-index).
+    pub fn reset(&self) {
+        self.files().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repeats_are_counted_then_summarized() {
+        let mut f = FileErrors::default();
+        let t = Instant::now();
+
+        assert_eq!(
+            f.note("block 7 decoded short", t),
+            vec!["block 7 decoded short"]
+        );
+        for i in 0..411 {
+            assert!(f.note(&format!("block {i} decoded short"), t).is_empty());
+        }
+        assert_eq!(
+            f.note("block 9 decoded short", t + SUMMARY_EVERY),
+            vec!["block 9 decoded short (repeated 412 times)"]
+        );
+
+        assert!(f
+            .note("block 1 decoded short", t + SUMMARY_EVERY)
+            .is_empty());
+        assert_eq!(
+            f.note("invalid hunk size", t + SUMMARY_EVERY),
+            vec!["previous error repeated 1 times", "invalid hunk size"]
+        );
+    }
+
+    #[test]
+    fn degrades_after_the_budget() {
+        let log = ErrorLog::default();
+        let path = Path::new("bad.chd");
+
+        assert!(!log.failed(path, "x", 3));
+        assert!(!log.failed(path, "x", 3));
+        assert!(!log.is_degraded(path));
+        assert!(log.failed(path, "bad hunk", 3));
+        assert!(!log.failed(path, "x", 3));
+        assert!(log.is_degraded(path));
+        assert_eq!(
+            log.degraded(),
+            vec![(PathBuf::from("bad.chd"), "bad hunk".to_string())]
+        );
+        let failures = log.failures();
+        assert_eq!((failures[0].count, &*failures[0].last), (4, "x"));
+
+        assert!(log.restore(path));
+        assert!(!log.is_degraded(path));
+        assert!(log.degrade(path, "by hand"));
+        assert!(!log.degrade(path, "again"));
+        assert!(log.is_degraded(path));
+
+        log.reset();
+        assert!(!log.is_degraded(path));
+    }
+}
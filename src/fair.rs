@@ -0,0 +1,192 @@
+//! `--fair-reads N`: at most N reads decode at once, process-wide, and while
+//! more wait, interactive reads go first. A read is bulk if it asks for
+//! `BULK_READ` bytes or more, or continues a sequential run of `BULK_RUN`
+//! bytes on its handle: a frontend copying or scraping a whole image. Other
+//! reads, small and near where the handle last read, are an emulator's and
+//! interactive. Readahead fills are bulk. A waiting bulk read is let in after
+//! every `BULK_EVERY` interactive ones, so a scan slows down but never stops.
+//!
+//! Reads served from a handle's readahead buffer skip the queue. The sync
+//! backend serves one request at a time, so there only readahead fills
+//! compete with reads; `--backend async` queues every concurrent read.
+
+use serde::Serialize;
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Condvar, Mutex, MutexGuard, OnceLock,
+    },
+    time::{Duration, Instant},
+};
+
+/// Reads this large are bulk whatever came before.
+pub const BULK_READ: u64 = 1024 * 1024;
+/// A sequential run this long makes its reads bulk.
+pub const BULK_RUN: u64 = 32 * 1024 * 1024;
+/// Interactive reads let in ahead of a waiting bulk read.
+const BULK_EVERY: u32 = 4;
+
+static GATE: OnceLock<Gate> = OnceLock::new();
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Class {
+    Interactive,
+    Bulk,
+}
+
+/// Let at most `slots` reads decode at once (0: no limit, no queue).
+pub fn configure(slots: usize) {
+    if slots > 0 {
+        let _ = GATE.set(Gate {
+            slots,
+            queue: Mutex::default(),
+            turn: Condvar::new(),
+        });
+    }
+}
+
+/// Run `op` once a `class` read may; its result and how long it queued.
+pub fn run<T>(class: Class, op: impl FnOnce() -> T) -> (T, Duration) {
+    let Some(gate) = GATE.get() else {
+        return (op(), Duration::ZERO);
+    };
+    let start = Instant::now();
+    let _permit = gate.enter(class);
+    let queued = start.elapsed();
+    (op(), queued)
+}
+
+/// (interactive, bulk) reads waiting now, if `--fair-reads` is on.
+pub fn waiting() -> Option<(usize, usize)> {
+    let queue = GATE.get()?.queue();
+    Some((queue.waiting[0].len(), queue.waiting[1].len()))
+}
+
+struct Gate {
+    slots: usize,
+    queue: Mutex<Queue>,
+    turn: Condvar,
+}
+
+/// Tickets of waiting reads by class, and reads in progress.
+#[derive(Default)]
+struct Queue {
+    busy: usize,
+    next: u64,
+    waiting: [VecDeque<u64>; 2],
+    /// Interactive reads let in since the last bulk one
+    since_bulk: u32,
+}
+
+impl Queue {
+    /// The class and ticket of the read to let in next.
+    fn head(&self) -> Option<(Class, u64)> {
+        let interactive = self.waiting[0].front().map(|&t| (Class::Interactive, t));
+        let bulk = self.waiting[1].front().map(|&t| (Class::Bulk, t));
+        match (interactive, bulk) {
+            (Some(_), Some(b)) if self.since_bulk >= BULK_EVERY => Some(b),
+            (Some(i), _) => Some(i),
+            (None, b) => b,
+        }
+    }
+
+    fn push(&mut self, class: Class) -> u64 {
+        let ticket = self.next;
+        self.next += 1;
+        self.waiting[class as usize].push_back(ticket);
+        ticket
+    }
+
+    /// Let the head in, if a slot is free and it is `ticket`.
+    fn admit(&mut self, slots: usize, class: Class, ticket: u64) -> bool {
+        if self.busy >= slots || self.head() != Some((class, ticket)) {
+            return false;
+        }
+        self.waiting[class as usize].pop_front();
+        self.busy += 1;
+        self.since_bulk = match class {
+            Class::Interactive => self.since_bulk.saturating_add(1),
+            Class::Bulk => 0,
+        };
+        true
+    }
+}
+
+impl Gate {
+    fn queue(&self) -> MutexGuard<'_, Queue> {
+        self.queue.lock().expect("fair read queue poisoned")
+    }
+
+    fn enter(&self, class: Class) -> Permit<'_> {
+        let mut queue = self.queue();
+        let ticket = queue.push(class);
+        while !queue.admit(self.slots, class, ticket) {
+            queue = self.turn.wait(queue).expect("fair read queue poisoned");
+        }
+        // Another slot may be free for the next in line.
+        self.turn.notify_all();
+        Permit(self)
+    }
+}
+
+struct Permit<'a>(&'a Gate);
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        self.0.queue().busy -= 1;
+        self.0.turn.notify_all();
+    }
+}
+
+/// What one handle read, by class, and how long it queued.
+#[derive(Default)]
+pub struct Tally {
+    bytes: [AtomicU64; 2],
+    queued_us: AtomicU64,
+}
+
+impl Tally {
+    pub fn record(&self, class: Class, bytes: usize, queued: Duration) {
+        self.bytes[class as usize].fetch_add(bytes as u64, Ordering::Relaxed);
+        self.queued_us
+            .fetch_add(queued.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// (interactive bytes, bulk bytes, time queued)
+    pub fn get(&self) -> (u64, u64, Duration) {
+        (
+            self.bytes[0].load(Ordering::Relaxed),
+            self.bytes[1].load(Ordering::Relaxed),
+            Duration::from_micros(self.queued_us.load(Ordering::Relaxed)),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interactive_reads_go_first_but_bulk_gets_turns() {
+        let mut queue = Queue::default();
+        let mut order = Vec::new();
+        let bulk: Vec<u64> = (0..2).map(|_| queue.push(Class::Bulk)).collect();
+        let interactive: Vec<u64> = (0..6).map(|_| queue.push(Class::Interactive)).collect();
+
+        // One slot, busy: nobody gets in.
+        queue.busy = 1;
+        assert!(!queue.admit(1, Class::Interactive, interactive[0]));
+        queue.busy = 0;
+
+        while let Some((class, ticket)) = queue.head() {
+            assert!(queue.admit(1, class, ticket));
+            queue.busy -= 1;
+            order.push(class);
+        }
+        use Class::{Bulk as B, Interactive as I};
+        assert_eq!(order, [I, I, I, I, B, I, I, B]);
+        assert!(!queue.admit(1, Class::Bulk, bulk[1]));
+    }
+}
@@ -0,0 +1,211 @@
+//! `/dev/fuse` checks before mounting, with advice for the ways containers
+//! (docker, podman, unprivileged user namespaces) get it wrong, and
+//! unmounting through the fusermount helpers.
+
+use anyhow::{anyhow, Result};
+use clap::ValueEnum;
+use serde::Deserialize;
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, ErrorKind},
+    path::Path,
+    process::Command,
+    thread,
+    time::{Duration, Instant},
+};
+use tracing::{info, warn};
+
+const DEVICE: &str = "/dev/fuse";
+const FUSE_CONF: &str = "/etc/fuse.conf";
+
+/// `--allow-other`: whether other users may use the mount.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(try_from = "AllowOtherSetting")]
+pub enum AllowOther {
+    #[default]
+    Off,
+    On,
+    /// On if /etc/fuse.conf permits it, else a mount for this user only
+    Auto,
+}
+
+/// `allow_other` in a config file: `true`/`false` as before, or a mode name.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum AllowOtherSetting {
+    Bool(bool),
+    Name(String),
+}
+
+impl TryFrom<AllowOtherSetting> for AllowOther {
+    type Error = String;
+
+    fn try_from(s: AllowOtherSetting) -> Result<Self, String> {
+        match s {
+            AllowOtherSetting::Bool(on) => Ok(if on { Self::On } else { Self::Off }),
+            AllowOtherSetting::Name(s) => match s.as_str() {
+                "off" => Ok(Self::Off),
+                "on" => Ok(Self::On),
+                "auto" => Ok(Self::Auto),
+                _ => Err(format!(
+                    "allow_other {s:?} is not true, false, off, on or auto"
+                )),
+            },
+        }
+    }
+}
+
+impl AllowOther {
+    /// Whether to mount with `allow_other`, warning once `auto` falls back.
+    pub fn resolve(self, mountpoint: &Path) -> bool {
+        match self {
+            Self::Off => false,
+            Self::On => true,
+            Self::Auto if allow_other_permitted() => true,
+            Self::Auto => {
+                warn!(
+                    "{:?}: allow_other is not permitted (add user_allow_other to {}); mounting for this user only",
+                    mountpoint, FUSE_CONF
+                );
+                false
+            }
+        }
+    }
+}
+
+/// Whether fuse.conf text `conf` has the `user_allow_other` line that lets
+/// users other than root mount with `allow_other`.
+fn user_allow_other(conf: &str) -> bool {
+    conf.lines()
+        .any(|l| l.split('#').next().unwrap_or_default().trim() == "user_allow_other")
+}
+
+/// Whether fusermount will accept `allow_other` from this process.
+pub fn allow_other_permitted() -> bool {
+    let root = unsafe { libc::geteuid() } == 0;
+    root || fs::read_to_string(FUSE_CONF).is_ok_and(|c| user_allow_other(&c))
+}
+
+/// Suffix for a mount with `allow_other` that failed where fusermount
+/// refuses the option.
+pub fn allow_other_hint(allow_other: bool) -> &'static str {
+    if allow_other && !allow_other_permitted() {
+        "; allow_other needs user_allow_other in /etc/fuse.conf (or use --allow-other=auto to mount for this user only)"
+    } else {
+        ""
+    }
+}
+
+/// What to do about `e` from opening `/dev/fuse`.
+fn advice(e: &io::Error) -> &'static str {
+    match e.kind() {
+        ErrorKind::NotFound => {
+            "load the fuse module (modprobe fuse), or in a container pass --device /dev/fuse"
+        }
+        ErrorKind::PermissionDenied => {
+            "it must be crw-rw-rw-; in a container pass --device /dev/fuse, and on AppArmor \
+             hosts --security-opt apparmor:unconfined"
+        }
+        _ => "check that the kernel supports FUSE",
+    }
+}
+
+fn open() -> io::Result<()> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(DEVICE)
+        .map(drop)
+}
+
+/// Make sure `/dev/fuse` can be opened, retrying for up to `wait` (e.g.
+/// while a container runtime is still setting up devices).
+pub fn ensure(wait: Duration) -> Result<()> {
+    let start = Instant::now();
+    let mut logged = false;
+
+    loop {
+        let e = match open() {
+            Ok(()) => return Ok(()),
+            Err(e) => e,
+        };
+        if start.elapsed() >= wait {
+            return Err(anyhow!("cannot open {DEVICE}: {e}; {}", advice(&e)));
+        }
+        if !logged {
+            info!(
+                "{} not available yet ({}), waiting up to {:?}",
+                DEVICE, e, wait
+            );
+            logged = true;
+        }
+        thread::sleep(Duration::from_millis(500));
+    }
+}
+
+/// Suffix for a failed mount with more to say than the error itself.
+pub fn mount_hint(e: &io::Error) -> &'static str {
+    match e.kind() {
+        ErrorKind::PermissionDenied => {
+            "; as non-root, fusermount3 must be installed setuid root, which unprivileged user \
+             namespaces do not honour: there, run as root inside the namespace with \
+             --cap-add SYS_ADMIN (docker) or use rootless podman with --device /dev/fuse"
+        }
+        ErrorKind::NotFound => "; is fusermount3 (the fuse3 package) installed?",
+        _ => "",
+    }
+}
+
+/// `fusermount3 -u` (`-z`: lazily, even while busy), falling back to the
+/// FUSE 2 helper.
+pub fn unmount(mountpoint: &Path, lazy: bool) -> Result<(), String> {
+    let mut last = String::new();
+    for helper in ["fusermount3", "fusermount"] {
+        let mut cmd = Command::new(helper);
+        cmd.arg("-u");
+        if lazy {
+            cmd.arg("-z");
+        }
+        match cmd.arg(mountpoint).output() {
+            Ok(out) if out.status.success() => return Ok(()),
+            Ok(out) => last = String::from_utf8_lossy(&out.stderr).trim().to_string(),
+            Err(e) => last = format!("{helper}: {e}"),
+        }
+    }
+    Err(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn advises_by_error_kind() {
+        let missing = io::Error::from(ErrorKind::NotFound);
+        assert!(advice(&missing).contains("--device /dev/fuse"));
+        assert!(mount_hint(&missing).contains("fusermount3"));
+
+        let denied = io::Error::from(ErrorKind::PermissionDenied);
+        assert!(advice(&denied).contains("crw-rw-rw-"));
+        assert!(mount_hint(&denied).contains("user namespaces"));
+
+        assert_eq!(mount_hint(&io::Error::from(ErrorKind::Other)), "");
+    }
+
+    #[test]
+    fn reads_user_allow_other() {
+        assert!(user_allow_other("# mount_max = 1000\nuser_allow_other\n"));
+        assert!(user_allow_other("  user_allow_other  # for chd2iso\n"));
+        assert!(!user_allow_other("#user_allow_other\n"));
+        assert!(!user_allow_other(""));
+
+        let setting = AllowOther::try_from;
+        assert_eq!(setting(AllowOtherSetting::Bool(true)), Ok(AllowOther::On));
+        assert_eq!(setting(AllowOtherSetting::Bool(false)), Ok(AllowOther::Off));
+        assert_eq!(
+            setting(AllowOtherSetting::Name("auto".into())),
+            Ok(AllowOther::Auto)
+        );
+        assert!(setting(AllowOtherSetting::Name("sometimes".into())).is_err());
+    }
+}
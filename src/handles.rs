@@ -0,0 +1,581 @@
+//! Open file handles: per-open state, file handle allocation and adaptive
+//! readahead.
+//!
+//! Each handle classifies its reads as sequential or random. A sequential
+//! streak opens a readahead window that doubles with every further
+//! sequential read, up to `READAHEAD_MAX`; a seek quarters it, and it closes
+//! below `READAHEAD_MIN`. The window is filled in the background into a
+//! per-handle buffer that later reads are served from.
+//!
+//! Each fill also hints the page cache about the local source file: the
+//! compressed bytes the window decodes from are requested early
+//! (`POSIX_FADV_WILLNEED`) and, with `--drop-source-cache`, those behind it
+//! are dropped (`POSIX_FADV_DONTNEED`) so memory is not spent caching both
+//! the compressed and the decoded data.
+
+use anyhow::Result;
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::File,
+    ops::Range,
+    os::fd::AsRawFd,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, SystemTime},
+};
+use tracing::debug;
+
+use crate::audit::Reader;
+use crate::fair::{self, Class, Tally};
+use crate::latency::{self, ReadTrace, SlowReads};
+use crate::otel::{self, Attr};
+use crate::provider::BackingProvider;
+use crate::throttle::TokenBucket;
+use crate::vfs;
+
+const READAHEAD_MIN: u64 = 128 * 1024;
+const READAHEAD_MAX: u64 = 4 * 1024 * 1024;
+
+/// Sequential reads needed before readahead starts.
+const READAHEAD_STREAK: u32 = 2;
+
+/// Slack either side of the estimated source range of a readahead window,
+/// as the compression ratio varies along an image.
+const SOURCE_ADVICE_SLACK: u64 = 1024 * 1024;
+
+/// Front of a source never dropped from the page cache: the CHD header, hunk
+/// map and metadata, read again on every open.
+const SOURCE_HEAD_KEEP: u64 = 8 * 1024 * 1024;
+
+/// Released handle numbers wait in a queue this long before reuse, so a
+/// number is never handed out again right after it was closed.
+const RECYCLE_AFTER: usize = 64;
+
+pub struct Handle {
+    /// Inode the handle was opened on; reads for any other inode are refused
+    pub ino: u64,
+    /// open(2) flags as passed by the kernel
+    pub flags: i32,
+    pub chd_path: PathBuf,
+    /// Per-handle limit from `--max-handle-throughput`
+    pub throttle: Option<Arc<TokenBucket>>,
+    /// Open file of an `--overlay` handle
+    pub file: Option<File>,
+    /// `--drop-source-cache`
+    drop_source_cache: bool,
+    /// `--audit-log`: who opened the handle and what it read
+    pub audit: Option<Reader>,
+    /// `--slow-read-ms`: the threshold and the mount's tally
+    slow_reads: Option<(Duration, Arc<SlowReads>)>,
+    /// `--trace-reads-*`: the mount's sampling rule
+    trace: Option<Arc<ReadTrace>>,
+    /// Bytes read by `--fair-reads` class and time queued
+    pub fair: Tally,
+    access: Mutex<Access>,
+}
+
+/// Set by `--max-memory` to stop filling readahead buffers.
+static READAHEAD_OFF: AtomicBool = AtomicBool::new(false);
+
+/// Whether sequential reads are prefetched; buffers filled already are
+/// still read from.
+pub fn set_readahead(on: bool) {
+    READAHEAD_OFF.store(!on, Ordering::Relaxed);
+}
+
+/// Read pattern of one handle and its readahead state.
+#[derive(Default)]
+struct Access {
+    /// Where the last read ended
+    next: u64,
+    /// Reads in a row that continued exactly from `next`
+    streak: u32,
+    /// Bytes read in the current sequential run
+    run: u64,
+    window: u64,
+    /// Prefetched bytes and the offset they start at
+    ahead: Option<(u64, Vec<u8>)>,
+    /// A background fill is running
+    filling: bool,
+}
+
+impl Access {
+    /// Copy what the readahead buffer holds at `offset` into `buf`.
+    fn take(&self, offset: u64, buf: &mut [u8]) -> usize {
+        let Some((start, data)) = &self.ahead else {
+            return 0;
+        };
+        if offset < *start || offset - start >= data.len() as u64 {
+            return 0;
+        }
+
+        let from = (offset - start) as usize;
+        let n = buf.len().min(data.len() - from);
+        buf[..n].copy_from_slice(&data[from..from + n]);
+        n
+    }
+
+    /// `--fair-reads` class of a read of `len` bytes at `offset`.
+    fn class(&self, offset: u64, len: u64) -> Class {
+        let run = if offset == self.next && offset != 0 {
+            self.run.saturating_add(len)
+        } else {
+            len
+        };
+        if len >= fair::BULK_READ || run >= fair::BULK_RUN {
+            Class::Bulk
+        } else {
+            Class::Interactive
+        }
+    }
+
+    /// Account for a read of `len` bytes at `offset`, resize the window and
+    /// return the range to prefetch next, if any.
+    fn record(&mut self, ino: u64, offset: u64, len: u64) -> Option<Range<u64>> {
+        let sequential = offset == self.next && offset != 0;
+        let old = self.window;
+
+        if sequential {
+            self.run = self.run.saturating_add(len);
+            self.streak = self.streak.saturating_add(1);
+            if self.streak >= READAHEAD_STREAK {
+                self.window = (self.window * 2).clamp(READAHEAD_MIN, READAHEAD_MAX);
+            }
+        } else {
+            self.run = len;
+            self.streak = 0;
+            self.window /= 4;
+            if self.window < READAHEAD_MIN {
+                self.window = 0;
+            }
+            self.ahead = None;
+        }
+        self.next = offset.saturating_add(len);
+
+        if self.window != old {
+            debug!(
+                "ino {}: {} read at {}, readahead window {} KiB",
+                ino,
+                if sequential { "sequential" } else { "random" },
+                offset,
+                self.window / 1024
+            );
+        }
+
+        if self.window == 0 || self.filling || READAHEAD_OFF.load(Ordering::Relaxed) {
+            return None;
+        }
+
+        // Refill once less than half a window is buffered past `next`.
+        let buffered = match &self.ahead {
+            Some((start, data)) => start + data.len() as u64,
+            None => self.next,
+        }
+        .max(self.next);
+        if buffered - self.next >= self.window / 2 {
+            return None;
+        }
+
+        self.filling = true;
+        Some(buffered..self.next + self.window)
+    }
+
+    /// Append freshly prefetched bytes at `start`, dropping what has already
+    /// been read.
+    fn fill(&mut self, start: u64, data: Vec<u8>) {
+        self.filling = false;
+
+        let mut merged = match self.ahead.take() {
+            Some((s, mut old)) if s + old.len() as u64 == start && s <= self.next => {
+                old.drain(..(self.next - s).min(old.len() as u64) as usize);
+                old
+            }
+            _ if start > self.next => return,
+            _ => Vec::new(),
+        };
+        let base = if merged.is_empty() { start } else { self.next };
+        merged.extend_from_slice(&data);
+        self.ahead = Some((base, merged));
+    }
+}
+
+impl Handle {
+    pub fn new(
+        ino: u64,
+        flags: i32,
+        chd_path: PathBuf,
+        throttle: Option<Arc<TokenBucket>>,
+    ) -> Self {
+        Self {
+            ino,
+            flags,
+            chd_path,
+            throttle,
+            file: None,
+            drop_source_cache: false,
+            audit: None,
+            slow_reads: None,
+            trace: None,
+            fair: Tally::default(),
+            access: Mutex::new(Access::default()),
+        }
+    }
+
+    /// Back the handle with an overlay file instead of an index entry.
+    pub fn with_file(mut self, file: File) -> Self {
+        self.file = Some(file);
+        self
+    }
+
+    /// Drop source pages behind sequential reads (`--drop-source-cache`).
+    pub fn dropping_source_cache(mut self, on: bool) -> Self {
+        self.drop_source_cache = on;
+        self
+    }
+
+    /// Record reads for the audit log (`--audit-log`).
+    pub fn audited(mut self, reader: Reader) -> Self {
+        self.audit = Some(reader);
+        self
+    }
+
+    /// Time reads and warn about those over `threshold` (`--slow-read-ms`).
+    pub fn timing_reads(mut self, threshold: Duration, tally: Arc<SlowReads>) -> Self {
+        self.slow_reads = Some((threshold, tally));
+        self
+    }
+
+    /// Log the reads `trace` samples (`--trace-reads-*`, `trace_reads`).
+    pub fn tracing_reads(mut self, trace: Arc<ReadTrace>) -> Self {
+        self.trace = Some(trace);
+        self
+    }
+
+    fn access(&self) -> std::sync::MutexGuard<'_, Access> {
+        self.access.lock().expect("handle access mutex poisoned")
+    }
+
+    /// Read `buf` at `offset`, from the readahead buffer where it can, and
+    /// start filling the buffer further ahead if the reads are sequential.
+    pub fn read(
+        self: &Arc<Self>,
+        provider: &Arc<dyn BackingProvider>,
+        offset: u64,
+        buf: &mut [u8],
+    ) -> Result<usize> {
+        let tracing = self.trace.as_ref().is_some_and(|t| t.active());
+        if self.slow_reads.is_none() && !tracing && !otel::enabled() {
+            return self.read_untimed(provider, offset, buf);
+        }
+        let len = buf.len();
+        let start = SystemTime::now();
+        let (res, took, spent) = SlowReads::time(|| self.read_untimed(provider, offset, buf));
+        if otel::enabled() {
+            let attrs = vec![
+                ("file", Attr::Str(self.chd_path.display().to_string())),
+                ("offset", Attr::Int(offset as i64)),
+                ("len", Attr::Int(len as i64)),
+                ("source_us", Attr::Int(spent.source.as_micros() as i64)),
+                ("decode_us", Attr::Int(spent.decode.as_micros() as i64)),
+                ("hunks", Attr::Int(spent.hunks.into())),
+                ("frame_cache_hits", Attr::Int(spent.cache_hits.into())),
+                ("frame_cache_misses", Attr::Int(spent.cache_misses.into())),
+                ("readahead", Attr::Int(spent.readahead as i64)),
+            ];
+            otel::span("read", start, took, res.is_ok(), attrs);
+        }
+        if let Some((threshold, tally)) = &self.slow_reads {
+            tally.record(*threshold, &self.chd_path, offset, len, took, spent);
+        }
+        if let Some(trace) = &self.trace {
+            trace.record(&self.chd_path, offset, len, took, spent);
+        }
+        res
+    }
+
+    fn read_untimed(
+        self: &Arc<Self>,
+        provider: &Arc<dyn BackingProvider>,
+        offset: u64,
+        buf: &mut [u8],
+    ) -> Result<usize> {
+        let mut n = self.access().take(offset, buf);
+        latency::readahead(n);
+        if n < buf.len() {
+            let at = offset + n as u64;
+            let class = self.access().class(at, (buf.len() - n) as u64);
+            let (read, queued) = fair::run(class, || provider.read_at(at, &mut buf[n..]));
+            let read = read?;
+            self.fair.record(class, read, queued);
+            n += read;
+        }
+        if let Some(audit) = &self.audit {
+            audit.read(offset, n as u64);
+        }
+
+        let Some(range) = self.access().record(self.ino, offset, n as u64) else {
+            return Ok(n);
+        };
+
+        let handle = Arc::clone(self);
+        let provider = Arc::clone(provider);
+        let spawned = thread::Builder::new()
+            .name("readahead".into())
+            .spawn(move || handle.prefetch(&*provider, range));
+        if let Err(e) = spawned {
+            debug!("readahead thread: {}", e);
+            self.access().filling = false;
+        }
+
+        Ok(n)
+    }
+
+    fn prefetch(&self, provider: &dyn BackingProvider, range: Range<u64>) {
+        self.advise_source(provider.size(), &range);
+
+        let len = range.end.min(provider.size()).saturating_sub(range.start);
+        let mut data = vec![0u8; len as usize];
+        let mut done = 0;
+
+        while done < data.len() {
+            let at = range.start + done as u64;
+            let (read, _) = fair::run(Class::Bulk, || provider.read_at(at, &mut data[done..]));
+            match read {
+                Ok(0) => break,
+                Ok(n) => done += n,
+                Err(e) => {
+                    debug!("readahead on ino {} at {}: {}", self.ino, range.start, e);
+                    break;
+                }
+            }
+        }
+
+        data.truncate(done);
+        self.access().fill(range.start, data);
+    }
+}
+
+impl Handle {
+    /// Page-cache hints for the local source ahead of, and behind, the
+    /// image `window` about to be decoded. Best effort.
+    fn advise_source(&self, image_size: u64, window: &Range<u64>) {
+        if vfs::is_url(&self.chd_path) {
+            return;
+        }
+        let Ok(file) = vfs::open_local(&self.chd_path) else {
+            return;
+        };
+        let Ok(meta) = file.metadata() else {
+            return;
+        };
+
+        let ahead = source_range(window, image_size, meta.len());
+        fadvise(&file, &ahead, libc::POSIX_FADV_WILLNEED);
+        if self.drop_source_cache && ahead.start > SOURCE_HEAD_KEEP {
+            fadvise(
+                &file,
+                &(SOURCE_HEAD_KEEP..ahead.start),
+                libc::POSIX_FADV_DONTNEED,
+            );
+        }
+    }
+}
+
+/// Bytes of a source of `source_size` that the image bytes `window` (of
+/// `image_size`) decode from, estimated by the overall compression ratio.
+fn source_range(window: &Range<u64>, image_size: u64, source_size: u64) -> Range<u64> {
+    if image_size == 0 {
+        return 0..0;
+    }
+    let scale = |n: u64| (n as u128 * source_size as u128 / image_size as u128) as u64;
+    let start = scale(window.start).saturating_sub(SOURCE_ADVICE_SLACK);
+    let end = scale(window.end).saturating_add(SOURCE_ADVICE_SLACK);
+    start..end.min(source_size)
+}
+
+fn fadvise(file: &File, range: &Range<u64>, advice: libc::c_int) {
+    if range.is_empty() {
+        return;
+    }
+    let rc = unsafe {
+        libc::posix_fadvise(
+            file.as_raw_fd(),
+            range.start as libc::off_t,
+            (range.end - range.start) as libc::off_t,
+            advice,
+        )
+    };
+    if rc != 0 {
+        debug!("posix_fadvise {} on {:?}: errno {}", advice, range, rc);
+    }
+}
+
+#[derive(Default)]
+struct Table {
+    open: HashMap<u64, Arc<Handle>>,
+    released: VecDeque<u64>,
+    next: u64,
+}
+
+#[derive(Default)]
+pub struct HandleTable(Mutex<Table>);
+
+impl HandleTable {
+    fn table(&self) -> std::sync::MutexGuard<'_, Table> {
+        self.0.lock().expect("handles mutex poisoned")
+    }
+
+    /// Register `handle` and return its number. Numbers start at 1.
+    pub fn insert(&self, handle: Handle) -> u64 {
+        let mut t = self.table();
+        let fh = if t.released.len() > RECYCLE_AFTER {
+            t.released.pop_front().expect("non-empty")
+        } else {
+            t.next += 1;
+            t.next
+        };
+
+        t.open.insert(fh, Arc::new(handle));
+        fh
+    }
+
+    /// The handle `fh`, provided it is open on `ino`; `EBADF` otherwise.
+    pub fn get(&self, fh: u64, ino: u64) -> Result<Arc<Handle>, i32> {
+        match self.table().open.get(&fh) {
+            Some(h) if h.ino == ino => Ok(Arc::clone(h)),
+            _ => Err(libc::EBADF),
+        }
+    }
+
+    /// Open handles and their numbers.
+    pub fn list(&self) -> Vec<(u64, Arc<Handle>)> {
+        let mut open: Vec<_> = self
+            .table()
+            .open
+            .iter()
+            .map(|(&fh, h)| (fh, Arc::clone(h)))
+            .collect();
+        open.sort_by_key(|&(fh, _)| fh);
+        open
+    }
+
+    pub fn open_count(&self) -> usize {
+        self.table().open.len()
+    }
+
+    /// Bytes held in the readahead buffers of open handles.
+    pub fn readahead_bytes(&self) -> usize {
+        self.table()
+            .open
+            .values()
+            .filter_map(|h| h.access().ahead.as_ref().map(|(_, data)| data.len()))
+            .sum()
+    }
+
+    pub fn remove(&self, fh: u64) -> Option<Arc<Handle>> {
+        let mut t = self.table();
+        let handle = t.open.remove(&fh)?;
+        t.released.push_back(fh);
+        Some(handle)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handle(ino: u64) -> Handle {
+        Handle::new(ino, libc::O_RDONLY, PathBuf::from("x.chd"), None)
+    }
+
+    #[test]
+    fn reads_must_match_the_opened_inode() {
+        let table = HandleTable::default();
+        let fh = table.insert(handle(7));
+
+        assert!(table.get(fh, 7).is_ok());
+        assert_eq!(table.get(fh, 8).err(), Some(libc::EBADF));
+
+        table.remove(fh);
+        assert_eq!(table.get(fh, 7).err(), Some(libc::EBADF));
+    }
+
+    #[test]
+    fn released_numbers_are_reused_only_after_a_delay() {
+        let table = HandleTable::default();
+        let first = table.insert(handle(2));
+        table.remove(first);
+
+        let mut seen = vec![first];
+        for _ in 0..RECYCLE_AFTER {
+            let fh = table.insert(handle(2));
+            assert!(!seen.contains(&fh));
+            seen.push(fh);
+            table.remove(fh);
+        }
+
+        assert_eq!(table.insert(handle(2)), first);
+    }
+
+    #[test]
+    fn readahead_window_grows_on_streaks_and_shrinks_on_seeks() {
+        let mut a = Access::default();
+        const K: u64 = 64 * 1024;
+
+        assert_eq!(a.record(2, 0, K), None);
+        assert_eq!(a.record(2, K, K), None);
+        assert_eq!(a.record(2, 2 * K, K), Some(3 * K..3 * K + READAHEAD_MIN));
+        assert_eq!(a.window, READAHEAD_MIN);
+
+        a.fill(3 * K, vec![0; READAHEAD_MIN as usize]);
+        for i in 3..20 {
+            a.record(2, i * K, K);
+            if a.filling {
+                a.filling = false;
+            }
+        }
+        assert_eq!(a.window, READAHEAD_MAX);
+
+        a.record(2, 1 << 30, K);
+        assert_eq!(a.window, READAHEAD_MAX / 4);
+        assert!(a.ahead.is_none());
+        a.record(2, 1 << 31, K);
+        a.record(2, 1 << 32, K);
+        assert_eq!(a.window, 0);
+    }
+
+    #[test]
+    fn source_range_follows_the_compression_ratio() {
+        const M: u64 = 1024 * 1024;
+        // 4 GiB image stored in 1 GiB: a window at 2 GiB maps to 512 MiB.
+        assert_eq!(
+            source_range(&(2048 * M..2052 * M), 4096 * M, 1024 * M),
+            511 * M..514 * M
+        );
+        assert_eq!(source_range(&(0..4 * M), 4096 * M, 1024 * M), 0..2 * M);
+        assert_eq!(
+            source_range(&(4092 * M..4096 * M), 4096 * M, 1024 * M),
+            1022 * M..1024 * M
+        );
+        assert_eq!(source_range(&(0..M), 0, M), 0..0);
+    }
+
+    #[test]
+    fn serves_reads_from_the_prefetched_buffer() {
+        let mut a = Access::default();
+        a.record(2, 0, 100);
+        a.record(2, 100, 100);
+        assert_eq!(a.record(2, 200, 100), Some(300..300 + READAHEAD_MIN));
+        a.fill(300, (0..=255).cycle().take(1000).collect());
+
+        let mut buf = [0u8; 8];
+        assert_eq!(a.take(301, &mut buf), 8);
+        assert_eq!(buf[0], 1);
+        assert_eq!(a.take(1300, &mut buf), 0);
+        assert_eq!(a.take(299, &mut buf), 0);
+    }
+}
@@ -0,0 +1,299 @@
+//! `chd2iso-fuse hash --source DIR`: CRC-32, MD5 and SHA-1 of the image each
+//! source is exposed as, for the whole library in parallel, written as a
+//! Logiqx DAT, CSV or JSON for catalog tools (clrmamepro, RomVault, a
+//! spreadsheet). Sources are probed as `cat` probes them, so the hashes are
+//! those of the files a mount with default settings would serve.
+
+use anyhow::{anyhow, Result};
+use clap::ValueEnum;
+use serde::Serialize;
+use std::{
+    cell::Cell,
+    fs,
+    io::{self, Write},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+};
+use tracing::error;
+
+use crate::{
+    cat,
+    chd_image::FrameCache,
+    check,
+    crc32::{self, Crc32},
+    md5::{self, Md5},
+    progress::{Progress, ProgressMode},
+    provider::Registry,
+    sha1::{self, Sha1},
+};
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct HashArgs {
+    /// Directory to scan for sources (recursively), or a single source
+    #[arg(short = 's', long = "source", value_name = "DIR")]
+    source: PathBuf,
+
+    /// Output on stdout: "dat" (Logiqx XML), "csv" or "json"
+    #[arg(long = "format", value_name = "FORMAT", default_value = "dat")]
+    format: HashFormat,
+
+    /// Images hashed at once (default: one per CPU)
+    #[arg(short = 'j', long = "jobs", value_name = "N")]
+    jobs: Option<usize>,
+
+    /// Progress on stderr: "none", "bar" or "json" (one object per line)
+    #[arg(long = "progress", value_name = "MODE", default_value = "none")]
+    progress: ProgressMode,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum HashFormat {
+    Dat,
+    Csv,
+    Json,
+}
+
+/// Hashes of one image.
+#[derive(Debug, Serialize)]
+struct Row {
+    /// Name the image is listed under, e.g. `Game.iso`
+    name: String,
+    /// Source, relative to `--source`
+    source: String,
+    size: u64,
+    crc32: String,
+    md5: String,
+    sha1: String,
+}
+
+/// All three digests, fed by `cat::copy_range`.
+#[derive(Default)]
+struct Digests {
+    crc32: Crc32,
+    md5: Md5,
+    sha1: Sha1,
+}
+
+impl Write for Digests {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.crc32.update(buf);
+        self.md5.update(buf);
+        self.sha1.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Hash every source under `args.source` and print the listing; fails if
+/// any source could not be hashed, after listing the others.
+pub fn run(args: &HashArgs) -> Result<()> {
+    let registry = Registry::with_builtin();
+    let mut files = Vec::new();
+    if args.source.is_dir() {
+        check::collect(&args.source, &|p| registry.matches(p), &mut files)?;
+    } else {
+        files.push(args.source.clone());
+    }
+    files.sort();
+
+    let jobs = args
+        .jobs
+        .unwrap_or_else(|| thread::available_parallelism().map_or(1, |n| n.get()))
+        .clamp(1, files.len().max(1));
+
+    // As in `check`, progress counts source bytes on disk, each file
+    // advancing in proportion to the image bytes hashed.
+    let sizes: Vec<u64> = files
+        .iter()
+        .map(|p| fs::metadata(p).map_or(0, |m| m.len()))
+        .collect();
+    let progress = Progress::new(args.progress, files.len(), sizes.iter().sum());
+
+    let frame_cache = Arc::new(FrameCache::new(256, 64 << 20));
+    let next = AtomicUsize::new(0);
+    let failed = AtomicUsize::new(0);
+    let rows = Mutex::new(Vec::new());
+    thread::scope(|s| {
+        for _ in 0..jobs {
+            s.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                let Some(path) = files.get(i) else {
+                    break;
+                };
+                let name = path.strip_prefix(&args.source).unwrap_or(path);
+                let name = match name.as_os_str().is_empty() {
+                    true => path.display().to_string(),
+                    false => name.display().to_string(),
+                };
+
+                let reported = Cell::new(0u64);
+                let hashed = Cell::new(0u64);
+                let image_size = Cell::new(0u64);
+                let advance = |n: u64| {
+                    hashed.set(hashed.get() + n);
+                    let now = sizes[i] * hashed.get() / image_size.get().max(1);
+                    progress.advance(&name, now.saturating_sub(reported.replace(now)));
+                };
+                let row = (|| {
+                    let probed = cat::probe(&registry, path, &frame_cache)?;
+                    image_size.set(probed.provider.size());
+                    let mut digests = Digests::default();
+                    let size = cat::copy_range(&*probed.provider, 0, None, &mut digests, &advance)?;
+                    Ok::<_, anyhow::Error>(Row {
+                        name: probed.name,
+                        source: name.clone(),
+                        size,
+                        crc32: crc32::hex(digests.crc32.finish()),
+                        md5: md5::hex(&digests.md5.finish()),
+                        sha1: sha1::hex(&digests.sha1.finish()),
+                    })
+                })();
+                progress.advance(&name, sizes[i].saturating_sub(reported.get()));
+
+                match row {
+                    Ok(row) => {
+                        rows.lock().expect("rows mutex poisoned").push((i, row));
+                        progress.finish_file(&name, "ok", None, "");
+                    }
+                    Err(e) => {
+                        failed.fetch_add(1, Ordering::Relaxed);
+                        let e = format!("{e:#}");
+                        error!("Not hashing {}: {}", name, e);
+                        progress.finish_file(&name, "failed", Some(&e), "");
+                    }
+                }
+            });
+        }
+    });
+    progress.done();
+
+    let mut rows = rows.into_inner().expect("rows mutex poisoned");
+    rows.sort_by_key(|(i, _)| *i);
+    let rows: Vec<Row> = rows.into_iter().map(|(_, row)| row).collect();
+    let title = args.source.display().to_string();
+    let mut out = io::stdout().lock();
+    match args.format {
+        HashFormat::Dat => out.write_all(dat(&title, &rows).as_bytes())?,
+        HashFormat::Csv => out.write_all(csv(&rows).as_bytes())?,
+        HashFormat::Json => {
+            serde_json::to_writer_pretty(&mut out, &rows)?;
+            writeln!(out)?;
+        }
+    }
+    out.flush()?;
+
+    let failed = failed.into_inner();
+    if failed > 0 {
+        return Err(anyhow!(
+            "{failed} of {} sources could not be hashed",
+            files.len()
+        ));
+    }
+    Ok(())
+}
+
+/// Logiqx datafile: one game per image, named after it without extension.
+fn dat(title: &str, rows: &[Row]) -> String {
+    let mut out = String::from(
+        "<?xml version=\"1.0\"?>\n\
+         <!DOCTYPE datafile PUBLIC \"-//Logiqx//DTD ROM Management Datafile//EN\" \
+         \"http://www.logiqx.com/Dats/datafile.dtd\">\n\
+         <datafile>\n",
+    );
+    out += &format!(
+        "\t<header>\n\t\t<name>{}</name>\n\t\t<description>{}, as served by {}</description>\n\t</header>\n",
+        escape(title),
+        escape(title),
+        env!("CARGO_PKG_NAME")
+    );
+    for row in rows {
+        let game = row.name.rsplit_once('.').map_or(&*row.name, |(s, _)| s);
+        out += &format!(
+            "\t<game name=\"{0}\">\n\t\t<description>{0}</description>\n\
+             \t\t<rom name=\"{1}\" size=\"{2}\" crc=\"{3}\" md5=\"{4}\" sha1=\"{5}\"/>\n\t</game>\n",
+            escape(game),
+            escape(&row.name),
+            row.size,
+            row.crc32,
+            row.md5,
+            row.sha1
+        );
+    }
+    out += "</datafile>\n";
+    out
+}
+
+fn csv(rows: &[Row]) -> String {
+    let mut out = String::from("name,size,crc32,md5,sha1,source\n");
+    for row in rows {
+        out += &format!(
+            "{},{},{},{},{},{}\n",
+            csv_field(&row.name),
+            row.size,
+            row.crc32,
+            row.md5,
+            row.sha1,
+            csv_field(&row.source)
+        );
+    }
+    out
+}
+
+/// Quoted if it holds a comma, quote or line break, `"` doubled.
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_dat_and_csv() {
+        let mut digests = Digests::default();
+        digests.write_all(b"abc").unwrap();
+        let rows = [Row {
+            name: "Tom & Jerry, \"Live\".iso".into(),
+            source: "ps2/Tom.chd".into(),
+            size: 3,
+            crc32: crc32::hex(digests.crc32.finish()),
+            md5: md5::hex(&digests.md5.finish()),
+            sha1: sha1::hex(&digests.sha1.finish()),
+        }];
+
+        let dat = dat("/srv/chd", &rows);
+        assert!(dat.contains("<game name=\"Tom &amp; Jerry, &quot;Live&quot;\">"));
+        assert!(dat.contains(
+            "<rom name=\"Tom &amp; Jerry, &quot;Live&quot;.iso\" size=\"3\" crc=\"352441c2\" \
+             md5=\"900150983cd24fb0d6963f7d28e17f72\" \
+             sha1=\"a9993e364706816aba3e25717850c26c9cd0d89d\"/>"
+        ));
+        assert!(dat.ends_with("</datafile>\n"));
+
+        assert_eq!(
+            csv(&rows),
+            "name,size,crc32,md5,sha1,source\n\
+             \"Tom & Jerry, \"\"Live\"\".iso\",3,352441c2,900150983cd24fb0d6963f7d28e17f72,\
+             a9993e364706816aba3e25717850c26c9cd0d89d,ps2/Tom.chd\n"
+        );
+    }
+}
@@ -0,0 +1,231 @@
+//! `--health`: a read-only `.chd2iso/health` file in the mount root, so a
+//! container orchestrator can probe a mount with a plain `cat` and restart
+//! it when it reports `degraded`.
+
+use anyhow::Result;
+use std::{
+    fmt::Write,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::SystemTime,
+};
+
+use crate::jobs::Progress;
+use crate::latency::SlowReads;
+
+/// Just below the overlay's inodes, far above the index's.
+pub const DIR_INO: u64 = (1 << 62) - 2;
+pub const FILE_INO: u64 = (1 << 62) - 1;
+
+pub const DIR_NAME: &str = ".chd2iso";
+pub const FILE_NAME: &str = "health";
+
+#[derive(Default)]
+struct LastIndex {
+    /// Last successful build
+    at: Option<SystemTime>,
+    /// Error of the last build, if it failed
+    error: Option<String>,
+}
+
+/// FUSE session limits as the kernel accepted them (sync backend).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Session {
+    pub max_background: u16,
+    pub congestion_threshold: u16,
+}
+
+#[derive(Default)]
+pub struct Health {
+    last_index: Mutex<LastIndex>,
+    source_errors: AtomicU64,
+    session: Mutex<Option<Session>>,
+    /// Largest read request the kernel has sent, the effective max_read
+    largest_read: AtomicU64,
+}
+
+/// What the report needs from the mount besides the counters.
+pub struct Probe<'a> {
+    pub source: &'a Path,
+    pub reachable: bool,
+    pub entries: usize,
+    pub open_handles: usize,
+    /// Sources past their `--error-budget`, with the last error
+    pub degraded: Vec<(PathBuf, String)>,
+    /// Latest run of each background job
+    pub jobs: Vec<(&'static str, Arc<Progress>)>,
+    /// Reads over `--slow-read-ms`, when set
+    pub slow_reads: Option<&'a SlowReads>,
+}
+
+impl Health {
+    fn last_index(&self) -> std::sync::MutexGuard<'_, LastIndex> {
+        self.last_index.lock().expect("health mutex poisoned")
+    }
+
+    pub fn record_index(&self, res: &Result<()>) {
+        let mut last = self.last_index();
+        match res {
+            Ok(()) => {
+                last.at = Some(SystemTime::now());
+                last.error = None;
+            }
+            Err(e) => last.error = Some(format!("{e:#}")),
+        }
+    }
+
+    pub fn record_session(&self, session: Session) {
+        *self.session.lock().expect("health mutex poisoned") = Some(session);
+    }
+
+    pub fn session(&self) -> Option<Session> {
+        *self.session.lock().expect("health mutex poisoned")
+    }
+
+    pub fn largest_read(&self) -> u64 {
+        self.largest_read.load(Ordering::Relaxed)
+    }
+
+    /// A read request of `size` bytes arrived; whether it is the largest yet.
+    pub fn read_request(&self, size: u32) -> bool {
+        self.largest_read.fetch_max(size as u64, Ordering::Relaxed) < size as u64
+    }
+
+    /// A source open or read failed.
+    pub fn source_error(&self) {
+        self.source_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The report: `status: ok` or `status: degraded` on the first line
+    /// (degraded while the source is unreachable or the last index failed),
+    /// then one `key: value` line per detail.
+    pub fn report(&self, probe: &Probe, now: SystemTime) -> String {
+        let last = self.last_index();
+        let degraded = !probe.reachable || last.error.is_some();
+
+        let mut out = String::new();
+        let status = if degraded { "degraded" } else { "ok" };
+        let _ = writeln!(out, "status: {status}");
+        let _ = writeln!(
+            out,
+            "source: {} ({})",
+            probe.source.display(),
+            if probe.reachable {
+                "reachable"
+            } else {
+                "unreachable"
+            }
+        );
+        let _ = writeln!(out, "entries: {}", probe.entries);
+        match last
+            .at
+            .and_then(|t| t.duration_since(SystemTime::UNIX_EPOCH).ok())
+        {
+            Some(at) => {
+                let age = now
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .map_or(0, |n| n.saturating_sub(at).as_secs());
+                let _ = writeln!(out, "last_index: {} ({age}s ago)", at.as_secs());
+            }
+            None => {
+                let _ = writeln!(out, "last_index: never");
+            }
+        }
+        let _ = writeln!(
+            out,
+            "last_index_error: {}",
+            last.error.as_deref().unwrap_or("none")
+        );
+        let _ = writeln!(
+            out,
+            "source_errors: {}",
+            self.source_errors.load(Ordering::Relaxed)
+        );
+        let _ = writeln!(out, "open_handles: {}", probe.open_handles);
+        let _ = writeln!(out, "largest_read: {}", self.largest_read());
+        if let Some(slow) = probe.slow_reads {
+            let _ = writeln!(out, "slow_reads: {}", slow.count());
+            let _ = writeln!(out, "slowest_read_ms: {}", slow.slowest().as_millis());
+        }
+        if let Some(s) = self.session() {
+            let _ = writeln!(out, "max_background: {}", s.max_background);
+            let _ = writeln!(out, "congestion_threshold: {}", s.congestion_threshold);
+        }
+        for (name, p) in &probe.jobs {
+            let _ = writeln!(
+                out,
+                "job_{name}: {}/{} done, {} failed, {}",
+                p.done.load(Ordering::Relaxed),
+                p.total,
+                p.failed.load(Ordering::Relaxed),
+                if p.finished.load(Ordering::Relaxed) {
+                    "finished"
+                } else {
+                    "running"
+                }
+            );
+        }
+        let _ = writeln!(out, "degraded_sources: {}", probe.degraded.len());
+        for (path, reason) in &probe.degraded {
+            let _ = writeln!(out, "degraded_source: {} ({reason})", path.display());
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+    use std::time::Duration;
+
+    #[test]
+    fn degrades_on_failed_index_or_lost_source() {
+        let health = Health::default();
+        let mut probe = Probe {
+            source: Path::new("/srv/roms"),
+            reachable: true,
+            entries: 3,
+            open_handles: 0,
+            degraded: Vec::new(),
+            jobs: Vec::new(),
+            slow_reads: None,
+        };
+        health.record_index(&Ok(()));
+        let later = SystemTime::now() + Duration::from_secs(90);
+
+        let report = health.report(&probe, later);
+        assert!(report.starts_with("status: ok\n"));
+        assert!(report.contains("(90s ago)"));
+
+        probe.reachable = false;
+        assert!(health
+            .report(&probe, later)
+            .starts_with("status: degraded\n"));
+
+        probe.reachable = true;
+        health.record_index(&Err(anyhow!("scan failed")));
+        let report = health.report(&probe, later);
+        assert!(report.starts_with("status: degraded\n"));
+        assert!(report.contains("last_index_error: scan failed\n"));
+
+        assert!(health.read_request(4096));
+        assert!(!health.read_request(1024));
+        health.record_session(Session {
+            max_background: 64,
+            congestion_threshold: 48,
+        });
+        let report = health.report(&probe, later);
+        assert!(
+            report.contains("largest_read: 4096\nmax_background: 64\ncongestion_threshold: 48\n")
+        );
+
+        probe.degraded = vec![(PathBuf::from("/srv/roms/Bad.chd"), "bad hunk".into())];
+        assert!(health
+            .report(&probe, later)
+            .contains("degraded_sources: 1\ndegraded_source: /srv/roms/Bad.chd (bad hunk)\n"));
+    }
+}
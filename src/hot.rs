@@ -0,0 +1,257 @@
+//! Hot set (`--hot`, control socket `pin`/`unpin`): titles whose whole
+//! image is decoded into memory ahead of use and served from there, so the
+//! game being played this week never waits on the source or the decoder.
+//! Images are loaded by a background job, paced like `--maintenance`, and
+//! must fit in `--hot-mib` together.
+//!
+//! Titles are pinned by virtual path. A re-index keeps a loaded image as
+//! long as the title's source file has the same size and mtime; otherwise
+//! it is loaded again.
+
+use anyhow::{anyhow, Result};
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+use crate::provider::{BackingProvider, ImageKind};
+use crate::vfs;
+
+/// One pinned title.
+struct Hot {
+    /// Virtual path, as pinned
+    path: String,
+    /// Inode of the entry when it was last seen in the index
+    ino: Option<u64>,
+    size: u64,
+    /// A load job has it
+    loading: bool,
+    /// The loaded image and the source it came from
+    image: Option<(Arc<Resident>, Origin)>,
+}
+
+/// A source file and its (size, mtime) when read.
+type Origin = (PathBuf, Option<(u64, Option<i64>)>);
+
+/// A pinned title as listed by the control socket.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Pinned {
+    pub path: String,
+    pub size: u64,
+    pub loaded: bool,
+}
+
+/// A title to load: its inode, virtual path and what serves it now.
+pub type Pending = (u64, String, Arc<dyn BackingProvider>, PathBuf);
+
+#[derive(Default)]
+pub struct HotSet {
+    titles: RwLock<Vec<Hot>>,
+}
+
+impl HotSet {
+    fn read(&self) -> std::sync::RwLockReadGuard<'_, Vec<Hot>> {
+        self.titles.read().expect("hot set lock poisoned")
+    }
+
+    fn write(&self) -> std::sync::RwLockWriteGuard<'_, Vec<Hot>> {
+        self.titles.write().expect("hot set lock poisoned")
+    }
+
+    /// Pin `path`, an image of `size` bytes at inode `ino`, if it fits in
+    /// `budget` bytes with the titles pinned already. False if it was
+    /// pinned.
+    pub fn pin(&self, path: &str, ino: u64, size: u64, budget: u64) -> Result<bool> {
+        let mut titles = self.write();
+        if titles.iter().any(|h| h.path == path) {
+            return Ok(false);
+        }
+        let used: u64 = titles.iter().map(|h| h.size).sum();
+        if used.saturating_add(size) > budget {
+            return Err(anyhow!(
+                "{path} needs {} MiB, {} MiB of --hot-mib {} are left",
+                size.div_ceil(1 << 20),
+                budget.saturating_sub(used) >> 20,
+                budget >> 20
+            ));
+        }
+        titles.push(Hot {
+            path: path.to_string(),
+            ino: Some(ino),
+            size,
+            loading: false,
+            image: None,
+        });
+        Ok(true)
+    }
+
+    /// Unpin `path`, freeing its image once no read is using it. False if
+    /// it was not pinned.
+    pub fn unpin(&self, path: &str) -> bool {
+        let mut titles = self.write();
+        let before = titles.len();
+        titles.retain(|h| h.path != path);
+        titles.len() != before
+    }
+
+    pub fn list(&self) -> Vec<Pinned> {
+        self.read()
+            .iter()
+            .map(|h| Pinned {
+                path: h.path.clone(),
+                size: h.size,
+                loaded: h.image.is_some(),
+            })
+            .collect()
+    }
+
+    /// (titles pinned, bytes loaded)
+    pub fn usage(&self) -> (usize, u64) {
+        let titles = self.read();
+        let loaded = titles
+            .iter()
+            .filter_map(|h| h.image.as_ref())
+            .map(|(image, _)| image.size())
+            .sum();
+        (titles.len(), loaded)
+    }
+
+    /// Serve the entry at `ino` from memory, if it is pinned and loaded.
+    pub fn provider(&self, ino: u64) -> Option<Arc<dyn BackingProvider>> {
+        self.read()
+            .iter()
+            .find(|h| h.ino == Some(ino))
+            .and_then(|h| h.image.as_ref())
+            .map(|(image, _)| Arc::clone(image) as Arc<dyn BackingProvider>)
+    }
+
+    /// After a re-index, find each title again with `find` (its inode,
+    /// provider and source, if still indexed), drop images whose source
+    /// changed, and return the titles to load.
+    pub fn refresh(
+        &self,
+        find: impl Fn(&str) -> Option<(u64, Arc<dyn BackingProvider>, PathBuf)>,
+    ) -> Vec<Pending> {
+        let mut pending = Vec::new();
+        for h in self.write().iter_mut() {
+            let Some((ino, provider, source)) = find(&h.path) else {
+                h.ino = None;
+                h.image = None;
+                continue;
+            };
+            h.ino = Some(ino);
+            h.size = provider.size();
+            if h.image.as_ref().is_some_and(|(image, origin)| {
+                image.size() != h.size || *origin != (source.clone(), stamp(&source))
+            }) {
+                h.image = None;
+            }
+            if h.image.is_none() && !h.loading {
+                h.loading = true;
+                pending.push((ino, h.path.clone(), provider, source));
+            }
+        }
+        pending
+    }
+
+    /// Store the image of `path` read from `source` (None: the load
+    /// failed), unless it was unpinned or re-indexed to another inode
+    /// meanwhile.
+    pub fn loaded(&self, path: &str, ino: u64, source: &Path, image: Option<(ImageKind, Vec<u8>)>) {
+        let mut titles = self.write();
+        let Some(h) = titles.iter_mut().find(|h| h.path == path) else {
+            return;
+        };
+        h.loading = false;
+        if let Some((kind, data)) = image.filter(|_| h.ino == Some(ino)) {
+            let image = Arc::new(Resident { kind, data });
+            h.image = Some((image, (source.to_path_buf(), stamp(source))));
+        }
+    }
+}
+
+fn stamp(source: &Path) -> Option<(u64, Option<i64>)> {
+    vfs::stat(source).ok().map(|m| (m.size, m.mtime))
+}
+
+/// A whole image in memory.
+pub struct Resident {
+    kind: ImageKind,
+    data: Vec<u8>,
+}
+
+impl std::fmt::Debug for Resident {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Resident")
+            .field("kind", &self.kind)
+            .field("size", &self.data.len())
+            .finish()
+    }
+}
+
+impl BackingProvider for Resident {
+    fn size(&self) -> u64 {
+        self.data.len() as u64
+    }
+
+    fn kind(&self) -> ImageKind {
+        self.kind
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        let start = usize::try_from(offset).map_or(self.data.len(), |o| o.min(self.data.len()));
+        let n = buf.len().min(self.data.len() - start);
+        buf[..n].copy_from_slice(&self.data[start..start + n]);
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::provider::SheetProvider;
+
+    #[test]
+    fn pins_within_budget_and_serves_loaded_images() {
+        let hot = HotSet::default();
+        let source = Path::new("/nonexistent/Ico.chd");
+        let image: Arc<dyn BackingProvider> = Arc::new(SheetProvider::new("abcdef".into()));
+
+        assert!(hot.pin("Ico.iso", 7, 6, 10).unwrap());
+        assert!(!hot.pin("Ico.iso", 7, 6, 10).unwrap());
+        assert!(hot.pin("Rez.iso", 8, 6, 10).is_err());
+        assert!(hot.provider(7).is_none());
+
+        hot.loaded(
+            "Ico.iso",
+            7,
+            source,
+            Some((ImageKind::Dvd, b"abcdef".to_vec())),
+        );
+        let served = hot.provider(7).unwrap();
+        let mut buf = [0; 4];
+        assert_eq!(served.read_at(4, &mut buf).unwrap(), 2);
+        assert_eq!(&buf[..2], b"ef");
+        assert_eq!(
+            hot.list(),
+            [Pinned {
+                path: "Ico.iso".into(),
+                size: 6,
+                loaded: true
+            }]
+        );
+
+        // Same source after a re-index: kept, at its new inode.
+        let found = |_: &str| Some((9, Arc::clone(&image), source.to_path_buf()));
+        assert!(hot.refresh(found).is_empty());
+        assert!(hot.provider(9).is_some());
+        // Gone from the index: dropped, loaded again when it returns.
+        assert!(hot.refresh(|_| None).is_empty());
+        assert!(hot.provider(9).is_none());
+        assert_eq!(hot.refresh(found).len(), 1);
+
+        assert!(hot.unpin("Ico.iso"));
+        assert!(!hot.unpin("Ico.iso"));
+        assert!(hot.pin("Rez.iso", 8, 6, 10).unwrap());
+    }
+}
@@ -0,0 +1,152 @@
+//! Single-flight execution: concurrent callers asking for the same key share
+//! one computation instead of each running it.
+//!
+//! Used for hunk decodes, where kernel readahead and the application's own
+//! read often hit the same hunk at the same time.
+
+use anyhow::{anyhow, Result};
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    sync::{Arc, Condvar, Mutex},
+};
+
+pub struct Inflight<K, V> {
+    calls: Mutex<HashMap<K, Arc<Call<V>>>>,
+}
+
+struct Call<V> {
+    /// Errors are shared as text; `anyhow::Error` is not `Clone`.
+    done: Mutex<Option<Result<V, String>>>,
+    cv: Condvar,
+}
+
+impl<K: Eq + Hash + Clone, V: Clone> Default for Inflight<K, V> {
+    fn default() -> Self {
+        Self {
+            calls: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl<K: Eq + Hash + Clone, V: Clone> Inflight<K, V> {
+    /// Run `f` for `key`, or wait for the call already in progress and return
+    /// its result.
+    pub fn run<F>(&self, key: K, f: F) -> Result<V>
+    where
+        F: FnOnce() -> Result<V>,
+    {
+        let call = {
+            let mut calls = self.calls.lock().expect("inflight mutex poisoned");
+
+            if let Some(c) = calls.get(&key) {
+                let c = Arc::clone(c);
+                drop(calls);
+                return Self::wait(&c);
+            }
+
+            let c = Arc::new(Call {
+                done: Mutex::new(None),
+                cv: Condvar::new(),
+            });
+            calls.insert(key.clone(), Arc::clone(&c));
+            c
+        };
+
+        // Publishes (and unregisters) even if `f` panics, so waiters never hang.
+        let mut guard = Publish {
+            owner: self,
+            key,
+            call,
+            result: Err("decode panicked".to_string()),
+        };
+
+        let res = f();
+        guard.result = match &res {
+            Ok(v) => Ok(v.clone()),
+            Err(e) => Err(format!("{e:#}")),
+        };
+
+        res
+    }
+
+    fn wait(call: &Call<V>) -> Result<V> {
+        let mut done = call.done.lock().expect("inflight call mutex poisoned");
+        while done.is_none() {
+            done = call.cv.wait(done).expect("inflight call mutex poisoned");
+        }
+
+        match done.as_ref().expect("checked above") {
+            Ok(v) => Ok(v.clone()),
+            Err(e) => Err(anyhow!("{e}")),
+        }
+    }
+}
+
+struct Publish<'a, K: Eq + Hash + Clone, V: Clone> {
+    owner: &'a Inflight<K, V>,
+    key: K,
+    call: Arc<Call<V>>,
+    result: Result<V, String>,
+}
+
+impl<K: Eq + Hash + Clone, V: Clone> Drop for Publish<'_, K, V> {
+    fn drop(&mut self) {
+        self.owner
+            .calls
+            .lock()
+            .expect("inflight mutex poisoned")
+            .remove(&self.key);
+
+        let result = std::mem::replace(&mut self.result, Err(String::new()));
+        *self.call.done.lock().expect("inflight call mutex poisoned") = Some(result);
+        self.call.cv.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Barrier,
+        },
+        thread,
+        time::Duration,
+    };
+
+    #[test]
+    fn concurrent_callers_share_one_computation() {
+        let flights: Arc<Inflight<u64, u64>> = Arc::new(Inflight::default());
+        let runs = Arc::new(AtomicUsize::new(0));
+        let barrier = Arc::new(Barrier::new(8));
+
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let (flights, runs, barrier) = (
+                    Arc::clone(&flights),
+                    Arc::clone(&runs),
+                    Arc::clone(&barrier),
+                );
+                thread::spawn(move || {
+                    barrier.wait();
+                    flights.run(7, || {
+                        runs.fetch_add(1, Ordering::SeqCst);
+                        thread::sleep(Duration::from_millis(100));
+                        Ok(42)
+                    })
+                })
+            })
+            .collect();
+
+        for t in threads {
+            assert_eq!(t.join().unwrap().unwrap(), 42);
+        }
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+
+        // Finished calls are not cached.
+        flights.run(7, || Ok(1)).unwrap();
+        assert_eq!(flights.run(7, || Ok(2)).unwrap(), 2);
+    }
+}
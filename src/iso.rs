@@ -0,0 +1,324 @@
+//! ISO9660 inspection of exposed 2048-byte images.
+
+use serde::{Deserialize, Serialize};
+
+use crate::provider::BackingProvider;
+
+const SECTOR: u64 = 2048;
+
+/// Largest single-layer DVD (DVD-5), in sectors.
+const DVD5_SECTORS: u64 = 2_295_104;
+
+/// Primary volume descriptor: type 1, "CD001", version 1.
+const PVD_MAGIC: &[u8; 7] = b"\x01CD001\x01";
+
+fn read_sector(p: &dyn BackingProvider, lba: u64) -> Option<Vec<u8>> {
+    let mut buf = vec![0u8; SECTOR as usize];
+    let mut done = 0;
+    while done < buf.len() {
+        match p.read_at(lba * SECTOR + done as u64, &mut buf[done..]) {
+            Ok(0) | Err(_) => return None,
+            Ok(n) => done += n,
+        }
+    }
+    Some(buf)
+}
+
+/// First sector of layer 1 of a dual-layer (DVD-9) image, as PS2 discs lay
+/// it out: layer 0 is a complete volume whose primary descriptor gives its
+/// size, and layer 1 repeats the descriptor 16 sectors in. Costs two sector
+/// reads, and none for images that fit on one layer.
+pub fn layer_break(p: &dyn BackingProvider) -> Option<u64> {
+    if p.size() <= DVD5_SECTORS * SECTOR {
+        return None;
+    }
+
+    let pvd = read_sector(p, 16)?;
+    if pvd[..7] != *PVD_MAGIC {
+        return None;
+    }
+
+    let layer0 = u32::from_le_bytes(pvd[80..84].try_into().expect("4 bytes")) as u64;
+    if layer0 == 0 || layer0 * SECTOR >= p.size() {
+        return None;
+    }
+
+    let second = read_sector(p, layer0 + 16)?;
+    (second[..7] == *PVD_MAGIC).then_some(layer0)
+}
+
+/// Volume identifier of the primary volume descriptor, blanks trimmed;
+/// none if empty. Costs one sector read.
+pub fn volume_label(p: &dyn BackingProvider) -> Option<String> {
+    let pvd = read_sector(p, 16)?;
+    if pvd[..7] != *PVD_MAGIC {
+        return None;
+    }
+    let label = String::from_utf8_lossy(&pvd[40..72]);
+    let label = label.trim_matches([' ', '\0']);
+    (!label.is_empty()).then(|| label.to_string())
+}
+
+fn le32(b: &[u8]) -> u64 {
+    u32::from_le_bytes(b[..4].try_into().expect("4 bytes")) as u64
+}
+
+/// Root-directory sectors searched for `SYSTEM.CNF`.
+const MAX_ROOT_SECTORS: u64 = 16;
+
+/// The `BOOT2` (PS2) or `BOOT` (PS1) line of `SYSTEM.CNF` in the root
+/// directory, as (key, value). Costs a few sector reads.
+fn boot_line(p: &dyn BackingProvider) -> Option<(&'static str, String)> {
+    let pvd = read_sector(p, 16)?;
+    if pvd[..7] != *PVD_MAGIC {
+        return None;
+    }
+
+    let root = &pvd[156..190];
+    let (extent, len) = (le32(&root[2..]), le32(&root[10..]));
+    let (cnf, cnf_len) = (0..len.div_ceil(SECTOR).min(MAX_ROOT_SECTORS))
+        .filter_map(|i| read_sector(p, extent + i))
+        .find_map(|sector| find_record(&sector, b"SYSTEM.CNF"))?;
+
+    let mut text = read_sector(p, cnf)?;
+    text.truncate(cnf_len.min(SECTOR) as usize);
+    let text = String::from_utf8_lossy(&text);
+    text.lines().find_map(|line| {
+        let (key, value) = line.split_once('=')?;
+        let key = match key.trim() {
+            "BOOT2" => "BOOT2",
+            "BOOT" => "BOOT",
+            _ => return None,
+        };
+        Some((key, value.trim().to_string()))
+    })
+}
+
+/// The boot executable named by `SYSTEM.CNF` in the root directory, as a
+/// PlayStation serial: `BOOT2 = cdrom0:\SLUS_200.62;1` gives `SLUS-20062`.
+/// Costs a few sector reads.
+pub fn serial(p: &dyn BackingProvider) -> Option<String> {
+    let (_, boot) = boot_line(p)?;
+    let file = boot.rsplit(['\\', ':', '/']).next()?;
+    let file = file.split(';').next()?;
+    let serial: String = file
+        .chars()
+        .filter(|c| *c != '.')
+        .map(|c| {
+            if c == '_' {
+                '-'
+            } else {
+                c.to_ascii_uppercase()
+            }
+        })
+        .collect();
+    (!serial.is_empty()).then_some(serial)
+}
+
+/// Console a disc is for, by `--console-dirs`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Console {
+    Ps2,
+    Ps1,
+    Dreamcast,
+    Saturn,
+    PcEngine,
+}
+
+impl Console {
+    /// Directory its discs are listed in.
+    pub fn dir(self) -> &'static str {
+        match self {
+            Console::Ps2 => "PS2",
+            Console::Ps1 => "PS1",
+            Console::Dreamcast => "Dreamcast",
+            Console::Saturn => "Saturn",
+            Console::PcEngine => "PC Engine",
+        }
+    }
+
+    /// Only loaded from a cue sheet of the whole disc (Mednafen, Beetle).
+    pub fn wants_cue(self) -> bool {
+        matches!(self, Console::Saturn | Console::PcEngine)
+    }
+}
+
+/// PlayStation license text in sector 4 of every PS1 disc.
+const PS1_LICENSE: &[u8] = b"Sony Computer Entertainment";
+
+/// PC Engine CD boot sector signature, 0x20 into sector 1 of the data track.
+const PCE_SIGNATURE: &[u8] = b"PC Engine CD-ROM SYSTEM";
+
+/// The console a disc is for by the security header of its system area:
+/// the Sega hardware ID opening sector 0 of Dreamcast (IP.BIN) and Saturn
+/// discs, or the PC Engine signature in sector 1. Costs two sector reads.
+pub fn system_header(p: &dyn BackingProvider) -> Option<Console> {
+    let head = read_sector(p, 0)?;
+    if head.starts_with(b"SEGA SEGAKATANA ") {
+        return Some(Console::Dreamcast);
+    }
+    if head.starts_with(b"SEGA SEGASATURN ") {
+        return Some(Console::Saturn);
+    }
+    let boot = read_sector(p, 1)?;
+    boot[0x20..]
+        .starts_with(PCE_SIGNATURE)
+        .then_some(Console::PcEngine)
+}
+
+/// The console a disc image is for: its system area's security header, the
+/// `SYSTEM.CNF` boot line of PlayStation discs, or the PS1 license text of
+/// those without one. Costs a few sector reads.
+pub fn console(p: &dyn BackingProvider) -> Option<Console> {
+    if let Some(console) = system_header(p) {
+        return Some(console);
+    }
+    match boot_line(p) {
+        Some(("BOOT2", _)) => return Some(Console::Ps2),
+        Some(_) => return Some(Console::Ps1),
+        None => {}
+    }
+    let license = read_sector(p, 4)?;
+    license
+        .windows(PS1_LICENSE.len())
+        .any(|w| w == PS1_LICENSE)
+        .then_some(Console::Ps1)
+}
+
+/// (extent, length) of the file `name` among the directory records in
+/// `sector`.
+fn find_record(sector: &[u8], name: &[u8]) -> Option<(u64, u64)> {
+    let mut at = 0;
+    while at + 33 < sector.len() {
+        let len = sector[at] as usize;
+        if len < 34 || at + len > sector.len() {
+            return None;
+        }
+        let rec = &sector[at..at + len];
+        let id = rec.get(33..33 + rec[32] as usize)?;
+        let id = id.split(|b| *b == b';').next()?;
+        if id.eq_ignore_ascii_case(name) {
+            return Some((le32(&rec[2..]), le32(&rec[10..])));
+        }
+        at += len;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::provider::ImageKind;
+    use anyhow::Result;
+    use std::collections::HashMap;
+
+    /// An 8 GB image that is all zeros except for the given sectors.
+    #[derive(Debug)]
+    struct Sparse(HashMap<u64, Vec<u8>>);
+
+    impl BackingProvider for Sparse {
+        fn size(&self) -> u64 {
+            8_000_000_000
+        }
+
+        fn kind(&self) -> ImageKind {
+            ImageKind::Dvd
+        }
+
+        fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize> {
+            let lba = offset / SECTOR;
+            let at = (offset % SECTOR) as usize;
+            let n = buf.len().min(SECTOR as usize - at);
+            match self.0.get(&lba) {
+                Some(s) => buf[..n].copy_from_slice(&s[at..at + n]),
+                None => buf[..n].fill(0),
+            }
+            Ok(n)
+        }
+    }
+
+    fn pvd(volume_sectors: u32) -> Vec<u8> {
+        let mut s = vec![0u8; SECTOR as usize];
+        s[..7].copy_from_slice(PVD_MAGIC);
+        s[80..84].copy_from_slice(&volume_sectors.to_le_bytes());
+        s
+    }
+
+    #[test]
+    fn finds_dvd9_layer_break() {
+        let layer0 = 2_100_000u32;
+        let mut sectors = HashMap::from([(16, pvd(layer0))]);
+        assert_eq!(layer_break(&Sparse(sectors.clone())), None);
+
+        sectors.insert(layer0 as u64 + 16, pvd(1_800_000));
+        assert_eq!(layer_break(&Sparse(sectors)), Some(layer0 as u64));
+    }
+
+    fn record(name: &[u8], extent: u32, len: u32) -> Vec<u8> {
+        let mut r = vec![0u8; 33 + name.len() + (name.len() + 1) % 2];
+        r[0] = r.len() as u8;
+        r[2..6].copy_from_slice(&extent.to_le_bytes());
+        r[10..14].copy_from_slice(&len.to_le_bytes());
+        r[32] = name.len() as u8;
+        r[33..33 + name.len()].copy_from_slice(name);
+        r
+    }
+
+    #[test]
+    fn reads_the_boot_serial() {
+        let mut pvd = pvd(1000);
+        pvd[156..190].copy_from_slice(&record(b"\0", 20, 2048)[..34]);
+
+        let mut root = [record(b"\0", 20, 2048), record(b"SYSTEM.CNF;1", 21, 60)].concat();
+        root.resize(SECTOR as usize, 0);
+
+        let mut cnf = b"BOOT2 = cdrom0:\\SLUS_200.62;1\r\nVER = 1.00\r\n".to_vec();
+        cnf.resize(SECTOR as usize, 0);
+
+        let sectors = HashMap::from([(16, pvd), (20, root), (21, cnf)]);
+        assert_eq!(
+            serial(&Sparse(sectors.clone())).as_deref(),
+            Some("SLUS-20062")
+        );
+        assert_eq!(console(&Sparse(sectors)), Some(Console::Ps2));
+    }
+
+    #[test]
+    fn tells_consoles_apart() {
+        let sector = |at: usize, text: &[u8]| {
+            let mut s = vec![0u8; SECTOR as usize];
+            s[at..at + text.len()].copy_from_slice(text);
+            s
+        };
+        let disc = |lba, s| Sparse(HashMap::from([(lba, s)]));
+
+        let saturn = sector(0, b"SEGA SEGASATURN SEGA TP T-81");
+        assert_eq!(console(&disc(0, saturn)), Some(Console::Saturn));
+        let dreamcast = sector(0, b"SEGA SEGAKATANA SEGA ENTERPRISES");
+        assert_eq!(console(&disc(0, dreamcast)), Some(Console::Dreamcast));
+        let license = sector(
+            8,
+            b"          Licensed  by          Sony Computer Entertainment America ",
+        );
+        assert_eq!(console(&disc(4, license)), Some(Console::Ps1));
+        let pce = sector(0x20, b"PC Engine CD-ROM SYSTEM\0Copyright HUDSON SOFT");
+        assert_eq!(console(&disc(1, pce)), Some(Console::PcEngine));
+        assert!(Console::PcEngine.wants_cue() && !Console::Ps1.wants_cue());
+        assert_eq!(console(&disc(0, sector(0, b"CD001"))), None);
+    }
+
+    #[test]
+    fn reads_the_volume_label() {
+        let mut sector = pvd(1000);
+        sector[40..72].fill(b' ');
+        sector[40..48].copy_from_slice(b"GTA_VICE");
+        assert_eq!(
+            volume_label(&Sparse(HashMap::from([(16, sector.clone())]))).as_deref(),
+            Some("GTA_VICE")
+        );
+
+        sector[40..72].fill(b' ');
+        assert_eq!(volume_label(&Sparse(HashMap::from([(16, sector)]))), None);
+    }
+}
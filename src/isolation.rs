@@ -0,0 +1,150 @@
+//! `--probe-isolation`: probe each source in a short-lived child process
+//! first, so a malformed CHD that crashes or hangs the parser takes down
+//! the child rather than the mount. The child is this binary again, run
+//! with the same arguments and `CHD2ISO_PROBE` naming the source; it
+//! probes the header and TOC and exits. A source whose child is killed by a
+//! signal, panics or outlives the timeout is quarantined: left out of the
+//! index until its size or mtime changes.
+
+use anyhow::{bail, Context, Result};
+use std::{
+    collections::HashMap,
+    env,
+    os::unix::process::ExitStatusExt,
+    path::{Path, PathBuf},
+    process::{Command, ExitStatus, Stdio},
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::vfs;
+
+/// Set in a child: the source to probe.
+pub const CHILD_ENV: &str = "CHD2ISO_PROBE";
+/// Set in a child: the mountpoint whose settings apply.
+pub const MOUNT_ENV: &str = "CHD2ISO_PROBE_MOUNT";
+
+/// Exit status of a Rust process that panicked.
+const PANICKED: i32 = 101;
+const POLL: Duration = Duration::from_millis(20);
+
+/// How a child probe ended.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Verdict {
+    /// Probed, or failed with an ordinary error the parent will see too
+    Clean,
+    Crashed(String),
+    Hung,
+}
+
+/// A verdict and the source size and mtime it was reached at.
+type Known = (u64, Option<i64>, Verdict);
+
+/// Verdicts by source, kept while a source stays the same.
+#[derive(Default)]
+pub struct Verdicts(Mutex<HashMap<PathBuf, Known>>);
+
+impl Verdicts {
+    /// Probe `path` in a child (for the mount at `mountpoint`) unless an
+    /// earlier verdict still applies; an error if it is quarantined.
+    pub fn check(&self, path: &Path, mountpoint: Option<&Path>, timeout: Duration) -> Result<()> {
+        let meta = vfs::stat(path)?;
+        let known = {
+            let map = self.0.lock().expect("verdicts mutex poisoned");
+            map.get(path)
+                .filter(|(size, mtime, _)| (*size, *mtime) == (meta.size, meta.mtime))
+                .map(|(_, _, v)| v.clone())
+        };
+        let verdict = match known {
+            Some(v) => v,
+            None => {
+                let v = probe_in_child(path, mountpoint, timeout)?;
+                self.0
+                    .lock()
+                    .expect("verdicts mutex poisoned")
+                    .insert(path.to_path_buf(), (meta.size, meta.mtime, v.clone()));
+                v
+            }
+        };
+
+        match verdict {
+            Verdict::Clean => Ok(()),
+            Verdict::Crashed(how) => {
+                bail!("quarantined until it changes: probing it {how} in a child process")
+            }
+            Verdict::Hung => bail!(
+                "quarantined until it changes: probing it took over {}s in a child process",
+                timeout.as_secs()
+            ),
+        }
+    }
+}
+
+fn probe_in_child(path: &Path, mountpoint: Option<&Path>, timeout: Duration) -> Result<Verdict> {
+    let mut cmd = Command::new(env::current_exe()?);
+    cmd.args(env::args_os().skip(1))
+        .env(CHILD_ENV, path)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null());
+    if let Some(m) = mountpoint {
+        cmd.env(MOUNT_ENV, m);
+    }
+    let mut child = cmd.spawn().context("starting a probe process")?;
+
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(verdict(status));
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Ok(Verdict::Hung);
+        }
+        thread::sleep(POLL);
+    }
+}
+
+/// What the exit `status` of a child probe says about its source.
+fn verdict(status: ExitStatus) -> Verdict {
+    match (status.signal(), status.code()) {
+        (Some(sig), _) => Verdict::Crashed(format!("was killed by signal {sig}")),
+        (_, Some(PANICKED)) => Verdict::Crashed("panicked".into()),
+        _ => Verdict::Clean,
+    }
+}
+
+/// In a child: no privileges gained through exec, no core dumps of a
+/// crash, and gone with the parent.
+pub fn confine() {
+    let no_core = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    unsafe {
+        libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0);
+        libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL);
+        libc::setrlimit(libc::RLIMIT_CORE, &no_core);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crashes_and_panics_quarantine() {
+        assert_eq!(verdict(ExitStatus::from_raw(0)), Verdict::Clean);
+        // An ordinary probe error: the parent reports it itself.
+        assert_eq!(verdict(ExitStatus::from_raw(1 << 8)), Verdict::Clean);
+        assert_eq!(
+            verdict(ExitStatus::from_raw(PANICKED << 8)),
+            Verdict::Crashed("panicked".into())
+        );
+        assert_eq!(
+            verdict(ExitStatus::from_raw(libc::SIGSEGV)),
+            Verdict::Crashed("was killed by signal 11".into())
+        );
+    }
+}
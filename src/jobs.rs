@@ -0,0 +1,224 @@
+//! Library-wide maintenance (`--maintenance`, `--warmup`): hashing,
+//! verifying and warming every image, on a few worker threads at nice 19 and
+//! idle I/O priority. Their reads are charged against
+//! `--maintenance-throughput`, and workers hold off while clients are
+//! reading, so a long job never sits in front of a client's request.
+//! Progress of each job is listed in the health file and D-Bus `Stats`.
+
+use anyhow::Result;
+use clap::ValueEnum;
+use serde::Deserialize;
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+use tracing::{info, warn};
+
+use crate::{sched, throttle::TokenBucket};
+
+/// How long clients must have stopped reading before workers carry on.
+const QUIET: Duration = Duration::from_millis(250);
+
+/// `--maintenance`: jobs run over the library after each index build.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Task {
+    /// Compute the `--checksum-files` digests ahead of the first read
+    Hash,
+    /// Check each CHD against the SHA-1s in its header, as `check` does
+    Verify,
+}
+
+impl Task {
+    pub fn name(self) -> &'static str {
+        match self {
+            Task::Hash => "hash",
+            Task::Verify => "verify",
+        }
+    }
+}
+
+/// Counts of one run of a job.
+#[derive(Debug, Default)]
+pub struct Progress {
+    pub total: usize,
+    pub done: AtomicUsize,
+    pub failed: AtomicUsize,
+    pub finished: AtomicBool,
+}
+
+/// Limits of one run, from the mount's settings when it starts.
+pub struct Budget {
+    pub workers: usize,
+    pub throughput: Option<TokenBucket>,
+}
+
+pub struct Jobs {
+    /// Last client read, in ms since `epoch`
+    last_read: AtomicU64,
+    epoch: Instant,
+    /// Latest run of each job
+    runs: Mutex<Vec<(&'static str, Arc<Progress>)>>,
+}
+
+impl Default for Jobs {
+    fn default() -> Self {
+        Self {
+            last_read: AtomicU64::new(0),
+            epoch: Instant::now(),
+            runs: Mutex::new(Vec::new()),
+        }
+    }
+}
+
+impl Jobs {
+    /// Note a client read; workers pause until reads stop for `QUIET`.
+    pub fn client_read(&self) {
+        let now = self.epoch.elapsed().as_millis() as u64;
+        self.last_read.store(now, Ordering::Relaxed);
+    }
+
+    /// Wait until `bytes` more fit the budget and clients are quiet.
+    pub fn pace(&self, budget: &Budget, bytes: usize) {
+        if let Some(bucket) = &budget.throughput {
+            let delay = bucket.take(bytes);
+            if !delay.is_zero() {
+                thread::sleep(delay);
+            }
+        }
+        loop {
+            let last = Duration::from_millis(self.last_read.load(Ordering::Relaxed));
+            let quiet = self.epoch.elapsed().saturating_sub(last);
+            if quiet >= QUIET {
+                return;
+            }
+            thread::sleep(QUIET - quiet);
+        }
+    }
+
+    /// Latest run of each job, in the order they were first started.
+    pub fn progress(&self) -> Vec<(&'static str, Arc<Progress>)> {
+        self.runs.lock().expect("jobs mutex poisoned").clone()
+    }
+
+    /// Run `work` over `items` in the background, `budget.workers` at a
+    /// time. `work` returns false for an item it skipped. Not started if
+    /// the previous run of `name` is still going.
+    pub fn spawn<T, F>(
+        self: &Arc<Self>,
+        label: String,
+        name: &'static str,
+        items: Vec<T>,
+        budget: Budget,
+        work: F,
+    ) where
+        T: Send + Sync + 'static,
+        F: Fn(&T, &dyn Fn(usize)) -> Result<bool> + Send + Sync + 'static,
+    {
+        let progress = Arc::new(Progress {
+            total: items.len(),
+            ..Progress::default()
+        });
+        {
+            let mut runs = self.runs.lock().expect("jobs mutex poisoned");
+            match runs.iter_mut().find(|(n, _)| *n == name) {
+                Some((_, p)) if !p.finished.load(Ordering::Relaxed) => {
+                    info!("{}: {} still running; not restarted", label, name);
+                    return;
+                }
+                Some((_, p)) => *p = Arc::clone(&progress),
+                None => runs.push((name, Arc::clone(&progress))),
+            }
+        }
+
+        let jobs = Arc::clone(self);
+        let spawned = thread::Builder::new()
+            .name(format!("job-{name}"))
+            .spawn(move || {
+                let start = Instant::now();
+                let next = AtomicUsize::new(0);
+                let skipped = AtomicUsize::new(0);
+                let pace = |bytes| jobs.pace(&budget, bytes);
+                thread::scope(|s| {
+                    for _ in 0..budget.workers.clamp(1, items.len().max(1)) {
+                        let worker = thread::Builder::new().name(format!("job-{name}"));
+                        let spawned = worker.spawn_scoped(s, || {
+                            sched::background_thread();
+                            while let Some(item) = items.get(next.fetch_add(1, Ordering::Relaxed)) {
+                                match work(item, &pace) {
+                                    Ok(true) => progress.done.fetch_add(1, Ordering::Relaxed),
+                                    Ok(false) => skipped.fetch_add(1, Ordering::Relaxed),
+                                    Err(e) => {
+                                        warn!("{}: {}: {:#}", label, name, e);
+                                        progress.failed.fetch_add(1, Ordering::Relaxed)
+                                    }
+                                };
+                            }
+                        });
+                        if let Err(e) = spawned {
+                            warn!("{} worker: {}", name, e);
+                        }
+                    }
+                });
+                progress.finished.store(true, Ordering::Relaxed);
+                info!(
+                    "{}: {} done for {} of {} images ({} failed, {} skipped) in {:.1}s",
+                    label,
+                    name,
+                    progress.done.load(Ordering::Relaxed),
+                    progress.total,
+                    progress.failed.load(Ordering::Relaxed),
+                    skipped.into_inner(),
+                    start.elapsed().as_secs_f64()
+                );
+            });
+        if let Err(e) = spawned {
+            warn!("{} thread: {}", name, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn runs_every_item_and_counts_outcomes() {
+        let jobs = Arc::new(Jobs::default());
+        let budget = Budget {
+            workers: 3,
+            throughput: None,
+        };
+        jobs.spawn(
+            "test".into(),
+            "hash",
+            (0..10).collect(),
+            budget,
+            |n: &u32, pace| {
+                pace(1);
+                match n % 5 {
+                    0 => anyhow::bail!("item {n}"),
+                    1 => Ok(false),
+                    _ => Ok(true),
+                }
+            },
+        );
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let (name, progress) = loop {
+            let runs = jobs.progress();
+            if runs[0].1.finished.load(Ordering::Relaxed) || Instant::now() > deadline {
+                break runs[0].clone();
+            }
+            thread::sleep(Duration::from_millis(5));
+        };
+        assert_eq!(name, "hash");
+        assert_eq!(progress.total, 10);
+        assert_eq!(progress.done.load(Ordering::Relaxed), 6);
+        assert_eq!(progress.failed.load(Ordering::Relaxed), 2);
+    }
+}
@@ -0,0 +1,289 @@
+//! `--slow-read-ms`: time every client read and warn about the ones over
+//! the threshold with where the time went, so stutter can be put down to
+//! decompression, source I/O or cache eviction.
+//!
+//! The layers below a read add to a per-thread breakdown as they work: the
+//! source reads, the hunk decodes and the frame cache lookups. A read that
+//! waits on a hunk another thread is decoding shows neither, only the time.
+//!
+//! `--trace-reads-*` logs the same line for a sample of reads instead: every
+//! Nth, those slower than a threshold, or those of matching sources, a rule
+//! the control socket can change while the mount runs.
+
+use serde::Serialize;
+use std::{
+    cell::Cell,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        RwLock,
+    },
+    time::{Duration, Instant},
+};
+use tracing::warn;
+
+thread_local! {
+    static SPENT: Cell<Spent> = Cell::new(Spent::default());
+}
+
+/// Where the time of a read on this thread went.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Spent {
+    /// Reading compressed data from the source
+    pub source: Duration,
+    /// Decoding hunks, source reads included
+    pub decode: Duration,
+    /// First hunk decoded
+    pub hunk: Option<u32>,
+    pub hunks: u32,
+    pub cache_hits: u32,
+    pub cache_misses: u32,
+    /// Bytes served from the handle's readahead buffer
+    pub readahead: usize,
+}
+
+fn add(f: impl FnOnce(&mut Spent)) {
+    SPENT.with(|s| {
+        let mut v = s.get();
+        f(&mut v);
+        s.set(v);
+    });
+}
+
+/// A source read took `d`.
+pub fn source_read(d: Duration) {
+    add(|s| s.source += d);
+}
+
+/// Hunk `n` was decoded in `d`.
+pub fn hunk_decoded(n: u32, d: Duration) {
+    add(|s| {
+        s.decode += d;
+        s.hunk.get_or_insert(n);
+        s.hunks += 1;
+    });
+}
+
+/// A frame cache lookup hit or missed.
+pub fn frame_cache(hit: bool) {
+    add(|s| {
+        if hit {
+            s.cache_hits += 1;
+        } else {
+            s.cache_misses += 1;
+        }
+    });
+}
+
+/// `n` bytes came from the readahead buffer.
+pub fn readahead(n: usize) {
+    add(|s| s.readahead += n);
+}
+
+/// Slow reads seen across the mount, for the health file.
+#[derive(Default)]
+pub struct SlowReads {
+    count: AtomicU64,
+    /// Slowest read of all, in microseconds
+    slowest_us: AtomicU64,
+}
+
+impl SlowReads {
+    /// Run `read` and return its result, how long it took and where the
+    /// time went.
+    pub fn time<T>(read: impl FnOnce() -> T) -> (T, Duration, Spent) {
+        SPENT.with(|s| s.set(Spent::default()));
+        let start = Instant::now();
+        let res = read();
+        (res, start.elapsed(), SPENT.with(Cell::take))
+    }
+
+    /// Account for a read of `len` bytes at `offset` of `file` and warn if
+    /// it took longer than `threshold`.
+    pub fn record(
+        &self,
+        threshold: Duration,
+        file: &Path,
+        offset: u64,
+        len: usize,
+        took: Duration,
+        spent: Spent,
+    ) {
+        self.slowest_us
+            .fetch_max(took.as_micros() as u64, Ordering::Relaxed);
+        if took < threshold {
+            return;
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        warn!("slow read: {}", describe(file, offset, len, took, spent));
+    }
+
+    /// Reads over the threshold so far.
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    pub fn slowest(&self) -> Duration {
+        Duration::from_micros(self.slowest_us.load(Ordering::Relaxed))
+    }
+}
+
+fn describe(file: &Path, offset: u64, len: usize, took: Duration, spent: Spent) -> String {
+    format!(
+        "file={:?} offset={} len={} hunk={} hunks={} ms={} source_ms={} decode_ms={} frame_cache={}/{} readahead={}",
+        file,
+        offset,
+        len,
+        spent.hunk.map_or("-".to_string(), |h| h.to_string()),
+        spent.hunks,
+        took.as_millis(),
+        spent.source.as_millis(),
+        spent.decode.as_millis(),
+        spent.cache_hits,
+        spent.cache_hits + spent.cache_misses,
+        spent.readahead
+    )
+}
+
+/// Which reads `--trace-reads-*` logs: those passing every filter set.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct TraceRule {
+    /// Every Nth read of those passing the other filters (1 = all)
+    pub every: u64,
+    /// Only reads taking at least this many milliseconds
+    pub slower_ms: u64,
+    /// Only sources whose path matches this glob (`*`, `?`)
+    pub file: Option<String>,
+}
+
+/// Sampled per-read log lines, under a rule that can change at runtime.
+#[derive(Default)]
+pub struct ReadTrace {
+    on: AtomicBool,
+    rule: RwLock<Option<TraceRule>>,
+    /// Reads that passed the filters, for `every`
+    seen: AtomicU64,
+}
+
+impl ReadTrace {
+    pub fn new(rule: Option<TraceRule>) -> Self {
+        let trace = Self::default();
+        trace.set(rule);
+        trace
+    }
+
+    /// Whether reads need timing for this.
+    pub fn active(&self) -> bool {
+        self.on.load(Ordering::Relaxed)
+    }
+
+    pub fn rule(&self) -> Option<TraceRule> {
+        self.rule.read().expect("trace rule lock poisoned").clone()
+    }
+
+    /// Replace the rule (None: stop tracing) and restart the count.
+    pub fn set(&self, rule: Option<TraceRule>) {
+        let mut current = self.rule.write().expect("trace rule lock poisoned");
+        self.on.store(rule.is_some(), Ordering::Relaxed);
+        self.seen.store(0, Ordering::Relaxed);
+        *current = rule;
+    }
+
+    /// Log a read of `len` bytes at `offset` of `file` if the rule picks it.
+    pub fn record(&self, file: &Path, offset: u64, len: usize, took: Duration, spent: Spent) {
+        if self.active() && self.sampled(file, took) {
+            warn!("read: {}", describe(file, offset, len, took, spent));
+        }
+    }
+
+    fn sampled(&self, file: &Path, took: Duration) -> bool {
+        let rule = self.rule.read().expect("trace rule lock poisoned");
+        let Some(rule) = rule.as_ref() else {
+            return false;
+        };
+        if took < Duration::from_millis(rule.slower_ms) {
+            return false;
+        }
+        if let Some(pattern) = &rule.file {
+            if !glob(pattern, &file.to_string_lossy()) {
+                return false;
+            }
+        }
+        self.seen.fetch_add(1, Ordering::Relaxed) % rule.every.max(1) == 0
+    }
+}
+
+/// `*` matches any run of characters, `/` included, and `?` any one.
+fn glob(pattern: &str, text: &str) -> bool {
+    fn go(p: &[char], t: &[char]) -> bool {
+        match p.split_first() {
+            None => t.is_empty(),
+            Some(('*', rest)) => (0..=t.len()).any(|i| go(rest, &t[i..])),
+            Some(('?', rest)) => !t.is_empty() && go(rest, &t[1..]),
+            Some((c, rest)) => t.first() == Some(c) && go(rest, &t[1..]),
+        }
+    }
+    let p: Vec<char> = pattern.chars().collect();
+    let t: Vec<char> = text.chars().collect();
+    go(&p, &t)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn breakdown_is_per_read() {
+        let ((), _, spent) = SlowReads::time(|| {
+            hunk_decoded(7, Duration::from_millis(3));
+            hunk_decoded(8, Duration::from_millis(2));
+            source_read(Duration::from_millis(1));
+            frame_cache(true);
+            frame_cache(false);
+            readahead(4096);
+        });
+        assert_eq!(
+            spent,
+            Spent {
+                source: Duration::from_millis(1),
+                decode: Duration::from_millis(5),
+                hunk: Some(7),
+                hunks: 2,
+                cache_hits: 1,
+                cache_misses: 1,
+                readahead: 4096,
+            }
+        );
+        let ((), _, spent) = SlowReads::time(|| ());
+        assert_eq!(spent, Spent::default());
+
+        let slow = SlowReads::default();
+        let file = Path::new("/srv/roms/Game.chd");
+        let threshold = Duration::from_millis(100);
+        slow.record(threshold, file, 0, 4096, Duration::from_millis(20), spent);
+        slow.record(threshold, file, 0, 4096, Duration::from_millis(150), spent);
+        assert_eq!(slow.count(), 1);
+        assert_eq!(slow.slowest(), Duration::from_millis(150));
+    }
+
+    #[test]
+    fn trace_rule_samples_matching_reads() {
+        let trace = ReadTrace::new(Some(TraceRule {
+            every: 2,
+            slower_ms: 5,
+            file: Some("*/Ico*.chd".into()),
+        }));
+        let ico = Path::new("/srv/roms/Ico (USA).chd");
+        let other = Path::new("/srv/roms/Rez (USA).chd");
+        let slow = Duration::from_millis(10);
+        let picked: Vec<bool> = (0..4).map(|_| trace.sampled(ico, slow)).collect();
+        assert_eq!(picked, [true, false, true, false]);
+        assert!(!trace.sampled(other, slow));
+        assert!(!trace.sampled(ico, Duration::from_millis(1)));
+
+        trace.set(None);
+        assert!(!trace.active());
+        assert!(!trace.sampled(ico, slow));
+        assert!(glob("a?c*", "abc/def") && !glob("a?c", "ac"));
+    }
+}
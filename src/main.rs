@@ -1,53 +1,204 @@
 use anyhow::{anyhow, Context, Result};
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
 use fuser::{
-    Config, Errno, FileAttr, FileHandle, FileType, Filesystem, FopenFlags, Generation, INodeNo,
-    LockOwner, MountOption, OpenFlags, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry, Request,
-    SessionACL,
+    BsdFileFlags, Config, Errno, FileAttr, FileHandle, FileType, Filesystem, FopenFlags,
+    Generation, INodeNo, InitFlags, KernelConfig, LockOwner, MountOption, Notifier, OpenFlags,
+    RenameFlags, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory, ReplyDirectoryPlus, ReplyEmpty,
+    ReplyEntry, ReplyWrite, ReplyXattr, Request, Session, SessionACL, TimeOrNow, WriteFlags,
 };
-use lru::LruCache;
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap, HashSet},
     ffi::OsStr,
-    fs::{self, File},
-    io::{BufReader, Read, Seek},
-    num::NonZeroUsize,
-    os::unix::fs::MetadataExt,
+    fs,
+    ops::Deref,
+    os::unix::fs::{FileExt, MetadataExt, OpenOptionsExt},
+    panic::{self, AssertUnwindSafe},
     path::{Path, PathBuf},
-    sync::Mutex,
-    time::{Duration, SystemTime},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc, Arc, Mutex, OnceLock, RwLock,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
 };
-use tracing::{error, info};
+use tracing::{debug, error, info, warn};
 use tracing_subscriber::EnvFilter;
 
-use chd::metadata::{KnownMetadata, Metadata, MetadataTag};
-use chd::Chd;
+#[cfg(feature = "archive")]
+mod archive;
+#[cfg(feature = "async")]
+mod async_fs;
+mod attrs;
+mod audit;
+mod cat;
+mod chd_image;
+mod check;
+mod config;
+mod control;
+mod crc32;
+mod cso;
+#[cfg(feature = "dbus")]
+mod dbus;
+mod errlog;
+mod fair;
+mod fusedev;
+mod handles;
+mod hash;
+mod health;
+mod hot;
+mod inflight;
+mod iso;
+mod isolation;
+mod jobs;
+mod latency;
+mod mapping;
+mod md5;
+mod media;
+mod memory;
+mod otel;
+mod overlay;
+mod panics;
+mod progress;
+mod provider;
+#[cfg(any(feature = "http", feature = "sftp"))]
+mod remote;
+mod sched;
+mod sha1;
+mod share;
+mod shared_cache;
+mod sheet;
+#[cfg(feature = "smb")]
+mod smb;
+mod snapshot;
+mod throttle;
+mod titles;
+mod vfs;
+mod watch;
+mod webui;
+
+use attrs::{AttrCache, SourceStat};
+use audit::AuditLog;
+use chd_image::{AudioCdMode, Form2Policy, FrameCache, TailPolicy};
+use config::ConfigFile;
+use cso::{CsoFormat, CsoViewProvider};
+use errlog::ErrorLog;
+use fusedev::AllowOther;
+use handles::{Handle, HandleTable};
+use health::Health;
+use hot::HotSet;
+use jobs::{Jobs, Task};
+use latency::{ReadTrace, SlowReads, TraceRule};
+use media::Media;
+use otel::Attr;
+use overlay::Overlay;
+use provider::{
+    Ambiguous, BackingProvider, Checksum, ChecksumProvider, ExtensionMap, HeaderCacheProvider,
+    ImageKind, NotReady, PinnedProvider, ProbeContext, Registry, SourceFileProvider,
+};
+use sched::{CpuList, IoPrio};
+use share::Only;
+use snapshot::{DeferredProvider, DeferredSource, EntryRecord, Snapshot, SourceRecord};
+use throttle::TokenBucket;
+use titles::{Title, TitleDb};
+use vfs::ScanOptions;
+use watch::{Change, Watcher};
 
 /// Expose 2048-byte ISO stream from CD CHDs and passthrough from DVD CHDs.
 const TTL: Duration = Duration::from_secs(1);
-const CD_FRAME_2352: usize = 2352;
+
+/// Entry and attribute TTL of `--smb-mode`. The library is immutable between
+/// re-indexes, and a re-index invalidates what changed.
+const SMB_TTL: Duration = Duration::from_secs(3600);
+
+/// Subcommands run instead of mounting.
+#[derive(Subcommand, Debug, Clone)]
+enum Command {
+    /// Verify CHDs like `chdman verify`: decode every hunk and compare the SHA-1s stored in the header
+    Check(check::CheckArgs),
+    /// Print a [[mount]] table for --config that serves only the given titles at a second mountpoint
+    Share(share::ShareArgs),
+    /// Write the ISO view of one CHD to stdout, as a mount would serve it
+    Cat(cat::CatArgs),
+    /// Hash the ISO view of every source in a directory, in parallel, and print a DAT, CSV or JSON listing
+    Hash(hash::HashArgs),
+}
 
 /// Flags / CLI
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Clone)]
 #[command(
     name = env!("CARGO_PKG_NAME"),
     author,
     version,
     about = env!("CARGO_PKG_DESCRIPTION"),
-    long_about = None
+    long_about = None,
+    subcommand_negates_reqs = true,
+    args_conflicts_with_subcommands = true
 )]
 struct Args {
-    /// Source directory containing *.chd files
-    #[arg(short = 's', long = "source", value_name = "DIR")]
-    source_dir: PathBuf,
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Source directory containing *.chd files (and .cso/.zso/.iso.gz), or the http(s):// (`http` feature) or sftp:// (`sftp` feature) URL of a CHD library
+    #[arg(
+        short = 's',
+        long = "source",
+        value_name = "DIR",
+        required_unless_present_any = ["source_list", "config"]
+    )]
+    source_dir: Option<PathBuf>,
+
+    /// Index exactly the CHDs listed in FILE (one path per line, .txt or .m3u) instead of a directory
+    #[arg(
+        long = "source-list",
+        value_name = "FILE",
+        conflicts_with = "source_dir"
+    )]
+    source_list: Option<PathBuf>,
 
     /// Mountpoint
-    #[arg(short = 'm', long = "mount", value_name = "DIR")]
-    mountpoint: PathBuf,
-
-    /// Allow other users to access the mount (requires user_allow_other in /etc/fuse.conf)
-    #[arg(long = "allow-other", default_value_t = false)]
-    allow_other: bool,
+    #[arg(
+        short = 'm',
+        long = "mount",
+        value_name = "DIR",
+        required_unless_present_any = ["config", "export_index"]
+    )]
+    mountpoint: Option<PathBuf>,
+
+    /// TOML config file; each [[mount]] table adds a source -> mountpoint pair served by this process
+    #[arg(long = "config", value_name = "FILE")]
+    config: Option<PathBuf>,
+
+    /// Allow other users to access the mount (requires user_allow_other in /etc/fuse.conf); auto: only if fuse.conf permits it
+    #[arg(
+        long = "allow-other",
+        value_enum,
+        default_value_t = AllowOther::Off,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "on"
+    )]
+    allow_other: AllowOther,
+
+    /// Tune for re-export via Samba: hour-long entry/attribute TTLs, negative lookups cached, page cache kept across opens, readdirplus, no periodic source re-stat
+    #[arg(long = "smb-mode", default_value_t = false)]
+    smb_mode: bool,
+
+    /// Let knfsd re-export the mount: NFS file handles resolve by inode and go stale on restart (sync backend)
+    #[arg(long = "nfs-export", default_value_t = false)]
+    nfs_export: bool,
+
+    /// Expose only these titles (repeatable): image or CHD names without extension, serials or --title-db names
+    #[arg(long = "only", value_name = "TITLE")]
+    only: Vec<String>,
+
+    /// Append a JSON line for every open and release of an image (user, process, file, ranges read) to FILE
+    #[arg(long = "audit-log", value_name = "FILE")]
+    audit_log: Option<PathBuf>,
+
+    /// With --allow-other, only these UIDs (comma-separated) and the mounting user may list and open files
+    #[arg(long = "visible-to", value_name = "UIDS", value_delimiter = ',')]
+    visible_to: Vec<u32>,
 
     /// Max in-memory cache entries (frames) across all files
     #[arg(long = "cache-hunks", default_value_t = 256)]
@@ -57,910 +208,4166 @@ struct Args {
     #[arg(long = "cache-bytes", default_value_t = 256 * 1024 * 1024)]
     cache_bytes: usize,
 
-    /// Permit exporting Mode2/Form2 payloads as raw 2324-byte sectors (exposed as "Name (Form2).bin")
-    #[arg(long = "cd-allow-form2", default_value_t = false)]
+    /// Keep caches and buffers within this many MiB, shrinking the frame cache and then readahead when over (0 = no cap)
+    #[arg(long = "max-memory", value_name = "MIB", default_value_t = 0)]
+    max_memory: u64,
+
+    /// Share decoded hunks with other instances through the Unix socket at PATH; the first instance there holds them
+    #[arg(long = "shared-cache", value_name = "PATH")]
+    shared_cache: Option<PathBuf>,
+
+    /// Size of the shared hunk cache, when this instance creates it
+    #[arg(long = "shared-cache-mib", value_name = "MIB", default_value_t = 256)]
+    shared_cache_mib: u64,
+
+    /// Decode at most N reads at once, small and scattered reads ahead of long sequential scans (0 = no limit)
+    #[arg(long = "fair-reads", value_name = "N", default_value_t = 0)]
+    fair_reads: usize,
+
+    /// Mode 2 Form 2 data (video, XA audio): "hide", "bin" ("Name (Form2).bin", 2324-byte sectors) or "xa-view" ("Name (XA).bin", 2336-byte sectors of either form)
+    #[arg(long = "form2-policy", value_name = "MODE", default_value = "hide")]
+    form2_policy: Form2Policy,
+
+    /// Deprecated: `--form2-policy bin`
+    #[arg(long = "cd-allow-form2", hide = true)]
     cd_allow_form2: bool,
 
+    /// Also expose each CD audio track as raw 2352-byte frames ("Name (Track NN).bin")
+    #[arg(long = "audio-tracks", default_value_t = false)]
+    audio_tracks: bool,
+
+    /// Start each --audio-tracks file at the track's INDEX 00, keeping audio stored in its pregap (such as hidden track one audio)
+    #[arg(long = "pregap-audio", default_value_t = false)]
+    pregap_audio: bool,
+
+    /// Serve audio tracks big-endian, as stored in the CHD, instead of little-endian .bin order
+    #[arg(long = "audio-byteswap", default_value_t = false)]
+    audio_byteswap: bool,
+
+    /// CHDs with only audio tracks: hide, or expose Name.cue plus per-track .bin or .wav files
+    #[arg(long = "audio-cd", value_name = "MODE", default_value = "hide")]
+    audio_cd: AudioCdMode,
+
+    /// With --audio-cd bin|wav, also expose a cdrdao "Name.toc" next to Name.cue
+    #[arg(long = "toc", default_value_t = false)]
+    toc: bool,
+
+    /// Also expose "Name (Full).bin" (every track as raw 2352-byte frames, as chdman extractcd writes them) and "Name (Full).cue" for CD CHDs
+    #[arg(long = "full-disc-image", default_value_t = false)]
+    full_disc_image: bool,
+
+    /// For CDs with several data tracks, also expose "Name (Merged).iso": the user data of every 2048-byte data track, in order
+    #[arg(long = "merged-iso", default_value_t = false)]
+    merged_iso: bool,
+
+    /// Extensions to expose images with, for frontends that scan for particular ones: KIND=EXT pairs, comma-separated; kinds left out keep their default
+    #[arg(
+        long = "extension-map",
+        value_name = "MAP",
+        default_value = "dvd=iso,cd-data=iso,form2=bin,xa=bin,hdd=iso"
+    )]
+    extension_map: ExtensionMap,
+
+    /// Reads of hunks past the end of a short CHD: "eio", "zero" (read zeros, for emulators probing the lead-out) or "truncate" (end the read there)
+    #[arg(long = "tail-policy", value_name = "MODE", default_value = "eio")]
+    tail_policy: TailPolicy,
+
+    /// Hide (and count) sources detected on a guess: CD data found by scanning, track frames beyond the stored data, a first hunk that does not decode
+    #[arg(long = "strict", default_value_t = false)]
+    strict: bool,
+
+    /// Probe each new or changed source in a child process first, killed after SECS; sources that crash or hang it are left out until they change (0 = off)
+    #[arg(long = "probe-isolation", value_name = "SECS", default_value_t = 0)]
+    probe_isolation: u64,
+
+    /// Expose "Name.sub" (deinterleaved 96-byte subchannel per sector) for CD CHDs stored with subcode
+    #[arg(long = "export-subchannel", default_value_t = false)]
+    export_subchannel: bool,
+
+    /// Resolve symlinked CHDs and descend into subdirectories, symlinked or not (with loop detection)
+    #[arg(long = "follow-symlinks", default_value_t = false)]
+    follow_symlinks: bool,
+
+    /// Also expose each DVD image as a compressed container view (Name.cso / Name.zso)
+    #[arg(long = "compressed-view", value_name = "FORMAT")]
+    compressed_view: Option<CsoFormat>,
+
+    /// Keep the first N MiB of each image decoded in memory, exempt from cache eviction (0 = off)
+    #[arg(long = "pin-mib", value_name = "MIB", default_value_t = 0)]
+    pin_mib: u64,
+
+    /// FUSE requests the kernel keeps in flight in the background, e.g. readahead (sync backend; 0 = fuser default)
+    #[arg(long = "max-background", value_name = "N", default_value_t = 0)]
+    max_background: u16,
+
+    /// Background requests in flight at which the kernel reports the mount congested (sync backend; 0 = 3/4 of --max-background)
+    #[arg(long = "congestion-threshold", value_name = "N", default_value_t = 0)]
+    congestion_threshold: u16,
+
+    /// Drop the page cache of source files behind sequential reads, so compressed and decoded data are not both cached
+    #[arg(long = "drop-source-cache", default_value_t = false)]
+    drop_source_cache: bool,
+
+    /// After mounting, spend up to this many seconds decoding the start of every image so the first scan finds them warm (0 = off)
+    #[arg(long = "warmup", value_name = "SECONDS", default_value_t = 0)]
+    warmup: u64,
+
+    /// Jobs to run over the whole library in the background after each index build: "hash" (--checksum-files digests) and "verify" (CHD SHA-1s), comma-separated
+    #[arg(long = "maintenance", value_name = "JOBS", value_delimiter = ',')]
+    maintenance: Vec<jobs::Task>,
+
+    /// Images --maintenance and --warmup work on at once
+    #[arg(long = "maintenance-workers", value_name = "N", default_value_t = 1)]
+    maintenance_workers: usize,
+
+    /// Cap background job reads in MB/s (0 = unlimited; they always pause while clients read)
+    #[arg(
+        long = "maintenance-throughput",
+        value_name = "MB/S",
+        default_value_t = 0.0
+    )]
+    maintenance_throughput: f64,
+
+    /// Cap total read throughput in MB/s (0 = unlimited)
+    #[arg(long = "max-throughput", value_name = "MB/S", default_value_t = 0.0)]
+    max_throughput: f64,
+
+    /// Cap read throughput of each open file handle in MB/s (0 = unlimited)
+    #[arg(
+        long = "max-handle-throughput",
+        value_name = "MB/S",
+        default_value_t = 0.0
+    )]
+    max_handle_throughput: f64,
+
+    /// Log a warning, with where the time went, for every read that takes longer than this many milliseconds (0 = off)
+    #[arg(long = "slow-read-ms", value_name = "MS", default_value_t = 0)]
+    slow_read_ms: u64,
+
+    /// Log every Nth read with where its time went (see also --trace-reads-slower-ms, --trace-reads-file; the control socket can change these)
+    #[arg(long = "trace-reads-every", value_name = "N")]
+    trace_reads_every: Option<u64>,
+
+    /// Log reads taking at least MS milliseconds (combines with the other --trace-reads-* filters)
+    #[arg(long = "trace-reads-slower-ms", value_name = "MS")]
+    trace_reads_slower_ms: Option<u64>,
+
+    /// Log reads of sources whose path matches GLOB ("*" and "?", e.g. "*/Ico*")
+    #[arg(long = "trace-reads-file", value_name = "GLOB")]
+    trace_reads_file: Option<String>,
+
+    /// Keep this title (a path in the mount, e.g. "PS2/Ico.iso") decoded in memory, within --hot-mib; repeatable
+    #[arg(long = "hot", value_name = "PATH")]
+    hot: Vec<String>,
+
+    /// Memory for --hot titles, in MiB (0 = none)
+    #[arg(long = "hot-mib", value_name = "MIB", default_value_t = 0)]
+    hot_mib: u64,
+
+    /// Run at this nice value (-20..19; negative needs CAP_SYS_NICE)
+    #[arg(long = "nice", value_name = "N", allow_negative_numbers = true)]
+    nice: Option<i32>,
+
+    /// I/O scheduling class and priority, as in ionice(1): realtime|best-effort|idle[:0-7]
+    #[arg(long = "ionice", value_name = "CLASS[:PRIO]")]
+    ionice: Option<IoPrio>,
+
+    /// Restrict decoding (and all other threads) to these CPUs, e.g. 2-3,6
+    #[arg(long = "decode-cpu-affinity", value_name = "LIST")]
+    decode_cpu_affinity: Option<CpuList>,
+
+    /// Where inflated copies of .iso.gz inputs are kept (reused across restarts)
+    #[arg(
+        long = "spill-dir",
+        value_name = "DIR",
+        default_value = "/var/tmp/chd2iso-fuse"
+    )]
+    spill_dir: PathBuf,
+
+    /// Keep the first MiB of each CHD image decoded in DIR, keyed by its SHA-1, so library scans need no decoding, even after a restart
+    #[arg(long = "header-cache", value_name = "DIR")]
+    header_cache: Option<PathBuf>,
+
+    /// Where chunks of remote (http, sftp) sources are cached
+    #[arg(
+        long = "http-cache",
+        value_name = "DIR",
+        default_value = "/var/tmp/chd2iso-fuse/http"
+    )]
+    #[cfg_attr(not(any(feature = "http", feature = "sftp")), allow(dead_code))]
+    http_cache: PathBuf,
+
+    /// Disk budget for --http-cache in MiB; 0 keeps chunks in memory only
+    #[arg(long = "http-cache-mib", value_name = "MIB", default_value_t = 1024)]
+    #[cfg_attr(not(any(feature = "http", feature = "sftp")), allow(dead_code))]
+    http_cache_mib: u64,
+
+    /// Let reads update the atime of local sources; by default they are opened with O_NOATIME where permitted
+    #[arg(long = "update-atime", default_value_t = false)]
+    update_atime: bool,
+
+    /// Fail opens and reads of local sources with EIO after SECS without an answer (a hung NFS server), degrading the source (0 = wait forever)
+    #[arg(long = "source-timeout", value_name = "SECS", default_value_t = 0)]
+    source_timeout: u64,
+
+    /// Collapse CHDs with identical header SHA1 into one entry; the others move to .duplicates/
+    #[arg(long = "dedupe", default_value_t = false)]
+    dedupe: bool,
+
+    /// Also expose each original source file, unmodified, under .sources/
+    #[arg(long = "expose-sources", default_value_t = false)]
+    expose_sources: bool,
+
+    /// Name images by their disc serial from this CSV (serial,title[,region]) or XML DAT; re-read on each re-index
+    #[arg(long = "title-db", value_name = "FILE")]
+    title_db: Option<PathBuf>,
+
+    /// With --title-db, list each titled image in a subdirectory named after its region
+    #[arg(
+        long = "title-region-dirs",
+        default_value_t = false,
+        requires = "title_db"
+    )]
+    title_region_dirs: bool,
+
+    /// List images in PS2/, PS1/, Dreamcast/ and Saturn/ by what their discs hold; others stay at the top level
+    #[arg(long = "console-dirs", default_value_t = false)]
+    console_dirs: bool,
+
+    /// Name images without a --title-db title after their ISO9660 volume label rather than their source file
+    #[arg(long = "name-from-label", default_value_t = false)]
+    name_from_label: bool,
+
+    /// Arrangement of images: "flat" (all in the root), "opl" (DVD/ and CD/ with SERIAL.Title.iso names, for Open PS2 Loader over SMB) or "sha1" (sha1/ab/cdef....iso, with the usual names as symlinks)
+    #[arg(long = "layout", value_name = "MODE", default_value = "flat")]
+    layout: Layout,
+
+    /// Also list "Name.iso.sha1" and/or "Name.iso.md5" files for sha1sum -c / md5sum -c, hashed on first read: "sha1", "md5" or "both"
+    #[arg(long = "checksum-files", value_name = "ALGO")]
+    checksum_files: Option<ChecksumFiles>,
+
+    /// List files from DIR named after an image (Game.png, Game.cfg) next to it, read-only
+    #[arg(long = "sidecar-dir", value_name = "DIR")]
+    sidecar_dir: Option<PathBuf>,
+
+    /// st_blocks of exposed files: "logical" (their full size) or "physical" (pro-rated from the source file, for du)
+    #[arg(long = "blocks-report", value_name = "MODE", default_value = "logical")]
+    blocks_report: BlocksReport,
+
+    /// Re-stat a source for getattr once its cached attributes are this old, in seconds (0 = only when inotify reports a change)
+    #[arg(long = "attr-refresh", value_name = "SECS", default_value_t = 0)]
+    attr_refresh: u64,
+
+    /// File timestamps: "source" (the source file's), "fixed:EPOCH" or "sha1" (derived from the CHD data SHA-1)
+    #[arg(long = "mtime", value_name = "POLICY", default_value = "source")]
+    mtime: MtimePolicy,
+
+    /// For mirroring with rsync: timestamps from the data SHA-1 (unless --mtime fixed:EPOCH), atime equal to mtime, fixed directory times, sources indexed in sorted order and never an atime update
+    #[arg(long = "reproducible", default_value_t = false)]
+    reproducible: bool,
+
+    /// Report unlink/rmdir as successful (leaving the entry in place) instead of failing with EROFS
+    #[arg(long = "deny-delete-silently", default_value_t = false)]
+    deny_delete_silently: bool,
+
+    /// Store files created in the mount (emulator configs, artwork) in DIR; image names stay read-only
+    #[arg(long = "overlay", value_name = "DIR")]
+    overlay: Option<PathBuf>,
+
+    /// Fail reads of a source at once after this many read errors, until the next re-index (0 = never)
+    #[arg(long = "error-budget", value_name = "N", default_value_t = 100)]
+    error_budget: u64,
+
+    /// How sources past their error budget are served: "eio", "hide" (ENOENT) or "empty" (0-byte placeholder)
+    #[arg(long = "quarantine", value_name = "POLICY", default_value = "eio")]
+    quarantine: Quarantine,
+
+    /// Write the probe results of each index to FILE (JSON); without --mount, index and exit
+    #[arg(long = "export-index", value_name = "FILE")]
+    export_index: Option<PathBuf>,
+
+    /// Reuse probe results from --export-index instead of opening every source; changed sources are probed again
+    #[arg(long = "import-index", value_name = "FILE")]
+    import_index: Option<PathBuf>,
+
+    /// Serve a .chd2iso/health status file in the mount root, for container health checks
+    #[arg(long = "health", default_value_t = false)]
+    health: bool,
+
+    /// Skip hidden (dot-prefixed) files and directories in the source scan
+    #[arg(long = "skip-hidden", default_value_t = false)]
+    skip_hidden: bool,
+
+    /// Serve the org.chd2iso.Fuse D-Bus interface on the "session" or "system" bus (needs the `dbus` build feature)
+    #[arg(long = "dbus", value_name = "BUS")]
+    dbus: Option<DbusBus>,
+
+    /// Serve a JSON-RPC 2.0 API (entries, stats, reload, evict, degrade/restore) on a Unix socket at PATH, one request per line
+    #[arg(long = "control-socket", value_name = "PATH")]
+    control_socket: Option<PathBuf>,
+
+    /// Serve a status page (titles, counters, read errors, re-index and evict buttons) over HTTP at ADDR, e.g. 127.0.0.1:8080; no authentication
+    #[arg(long = "web-ui", value_name = "ADDR")]
+    web_ui: Option<std::net::SocketAddr>,
+
+    /// Export read, open and index spans and the counters to an OpenTelemetry collector over OTLP/HTTP, e.g. http://localhost:4318 (needs the `otel` build feature)
+    #[arg(long = "otlp-endpoint", value_name = "URL")]
+    otlp_endpoint: Option<String>,
+
+    /// Seconds between OTLP exports
+    #[arg(long = "otlp-interval", value_name = "SECS", default_value_t = 10)]
+    otlp_interval: u64,
+
+    /// Also serve every mount as a read-only share over "smb" (experimental; needs the `smb` build feature)
+    #[arg(long = "serve", value_name = "PROTOCOL")]
+    serve: Option<Serve>,
+
+    /// Address --serve listens on
+    #[arg(
+        long = "serve-addr",
+        value_name = "ADDR",
+        default_value = "0.0.0.0:445"
+    )]
+    #[cfg_attr(not(feature = "smb"), allow(dead_code))]
+    serve_addr: std::net::SocketAddr,
+
+    /// Keep the mount after the process dies instead of having fusermount3 remove it (for containers where its watchdog cannot run)
+    #[arg(long = "no-auto-unmount", default_value_t = false)]
+    no_auto_unmount: bool,
+
+    /// Wait up to SECS for /dev/fuse to become available before giving up (0 = check once)
+    #[arg(long = "wait-for-fuse", value_name = "SECS", default_value_t = 0)]
+    wait_for_fuse: u64,
+
+    /// FUSE request loop: "sync" (fuser, one thread) or "async" (fuse3/tokio; needs the `async` build feature)
+    #[arg(long = "backend", value_name = "BACKEND", default_value = "sync")]
+    backend: Backend,
+
     /// Verbose logging
     #[arg(long = "verbose", default_value_t = false)]
     verbose: bool,
 }
 
-#[derive(Clone, Debug)]
-enum BackingKind {
-    /// DVD (or generic 2048 units): direct 2048 sector passthrough
-    Dvd2048,
-    /// CD-style (2352 frames) -> user-data view with offsets & mapping
-    Cd2352 {
-        first_data_lba: u64,
-        payload_kind: CdPayloadKind,
-        track_frames: Option<u64>,
-    },
-    /// Raw/unrecognized, default to 2048 passthrough (rare/fallback)
-    Raw2048,
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum Backend {
+    Sync,
+    Async,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum DbusBus {
+    Session,
+    System,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum Serve {
+    Smb,
+}
+
+/// `--mtime`: where file timestamps come from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize)]
+#[serde(try_from = "String")]
+pub enum MtimePolicy {
+    /// The source file's mtime and ctime
+    Source,
+    /// This many seconds since the epoch, for every file
+    Fixed(u64),
+    /// A time derived from the data SHA-1, so it changes with the content;
+    /// the source's for files without one
+    Sha1,
+}
+
+/// `--mtime sha1`: the earliest time handed out, 2000-01-01, and the time of
+/// directories under `--reproducible`.
+const SHA1_MTIME_BASE: u64 = 946_684_800;
+
+impl MtimePolicy {
+    /// Time of a file with data SHA-1 `sha1`, where the policy decides it.
+    fn fixed(self, sha1: Option<&[u8; 20]>) -> Option<SystemTime> {
+        let secs = match (self, sha1) {
+            (Self::Fixed(secs), _) => secs,
+            (Self::Sha1, Some(sha1)) => {
+                let n = u32::from_be_bytes([sha1[0], sha1[1], sha1[2], sha1[3]]);
+                SHA1_MTIME_BASE + u64::from(n % (1 << 28))
+            }
+            (Self::Sha1, None) | (Self::Source, _) => return None,
+        };
+        Some(SystemTime::UNIX_EPOCH + Duration::from_secs(secs))
+    }
+
+    /// Time of directories, where the policy fixes one.
+    fn dir_time(self) -> Option<SystemTime> {
+        match self {
+            Self::Fixed(secs) => Some(SystemTime::UNIX_EPOCH + Duration::from_secs(secs)),
+            Self::Sha1 => Some(SystemTime::UNIX_EPOCH + Duration::from_secs(SHA1_MTIME_BASE)),
+            Self::Source => None,
+        }
+    }
+}
+
+impl FromStr for MtimePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s.split_once(':') {
+            None if s == "source" => Ok(Self::Source),
+            None if s == "sha1" => Ok(Self::Sha1),
+            Some(("fixed", secs)) => secs
+                .parse()
+                .map(Self::Fixed)
+                .map_err(|_| format!("invalid epoch seconds {secs:?}")),
+            _ => Err(format!("expected source, sha1 or fixed:EPOCH, got {s:?}")),
+        }
+    }
+}
+
+impl TryFrom<String> for MtimePolicy {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, String> {
+        s.parse()
+    }
+}
+
+/// `--quarantine`: how a source degraded by `--error-budget` is served.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Quarantine {
+    /// Listed as before; reads fail with EIO
+    Eio,
+    /// Left out of listings and lookups (ENOENT)
+    Hide,
+    /// Listed as an empty file
+    Empty,
+}
+
+/// `--blocks-report`: what `st_blocks` (and so `du`) counts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BlocksReport {
+    /// The exposed size, as if the image were stored uncompressed
+    Logical,
+    /// The source file's allocated blocks, split across the entries it backs
+    Physical,
+}
+
+/// `--checksum-files`: which digests to list beside each image.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChecksumFiles {
+    Sha1,
+    Md5,
+    Both,
+}
+
+impl ChecksumFiles {
+    fn algos(self) -> &'static [Checksum] {
+        match self {
+            ChecksumFiles::Sha1 => &[Checksum::Sha1],
+            ChecksumFiles::Md5 => &[Checksum::Md5],
+            ChecksumFiles::Both => &[Checksum::Sha1, Checksum::Md5],
+        }
+    }
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
-enum CdPayloadKind {
-    Mode1_2048,
-    Mode2Form1_2048,
-    Mode2Form2_2324,
+/// `--layout`: how images are arranged in the mount.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Layout {
+    /// Every image in the mount root (or its region directory)
+    Flat,
+    /// PS2 images in `DVD/` and `CD/` as `SLUS_200.62.Title.iso`, the way
+    /// Open PS2 Loader expects an SMB share
+    Opl,
+    /// Images stored by CHD SHA1 as `sha1/ab/cdef....iso`, with the names
+    /// `flat` would give them as symlinks there
+    Sha1,
 }
 
 #[derive(Clone, Debug)]
 struct IndexEntry {
     ino: u64,
+    /// Inode of the containing directory (1 = mount root)
+    parent: u64,
+    /// Virtual directory path relative to the mount root ("" = root)
+    dir: String,
     name: String,
+    /// Source file (a CHD, or another supported input)
     chd_path: PathBuf,
-    kind: BackingKind,
-    iso_size: u64,
+    provider: Arc<dyn BackingProvider>,
+    /// CHD header SHA1 (None for CHD versions that lack one)
+    sha1: Option<[u8; 20]>,
+    /// First sector of layer 1 for dual-layer DVD images
+    layer_break: Option<u64>,
+    /// Name before `--title-db` or `--name-from-label` renamed the entry
+    original_name: Option<String>,
+    /// ISO9660 volume label
+    label: Option<String>,
+    /// Logical bytes exposed from `chd_path` in all, for pro-rating its
+    /// on-disk size (`--blocks-report physical`)
+    source_bytes: u64,
 }
 
-struct Handle {
-    file_id: u64,
-    chd_path: PathBuf,
+/// Directory synthesized by the index (e.g. `.duplicates`).
+#[derive(Clone, Debug)]
+struct VirtualDir {
+    ino: u64,
+    parent: u64,
+    /// Full path relative to the mount root
+    path: String,
+    name: String,
 }
 
-struct FsState {
-    args: Args,
+/// Symlink synthesized by the index (`--layout sha1`).
+#[derive(Clone, Debug)]
+struct VirtualLink {
+    ino: u64,
+    parent: u64,
+    dir: String,
+    name: String,
+    /// Relative to the link's directory
+    target: String,
+}
+
+/// Snapshot of the exposed tree; swapped wholesale on re-index.
+#[derive(Default)]
+struct Index {
+    dirs: Vec<VirtualDir>,
     entries: Vec<IndexEntry>,
-    handles: Mutex<HashMap<u64, Handle>>,
-    next_fh: Mutex<u64>,
-    frame_cache: Mutex<LruCache<(u64, u64), Vec<u8>>>,
-    approx_cache_bytes: Mutex<usize>,
+    links: Vec<VirtualLink>,
 }
 
-impl FsState {
-    fn new(args: Args) -> Result<Self> {
-        let cache_cap =
-            NonZeroUsize::new(args.cache_hunks).unwrap_or(NonZeroUsize::new(64).unwrap());
+impl Index {
+    fn is_dir(&self, ino: u64) -> bool {
+        ino == 1 || self.dirs.iter().any(|d| d.ino == ino)
+    }
 
-        Ok(Self {
-            entries: Vec::new(),
-            handles: Mutex::new(HashMap::new()),
-            next_fh: Mutex::new(1),
-            frame_cache: Mutex::new(LruCache::new(cache_cap)),
-            approx_cache_bytes: Mutex::new(0),
-            args,
-        })
+    fn dir(&self, ino: u64) -> Option<&VirtualDir> {
+        self.dirs.iter().find(|d| d.ino == ino)
     }
 
-    fn build_index(&mut self) -> Result<()> {
-        let dir = &self.args.source_dir;
-        let mut tmp: Vec<IndexEntry> = Vec::new();
+    fn entry(&self, ino: u64) -> Option<&IndexEntry> {
+        self.entries.iter().find(|e| e.ino == ino)
+    }
 
-        for ent in fs::read_dir(dir).with_context(|| format!("reading {dir:?}"))? {
-            let ent = ent?;
-            let path = ent.path();
+    fn link(&self, ino: u64) -> Option<&VirtualLink> {
+        self.links.iter().find(|l| l.ino == ino)
+    }
 
-            if path
-                .extension()
-                .and_then(|s| s.to_str())
-                .map(|s| s.eq_ignore_ascii_case("chd"))
-                != Some(true)
-            {
-                continue;
+    fn lookup(&self, parent: u64, name: &str) -> Option<Lookup> {
+        // Only sent for NFS file handles: "." resolves an inode by number,
+        // ".." finds a directory's parent.
+        match name {
+            "." if self.is_dir(parent) => return Some(Lookup::Dir(parent)),
+            "." => {
+                return (self.entry(parent).cloned().map(Lookup::Entry))
+                    .or_else(|| self.link(parent).map(|l| Lookup::Link(l.ino)))
             }
-
-            match self.build_index_entry(&path) {
-                Ok(Some((name, kind, size))) => {
-                    tmp.push(IndexEntry {
-                        ino: 0,
-                        name,
-                        chd_path: path.clone(),
-                        kind,
-                        iso_size: size,
-                    });
-                }
-                Ok(None) => {}
-                Err(e) => {
-                    error!("Skipping {:?}: {}", path, e);
-                }
+            ".." if self.is_dir(parent) => {
+                return Some(Lookup::Dir(self.dir(parent).map_or(1, |d| d.parent)))
             }
+            _ => {}
         }
 
-        tmp.sort_by_key(|a| a.name.to_lowercase());
-
-        for (i, e) in tmp.iter_mut().enumerate() {
-            e.ino = (i as u64) + 2;
+        if let Some(d) = self
+            .dirs
+            .iter()
+            .find(|d| d.parent == parent && d.name == name)
+        {
+            return Some(Lookup::Dir(d.ino));
+        }
+        if let Some(l) = self
+            .links
+            .iter()
+            .find(|l| l.parent == parent && l.name == name)
+        {
+            return Some(Lookup::Link(l.ino));
         }
 
-        self.entries = tmp;
-        Ok(())
+        self.entries
+            .iter()
+            .find(|e| e.parent == parent && e.name == name)
+            .map(|e| Lookup::Entry(e.clone()))
     }
+}
 
-    fn build_index_entry(&self, chd_path: &Path) -> Result<Option<(String, BackingKind, u64)>> {
-        let f = File::open(chd_path)?;
-        let mut chd = Chd::open(BufReader::new(f), None)?;
-
-        let hdr = chd.header();
-        let unit_bytes = hdr.unit_bytes() as usize;
-        let logical_bytes = hdr.logical_bytes();
+enum Lookup {
+    Dir(u64),
+    Link(u64),
+    Entry(IndexEntry),
+}
 
-        let stem = chd_path
-            .file_stem()
-            .and_then(|s| s.to_str())
-            .unwrap_or("unknown");
+/// A file found in a source: how it is recorded for `--export-index`, and
+/// what serves it.
+type Found = (EntryRecord, Arc<dyn BackingProvider>);
 
-        if unit_bytes == 2048 {
-            let iso_size = logical_bytes;
-            let name = format!("{stem}.iso");
-            return Ok(Some((name, BackingKind::Dvd2048, iso_size)));
-        }
+/// Bytes `--warmup` decodes from the start of each image: the volume
+/// descriptors, path table, root directory and boot files.
+const WARMUP_BYTES: u64 = 4 << 20;
 
-        if unit_bytes == 2352 {
-            let total_frames = logical_bytes / 2352;
+const DUPLICATES_DIR: &str = ".duplicates";
+const SOURCES_DIR: &str = ".sources";
 
-            if let Some((first_lba, payload, track_frames)) = {
-                let mut rf = BufReader::new(File::open(chd_path)?);
-                parse_cd_toc_from_metadata(&mut chd, &mut rf, self.args.cd_allow_form2)?
-            } {
-                let (per_sector, name) = match payload {
-                    CdPayloadKind::Mode1_2048 | CdPayloadKind::Mode2Form1_2048 => {
-                        (2048u64, format!("{stem}.iso"))
-                    }
-                    CdPayloadKind::Mode2Form2_2324 => {
-                        if self.args.cd_allow_form2 {
-                            (2324u64, format!("{stem} (Form2).bin"))
-                        } else {
-                            return Ok(None);
-                        }
-                    }
-                };
+struct FsState {
+    /// Swapped by a config reload; see `apply_settings`
+    args: RwLock<Arc<Args>>,
+    /// Replaced wholesale by `build_index`; handlers work on a snapshot
+    index: RwLock<Arc<Index>>,
+    next_ino: Mutex<u64>,
+    handles: HandleTable,
+    registry: Registry,
+    frame_cache: Arc<FrameCache>,
+    /// Mount-wide limit from `--max-throughput`
+    throttle: RwLock<Option<Arc<TokenBucket>>>,
+    /// Kernel cache invalidation channel; set once the session exists
+    notifier: Mutex<Option<Notifier>>,
+    attrs: AttrCache,
+    /// Set by `watch_sources`; `build_index` adds source directories to it
+    watcher: OnceLock<Watcher>,
+    /// `--audit-log`
+    audit: Option<AuditLog>,
+    /// Generation of every inode: the start time, so an NFS file handle from
+    /// an earlier run, whose inode number may now be another file, is stale
+    generation: u64,
+    /// Mountpoints inside the source directory, which scans skip; see
+    /// `nested_mountpoints`
+    nested: OnceLock<Vec<PathBuf>>,
+    /// Writable layer from `--overlay`
+    overlay: Option<Overlay>,
+    /// Provider reads served, for the D-Bus `Stats` method
+    reads: AtomicU64,
+    bytes_read: AtomicU64,
+    media: Media,
+    health: Health,
+    /// Reads over `--slow-read-ms`
+    slow_reads: Arc<SlowReads>,
+    /// `--trace-reads-*`, changeable over the control socket
+    read_trace: Arc<ReadTrace>,
+    /// `--hot` titles, and the loader's wake-up call once it runs
+    hot: HotSet,
+    hot_wake: OnceLock<mpsc::Sender<()>>,
+    /// `--probe-isolation` outcomes
+    probe_verdicts: isolation::Verdicts,
+    /// A re-index for a source found gone on `open` is running
+    reindexing: AtomicBool,
+    /// `--maintenance` and `--warmup` runs
+    jobs: Arc<Jobs>,
+    errors: ErrorLog,
+}
 
-                let frames = track_frames.unwrap_or(total_frames - first_lba);
-                let iso_size = frames * per_sector;
-                let kind = BackingKind::Cd2352 {
-                    first_data_lba: first_lba,
-                    payload_kind: payload,
-                    track_frames,
-                };
+impl FsState {
+    fn new(args: Args, frame_cache: Arc<FrameCache>) -> Result<Self> {
+        Ok(Self {
+            index: RwLock::new(Arc::new(Index::default())),
+            next_ino: Mutex::new(2),
+            handles: HandleTable::default(),
+            registry: Registry::with_builtin(),
+            frame_cache,
+            throttle: RwLock::new(TokenBucket::from_mbps(args.max_throughput).map(Arc::new)),
+            notifier: Mutex::new(None),
+            attrs: AttrCache::default(),
+            watcher: OnceLock::new(),
+            nested: OnceLock::new(),
+            audit: args.audit_log.as_deref().map(AuditLog::open).transpose()?,
+            generation: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map_or(1, |d| d.as_secs()),
+            overlay: args.overlay.as_deref().map(Overlay::new).transpose()?,
+            reads: AtomicU64::new(0),
+            bytes_read: AtomicU64::new(0),
+            media: Media::default(),
+            health: Health::default(),
+            slow_reads: Arc::new(SlowReads::default()),
+            read_trace: Arc::new(ReadTrace::new(args.trace_rule())),
+            hot: HotSet::default(),
+            hot_wake: OnceLock::new(),
+            probe_verdicts: isolation::Verdicts::default(),
+            reindexing: AtomicBool::new(false),
+            jobs: Arc::new(Jobs::default()),
+            errors: ErrorLog::default(),
+            args: RwLock::new(Arc::new(args)),
+        })
+    }
 
-                return Ok(Some((name, kind, iso_size)));
-            }
+    fn args(&self) -> Arc<Args> {
+        Arc::clone(&self.args.read().expect("args lock poisoned"))
+    }
 
-            let (first_lba, payload) =
-                quick_scan_first_data(&mut chd, total_frames, self.args.cd_allow_form2)?;
+    /// The current index. A rebuild swaps in a new one without waiting for
+    /// handlers still using this snapshot.
+    fn index(&self) -> Arc<Index> {
+        Arc::clone(&self.index.read().expect("index lock poisoned"))
+    }
 
-            let (per_sector, name) = match payload {
-                CdPayloadKind::Mode1_2048 | CdPayloadKind::Mode2Form1_2048 => {
-                    (2048u64, format!("{stem}.iso"))
-                }
-                CdPayloadKind::Mode2Form2_2324 => {
-                    if self.args.cd_allow_form2 {
-                        (2324u64, format!("{stem} (Form2).bin"))
-                    } else {
-                        return Ok(None);
-                    }
-                }
-            };
+    /// Rough heap size of the index: records and their strings, not the
+    /// providers behind them.
+    fn index_bytes(&self) -> u64 {
+        use std::mem::size_of;
+        let index = self.index();
+        let entries = index.entries.iter().map(|e| {
+            size_of::<IndexEntry>()
+                + e.dir.len()
+                + e.name.len()
+                + e.chd_path.as_os_str().len()
+                + e.original_name.as_ref().map_or(0, String::len)
+                + e.label.as_ref().map_or(0, String::len)
+        });
+        let dirs = index
+            .dirs
+            .iter()
+            .map(|d| size_of::<VirtualDir>() + d.path.len() + d.name.len());
+        let links = index
+            .links
+            .iter()
+            .map(|l| size_of::<VirtualLink>() + l.dir.len() + l.name.len() + l.target.len());
+        entries.chain(dirs).chain(links).sum::<usize>() as u64
+    }
 
-            let iso_size = (total_frames - first_lba) * per_sector;
-            let kind = BackingKind::Cd2352 {
-                first_data_lba: first_lba,
-                payload_kind: payload,
-                track_frames: None,
-            };
+    /// Adopt reloaded settings for this mount. Index options take effect on
+    /// the next `build_index`, throughput limits immediately (per-handle ones
+    /// for handles opened from now on); `allow_other` needs a remount.
+    fn apply_settings(&self, mut new: Args) {
+        let old = self.args();
 
-            return Ok(Some((name, kind, iso_size)));
+        if new.allow_other != old.allow_other {
+            warn!(
+                "reload: allow_other for {:?} changed; restart to apply",
+                old.mountpoint()
+            );
+        }
+        // These decide whether the mount is read-only, so they stay as
+        // mounted.
+        if new.deny_delete_silently != old.deny_delete_silently {
+            warn!(
+                "reload: deny_delete_silently for {:?} changed; restart to apply",
+                old.mountpoint()
+            );
+            new.deny_delete_silently = old.deny_delete_silently;
+        }
+        if new.overlay != old.overlay {
+            warn!(
+                "reload: overlay for {:?} changed; restart to apply",
+                old.mountpoint()
+            );
+            new.overlay = old.overlay.clone();
+        }
+        if new.audit_log != old.audit_log {
+            warn!(
+                "reload: audit_log for {:?} changed; restart to apply",
+                old.mountpoint()
+            );
+            new.audit_log = old.audit_log.clone();
+        }
+        if new.max_throughput != old.max_throughput {
+            *self.throttle.write().expect("throttle lock poisoned") =
+                TokenBucket::from_mbps(new.max_throughput).map(Arc::new);
         }
 
-        let name = format!("{stem}.iso");
-        Ok(Some((name, BackingKind::Raw2048, logical_bytes)))
+        *self.args.write().expect("args lock poisoned") = Arc::new(new);
     }
 
-    fn alloc_fh(&self) -> u64 {
-        let mut next_fh = self.next_fh.lock().expect("next_fh mutex poisoned");
-        let fh = *next_fh;
-        *next_fh += 1;
-        fh
+    /// (Re)build the index. Entries and directories keep their inode across
+    /// rebuilds as long as their virtual path is unchanged, so open handles
+    /// stay valid.
+    fn build_index(&self) -> Result<()> {
+        let mount = || {
+            let mount = self.args().mountpoint().display().to_string();
+            vec![("mount", Attr::Str(mount))]
+        };
+        let res = otel::timed("index", mount, || self.rebuild_index());
+        self.health.record_index(&res);
+        if res.is_ok() {
+            self.errors.reset();
+            self.wake_hot();
+        }
+        res
     }
 
-    #[allow(clippy::too_many_arguments)]
-    fn read_iso_from_cd(
-        &self,
-        file_id: u64,
-        path: &Path,
-        start_frame: u64,
-        payload_kind: CdPayloadKind,
-        offset: u64,
-        size: u32,
-        max_len: u64,
-        reply: ReplyData,
-    ) {
-        let per_sector = match payload_kind {
-            CdPayloadKind::Mode1_2048 | CdPayloadKind::Mode2Form1_2048 => 2048usize,
-            CdPayloadKind::Mode2Form2_2324 => 2324usize,
-        };
+    fn rebuild_index(&self) -> Result<()> {
+        let args = self.args();
+        let mut tmp: Vec<IndexEntry> = Vec::new();
 
-        let payload_start = match payload_kind {
-            CdPayloadKind::Mode1_2048 => 16usize,
-            CdPayloadKind::Mode2Form1_2048 => 24usize,
-            CdPayloadKind::Mode2Form2_2324 => 24usize,
+        let mut paths = match &args.source_list {
+            Some(list) => read_source_list(list)?,
+            None => self.scan_source_dir()?,
         };
-
-        if offset >= max_len || size == 0 {
-            reply.data(&[]);
-            return;
+        // Name clashes are settled in source order, which a directory scan
+        // leaves to the filesystem.
+        if args.reproducible {
+            paths.sort();
         }
 
-        let end = offset.saturating_add(size as u64).min(max_len);
+        let ctx = probe_context(&args, &self.frame_cache);
+        let titles = load_titles(&args);
+        let only = Only::new(&args.only);
+        let root = args.source_dir.as_deref();
+        let mut imported = imported_index(&args, &ctx.fingerprint());
+        let head_settings = format!("{} tail={:?}", ctx.fingerprint(), ctx.tail);
+        let mut records = Vec::new();
+        let sources = paths.len();
+        let mut ambiguous = Vec::new();
+
+        for path in paths {
+            let found = match imported.remove(&path) {
+                Some(record) => Ok(Some(self.import_source(&args, &path, record))),
+                None => self.probe_source(&args, &path, &ctx),
+            };
 
-        let mut want = end - offset;
-        let mut out = Vec::with_capacity(want as usize);
-        let mut cur_iso_sector = offset / per_sector as u64;
-        let mut cur_in_sector_off = offset % per_sector as u64;
+            match found {
+                Ok(Some(found)) => {
+                    let main = &found[0].0;
+                    let title = titles
+                        .as_ref()
+                        .zip(main.serial.as_deref())
+                        .and_then(|(db, serial)| db.get(serial))
+                        .cloned()
+                        .or_else(|| {
+                            let name = main.label.clone().filter(|_| args.name_from_label)?;
+                            Some(Title { name, region: None })
+                        });
+                    let stem = main
+                        .name
+                        .rsplit_once('.')
+                        .map_or(&*main.name, |(s, _)| s)
+                        .to_string();
+
+                    if let Some(only) = &only {
+                        let source_stem = path.file_stem().map(|s| s.to_string_lossy());
+                        let names = [Some(&*stem), source_stem.as_deref()]
+                            .into_iter()
+                            .chain([main.serial.as_deref(), title.as_ref().map(|t| &*t.name)])
+                            .flatten();
+                        if !only.matches(names) {
+                            continue;
+                        }
+                    }
 
-        while want > 0 {
-            let frame_idx = start_frame + cur_iso_sector;
-            let sec = match self.get_cd_frame(file_id, path, frame_idx) {
-                Ok(v) => v,
+                    let opl = (args.layout == Layout::Opl)
+                        .then(|| main.serial.as_deref().and_then(opl_serial))
+                        .flatten();
+                    let console = main.console.filter(|_| args.console_dirs);
+
+                    let mut entries = Vec::new();
+                    for (i, (record, provider)) in found.into_iter().enumerate() {
+                        let (dir, name, original_name) = match (&title, &opl) {
+                            (title, Some(serial)) if i == 0 => (
+                                match provider.kind() {
+                                    ImageKind::Cd => "CD",
+                                    _ => "DVD",
+                                }
+                                .to_string(),
+                                opl_name(serial, title.as_ref().map_or(&*stem, |t| &t.name)),
+                                Some(record.name.clone()),
+                            ),
+                            (Some(t), _) => (
+                                t.region
+                                    .as_ref()
+                                    .filter(|_| args.title_region_dirs)
+                                    .map_or_else(String::new, |r| r.replace('/', "-")),
+                                titled_name(&record.name, &stem, &t.name),
+                                Some(record.name.clone()),
+                            ),
+                            (None, _) => (String::new(), record.name.clone(), None),
+                        };
+                        let dir = match console.filter(|_| opl.is_none()) {
+                            Some(c) if dir.is_empty() => c.dir().to_string(),
+                            Some(c) => format!("{}/{dir}", c.dir()),
+                            None => dir,
+                        };
+                        let provider: Arc<dyn BackingProvider> =
+                            match (&args.header_cache, record.sha1()) {
+                                (Some(dir), Some(sha1)) if is_image(record.kind) => Arc::new(
+                                    HeaderCacheProvider::new(provider, dir, &sha1, &head_settings),
+                                ),
+                                _ => provider,
+                            };
+                        tmp.push(IndexEntry {
+                            ino: 0,
+                            parent: 1,
+                            dir,
+                            name,
+                            chd_path: path.clone(),
+                            provider,
+                            sha1: record.sha1(),
+                            layer_break: record.layer_break,
+                            label: record.label.clone(),
+                            original_name,
+                            source_bytes: 0,
+                        });
+                        entries.push(record);
+                    }
+                    records.extend(SourceRecord::new(&path, root, entries));
+                }
+                Ok(None) => {}
+                Err(e) if e.downcast_ref::<Ambiguous>().is_some() => {
+                    warn!("Hiding {:?} (--strict): {}", path, e);
+                    ambiguous.push(path);
+                }
                 Err(e) => {
-                    error!("frame read error: {:?}", e);
-                    reply.error(Errno::from_i32(libc::EIO));
-                    return;
+                    error!("Skipping {:?}: {}", path, e);
                 }
-            };
+            }
+        }
 
-            let payload = &sec[payload_start..payload_start + per_sector];
-            let avail = per_sector as u64 - cur_in_sector_off;
-            let take = avail.min(want);
+        if args.strict {
+            match ambiguous.len() {
+                0 => info!("--strict: all {} sources detected unambiguously", sources),
+                n => warn!(
+                    "--strict: hid {} of {} sources detected on a guess: {:?}",
+                    n, sources, ambiguous
+                ),
+            }
+        }
 
-            out.extend_from_slice(
-                &payload[cur_in_sector_off as usize..(cur_in_sector_off + take) as usize],
+        if let Some(file) = &args.export_index {
+            let sources = records.len();
+            Snapshot::new(ctx.fingerprint(), records).save(file)?;
+            info!(
+                "exported the probe results of {} sources to {:?}",
+                sources, file
             );
+        }
+
+        if args.expose_sources {
+            let mut seen = HashSet::new();
+            let sources: Vec<IndexEntry> = tmp
+                .iter()
+                .filter(|e| seen.insert(e.chd_path.clone()))
+                .filter_map(|e| match SourceFileProvider::new(&e.chd_path) {
+                    Ok(p) => Some(IndexEntry {
+                        dir: SOURCES_DIR.to_string(),
+                        name: e.chd_path.file_name()?.to_string_lossy().into_owned(),
+                        provider: Arc::new(p),
+                        sha1: None,
+                        layer_break: None,
+                        original_name: None,
+                        label: None,
+                        ..e.clone()
+                    }),
+                    Err(err) => {
+                        error!("Not exposing source: {:#}", err);
+                        None
+                    }
+                })
+                .collect();
 
-            want -= take;
-            cur_iso_sector += 1;
-            cur_in_sector_off = 0;
+            tmp.extend(sources);
         }
 
-        reply.data(&out);
-    }
+        tmp.sort_by_key(|a| a.name.to_lowercase());
 
-    fn get_cd_frame(&self, file_id: u64, path: &Path, frame_index: u64) -> Result<Vec<u8>> {
-        {
-            let mut cache = self.frame_cache.lock().expect("frame_cache mutex poisoned");
-            if let Some(buf) = cache.get(&(file_id, frame_index)) {
-                return Ok(buf.clone());
-            }
+        if args.dedupe {
+            move_duplicates(&mut tmp);
         }
 
-        let f = File::open(path)?;
-        let mut chd = Chd::open(BufReader::new(f), None)?;
+        if let Some(format) = args.compressed_view {
+            let views: Vec<IndexEntry> = tmp
+                .iter()
+                .filter(|e| e.provider.kind() == ImageKind::Dvd)
+                .map(|e| IndexEntry {
+                    name: cso_view_name(&e.name, format),
+                    provider: Arc::new(CsoViewProvider::new(Arc::clone(&e.provider), format)),
+                    ..e.clone()
+                })
+                .collect();
+
+            tmp.extend(views);
+            tmp.sort_by_key(|a| a.name.to_lowercase());
+        }
 
-        let hunk_bytes = chd.header().hunk_size() as usize;
-        let frames_per_hunk = hunk_bytes / CD_FRAME_2352;
+        disambiguate_names(&mut tmp);
 
-        if frames_per_hunk == 0 {
-            return Err(anyhow!("invalid hunk size for CD"));
+        if let Some(which) = args.checksum_files {
+            let sums = checksum_entries(which, &tmp);
+            tmp.extend(sums);
         }
 
-        let hunk_index = (frame_index as usize) / frames_per_hunk;
-        let frame_in_hunk = (frame_index as usize) % frames_per_hunk;
+        if let Some(dir) = &args.sidecar_dir {
+            let sidecars = sidecar_entries(dir, &tmp);
+            tmp.extend(sidecars);
+        }
 
-        let mut hunk_buf = chd.get_hunksized_buffer();
-        let mut cmp_buf = Vec::new();
+        let mut links = match args.layout {
+            Layout::Sha1 => content_address(&mut tmp),
+            _ => Vec::new(),
+        };
 
-        let mut hk = chd.hunk(hunk_index as u32)?;
-        hk.read_hunk_in(&mut cmp_buf, &mut hunk_buf)?;
+        let mut source_bytes: HashMap<PathBuf, u64> = HashMap::new();
+        for e in tmp.iter().filter(|e| !is_synthetic(&*e.provider)) {
+            *source_bytes.entry(e.chd_path.clone()).or_default() += e.provider.size();
+        }
+        for e in tmp.iter_mut() {
+            e.source_bytes = source_bytes.get(&e.chd_path).copied().unwrap_or(0);
+        }
 
-        let frame_off = frame_in_hunk * CD_FRAME_2352;
-        let owned = hunk_buf[frame_off..frame_off + CD_FRAME_2352].to_vec();
+        self.attrs.reset(tmp.iter().map(|e| e.chd_path.as_path()));
+        self.watch_source_dirs(&tmp);
+
+        let mut index = self.index.write().expect("index lock poisoned");
+        let mut next_ino = self.next_ino.lock().expect("next_ino mutex poisoned");
+
+        let prev: HashMap<String, u64> =
+            index.dirs.iter().map(|d| (d.path.clone(), d.ino)).collect();
+        let prev_entries: HashMap<String, u64> = index
+            .entries
+            .iter()
+            .map(|e| (virtual_path(&e.dir, &e.name), e.ino))
+            .chain(
+                index
+                    .links
+                    .iter()
+                    .map(|l| (virtual_path(&l.dir, &l.name), l.ino)),
+            )
+            .collect();
+
+        let mut alloc = || {
+            let ino = *next_ino;
+            *next_ino += 1;
+            ino
+        };
 
-        {
-            let mut cache = self.frame_cache.lock().expect("frame_cache mutex poisoned");
-            let mut approx_cache_bytes = self
-                .approx_cache_bytes
-                .lock()
-                .expect("approx_cache_bytes mutex poisoned");
-
-            *approx_cache_bytes += owned.len();
-
-            while *approx_cache_bytes > self.args.cache_bytes {
-                if let Some((_k, v)) = cache.pop_lru() {
-                    *approx_cache_bytes = approx_cache_bytes.saturating_sub(v.len());
-                } else {
-                    break;
+        let mut dirs: Vec<VirtualDir> = Vec::new();
+        let mut dir_paths: Vec<&str> = tmp
+            .iter()
+            .map(|e| e.dir.as_str())
+            .chain(links.iter().map(|l| l.dir.as_str()))
+            .filter(|d| !d.is_empty())
+            .collect();
+        dir_paths.sort_unstable();
+        dir_paths.dedup();
+
+        for path in dir_paths {
+            // Materialize every ancestor too, parents first.
+            let mut parent = 1;
+            let mut cur = String::new();
+            for comp in path.split('/') {
+                cur = virtual_path(&cur, comp);
+                if let Some(d) = dirs.iter().find(|d| d.path == cur) {
+                    parent = d.ino;
+                    continue;
                 }
-            }
 
-            cache.put((file_id, frame_index), owned.clone());
+                let ino = prev.get(&cur).copied().unwrap_or_else(&mut alloc);
+                dirs.push(VirtualDir {
+                    ino,
+                    parent,
+                    path: cur.clone(),
+                    name: comp.to_string(),
+                });
+                parent = ino;
+            }
         }
 
-        Ok(owned)
-    }
-}
-
-/// Parse CD TOC from CHD metadata (CHTR/CHT2). Returns (first_data_lba, payload_kind, frames_in_track).
-fn parse_cd_toc_from_metadata<R: Read + Seek>(
-    chd: &mut Chd<R>,
-    file: &mut R,
-    allow_form2: bool,
-) -> Result<Option<(u64, CdPayloadKind, Option<u64>)>> {
-    let mut tracks: Vec<TrackInfo> = Vec::new();
-
-    let it = chd.metadata_refs();
-    for mref in it {
-        let md: Metadata = mref.read(file)?;
-        let tag = md.metatag;
+        for e in tmp.iter_mut() {
+            let vpath = virtual_path(&e.dir, &e.name);
+            e.parent = dirs.iter().find(|d| d.path == e.dir).map_or(1, |d| d.ino);
 
-        if tag != KnownMetadata::CdRomTrack.metatag() && tag != KnownMetadata::CdRomTrack2.metatag()
-        {
-            continue;
+            e.ino = prev_entries.get(&vpath).copied().unwrap_or_else(&mut alloc);
+        }
+        for l in links.iter_mut() {
+            let vpath = virtual_path(&l.dir, &l.name);
+            l.parent = dirs.iter().find(|d| d.path == l.dir).map_or(1, |d| d.ino);
+            l.ino = prev_entries.get(&vpath).copied().unwrap_or_else(&mut alloc);
         }
 
-        let s = String::from_utf8_lossy(&md.value).to_string();
-        if let Some(ti) = parse_track_line(&s) {
-            tracks.push(ti);
+        let new = Arc::new(Index {
+            dirs,
+            entries: tmp,
+            links,
+        });
+        let old = std::mem::replace(&mut *index, Arc::clone(&new));
+        drop(next_ino);
+        drop(index);
+
+        self.invalidate_changes(&old, &new);
+        if let Some(dir) = &args.source_dir {
+            self.media.indexed(dir);
         }
-    }
 
-    if tracks.is_empty() {
-        return Ok(None);
+        Ok(())
     }
 
-    tracks.sort_by_key(|t| t.number);
+    /// Tell the kernel about entries that disappeared, appeared or now have
+    /// different content, so cached dentries, attributes and pages are
+    /// dropped right away instead of after TTL expiry.
+    fn invalidate_changes(&self, old: &Index, new: &Index) {
+        let notifier = self.notifier.lock().expect("notifier mutex poisoned");
+        let Some(notifier) = notifier.as_ref() else {
+            return;
+        };
 
-    let mut lba: u64 = 0;
-    for t in &tracks {
-        lba += t.pregap as u64;
+        let new_entries: HashMap<(u64, &str), &IndexEntry> = new
+            .entries
+            .iter()
+            .map(|e| ((e.parent, e.name.as_str()), e))
+            .collect();
+        let old_entries: HashSet<(u64, &str)> = old
+            .entries
+            .iter()
+            .map(|e| (e.parent, e.name.as_str()))
+            .collect();
+
+        let mut dirty_dirs = HashSet::new();
+        let mut inval_entry = |parent: u64, name: &str| {
+            dirty_dirs.insert(parent);
+            if let Err(e) = notifier.inval_entry(INodeNo(parent), OsStr::new(name)) {
+                debug!("inval_entry {}/{}: {}", parent, name, e);
+            }
+        };
 
-        let payload = match t.kind {
-            TrackKind::Audio => None,
-            TrackKind::Mode1 => Some(CdPayloadKind::Mode1_2048),
-            TrackKind::Mode2Form1 => Some(CdPayloadKind::Mode2Form1_2048),
-            TrackKind::Mode2Form2 => {
-                if allow_form2 {
-                    Some(CdPayloadKind::Mode2Form2_2324)
-                } else {
-                    None
+        for e in &old.entries {
+            match new_entries.get(&(e.parent, e.name.as_str())) {
+                None => inval_entry(e.parent, &e.name),
+                Some(n) if content_changed(e, n) => {
+                    if let Err(err) = notifier.inval_inode(INodeNo(n.ino), 0, 0) {
+                        debug!("inval_inode {}: {}", n.ino, err);
+                    }
                 }
+                Some(_) => {}
             }
-            TrackKind::Mode2Raw => None,
-        };
+        }
 
-        if let Some(pk) = payload {
-            let frames_in_track = t.frames as u64;
-            return Ok(Some((lba, pk, Some(frames_in_track))));
+        // New names may be cached as negative dentries.
+        for e in &new.entries {
+            if !old_entries.contains(&(e.parent, e.name.as_str())) {
+                inval_entry(e.parent, &e.name);
+            }
         }
 
-        lba += t.frames as u64;
-        lba += t.postgap as u64;
-    }
+        for d in &old.dirs {
+            if new.dir(d.ino).is_none() {
+                inval_entry(d.parent, &d.name);
+            }
+        }
 
-    Ok(None)
-}
+        let old_links: HashSet<(u64, &str)> = old
+            .links
+            .iter()
+            .map(|l| (l.parent, l.name.as_str()))
+            .collect();
+        let new_links: HashMap<(u64, &str), &str> = new
+            .links
+            .iter()
+            .map(|l| ((l.parent, l.name.as_str()), l.target.as_str()))
+            .collect();
+        for l in &old.links {
+            if new_links.get(&(l.parent, l.name.as_str())) != Some(&l.target.as_str()) {
+                inval_entry(l.parent, &l.name);
+            }
+        }
+        for l in &new.links {
+            if !old_links.contains(&(l.parent, l.name.as_str())) {
+                inval_entry(l.parent, &l.name);
+            }
+        }
 
-#[derive(Debug, Clone)]
-struct TrackInfo {
-    number: u32,
-    kind: TrackKind,
-    frames: u32,
-    pregap: u32,
-    postgap: u32,
-}
+        for ino in dirty_dirs {
+            if let Err(e) = notifier.inval_inode(INodeNo(ino), 0, 0) {
+                debug!("inval_inode {}: {}", ino, e);
+            }
+        }
+    }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-enum TrackKind {
-    Audio,
-    Mode1,
-    Mode2Form1,
-    Mode2Form2,
-    Mode2Raw,
-}
+    /// Counters for D-Bus `Stats` and the control socket; the frame cache
+    /// figures are process-wide.
+    fn stats(&self) -> BTreeMap<String, u64> {
+        let (cache_entries, cache_bytes) = self.frame_cache.usage();
+        let (hot_titles, hot_bytes) = self.hot.usage();
+        let mut stats = BTreeMap::from([
+            ("entries".into(), self.index().entries.len() as u64),
+            ("open_handles".into(), self.handles.open_count() as u64),
+            ("reads".into(), self.reads.load(Ordering::Relaxed)),
+            ("bytes_read".into(), self.bytes_read.load(Ordering::Relaxed)),
+            ("cache_entries".into(), cache_entries as u64),
+            ("cache_bytes".into(), cache_bytes as u64),
+            (
+                "degraded_sources".into(),
+                self.errors.degraded().len() as u64,
+            ),
+            ("largest_read".into(), self.health.largest_read()),
+            ("hot_titles".into(), hot_titles as u64),
+            ("hot_bytes".into(), hot_bytes),
+            ("index_bytes".into(), self.index_bytes()),
+            (
+                "readahead_bytes".into(),
+                self.handles.readahead_bytes() as u64,
+            ),
+            ("pinned_bytes".into(), provider::pinned_bytes() as u64),
+            (
+                "hunk_buffer_bytes".into(),
+                chd_image::spare_hunk_bytes() as u64,
+            ),
+            ("memory_capped".into(), memory::capped().into()),
+        ]);
+        if let Some((interactive, bulk)) = fair::waiting() {
+            stats.insert("queued_interactive".into(), interactive as u64);
+            stats.insert("queued_bulk".into(), bulk as u64);
+        }
+        if let Some((hits, stores)) = shared_cache::counts() {
+            stats.insert("shared_cache_hits".into(), hits);
+            stats.insert("shared_cache_stores".into(), stores);
+        }
+        if let Some(rss) = memory::rss() {
+            stats.insert("rss_bytes".into(), rss);
+        }
+        if let Some(session) = self.health.session() {
+            stats.insert("max_background".into(), session.max_background.into());
+            stats.insert(
+                "congestion_threshold".into(),
+                session.congestion_threshold.into(),
+            );
+        }
+        for (name, p) in self.jobs.progress() {
+            stats.insert(
+                format!("job_{name}_done"),
+                p.done.load(Ordering::Relaxed) as u64,
+            );
+            stats.insert(
+                format!("job_{name}_failed"),
+                p.failed.load(Ordering::Relaxed) as u64,
+            );
+            stats.insert(format!("job_{name}_total"), p.total as u64);
+        }
+        stats
+    }
 
-#[cfg(feature = "doccheck")]
-fn dump_all_flags_and_exit() -> ! {
-    use clap::CommandFactory;
-    use std::process;
+    fn entry_by_ino(&self, ino: u64) -> Option<IndexEntry> {
+        self.index().entry(ino).cloned()
+    }
 
-    let cmd = <Args as CommandFactory>::command();
-    let mut flags: Vec<String> = Vec::new();
+    /// Attributes of directory `ino`: the current time, unless
+    /// `--reproducible` fixes it.
+    fn dir_attr(&self, ino: u64) -> FileAttr {
+        let mut attr = dir_attr(ino);
+        let args = self.args();
+        if let Some(t) = args.mtime_policy().dir_time().filter(|_| args.reproducible) {
+            (attr.atime, attr.mtime, attr.ctime) = (t, t, t);
+        }
+        attr
+    }
 
-    for arg in cmd.get_arguments() {
-        if let Some(long) = arg.get_long() {
-            flags.push(format!("--{}", long));
+    /// Attributes of symlink `l`: those of a directory, but a link.
+    fn link_attr(&self, l: &VirtualLink) -> FileAttr {
+        FileAttr {
+            size: l.target.len() as u64,
+            blocks: 0,
+            kind: FileType::Symlink,
+            perm: 0o777,
+            nlink: 1,
+            ..self.dir_attr(l.ino)
         }
     }
 
-    flags.sort();
-    flags.dedup();
+    /// Target of symlink `ino`.
+    fn readlink(&self, ino: u64) -> Result<String, i32> {
+        self.online()?;
+        self.index()
+            .link(ino)
+            .map(|l| l.target.clone())
+            .ok_or(libc::EINVAL)
+    }
 
-    for f in flags {
-        println!("{f}");
+    fn file_attr(&self, e: &IndexEntry) -> FileAttr {
+        let args = self.args();
+        // --smb-mode: stat each source once, then only on inotify changes.
+        let refresh = Duration::from_secs(if args.smb_mode { 0 } else { args.attr_refresh });
+        let mut attr = file_attr_for(e, self.attrs.get(&e.chd_path, refresh), &args);
+        if args.quarantine == Quarantine::Empty && self.errors.is_degraded(&e.chd_path) {
+            attr.size = 0;
+            attr.blocks = 0;
+        }
+        attr
     }
 
-    process::exit(0);
-}
+    /// `--warmup`: decode the first `WARMUP_BYTES` of each image in the
+    /// background until the time budget runs out, so the frame cache (and
+    /// with `--pin-mib` the pinned heads) is warm for the first scan.
+    fn warmup(self: &Arc<Self>) {
+        let budget = Duration::from_secs(self.args().warmup);
+        if budget.is_zero() {
+            return;
+        }
 
-fn parse_track_line(s: &str) -> Option<TrackInfo> {
-    let mut number = None;
-    let mut frames = 0u32;
-    let mut pregap = 0u32;
-    let mut postgap = 0u32;
-    let mut kind = None::<TrackKind>;
+        let deadline = Instant::now() + budget;
+        self.spawn_job("warmup", self.images(), move |e, pace| {
+            let end = e.provider.size().min(WARMUP_BYTES);
+            let mut buf = vec![0u8; 256 << 10];
+            let mut pos = 0;
+            while pos < end {
+                if Instant::now() >= deadline {
+                    return Ok(false);
+                }
+                pace(buf.len());
+                match e.provider.read_at(pos, &mut buf) {
+                    Ok(0) => break,
+                    Ok(n) => pos += n as u64,
+                    Err(err) => return Err(err.context(format!("warming {:?}", e.name))),
+                }
+            }
+            Ok(true)
+        });
+    }
 
-    for tok in s.split(|c: char| c.is_whitespace() || c == ',') {
-        if tok.is_empty() {
-            continue;
+    /// `--hot`: pin the titles given and start the thread that loads them,
+    /// and pinned titles whose source changed, after every index build.
+    fn start_hot(self: &Arc<Self>) {
+        for path in &self.args().hot {
+            if let Err(e) = self.pin_title(path) {
+                warn!("Not keeping {:?} hot: {:#}", path, e);
+            }
         }
 
-        if let Some((k, v)) = tok.split_once(':') {
-            match k {
-                "TRACK" => number = v.parse().ok(),
-                "FRAMES" => frames = v.parse().unwrap_or(0),
-                "PREGAP" => pregap = v.parse().unwrap_or(0),
-                "POSTGAP" => postgap = v.parse().unwrap_or(0),
-                "TYPE" => {
-                    kind = Some(match v {
-                        "MODE1" => TrackKind::Mode1,
-                        "MODE2/2048" | "MODE2_FORM1" => TrackKind::Mode2Form1,
-                        "MODE2/2324" | "MODE2_FORM2" => TrackKind::Mode2Form2,
-                        "MODE2/2352" | "MODE2_RAW" | "CDI/2352" => TrackKind::Mode2Raw,
-                        "AUDIO" => TrackKind::Audio,
-                        other => {
-                            if other.starts_with("MODE2") && other.contains("2048") {
-                                TrackKind::Mode2Form1
-                            } else if other.starts_with("MODE2") && other.contains("2324") {
-                                TrackKind::Mode2Form2
-                            } else {
-                                TrackKind::Audio
-                            }
-                        }
-                    })
+        let (tx, rx) = mpsc::channel();
+        if self.hot_wake.set(tx).is_err() {
+            return;
+        }
+        let fs = Arc::clone(self);
+        let spawned = thread::Builder::new().name("hot".into()).spawn(move || {
+            sched::background_thread();
+            while rx.recv().is_ok() {
+                while rx.try_recv().is_ok() {}
+                let pending = fs.hot.refresh(|path| {
+                    let e = fs.find_entry(path)?;
+                    Some((e.ino, e.provider, e.chd_path))
+                });
+                for title in pending {
+                    fs.load_hot(title);
                 }
-                _ => {}
             }
+        });
+        match spawned {
+            Ok(_) => self.wake_hot(),
+            Err(e) => warn!("hot loader thread: {}", e),
         }
     }
 
-    Some(TrackInfo {
-        number: number?,
-        kind: kind?,
-        frames,
-        pregap,
-        postgap,
-    })
-}
-
-/// Fallback when metadata is missing: scan early frames to find a data sector.
-fn quick_scan_first_data<R: Read + Seek>(
-    chd: &mut Chd<R>,
-    total_frames: u64,
-    allow_form2: bool,
-) -> Result<(u64, CdPayloadKind)> {
-    let scan_limit = total_frames.min(2000);
-    let mut cmp = Vec::new();
-    let mut hbuf = chd.get_hunksized_buffer();
-    let frames_per_hunk = (chd.header().hunk_size() as usize) / CD_FRAME_2352;
-
-    let mut frame: u64 = 0;
-    while frame < scan_limit {
-        let hunk_index = (frame as usize) / frames_per_hunk;
-        let frame_in_hunk = (frame as usize) % frames_per_hunk;
-
-        let mut hk = chd.hunk(hunk_index as u32)?;
-        hk.read_hunk_in(&mut cmp, &mut hbuf)?;
-
-        let base = frame_in_hunk * CD_FRAME_2352;
-        let sec = &hbuf[base..base + CD_FRAME_2352];
+    fn wake_hot(&self) {
+        if let Some(tx) = self.hot_wake.get() {
+            let _ = tx.send(());
+        }
+    }
 
-        let mode = sec[0x0F];
+    /// Keep the title at `path` in memory (`--hot`, control socket `pin`).
+    /// False if it already was.
+    fn pin_title(&self, path: &str) -> Result<bool> {
+        let e = self
+            .find_entry(path)
+            .ok_or_else(|| anyhow!("no entry {path:?}"))?;
+        let budget = self.args().hot_mib.saturating_mul(1 << 20);
+        let pinned = self.hot.pin(
+            &virtual_path(&e.dir, &e.name),
+            e.ino,
+            e.provider.size(),
+            budget,
+        )?;
+        if pinned {
+            info!("{:?}: keeping {:?} hot", self.args().mountpoint(), path);
+            self.wake_hot();
+        }
+        Ok(pinned)
+    }
 
-        if mode == 0x01 {
-            return Ok((frame, CdPayloadKind::Mode1_2048));
-        } else if mode == 0x02 {
-            if allow_form2 {
-                return Ok((frame, CdPayloadKind::Mode2Form2_2324));
-            } else {
-                return Ok((frame, CdPayloadKind::Mode2Form1_2048));
+    /// Decode a pinned title into memory at the pace of `--maintenance`.
+    fn load_hot(&self, (ino, path, provider, source): hot::Pending) {
+        let args = self.args();
+        let budget = jobs::Budget {
+            workers: 1,
+            throughput: TokenBucket::from_mbps(args.maintenance_throughput),
+        };
+        let start = Instant::now();
+        let res = (|| {
+            let size = usize::try_from(provider.size())?;
+            let mut data = vec![0u8; size];
+            let mut pos = 0;
+            while pos < size {
+                let end = size.min(pos + (1 << 20));
+                self.jobs.pace(&budget, end - pos);
+                match provider.read_at(pos as u64, &mut data[pos..end])? {
+                    0 => return Err(anyhow!("{path:?} ended at {pos} of {size} bytes")),
+                    n => pos += n,
+                }
+            }
+            Ok::<_, anyhow::Error>(data)
+        })();
+        match res {
+            Ok(data) => {
+                info!(
+                    "{:?}: {:?} is hot ({} MiB in {:.1}s)",
+                    args.mountpoint(),
+                    path,
+                    data.len() >> 20,
+                    start.elapsed().as_secs_f64()
+                );
+                self.hot
+                    .loaded(&path, ino, &source, Some((provider.kind(), data)));
+            }
+            Err(e) => {
+                warn!(
+                    "{:?}: loading {:?} failed: {:#}",
+                    args.mountpoint(),
+                    path,
+                    e
+                );
+                self.hot.loaded(&path, ino, &source, None);
             }
         }
-
-        frame += 1;
     }
 
-    Ok((0, CdPayloadKind::Mode1_2048))
-}
-
-impl Filesystem for FsState {
-    fn lookup(&self, _req: &Request, _parent: INodeNo, name: &OsStr, reply: ReplyEntry) {
-        let name_str = name.to_string_lossy().to_string();
+    /// The entry at virtual path `path` (leading and trailing `/` ignored).
+    fn find_entry(&self, path: &str) -> Option<IndexEntry> {
+        let path = path.trim_matches('/');
+        self.index()
+            .entries
+            .iter()
+            .find(|e| virtual_path(&e.dir, &e.name) == path)
+            .cloned()
+    }
 
-        if let Some(e) = self.entries.iter().find(|e| e.name == name_str) {
-            let attr = file_attr_for(e).unwrap_or_else(|_| default_file_attr(e));
-            reply.entry(&TTL, &attr, Generation(0));
-        } else {
-            reply.error(Errno::from_i32(libc::ENOENT));
+    /// `--maintenance`: start each job over the current index.
+    fn maintain(self: &Arc<Self>) {
+        let args = self.args();
+        for task in &args.maintenance {
+            match task {
+                Task::Hash => {
+                    if args.checksum_files.is_none() {
+                        warn!("--maintenance hash does nothing without --checksum-files");
+                        continue;
+                    }
+                    let sums = self
+                        .index()
+                        .entries
+                        .iter()
+                        .filter(|e| e.provider.kind() == ImageKind::Sheet)
+                        .cloned()
+                        .collect();
+                    self.spawn_job(task.name(), sums, |e, pace| {
+                        e.provider
+                            .prepare(pace)
+                            .with_context(|| format!("hashing for {:?}", e.name))
+                    });
+                }
+                Task::Verify => {
+                    let mut chds: Vec<PathBuf> = self
+                        .images()
+                        .into_iter()
+                        .map(|e| e.chd_path)
+                        .filter(|p| p.extension().is_some_and(|x| x.eq_ignore_ascii_case("chd")))
+                        .collect();
+                    chds.sort();
+                    chds.dedup();
+                    self.spawn_job(task.name(), chds, |path, pace| {
+                        match check::verify(path, &|_, _, size| pace(size))
+                            .with_context(|| format!("{path:?}"))?
+                        {
+                            check::Outcome::Verified => Ok(true),
+                            check::Outcome::NoDigest => Ok(false),
+                        }
+                    });
+                }
+            }
         }
     }
 
-    fn getattr(&self, _req: &Request, ino: INodeNo, fh: Option<FileHandle>, reply: ReplyAttr) {
-        let _ = fh;
+    /// Images of the current index, without `.sources` and `.duplicates`.
+    fn images(&self) -> Vec<IndexEntry> {
+        self.index()
+            .entries
+            .iter()
+            .filter(|e| {
+                e.dir != SOURCES_DIR && e.dir != DUPLICATES_DIR && is_image(e.provider.kind())
+            })
+            .cloned()
+            .collect()
+    }
 
-        if ino.0 == 1 {
-            let attr = FileAttr {
-                ino: INodeNo(1),
-                size: 0,
-                blocks: 1,
-                atime: SystemTime::now(),
-                mtime: SystemTime::now(),
-                ctime: SystemTime::now(),
-                crtime: SystemTime::UNIX_EPOCH,
-                kind: FileType::Directory,
-                perm: 0o755,
-                nlink: 2,
-                uid: unsafe { libc::geteuid() },
-                gid: unsafe { libc::getegid() },
-                rdev: 0,
-                flags: 0,
-                blksize: 4096,
-            };
+    /// Run a background job with the mount's `--maintenance-*` budget.
+    fn spawn_job<T, F>(&self, name: &'static str, items: Vec<T>, work: F)
+    where
+        T: Send + Sync + 'static,
+        F: Fn(&T, &dyn Fn(usize)) -> Result<bool> + Send + Sync + 'static,
+    {
+        let args = self.args();
+        let budget = jobs::Budget {
+            workers: args.maintenance_workers,
+            throughput: TokenBucket::from_mbps(args.maintenance_throughput),
+        };
+        let label = format!("{:?}", args.mountpoint());
+        self.jobs.spawn(label, name, items, budget, work);
+    }
 
-            reply.attr(&TTL, &attr);
-            return;
+    /// Start an inotify thread that marks cached source attributes stale as
+    /// soon as a source directory changes. Failure only costs freshness.
+    fn watch_sources(self: &Arc<Self>) {
+        let watcher = match Watcher::new() {
+            Ok(w) => w,
+            Err(e) => {
+                warn!(
+                    "inotify unavailable, attributes refresh on re-index only: {}",
+                    e
+                );
+                return;
+            }
+        };
+        if self.watcher.set(watcher).is_err() {
+            return;
+        }
+        self.watch_source_dirs(&self.index().entries);
+
+        let fs = Arc::clone(self);
+        let spawned = thread::Builder::new()
+            .name("attr-watch".into())
+            .spawn(move || {
+                let watcher = fs.watcher.get().expect("set above");
+                let res = watcher.run(|change| match change {
+                    Change::File(path) => fs.attrs.invalidate(&path),
+                    Change::Overflow => fs.attrs.invalidate_all(),
+                    Change::Unmount => {
+                        fs.source_lost();
+                    }
+                });
+                if let Err(e) = res {
+                    warn!("inotify watch stopped: {}", e);
+                }
+            });
+        if let Err(e) = spawned {
+            warn!("inotify thread: {}", e);
+        }
+    }
+
+    fn watch_source_dirs(&self, entries: &[IndexEntry]) {
+        let Some(watcher) = self.watcher.get() else {
+            return;
+        };
+
+        let dirs: HashSet<&Path> = entries.iter().filter_map(|e| e.chd_path.parent()).collect();
+        for dir in dirs {
+            if let Err(e) = watcher.add(dir) {
+                debug!("inotify watch on {:?}: {}", dir, e);
+            }
+        }
+    }
+
+    /// With `--pin-mib`, keep the head of `provider` in memory.
+    fn pin(&self, provider: Arc<dyn BackingProvider>, args: &Args) -> Arc<dyn BackingProvider> {
+        if args.pin_mib == 0 {
+            return provider;
+        }
+        Arc::new(PinnedProvider::new(
+            provider,
+            args.pin_mib.saturating_mul(1 << 20),
+        ))
+    }
+
+    /// Probe `path`: the main file first, then its extras.
+    fn probe_source(
+        &self,
+        args: &Args,
+        path: &Path,
+        ctx: &ProbeContext,
+    ) -> Result<Option<Vec<Found>>> {
+        if args.probe_isolation > 0 {
+            let timeout = Duration::from_secs(args.probe_isolation);
+            self.probe_verdicts
+                .check(path, args.mountpoint.as_deref(), timeout)?;
+        }
+        let Some(p) = self.registry.probe(path, ctx)? else {
+            return Ok(None);
+        };
+
+        let provider = self.pin(p.provider, args);
+        let layer_break = match provider.kind() {
+            ImageKind::Dvd | ImageKind::Decoded => iso::layer_break(&*provider),
+            _ => None,
+        };
+        let serial = match provider.kind() {
+            ImageKind::Dvd | ImageKind::Decoded | ImageKind::Cd if ctx.serials => {
+                iso::serial(&*provider)
+            }
+            _ => None,
+        };
+        let label = match provider.kind() {
+            ImageKind::Dvd | ImageKind::Decoded | ImageKind::Cd => iso::volume_label(&*provider),
+            _ => None,
+        };
+        let console = match provider.kind() {
+            ImageKind::Dvd | ImageKind::Decoded | ImageKind::Cd if ctx.consoles => {
+                iso::console(&*provider)
+            }
+            _ => None,
+        };
+
+        let mut record = EntryRecord::new(&p.name, &*provider, p.sha1, layer_break, serial, label);
+        record.console = console;
+
+        let mut out = vec![(record, provider)];
+        for (name, provider) in p.extras {
+            out.push((
+                EntryRecord::new(&name, &*provider, None, None, None, None),
+                provider,
+            ));
+        }
+        Ok(Some(out))
+    }
+
+    /// Entries of `path` as `--import-index` recorded them; the source is
+    /// probed on first read.
+    fn import_source(&self, args: &Arc<Args>, path: &Path, record: SourceRecord) -> Vec<Found> {
+        let probe = {
+            let args = Arc::clone(args);
+            let frame_cache = Arc::clone(&self.frame_cache);
+            let path = path.to_path_buf();
+            Box::new(move || {
+                Registry::with_builtin().probe(&path, &probe_context(&args, &frame_cache))
+            })
+        };
+        let source = DeferredSource::new(path.to_path_buf(), probe);
+
+        record
+            .entries
+            .into_iter()
+            .enumerate()
+            .map(|(i, e)| {
+                let provider: Arc<dyn BackingProvider> =
+                    Arc::new(DeferredProvider::new(&source, &e));
+                let provider = match i {
+                    0 => self.pin(provider, args),
+                    _ => provider,
+                };
+                (e, provider)
+            })
+            .collect()
+    }
+
+    /// Collect candidate `*.chd` paths from the source directory, applying the
+    /// hidden-file and symlink policies.
+    fn scan_source_dir(&self) -> Result<Vec<PathBuf>> {
+        let args = self.args();
+        let Some(dir) = &args.source_dir else {
+            return Ok(Vec::new());
+        };
+        let wanted = |path: &Path| self.registry.matches(path);
+        vfs::list(
+            dir,
+            &ScanOptions {
+                follow_symlinks: args.follow_symlinks,
+                skip_hidden: args.skip_hidden,
+                wanted: &wanted,
+                exclude: self.nested.get().map_or(&[], Vec::as_slice),
+            },
+        )
+    }
+
+    /// Register a new open handle on `e` for process `pid` of user `uid`
+    /// and return its number.
+    fn open_handle(&self, e: &IndexEntry, flags: i32, uid: u32, pid: u32) -> u64 {
+        let throttle = TokenBucket::from_mbps(self.args().max_handle_throughput).map(Arc::new);
+        let mut handle = Handle::new(e.ino, flags, e.chd_path.clone(), throttle)
+            .dropping_source_cache(self.args().drop_source_cache);
+        if self.args().slow_read_ms > 0 {
+            let threshold = Duration::from_millis(self.args().slow_read_ms);
+            handle = handle.timing_reads(threshold, Arc::clone(&self.slow_reads));
+        }
+        handle = handle.tracing_reads(Arc::clone(&self.read_trace));
+        if let Some(log) = &self.audit {
+            let reader = audit::Reader::new(uid, pid, virtual_path(&e.dir, &e.name));
+            log.opened(&reader);
+            handle = handle.audited(reader);
+        }
+        self.handles.insert(handle)
+    }
+
+    fn release_handle(&self, fh: u64) {
+        let Some(handle) = self.handles.remove(fh) else {
+            return;
+        };
+        if let (Some(log), Some(reader)) = (&self.audit, &handle.audit) {
+            log.released(reader);
+        }
+    }
+
+    fn source_reachable(&self) -> bool {
+        let args = self.args();
+        if let Some(url) = args.source_dir.as_deref().filter(|p| vfs::is_url(p)) {
+            return vfs::stat(url).is_ok();
+        }
+        match (&args.source_list, &args.source_dir) {
+            (Some(list), _) => list.is_file(),
+            (None, Some(dir)) => !self.media.is_offline() && self.media.present(dir),
+            (None, None) => false,
+        }
+    }
+
+    /// Attributes of `.chd2iso` and its `health` file, with `--health`.
+    fn control_attr(&self, ino: u64) -> Option<FileAttr> {
+        if !self.args().health {
+            return None;
+        }
+        match ino {
+            health::DIR_INO => Some(self.dir_attr(ino)),
+            health::FILE_INO => Some(control_file_attr(ino, self.health_report().len())),
+            _ => None,
+        }
+    }
+
+    fn control_lookup(&self, parent: u64, name: &OsStr) -> Option<FileAttr> {
+        let ino = match (parent, name.to_str()?) {
+            (1, health::DIR_NAME) => health::DIR_INO,
+            (health::DIR_INO, health::FILE_NAME) => health::FILE_INO,
+            _ => return None,
+        };
+        self.control_attr(ino)
+    }
+
+    fn control_children(&self, parent: u64) -> Vec<(u64, FileType, &'static str)> {
+        if !self.args().health {
+            return Vec::new();
+        }
+        match parent {
+            1 => vec![(health::DIR_INO, FileType::Directory, health::DIR_NAME)],
+            health::DIR_INO => vec![(health::FILE_INO, FileType::RegularFile, health::FILE_NAME)],
+            _ => Vec::new(),
+        }
+    }
+
+    fn health_report(&self) -> String {
+        let args = self.args();
+        let source = args
+            .source_list
+            .as_deref()
+            .or(args.source_dir.as_deref())
+            .unwrap_or(Path::new(""));
+        let probe = health::Probe {
+            source,
+            reachable: self.source_reachable(),
+            entries: self.index().entries.len(),
+            open_handles: self.handles.open_count(),
+            degraded: self.errors.degraded(),
+            jobs: self.jobs.progress(),
+            slow_reads: (self.args().slow_read_ms > 0).then_some(&*self.slow_reads),
+        };
+        self.health.report(&probe, SystemTime::now())
+    }
+
+    /// `size` bytes of a fresh health report from `offset`.
+    fn health_read(&self, offset: u64, size: u32) -> Vec<u8> {
+        let report = self.health_report().into_bytes();
+        let start = (offset as usize).min(report.len());
+        let end = start.saturating_add(size as usize).min(report.len());
+        report[start..end].to_vec()
+    }
+
+    /// Attributes of any inode, as getattr reports them.
+    fn attr(&self, ino: u64) -> Result<FileAttr, i32> {
+        if let Some(attr) = self.control_attr(ino) {
+            return Ok(attr);
+        }
+        if Overlay::owns(ino) {
+            return self.overlay_getattr(ino);
+        }
+        if ino == 1 {
+            return Ok(self.dir_attr(ino));
+        }
+        self.online()?;
+        if self.index().is_dir(ino) {
+            return Ok(self.dir_attr(ino));
+        }
+        if let Some(l) = self.index().link(ino) {
+            return Ok(self.link_attr(l));
+        }
+        self.entry_by_ino(ino)
+            .map(|e| self.file_attr(&e))
+            .ok_or(libc::ENOENT)
+    }
+
+    /// How long the kernel may cache the entry and attributes of `ino`:
+    /// `SMB_TTL` with `--smb-mode`, except for the health file and overlay
+    /// files, which change in place.
+    fn ttl(&self, ino: u64) -> Duration {
+        let mutable = Overlay::owns(ino) || ino == health::DIR_INO || ino == health::FILE_INO;
+        if self.args().smb_mode && !mutable {
+            SMB_TTL
+        } else {
+            TTL
+        }
+    }
+
+    /// fuser open flags for an image handle: with `--smb-mode` the kernel
+    /// keeps cached pages across opens, as the data only changes on a
+    /// re-index, which invalidates it.
+    fn open_flags(&self) -> FopenFlags {
+        if self.args().smb_mode {
+            FopenFlags::FOPEN_KEEP_CACHE
+        } else {
+            FopenFlags::empty()
+        }
+    }
+
+    /// Directory `ino` as readdir lists it: ".", ".." and the children.
+    fn dir_listing(&self, ino: u64) -> Result<Vec<(u64, FileType, String)>, i32> {
+        let control = self.control_children(ino);
+        if ino != health::DIR_INO {
+            self.online()?;
+        }
+
+        let index = self.index();
+
+        if !index.is_dir(ino) && ino != health::DIR_INO {
+            return Err(libc::ENOTDIR);
+        }
+
+        let parent = index.dir(ino).map_or(1, |d| d.parent);
+        let overlay = self.overlay_children(&index, ino);
+        let children = index
+            .dirs
+            .iter()
+            .filter(|d| d.parent == ino)
+            .map(|d| (d.ino, FileType::Directory, d.name.as_str()))
+            .chain(
+                index
+                    .links
+                    .iter()
+                    .filter(|l| l.parent == ino)
+                    .map(|l| (l.ino, FileType::Symlink, l.name.as_str())),
+            )
+            .chain(
+                index
+                    .entries
+                    .iter()
+                    .filter(|e| e.parent == ino && self.visible(e))
+                    .map(|e| (e.ino, FileType::RegularFile, e.name.as_str())),
+            )
+            .chain(
+                overlay
+                    .iter()
+                    .map(|(i, n)| (*i, FileType::RegularFile, n.as_str())),
+            )
+            .chain(control.iter().copied());
+
+        Ok([
+            (ino, FileType::Directory, "."),
+            (parent, FileType::Directory, ".."),
+        ]
+        .into_iter()
+        .chain(children)
+        .map(|(i, kind, name)| (i, kind, name.to_string()))
+        .collect())
+    }
+
+    /// `--visible-to`: whether `uid` may list and open the mount's files.
+    /// The user running chd2iso-fuse always may.
+    fn visible_to(&self, uid: u32) -> Result<(), i32> {
+        let args = self.args();
+        if args.visible_to.is_empty()
+            || args.visible_to.contains(&uid)
+            || uid == unsafe { libc::geteuid() }
+        {
+            return Ok(());
+        }
+        Err(libc::EACCES)
+    }
+
+    /// `ENODEV` while the source drive is gone.
+    fn online(&self) -> Result<(), i32> {
+        if self.media.is_offline() {
+            return Err(libc::ENODEV);
+        }
+        Ok(())
+    }
+
+    /// After a source I/O failure: take the mount offline if the drive under
+    /// `--source` is gone and probe until it returns. Returns whether the
+    /// mount is offline.
+    fn source_lost(self: &Arc<Self>) -> bool {
+        let Some(root) = self.args().source_dir.clone() else {
+            return false;
+        };
+        if vfs::is_url(&root) {
+            return false;
+        }
+        if self.media.present(&root) {
+            return self.media.is_offline();
+        }
+        if !self.media.go_offline() {
+            return true;
+        }
+
+        warn!(
+            "{:?} is gone; {:?} is offline until it returns",
+            root,
+            self.args().mountpoint()
+        );
+        let fs = Arc::clone(self);
+        let spawned = thread::Builder::new()
+            .name("media-probe".into())
+            .spawn(move || loop {
+                thread::sleep(media::PROBE_INTERVAL);
+                if !fs.media.present(&root) {
+                    continue;
+                }
+                match fs.build_index() {
+                    Ok(()) => {
+                        fs.media.go_online();
+                        info!(
+                            "{:?} is back; {:?} online (entries: {})",
+                            root,
+                            fs.args().mountpoint(),
+                            fs.index().entries.len()
+                        );
+                        return;
+                    }
+                    Err(e) => debug!("re-index of returning {:?}: {}", root, e),
+                }
+            });
+        if let Err(e) = spawned {
+            warn!("media probe thread: {}", e);
+            self.media.go_online();
+            return false;
+        }
+        true
+    }
+
+    /// `open` found the source at `path` gone for good: unless the whole
+    /// drive went with it, re-index in the background to drop its entries.
+    /// Returns the errno for the open.
+    fn source_gone(self: &Arc<Self>, path: &Path) -> i32 {
+        if self.source_lost() {
+            return libc::ENODEV;
+        }
+        if self.reindexing.swap(true, Ordering::SeqCst) {
+            return libc::ENOENT;
+        }
+
+        info!(
+            "{:?} is gone; re-indexing {:?}",
+            path,
+            self.args().mountpoint()
+        );
+        let fs = Arc::clone(self);
+        let spawned = thread::Builder::new()
+            .name("reindex".into())
+            .spawn(move || {
+                if let Err(e) = fs.build_index() {
+                    warn!("re-index of {:?} failed: {}", fs.args().mountpoint(), e);
+                }
+                fs.reindexing.store(false, Ordering::SeqCst);
+            });
+        if let Err(e) = spawned {
+            warn!("re-index thread: {}", e);
+            self.reindexing.store(false, Ordering::SeqCst);
+        }
+        libc::ENOENT
+    }
+
+    /// unlink/rmdir: `EROFS`, or with `--deny-delete-silently` a success
+    /// that leaves the entry in place.
+    fn deny_delete(&self, parent: u64, name: &OsStr) -> Result<(), i32> {
+        if !self.args().deny_delete_silently {
+            return Err(libc::EROFS);
+        }
+        debug!("ignoring delete of {:?} in directory {}", name, parent);
+        Ok(())
+    }
+
+    /// Mount read-only, unless deletes must reach `deny_delete` or files
+    /// can be written to the overlay.
+    fn read_only_mount(&self) -> bool {
+        !self.args().deny_delete_silently && self.overlay.is_none()
+    }
+
+    /// Virtual path of directory `ino` ("" for the root).
+    fn dir_path(index: &Index, ino: u64) -> Option<String> {
+        if ino == 1 {
+            return Some(String::new());
+        }
+        index.dir(ino).map(|d| d.path.clone())
+    }
+
+    /// On-disk path of overlay file `name` in `parent`, provided the overlay
+    /// may hold it: `EROFS` without an overlay, for an index name and for an
+    /// image name.
+    fn overlay_file(&self, parent: u64, name: &OsStr) -> Result<(&Overlay, PathBuf), i32> {
+        let overlay = self.overlay.as_ref().ok_or(libc::EROFS)?;
+        let index = self.index();
+        let dir = Self::dir_path(&index, parent).ok_or(libc::ENOENT)?;
+        if index.lookup(parent, &name.to_string_lossy()).is_some()
+            || self.control_lookup(parent, name).is_some()
+            || !Overlay::allows(name)
+        {
+            return Err(libc::EROFS);
+        }
+        Ok((overlay, overlay.path_in(&dir, name)))
+    }
+
+    /// On-disk path of overlay inode `ino`.
+    fn overlay_path(&self, ino: u64) -> Result<PathBuf, i32> {
+        let overlay = self.overlay.as_ref().ok_or(libc::ENOENT)?;
+        let (parent, name) = overlay.name_of(ino).ok_or(libc::ENOENT)?;
+        let dir = Self::dir_path(&self.index(), parent).ok_or(libc::ENOENT)?;
+        Ok(overlay.path_in(&dir, &name))
+    }
+
+    /// Lookup fallback for names the index does not have.
+    fn overlay_lookup(&self, parent: u64, name: &OsStr) -> Option<FileAttr> {
+        let (overlay, path) = self.overlay_file(parent, name).ok()?;
+        let meta = path.metadata().ok().filter(|m| m.is_file())?;
+        Some(overlay_attr(overlay.ino(parent, name), &meta))
+    }
+
+    fn overlay_getattr(&self, ino: u64) -> Result<FileAttr, i32> {
+        let meta = self.overlay_path(ino)?.metadata().map_err(errno)?;
+        Ok(overlay_attr(ino, &meta))
+    }
+
+    /// Overlay files of directory `parent` not shadowed by the index, as
+    /// (inode, name).
+    fn overlay_children(&self, index: &Index, parent: u64) -> Vec<(u64, String)> {
+        let (Some(overlay), Some(dir)) = (&self.overlay, Self::dir_path(index, parent)) else {
+            return Vec::new();
+        };
+
+        overlay
+            .list(&dir)
+            .into_iter()
+            .filter(|n| index.lookup(parent, &n.to_string_lossy()).is_none())
+            .map(|n| (overlay.ino(parent, &n), n.to_string_lossy().into_owned()))
+            .collect()
+    }
+
+    fn overlay_open(&self, ino: u64, flags: i32) -> Result<u64, i32> {
+        let path = self.overlay_path(ino)?;
+        let access = flags & libc::O_ACCMODE;
+        let file = fs::OpenOptions::new()
+            .read(access != libc::O_WRONLY)
+            .write(access != libc::O_RDONLY)
+            .truncate(flags & libc::O_TRUNC != 0)
+            .open(&path)
+            .map_err(errno)?;
+        Ok(self
+            .handles
+            .insert(Handle::new(ino, flags, path, None).with_file(file)))
+    }
+
+    /// create(2) in the overlay; returns the new file's attributes and
+    /// handle.
+    fn overlay_create(
+        &self,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        flags: i32,
+    ) -> Result<(FileAttr, u64), i32> {
+        let (overlay, path) = self.overlay_file(parent, name)?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(errno)?;
+        }
+
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(flags & libc::O_TRUNC != 0)
+            .mode(mode & 0o7777)
+            .open(&path)
+            .map_err(errno)?;
+        let meta = file.metadata().map_err(errno)?;
+        let ino = overlay.ino(parent, name);
+        let fh = self
+            .handles
+            .insert(Handle::new(ino, flags, path, None).with_file(file));
+        Ok((overlay_attr(ino, &meta), fh))
+    }
+
+    fn overlay_read(&self, ino: u64, fh: u64, offset: u64, size: u32) -> Result<Vec<u8>, i32> {
+        let handle = self.handles.get(fh, ino)?;
+        let file = handle.file.as_ref().ok_or(libc::EBADF)?;
+        let mut buf = vec![0u8; size as usize];
+        let n = file.read_at(&mut buf, offset).map_err(errno)?;
+        buf.truncate(n);
+        Ok(buf)
+    }
+
+    fn overlay_write(&self, ino: u64, fh: u64, offset: u64, data: &[u8]) -> Result<u32, i32> {
+        let handle = self.handles.get(fh, ino)?;
+        let file = handle.file.as_ref().ok_or(libc::EBADF)?;
+        file.write_all_at(data, offset).map_err(errno)?;
+        Ok(data.len() as u32)
+    }
+
+    /// setattr: only truncating an overlay file is supported; other
+    /// changes to it are accepted and ignored.
+    fn overlay_setattr(&self, ino: u64, size: Option<u64>) -> Result<FileAttr, i32> {
+        if !Overlay::owns(ino) {
+            return Err(libc::EROFS);
+        }
+        if let Some(size) = size {
+            let path = self.overlay_path(ino)?;
+            let file = fs::OpenOptions::new()
+                .write(true)
+                .open(path)
+                .map_err(errno)?;
+            file.set_len(size).map_err(errno)?;
+        }
+        self.overlay_getattr(ino)
+    }
+
+    /// unlink: overlay files are removed, anything else goes to
+    /// `deny_delete`.
+    fn unlink_file(&self, parent: u64, name: &OsStr) -> Result<(), i32> {
+        if let Ok((overlay, path)) = self.overlay_file(parent, name) {
+            if path.is_file() {
+                fs::remove_file(&path).map_err(errno)?;
+                overlay.forget(parent, name);
+                return Ok(());
+            }
+        }
+        self.deny_delete(parent, name)
+    }
+
+    /// rename within the overlay; index entries cannot be renamed.
+    fn overlay_rename(&self, from: (u64, &OsStr), to: (u64, &OsStr)) -> Result<(), i32> {
+        let (overlay, src) = self.overlay_file(from.0, from.1)?;
+        let (_, dst) = self.overlay_file(to.0, to.1)?;
+        if !src.is_file() {
+            return Err(libc::ENOENT);
+        }
+        if let Some(dir) = dst.parent() {
+            fs::create_dir_all(dir).map_err(errno)?;
+        }
+
+        fs::rename(&src, &dst).map_err(errno)?;
+        overlay.rename(from, to);
+        Ok(())
+    }
+
+    /// getxattr (`name` given) or listxattr (NUL-terminated names) for
+    /// `ino`, following the xattr size protocol: `size` 0 asks for the length.
+    fn xattr(&self, ino: u64, name: Option<&OsStr>, size: u32) -> Result<Xattr, i32> {
+        self.online()?;
+        let attrs = match self.entry_by_ino(ino) {
+            Some(e) => entry_xattrs(&e),
+            None if self.index().is_dir(ino) => Vec::new(),
+            None => return Err(libc::ENOENT),
+        };
+
+        let value: Vec<u8> = match name {
+            Some(name) => attrs
+                .into_iter()
+                .find(|(n, _)| OsStr::new(n) == name)
+                .ok_or(libc::ENODATA)?
+                .1
+                .into_bytes(),
+            None => attrs
+                .iter()
+                .flat_map(|(n, _)| n.bytes().chain([0]))
+                .collect(),
+        };
+
+        if size == 0 {
+            Ok(Xattr::Size(value.len() as u32))
+        } else if value.len() > size as usize {
+            Err(libc::ERANGE)
+        } else {
+            Ok(Xattr::Data(value))
+        }
+    }
+
+    /// Charge `bytes` against the global and per-handle buckets; returns how
+    /// long to hold the reply.
+    fn throttle_delay(&self, handle: Option<&TokenBucket>, bytes: usize) -> Duration {
+        let global = self
+            .throttle
+            .read()
+            .expect("throttle lock poisoned")
+            .as_ref()
+            .map_or(Duration::ZERO, |b| b.take(bytes));
+        let local = handle.map_or(Duration::ZERO, |b| b.take(bytes));
+        global.max(local)
+    }
+
+    /// Reads of a source degraded by its error budget: `EIO`, or `Ok(true)`
+    /// (serve nothing) under `--quarantine empty`. `Ok(false)`: read it.
+    fn check_quarantine(&self, handle: &Handle) -> Result<bool, i32> {
+        if !self.errors.is_degraded(&handle.chd_path) {
+            return Ok(false);
+        }
+        match self.args().quarantine {
+            Quarantine::Empty => Ok(true),
+            Quarantine::Eio | Quarantine::Hide => Err(libc::EIO),
+        }
+    }
+
+    fn read_failed(self: &Arc<Self>, handle: &Handle, e: &anyhow::Error) {
+        let msg = format!("{e:#} (open flags {:#o})", handle.flags);
+        let args = self.args();
+        // A hung source would tie up a thread per read: degrade it now.
+        let budget = if vfs::take_timed_out(&handle.chd_path) {
+            1
+        } else {
+            args.error_budget
+        };
+        if !self.errors.failed(&handle.chd_path, &msg, budget) || args.quarantine == Quarantine::Eio
+        {
+            return;
+        }
+
+        // Not from the handler: invalidating the inode being read would
+        // wait on the pages this very read holds.
+        let fs = Arc::clone(self);
+        let chd_path = handle.chd_path.clone();
+        let spawned = thread::Builder::new()
+            .name("quarantine".into())
+            .spawn(move || fs.invalidate_source(&chd_path));
+        if let Err(e) = spawned {
+            debug!("quarantine thread: {}", e);
+        }
+    }
+
+    /// Whether `e` is listed and can be looked up: not under
+    /// `--quarantine hide` once its source is degraded.
+    fn visible(&self, e: &IndexEntry) -> bool {
+        self.args().quarantine != Quarantine::Hide || !self.errors.is_degraded(&e.chd_path)
+    }
+
+    /// Make the kernel forget the entries of `chd_path`, which just changed
+    /// size or visibility.
+    fn invalidate_source(&self, chd_path: &Path) {
+        let notifier = self.notifier.lock().expect("notifier mutex poisoned");
+        let Some(notifier) = notifier.as_ref() else {
+            return;
+        };
+
+        for e in self
+            .index()
+            .entries
+            .iter()
+            .filter(|e| e.chd_path == chd_path)
+        {
+            if let Err(err) = notifier.inval_entry(INodeNo(e.parent), OsStr::new(&e.name)) {
+                debug!("inval_entry {}/{}: {}", e.parent, e.name, err);
+            }
+            if let Err(err) = notifier.inval_inode(INodeNo(e.ino), 0, 0) {
+                debug!("inval_inode {}: {}", e.ino, err);
+            }
+        }
+    }
+
+    /// Track the largest read the kernel sends, the session's effective max_read.
+    fn note_read_size(&self, size: u32) {
+        if self.health.read_request(size) {
+            debug!("largest read request so far: {size} bytes");
+        }
+    }
+
+    fn count_read(&self, bytes: usize) {
+        self.jobs.client_read();
+        self.reads.fetch_add(1, Ordering::Relaxed);
+        self.bytes_read.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+}
+
+#[cfg(feature = "doccheck")]
+fn dump_all_flags_and_exit() -> ! {
+    use clap::CommandFactory;
+    use std::process;
+
+    let cmd = <Args as CommandFactory>::command();
+    let mut flags: Vec<String> = Vec::new();
+
+    // Deprecated spellings stay out of --help and the manpage.
+    for arg in cmd.get_arguments().filter(|a| !a.is_hide_set()) {
+        if let Some(long) = arg.get_long() {
+            flags.push(format!("--{}", long));
+        }
+    }
+
+    flags.sort();
+    flags.dedup();
+
+    for f in flags {
+        println!("{f}");
+    }
+
+    process::exit(0);
+}
+
+/// Whether an entry kept under the same name is now backed by different data.
+/// Compressed views are compared by source only; their size is expensive.
+fn content_changed(old: &IndexEntry, new: &IndexEntry) -> bool {
+    old.chd_path != new.chd_path
+        || (new.provider.kind() != ImageKind::View && old.provider.size() != new.provider.size())
+}
+
+/// `Game.iso` -> `Game.zso`
+fn cso_view_name(iso_name: &str, format: CsoFormat) -> String {
+    let stem = iso_name.rsplit_once('.').map_or(iso_name, |(stem, _)| stem);
+    format!("{stem}.{}", format.extension())
+}
+
+fn virtual_path(dir: &str, name: &str) -> String {
+    if dir.is_empty() {
+        name.to_string()
+    } else {
+        format!("{dir}/{name}")
+    }
+}
+
+/// `--layout sha1`: move each image with a SHA1 to `sha1/ab/cdef....ext`
+/// and return symlinks to it from where it was. Images sharing a SHA1 and
+/// extension are stored once.
+fn content_address(entries: &mut Vec<IndexEntry>) -> Vec<VirtualLink> {
+    let mut links = Vec::new();
+    let mut stored = HashSet::new();
+
+    entries.retain_mut(|e| {
+        let Some(sha1) = e.sha1.filter(|_| is_image(e.provider.kind())) else {
+            return true;
+        };
+        let hex = sha1::hex(&sha1);
+        let dir = format!("sha1/{}", &hex[..2]);
+        let name = match e.name.rsplit_once('.') {
+            Some((_, ext)) => format!("{}.{ext}", &hex[2..]),
+            None => hex[2..].to_string(),
+        };
+        let up = "../".repeat(e.dir.split('/').filter(|c| !c.is_empty()).count());
+        links.push(VirtualLink {
+            ino: 0,
+            parent: 1,
+            target: format!("{up}{}", virtual_path(&dir, &name)),
+            dir: std::mem::replace(&mut e.dir, dir),
+            name: std::mem::replace(&mut e.name, name),
+        });
+        stored.insert(virtual_path(&e.dir, &e.name))
+    });
+
+    links
+}
+
+/// Keep the first entry (in name order) of every SHA1 group in place and move
+/// the rest under `.duplicates/`.
+fn move_duplicates(entries: &mut [IndexEntry]) {
+    let mut seen: HashMap<[u8; 20], &Path> = HashMap::new();
+    let mut dups = Vec::new();
+
+    for (i, e) in entries.iter().enumerate() {
+        let Some(sha1) = e.sha1 else { continue };
+        match seen.get(&sha1) {
+            Some(primary) => {
+                info!(
+                    "{:?} duplicates {:?}; moving to {}/",
+                    e.chd_path, primary, DUPLICATES_DIR
+                );
+                dups.push(i);
+            }
+            None => {
+                seen.insert(sha1, &e.chd_path);
+            }
+        }
+    }
+
+    for i in dups {
+        entries[i].dir = DUPLICATES_DIR.to_string();
+    }
+}
+
+/// `name` less its extension.
+fn stem_of(name: &str) -> &str {
+    name.rsplit_once('.').map_or(name, |(stem, _)| stem)
+}
+
+/// `Name.iso.sha1` / `.md5` beside each image (`--checksum-files`).
+fn checksum_entries(which: ChecksumFiles, entries: &[IndexEntry]) -> Vec<IndexEntry> {
+    entries
+        .iter()
+        .filter(|e| e.dir != SOURCES_DIR && is_image(e.provider.kind()))
+        .flat_map(|e| {
+            which.algos().iter().map(move |&algo| IndexEntry {
+                name: format!("{}.{}", e.name, algo.extension()),
+                provider: Arc::new(ChecksumProvider::new(
+                    Arc::clone(&e.provider),
+                    &e.name,
+                    algo,
+                )),
+                ..e.clone()
+            })
+        })
+        .collect()
+}
+
+/// Files of `--sidecar-dir` named after an image (its exposed name or its
+/// source file, less the extension), listed beside it under the image's
+/// stem: `Game.png` next to `Game.iso`, or next to `Ico.iso` once
+/// `--title-db` has renamed `Game.chd`.
+fn sidecar_entries(dir: &Path, entries: &[IndexEntry]) -> Vec<IndexEntry> {
+    let mut images: HashMap<&str, &IndexEntry> = HashMap::new();
+    for e in entries
+        .iter()
+        .filter(|e| e.dir != SOURCES_DIR && e.dir != DUPLICATES_DIR && is_image(e.provider.kind()))
+    {
+        images.entry(stem_of(&e.name)).or_insert(e);
+        if let Some(source) = e.chd_path.file_name().and_then(OsStr::to_str) {
+            images.entry(stem_of(source)).or_insert(e);
+        }
+    }
+
+    let every = |_: &Path| true;
+    let files = match vfs::list(
+        dir,
+        &ScanOptions {
+            follow_symlinks: true,
+            skip_hidden: true,
+            wanted: &every,
+            exclude: &[],
+        },
+    ) {
+        Ok(files) => files,
+        Err(e) => {
+            warn!("No sidecars: {:#}", e);
+            return Vec::new();
+        }
+    };
+
+    let mut taken: HashSet<String> = entries
+        .iter()
+        .map(|e| virtual_path(&e.dir, &e.name))
+        .collect();
+    let mut out = Vec::new();
+    for path in files {
+        let Some((stem, ext)) = path
+            .file_name()
+            .and_then(OsStr::to_str)
+            .and_then(|n| n.rsplit_once('.'))
+        else {
+            continue;
+        };
+        let Some(image) = images.get(stem) else {
+            continue;
+        };
+
+        let name = format!("{}.{ext}", stem_of(&image.name));
+        if !taken.insert(virtual_path(&image.dir, &name)) {
+            debug!("Skipping sidecar {:?}: {} is taken", path, name);
+            continue;
+        }
+        match SourceFileProvider::new(&path) {
+            Ok(p) => out.push(IndexEntry {
+                name,
+                chd_path: path,
+                provider: Arc::new(p),
+                sha1: None,
+                layer_break: None,
+                original_name: None,
+                label: None,
+                ..(*image).clone()
+            }),
+            Err(e) => warn!("Skipping sidecar {:?}: {:#}", path, e),
+        }
+    }
+    out
+}
+
+/// Suffix colliding names within one directory as `Name (2).iso`, `Name (3).iso`, ...
+fn disambiguate_names(entries: &mut [IndexEntry]) {
+    let mut taken: HashSet<String> = HashSet::new();
+
+    for e in entries.iter_mut() {
+        if taken.insert(virtual_path(&e.dir, &e.name)) {
+            continue;
+        }
+
+        let (stem, ext) = match e.name.rsplit_once('.') {
+            Some((stem, ext)) => (stem.to_string(), format!(".{ext}")),
+            None => (e.name.clone(), String::new()),
+        };
+
+        let mut n = 2;
+        loop {
+            let candidate = format!("{stem} ({n}){ext}");
+            if taken.insert(virtual_path(&e.dir, &candidate)) {
+                warn!(
+                    "{:?}: name {:?} already taken; exposing as {:?}",
+                    e.chd_path, e.name, candidate
+                );
+                e.name = candidate;
+                break;
+            }
+            n += 1;
+        }
+    }
+}
+
+/// Read a `--source-list` file: one CHD path per line. Blank lines and `#`
+/// lines (including M3U directives) are ignored; relative paths resolve
+/// against the list's own directory. Lines may also be remote URLs.
+fn read_source_list(list: &Path) -> Result<Vec<PathBuf>> {
+    let text = fs::read_to_string(list).with_context(|| format!("reading {list:?}"))?;
+    let base = list.parent().unwrap_or_else(|| Path::new("."));
+
+    let mut seen = HashSet::new();
+    let mut out = Vec::new();
+
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let path = match vfs::is_url(Path::new(line)) {
+            true => PathBuf::from(line),
+            false => base.join(line),
+        };
+        if !vfs::stat(&path).is_ok_and(|m| !m.is_dir) {
+            warn!("Skipping {:?} from {:?}: not a file", path, list);
+            continue;
+        }
+
+        if seen.insert(path.clone()) {
+            out.push(path);
+        }
+    }
+
+    Ok(out)
+}
+
+/// FUSE-facing handle on the shared state; the reload thread holds another.
+struct ChdFs(Arc<FsState>);
+
+impl Deref for ChdFs {
+    type Target = FsState;
+
+    fn deref(&self) -> &FsState {
+        &self.0
+    }
+}
+
+impl ChdFs {
+    /// errno for a failed source access: `ENODEV` if the drive is gone.
+    fn io_errno(&self) -> i32 {
+        self.health.source_error();
+        if self.0.source_lost() {
+            libc::ENODEV
+        } else {
+            libc::EIO
+        }
+    }
+}
+
+impl Filesystem for ChdFs {
+    fn init(&mut self, _req: &Request, config: &mut KernelConfig) -> std::io::Result<()> {
+        let args = self.args();
+        if args.smb_mode
+            && config
+                .add_capabilities(InitFlags::FUSE_DO_READDIRPLUS)
+                .is_err()
+        {
+            debug!(
+                "{:?}: the kernel does not support readdirplus",
+                args.mountpoint()
+            );
+        }
+        if args.nfs_export
+            && config
+                .add_capabilities(InitFlags::FUSE_EXPORT_SUPPORT)
+                .is_err()
+        {
+            warn!(
+                "{:?}: the kernel does not support exporting FUSE over NFS",
+                args.mountpoint()
+            );
+        }
+        let max_background = negotiate("max-background", args.max_background, |n| {
+            config.set_max_background(n)
+        });
+        let threshold = match args.congestion_threshold {
+            0 => max_background.saturating_mul(3) / 4,
+            n => n,
+        };
+        let congestion_threshold = negotiate("congestion-threshold", threshold, |n| {
+            config.set_congestion_threshold(n)
+        });
+
+        info!(
+            "{:?}: FUSE session max_background {}, congestion_threshold {}",
+            args.mountpoint(),
+            max_background,
+            congestion_threshold
+        );
+        self.health.record_session(health::Session {
+            max_background,
+            congestion_threshold,
+        });
+        Ok(())
+    }
+
+    fn lookup(&self, req: &Request, parent: INodeNo, name: &OsStr, reply: ReplyEntry) {
+        if let Err(e) = self.visible_to(req.uid()) {
+            reply.error(Errno::from_i32(e));
+            return;
+        }
+        if let Some(attr) = self.control_lookup(parent.0, name) {
+            reply.entry(&TTL, &attr, Generation(self.generation));
+            return;
+        }
+        if let Err(e) = self.online() {
+            reply.error(Errno::from_i32(e));
+            return;
+        }
+
+        let name_str = name.to_string_lossy().to_string();
+        let found = self.index().lookup(parent.0, &name_str);
+
+        let generation = Generation(self.generation);
+        match found {
+            Some(Lookup::Dir(ino)) => {
+                reply.entry(&self.ttl(ino), &self.0.dir_attr(ino), generation)
+            }
+            Some(Lookup::Link(ino)) => match self.attr(ino) {
+                Ok(attr) => reply.entry(&self.ttl(ino), &attr, generation),
+                Err(e) => reply.error(Errno::from_i32(e)),
+            },
+            Some(Lookup::Entry(e)) if self.visible(&e) => {
+                reply.entry(&self.ttl(e.ino), &self.file_attr(&e), generation);
+            }
+            Some(Lookup::Entry(_)) => reply.error(Errno::from_i32(libc::ENOENT)),
+            None => match self.overlay_lookup(parent.0, name) {
+                Some(attr) => reply.entry(&TTL, &attr, generation),
+                None if self.args().smb_mode => reply.entry(&SMB_TTL, &negative_attr(), generation),
+                None => reply.error(Errno::from_i32(libc::ENOENT)),
+            },
+        }
+    }
+
+    fn getattr(&self, _req: &Request, ino: INodeNo, fh: Option<FileHandle>, reply: ReplyAttr) {
+        let _ = fh;
+
+        match self.attr(ino.0) {
+            Ok(attr) => reply.attr(&self.ttl(ino.0), &attr),
+            Err(e) => reply.error(Errno::from_i32(e)),
+        }
+    }
+
+    fn readlink(&self, _req: &Request, ino: INodeNo, reply: ReplyData) {
+        match self.0.readlink(ino.0) {
+            Ok(target) => reply.data(target.as_bytes()),
+            Err(e) => reply.error(Errno::from_i32(e)),
+        }
+    }
+
+    fn readdir(
+        &self,
+        req: &Request,
+        ino: INodeNo,
+        _fh: FileHandle,
+        offset: u64,
+        mut reply: ReplyDirectory,
+    ) {
+        if let Err(e) = self.visible_to(req.uid()) {
+            reply.error(Errno::from_i32(e));
+            return;
+        }
+        match self.dir_listing(ino.0) {
+            Ok(listing) => {
+                let listing = listing
+                    .iter()
+                    .map(|(i, kind, name)| (*i, *kind, name.as_str()));
+                fill_dir(listing, offset, |child, next, kind, name| {
+                    reply.add(INodeNo(child), next, kind, name)
+                });
+                reply.ok();
+            }
+            Err(e) => reply.error(Errno::from_i32(e)),
+        }
+    }
+
+    /// readdir with each entry's attributes, so a client listing a directory
+    /// (Samba does for every query) needs no lookup per file.
+    fn readdirplus(
+        &self,
+        req: &Request,
+        ino: INodeNo,
+        _fh: FileHandle,
+        offset: u64,
+        mut reply: ReplyDirectoryPlus,
+    ) {
+        if let Err(e) = self.visible_to(req.uid()) {
+            reply.error(Errno::from_i32(e));
+            return;
+        }
+        match self.dir_listing(ino.0) {
+            Ok(listing) => {
+                let listing = listing
+                    .iter()
+                    .map(|(i, kind, name)| (*i, *kind, name.as_str()));
+                fill_dir(listing, offset, |child, next, _kind, name| {
+                    // Skipped, not fatal: the entry went away mid-listing.
+                    let Ok(attr) = self.attr(child) else {
+                        return false;
+                    };
+                    let ttl = self.ttl(child);
+                    reply.add(
+                        INodeNo(child),
+                        next,
+                        name,
+                        &ttl,
+                        &attr,
+                        Generation(self.generation),
+                    )
+                });
+                reply.ok();
+            }
+            Err(e) => reply.error(Errno::from_i32(e)),
+        }
+    }
+
+    fn open(&self, req: &Request, ino: INodeNo, flags: OpenFlags, reply: fuser::ReplyOpen) {
+        if let Err(e) = self.visible_to(req.uid()) {
+            reply.error(Errno::from_i32(e));
+            return;
+        }
+        if Overlay::owns(ino.0) {
+            match self.overlay_open(ino.0, flags.0) {
+                Ok(fh) => reply.opened(FileHandle(fh), FopenFlags::empty()),
+                Err(e) => reply.error(Errno::from_i32(e)),
+            }
+            return;
+        }
+
+        if flags.0 & libc::O_ACCMODE != libc::O_RDONLY {
+            reply.error(Errno::from_i32(libc::EROFS));
+            return;
+        }
+        if ino.0 == health::FILE_INO {
+            // Direct I/O: the report changes size between reads.
+            reply.opened(FileHandle(0), FopenFlags::FOPEN_DIRECT_IO);
+            return;
+        }
+        if let Err(e) = self.online() {
+            reply.error(Errno::from_i32(e));
+            return;
+        }
+
+        let Some(e) = self.entry_by_ino(ino.0) else {
+            reply.error(Errno::from_i32(libc::ENOENT));
+            return;
+        };
+
+        match vfs::check_open(&e.chd_path) {
+            Ok(()) => {}
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                reply.error(Errno::from_i32(self.0.source_gone(&e.chd_path)));
+                return;
+            }
+            Err(_) => {
+                reply.error(Errno::from_i32(self.io_errno()));
+                return;
+            }
+        }
+
+        let fh = self.open_handle(&e, flags.0, req.uid(), req.pid());
+        reply.opened(FileHandle(fh), self.open_flags());
+    }
+
+    fn getxattr(&self, _req: &Request, ino: INodeNo, name: &OsStr, size: u32, reply: ReplyXattr) {
+        match self.xattr(ino.0, Some(name), size) {
+            Ok(Xattr::Size(n)) => reply.size(n),
+            Ok(Xattr::Data(v)) => reply.data(&v),
+            Err(e) => reply.error(Errno::from_i32(e)),
+        }
+    }
+
+    fn listxattr(&self, _req: &Request, ino: INodeNo, size: u32, reply: ReplyXattr) {
+        match self.xattr(ino.0, None, size) {
+            Ok(Xattr::Size(n)) => reply.size(n),
+            Ok(Xattr::Data(v)) => reply.data(&v),
+            Err(e) => reply.error(Errno::from_i32(e)),
+        }
+    }
+
+    fn setattr(
+        &self,
+        _req: &Request,
+        ino: INodeNo,
+        _mode: Option<u32>,
+        _uid: Option<u32>,
+        _gid: Option<u32>,
+        size: Option<u64>,
+        _atime: Option<TimeOrNow>,
+        _mtime: Option<TimeOrNow>,
+        _ctime: Option<SystemTime>,
+        _fh: Option<FileHandle>,
+        _crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        _flags: Option<BsdFileFlags>,
+        reply: ReplyAttr,
+    ) {
+        match self.overlay_setattr(ino.0, size) {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(e) => reply.error(Errno::from_i32(e)),
+        }
+    }
+
+    fn mknod(
+        &self,
+        _req: &Request,
+        _parent: INodeNo,
+        _name: &OsStr,
+        _mode: u32,
+        _umask: u32,
+        _rdev: u32,
+        reply: ReplyEntry,
+    ) {
+        reply.error(Errno::from_i32(libc::EROFS));
+    }
+
+    fn mkdir(
+        &self,
+        _req: &Request,
+        _parent: INodeNo,
+        _name: &OsStr,
+        _mode: u32,
+        _umask: u32,
+        reply: ReplyEntry,
+    ) {
+        reply.error(Errno::from_i32(libc::EROFS));
+    }
+
+    fn unlink(&self, _req: &Request, parent: INodeNo, name: &OsStr, reply: ReplyEmpty) {
+        match self.unlink_file(parent.0, name) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(Errno::from_i32(e)),
+        }
+    }
+
+    fn rmdir(&self, _req: &Request, parent: INodeNo, name: &OsStr, reply: ReplyEmpty) {
+        match self.deny_delete(parent.0, name) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(Errno::from_i32(e)),
+        }
+    }
+
+    fn rename(
+        &self,
+        _req: &Request,
+        parent: INodeNo,
+        name: &OsStr,
+        newparent: INodeNo,
+        newname: &OsStr,
+        _flags: RenameFlags,
+        reply: ReplyEmpty,
+    ) {
+        match self.overlay_rename((parent.0, name), (newparent.0, newname)) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(Errno::from_i32(e)),
+        }
+    }
+
+    fn write(
+        &self,
+        _req: &Request,
+        ino: INodeNo,
+        fh: FileHandle,
+        offset: u64,
+        data: &[u8],
+        _write_flags: WriteFlags,
+        _flags: OpenFlags,
+        _lock_owner: Option<LockOwner>,
+        reply: ReplyWrite,
+    ) {
+        if !Overlay::owns(ino.0) {
+            reply.error(Errno::from_i32(libc::EROFS));
+            return;
+        }
+        match self.overlay_write(ino.0, fh.0, offset, data) {
+            Ok(n) => reply.written(n),
+            Err(e) => reply.error(Errno::from_i32(e)),
+        }
+    }
+
+    fn create(
+        &self,
+        _req: &Request,
+        parent: INodeNo,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        flags: i32,
+        reply: ReplyCreate,
+    ) {
+        match self.overlay_create(parent.0, name, mode & !umask, flags) {
+            Ok((attr, fh)) => reply.created(
+                &TTL,
+                &attr,
+                Generation(self.generation),
+                FileHandle(fh),
+                FopenFlags::empty(),
+            ),
+            Err(e) => reply.error(Errno::from_i32(e)),
+        }
+    }
+
+    fn release(
+        &self,
+        _req: &Request,
+        _ino: INodeNo,
+        fh: FileHandle,
+        _flags: OpenFlags,
+        _lock_owner: Option<LockOwner>,
+        _flush: bool,
+        reply: fuser::ReplyEmpty,
+    ) {
+        self.release_handle(fh.0);
+
+        reply.ok();
+    }
+
+    fn read(
+        &self,
+        _req: &Request,
+        ino: INodeNo,
+        fh: FileHandle,
+        offset: u64,
+        size: u32,
+        _flags: OpenFlags,
+        _lock_owner: Option<LockOwner>,
+        reply: ReplyData,
+    ) {
+        if Overlay::owns(ino.0) {
+            match self.overlay_read(ino.0, fh.0, offset, size) {
+                Ok(buf) => reply.data(&buf),
+                Err(e) => reply.error(Errno::from_i32(e)),
+            }
+            return;
+        }
+
+        if ino.0 == health::FILE_INO {
+            reply.data(&self.health_read(offset, size));
+            return;
+        }
+        if let Err(e) = self.online() {
+            reply.error(Errno::from_i32(e));
+            return;
+        }
+
+        let ent = match self.entry_by_ino(ino.0) {
+            Some(e) => e,
+            None => {
+                reply.error(Errno::from_i32(libc::ENOENT));
+                return;
+            }
+        };
+
+        self.note_read_size(size);
+        if size == 0 {
+            reply.data(&[]);
+            return;
+        }
+
+        let handle = match self.handles.get(fh.0, ino.0) {
+            Ok(h) => h,
+            Err(e) => {
+                reply.error(Errno::from_i32(e));
+                return;
+            }
+        };
+
+        match self.check_quarantine(&handle) {
+            Ok(false) => {}
+            Ok(true) => {
+                reply.data(&[]);
+                return;
+            }
+            Err(e) => {
+                reply.error(Errno::from_i32(e));
+                return;
+            }
+        }
+
+        let len = (size as u64).min(ent.provider.size().saturating_sub(offset));
+        let mut buf = vec![0u8; len as usize];
+        let provider = self.hot.provider(ino.0).unwrap_or(ent.provider);
+
+        match handle.read(&provider, offset, &mut buf) {
+            Ok(n) => {
+                self.count_read(n);
+                let delay = self.throttle_delay(handle.throttle.as_deref(), n);
+                if !delay.is_zero() {
+                    thread::sleep(delay);
+                }
+                reply.data(&buf[..n]);
+            }
+            // A checksum file still being hashed: not the source's fault.
+            Err(e) if e.is::<NotReady>() => reply.error(Errno::from_i32(libc::EAGAIN)),
+            Err(e) => {
+                self.0.read_failed(&handle, &e);
+                reply.error(Errno::from_i32(self.io_errno()));
+            }
+        }
+    }
+}
+
+/// Apply a session limit through one of fuser's `KernelConfig` setters, which
+/// return the previous value or, if refused, the nearest the kernel allows.
+/// 0 keeps the default, read back by setting it twice.
+fn negotiate(flag: &str, want: u16, mut set: impl FnMut(u16) -> Result<u16, u16>) -> u16 {
+    if want == 0 {
+        return match set(1) {
+            Ok(default) => {
+                let _ = set(default);
+                default
+            }
+            Err(nearest) => nearest,
+        };
+    }
+    match set(want) {
+        Ok(_) => want,
+        Err(nearest) => {
+            warn!("--{flag} {want} refused, using {nearest}");
+            let _ = set(nearest);
+            nearest
+        }
+    }
+}
+
+/// Feed `add` the entries of a listing from position `offset` on, each with
+/// the offset that resumes after it (its position + 1), until `add` reports
+/// the reply buffer full. A listing split across buffers, or seeked back to
+/// any offset it handed out, so continues where it left off; 0 starts over.
+fn fill_dir<'a>(
+    listing: impl IntoIterator<Item = (u64, FileType, &'a str)>,
+    offset: u64,
+    mut add: impl FnMut(u64, u64, FileType, &'a str) -> bool,
+) {
+    let skip = usize::try_from(offset).unwrap_or(usize::MAX);
+    for (pos, (ino, kind, name)) in listing.into_iter().enumerate().skip(skip) {
+        if add(ino, pos as u64 + 1, kind, name) {
+            break;
+        }
+    }
+}
+
+const XATTR_LAYER_BREAK: &str = "user.chd2iso.layerbreak";
+const XATTR_ORIGINAL: &str = "user.chd2iso.original";
+const XATTR_LABEL: &str = "user.chd2iso.label";
+/// Content type hint, as read by freedesktop file managers
+const XATTR_MIME: &str = "user.mime_type";
+
+/// Content type of an entry by its extension, for the name a frontend sees
+/// whatever `--extension-map` made it.
+fn mime_type(e: &IndexEntry) -> Option<&'static str> {
+    let ext = e.name.rsplit_once('.')?.1.to_ascii_lowercase();
+    Some(match (ext.as_str(), e.provider.kind()) {
+        ("cue", _) => "application/x-cue",
+        ("toc", _) => "application/x-cdrdao-toc",
+        ("wav", _) => "audio/x-wav",
+        ("cso" | "zso", _) => "application/x-compressed-iso",
+        (_, ImageKind::Dvd | ImageKind::Cd | ImageKind::Disc | ImageKind::Decoded) => {
+            "application/x-cd-image"
+        }
+        (_, ImageKind::Raw) => "application/x-raw-disk-image",
+        _ => return None,
+    })
+}
+
+/// Extended attributes of an entry, as (name, value).
+fn entry_xattrs(e: &IndexEntry) -> Vec<(&'static str, String)> {
+    let mut attrs = Vec::new();
+    if let Some(lba) = e.layer_break {
+        attrs.push((XATTR_LAYER_BREAK, lba.to_string()));
+    }
+    if let Some(name) = &e.original_name {
+        attrs.push((XATTR_ORIGINAL, name.clone()));
+    }
+    if let Some(label) = &e.label {
+        attrs.push((XATTR_LABEL, label.clone()));
+    }
+    if let Some(mime) = mime_type(e) {
+        attrs.push((XATTR_MIME, mime.to_string()));
+    }
+    attrs
+}
+
+enum Xattr {
+    Size(u32),
+    Data(Vec<u8>),
+}
+
+fn dir_attr(ino: u64) -> FileAttr {
+    FileAttr {
+        ino: INodeNo(ino),
+        size: 0,
+        blocks: 1,
+        atime: SystemTime::now(),
+        mtime: SystemTime::now(),
+        ctime: SystemTime::now(),
+        crtime: SystemTime::UNIX_EPOCH,
+        kind: FileType::Directory,
+        perm: 0o755,
+        nlink: 2,
+        uid: unsafe { libc::geteuid() },
+        gid: unsafe { libc::getegid() },
+        rdev: 0,
+        flags: 0,
+        blksize: 4096,
+    }
+}
+
+/// A negative entry: inode 0 has the kernel cache that the name does not
+/// exist.
+fn negative_attr() -> FileAttr {
+    FileAttr {
+        ino: INodeNo(0),
+        ..dir_attr(0)
+    }
+}
+
+/// Attributes of the read-only `health` file.
+fn control_file_attr(ino: u64, size: usize) -> FileAttr {
+    FileAttr {
+        size: size as u64,
+        blocks: 1,
+        kind: FileType::RegularFile,
+        perm: 0o444,
+        nlink: 1,
+        ..dir_attr(ino)
+    }
+}
+
+/// Attributes of an overlay file, straight from its on-disk `meta`.
+fn overlay_attr(ino: u64, meta: &fs::Metadata) -> FileAttr {
+    let time = |secs: i64| SystemTime::UNIX_EPOCH + Duration::from_secs(secs.max(0) as u64);
+    FileAttr {
+        ino: INodeNo(ino),
+        size: meta.len(),
+        blocks: meta.blocks(),
+        atime: time(meta.atime()),
+        mtime: time(meta.mtime()),
+        ctime: time(meta.ctime()),
+        crtime: SystemTime::UNIX_EPOCH,
+        kind: FileType::RegularFile,
+        perm: (meta.mode() & 0o7777) as u16,
+        nlink: 1,
+        uid: meta.uid(),
+        gid: meta.gid(),
+        rdev: 0,
+        flags: 0,
+        blksize: 4096,
+    }
+}
+
+/// errno of an overlay I/O error.
+fn errno(e: std::io::Error) -> i32 {
+    e.raw_os_error().unwrap_or(libc::EIO)
+}
+
+/// Whether entries of `kind` are disc images rather than derived files.
+fn is_image(kind: ImageKind) -> bool {
+    matches!(
+        kind,
+        ImageKind::Dvd | ImageKind::Cd | ImageKind::Decoded | ImageKind::Raw
+    )
+}
+
+/// Entries that do not share their source's storage: container views are
+/// synthesized, and `.sources/` entries are the source itself.
+fn is_synthetic(p: &dyn BackingProvider) -> bool {
+    matches!(p.kind(), ImageKind::View | ImageKind::Source)
+}
+
+/// `st_blocks` of `e` under `report`, given its source's allocated blocks.
+fn blocks_for(e: &IndexEntry, source_blocks: Option<u64>, report: BlocksReport) -> u64 {
+    let size = e.provider.size();
+    match (report, source_blocks, e.provider.kind()) {
+        (BlocksReport::Physical, Some(blocks), ImageKind::Source) => blocks,
+        (BlocksReport::Physical, Some(blocks), _) if !is_synthetic(&*e.provider) => {
+            pro_rate(blocks, size, e.source_bytes)
+        }
+        _ => size.div_ceil(512),
+    }
+}
+
+/// `blocks` split in proportion `part / whole`, rounded up.
+fn pro_rate(blocks: u64, part: u64, whole: u64) -> u64 {
+    if whole == 0 {
+        return 0;
+    }
+    (blocks as u128 * part as u128).div_ceil(whole as u128) as u64
+}
+
+/// Attributes of `e` given its source's `stat`; with none, the mount's
+/// owner and the current time stand in.
+fn file_attr_for(e: &IndexEntry, stat: Option<SourceStat>, args: &Args) -> FileAttr {
+    let now = SystemTime::now();
+    let (mtime, ctime) = match (args.mtime_policy().fixed(e.sha1.as_ref()), stat) {
+        (Some(t), _) => (t, t),
+        (None, Some(st)) => (st.mtime, st.ctime),
+        (None, None) => (now, now),
+    };
+
+    FileAttr {
+        ino: INodeNo(e.ino),
+        size: e.provider.size(),
+        blocks: blocks_for(e, stat.map(|st| st.blocks), args.blocks_report),
+        atime: if args.reproducible { mtime } else { now },
+        mtime,
+        ctime,
+        crtime: SystemTime::UNIX_EPOCH,
+        kind: FileType::RegularFile,
+        perm: 0o444,
+        nlink: 1,
+        uid: stat.map_or_else(|| unsafe { libc::geteuid() }, |st| st.uid),
+        gid: stat.map_or_else(|| unsafe { libc::getegid() }, |st| st.gid),
+        rdev: 0,
+        flags: 0,
+        blksize: 4096,
+    }
+}
+
+static RELOAD_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_sighup(_sig: libc::c_int) {
+    RELOAD_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Swaps the live `tracing` filter.
+type SetLogFilter = Box<dyn Fn(&str) -> Result<()> + Send>;
+
+/// What a SIGHUP re-applies: the `--config` file (if any) layered over the
+/// original command line, then a re-index of every mount.
+struct Reload {
+    cli: Args,
+    mounts: Vec<Arc<FsState>>,
+    frame_cache: Arc<FrameCache>,
+    set_log_filter: SetLogFilter,
+}
+
+impl Reload {
+    fn run(&self) {
+        if let Some(path) = &self.cli.config {
+            if let Err(e) = self.reload_config(path) {
+                error!(
+                    "SIGHUP: {:?} not applied, keeping previous settings: {:#}",
+                    path, e
+                );
+            }
         }
 
-        if let Some(e) = self.entries.iter().find(|e| e.ino == ino.0) {
-            match file_attr_for(e) {
-                Ok(attr) => reply.attr(&TTL, &attr),
-                Err(_) => reply.error(Errno::from_i32(libc::EIO)),
+        for fs in &self.mounts {
+            match fs.build_index() {
+                Ok(()) => {
+                    info!(
+                        "SIGHUP: re-indexed {:?} (entries: {})",
+                        fs.args().mountpoint(),
+                        fs.index().entries.len()
+                    );
+                    fs.maintain();
+                }
+                Err(e) => error!(
+                    "SIGHUP: re-index of {:?} failed, keeping previous index: {}",
+                    fs.args().mountpoint(),
+                    e
+                ),
             }
-        } else {
-            reply.error(Errno::from_i32(libc::ENOENT));
         }
     }
 
-    fn readdir(
-        &self,
-        _req: &Request,
-        ino: INodeNo,
-        _fh: FileHandle,
-        offset: u64,
-        mut reply: ReplyDirectory,
-    ) {
-        if ino.0 != 1 {
-            reply.error(Errno::from_i32(libc::ENOTDIR));
-            return;
+    /// Validate the whole file before applying any of it.
+    fn reload_config(&self, path: &Path) -> Result<()> {
+        let file = config::load(path)?;
+        let mut resolved = resolve_mounts(&self.cli, Some(&file))?;
+
+        (self.set_log_filter)(&log_filter(&self.cli, Some(&file)))?;
+        let (hunks, bytes) = cache_budget(&self.cli, Some(&file));
+        self.frame_cache.resize(hunks, bytes);
+        memory::set_cap(max_memory(&self.cli, Some(&file)));
+
+        for fs in &self.mounts {
+            let mountpoint = fs.args().mountpoint().to_path_buf();
+            match resolved.iter().position(|a| a.mountpoint() == mountpoint) {
+                Some(i) => fs.apply_settings(resolved.swap_remove(i)),
+                None => warn!(
+                    "reload: [[mount]] for {:?} removed; still served until restart",
+                    mountpoint
+                ),
+            }
+        }
+        for a in resolved {
+            warn!(
+                "reload: new [[mount]] for {:?} needs a restart",
+                a.mountpoint()
+            );
         }
 
-        let mut idx = offset;
+        info!("SIGHUP: applied {:?}", path);
+        Ok(())
+    }
+}
 
-        if idx == 0 {
-            let _ = reply.add(INodeNo(1), 1, FileType::Directory, ".");
-            let _ = reply.add(INodeNo(1), 2, FileType::Directory, "..");
-            idx = 2;
-        }
+fn spawn_reload_on_sighup(reload: Reload) -> Result<()> {
+    let handler = on_sighup as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    if unsafe { libc::signal(libc::SIGHUP, handler) } == libc::SIG_ERR {
+        return Err(anyhow!("installing SIGHUP handler failed"));
+    }
 
-        let mut ent_idx = 3u64;
-        for e in &self.entries {
-            if ent_idx <= idx {
-                ent_idx += 1;
-                continue;
-            }
+    thread::Builder::new()
+        .name("reload".into())
+        .spawn(move || loop {
+            thread::sleep(Duration::from_millis(250));
 
-            if reply.add(
-                INodeNo(e.ino),
-                ent_idx,
-                FileType::RegularFile,
-                e.name.as_str(),
-            ) {
-                break;
+            if RELOAD_REQUESTED.swap(false, Ordering::SeqCst) {
+                reload.run();
             }
+        })?;
 
-            ent_idx += 1;
-        }
+    Ok(())
+}
 
-        reply.ok();
+fn main() -> Result<()> {
+    #[cfg(feature = "doccheck")]
+    if std::env::args().any(|a| a == "--dump-flags") {
+        dump_all_flags_and_exit();
     }
 
-    fn open(&self, _req: &Request, ino: INodeNo, _flags: OpenFlags, reply: fuser::ReplyOpen) {
-        let (file_id, chd_path) = if let Some(e) = self.entries.iter().find(|e| e.ino == ino.0) {
-            (e.ino, e.chd_path.clone())
-        } else {
-            reply.error(Errno::from_i32(libc::ENOENT));
-            return;
-        };
-
-        if File::open(&chd_path).is_err() {
-            reply.error(Errno::from_i32(libc::EIO));
-            return;
-        }
+    let mut args = Args::parse();
+    let file = args.config.as_deref().map(config::load).transpose()?;
+
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(parse_log_filter(&log_filter(&args, file.as_ref()))?)
+        .with_filter_reloading();
+    let log = builder.reload_handle();
+    builder.init();
+    args.upgrade_deprecated();
+
+    match &args.command {
+        Some(Command::Check(check)) => return check::run(check),
+        Some(Command::Share(share)) => return share::run(share),
+        Some(Command::Cat(cat)) => return cat::run(cat),
+        Some(Command::Hash(hash)) => return hash::run(hash),
+        None => {}
+    }
+    if let Some(path) = std::env::var_os(isolation::CHILD_ENV) {
+        return probe_child(&args, file.as_ref(), Path::new(&path));
+    }
 
-        let fh = self.alloc_fh();
+    // Before any thread is spawned, so every thread inherits them.
+    if let Some(n) = args.nice {
+        sched::set_nice(n)?;
+    }
+    if let Some(p) = args.ionice {
+        sched::set_ionice(p)?;
+    }
+    if let Some(cpus) = &args.decode_cpu_affinity {
+        sched::set_affinity(cpus)?;
+    }
 
-        self.handles
-            .lock()
-            .expect("handles mutex poisoned")
-            .insert(fh, Handle { file_id, chd_path });
+    #[cfg(not(feature = "async"))]
+    if args.backend == Backend::Async {
+        return Err(anyhow!(
+            "--backend async requires a build with the `async` feature"
+        ));
+    }
 
-        reply.opened(FileHandle(fh), FopenFlags::empty());
+    #[cfg(not(feature = "dbus"))]
+    if args.dbus.is_some() {
+        return Err(anyhow!("--dbus requires a build with the `dbus` feature"));
+    }
+    if args.otlp_interval == 0 {
+        return Err(anyhow!("--otlp-interval must be at least 1"));
+    }
+    #[cfg(not(feature = "otel"))]
+    if args.otlp_endpoint.is_some() {
+        return Err(anyhow!(
+            "--otlp-endpoint requires a build with the `otel` feature"
+        ));
+    }
+    #[cfg(not(feature = "smb"))]
+    if args.serve.is_some() {
+        return Err(anyhow!("--serve requires a build with the `smb` feature"));
     }
 
-    fn release(
-        &self,
-        _req: &Request,
-        _ino: INodeNo,
-        fh: FileHandle,
-        _flags: OpenFlags,
-        _lock_owner: Option<LockOwner>,
-        _flush: bool,
-        reply: fuser::ReplyEmpty,
-    ) {
-        self.handles
-            .lock()
-            .expect("handles mutex poisoned")
-            .remove(&fh.0);
+    fusedev::ensure(Duration::from_secs(args.wait_for_fuse))?;
 
-        reply.ok();
+    #[cfg(any(feature = "http", feature = "sftp"))]
+    remote::configure(&args.http_cache, args.http_cache_mib);
+    vfs::set_noatime(!args.update_atime || args.reproducible);
+    vfs::set_source_timeout(Duration::from_secs(args.source_timeout));
+    if let Some(path) = &args.shared_cache {
+        shared_cache::configure(path, args.shared_cache_mib)?;
     }
+    fair::configure(args.fair_reads);
 
-    fn read(
-        &self,
-        _req: &Request,
-        ino: INodeNo,
-        fh: FileHandle,
-        offset: u64,
-        size: u32,
-        _flags: OpenFlags,
-        _lock_owner: Option<LockOwner>,
-        reply: ReplyData,
-    ) {
-        let ent = match self.entries.iter().find(|e| e.ino == ino.0) {
-            Some(e) => e.clone(),
-            None => {
-                reply.error(Errno::from_i32(libc::ENOENT));
-                return;
-            }
-        };
+    #[cfg(feature = "async")]
+    let backend = args.backend;
+    let (hunks, bytes) = cache_budget(&args, file.as_ref());
+    let frame_cache = Arc::new(FrameCache::new(hunks, bytes));
+    memory::set_cap(max_memory(&args, file.as_ref()));
 
-        if size == 0 {
-            reply.data(&[]);
-            return;
-        }
+    // Export only: probe, write the index and exit without mounting.
+    if args.mountpoint.is_none() && args.config.is_none() {
+        return FsState::new(args, frame_cache)?.build_index();
+    }
 
-        let (file_id, chd_path) = match self
-            .handles
-            .lock()
-            .expect("handles mutex poisoned")
-            .get(&fh.0)
-        {
-            Some(h) => (h.file_id, h.chd_path.clone()),
-            None => {
-                reply.error(Errno::from_i32(libc::EBADF));
-                return;
-            }
-        };
+    let resolved = resolve_mounts(&args, file.as_ref())?;
+    for args in &resolved {
+        if args.mountpoint().metadata().is_err() {
+            return Err(anyhow!(
+                "Mountpoint {:?} does not exist or is not accessible",
+                args.mountpoint()
+            ));
+        }
+        if args.nfs_export && args.backend == Backend::Async {
+            return Err(anyhow!(
+                "--nfs-export for {:?} requires --backend sync",
+                args.mountpoint()
+            ));
+        }
+    }
+    let pairs: Vec<_> = resolved
+        .iter()
+        .map(|a| (a.source_dir.as_deref(), a.mountpoint()))
+        .collect();
+    let nested = nested_mountpoints(&pairs)?;
+
+    let mut mounts = Vec::new();
+    for (args, nested) in resolved.into_iter().zip(nested) {
+        for point in &nested {
+            info!(
+                "mountpoint {:?} is inside source {:?}; not indexing it",
+                point, args.source_dir
+            );
+        }
+        let fs = Arc::new(FsState::new(args, Arc::clone(&frame_cache))?);
+        let _ = fs.nested.set(nested);
+        fs.build_index()?;
+        fs.watch_sources();
+        fs.warmup();
+        fs.maintain();
+        fs.start_hot();
+
+        info!(
+            "mounting {:?} -> {:?} (entries: {})",
+            fs.args()
+                .source_list
+                .as_ref()
+                .or(fs.args().source_dir.as_ref()),
+            fs.args().mountpoint(),
+            fs.index().entries.len()
+        );
+        mounts.push(fs);
+    }
 
-        match ent.kind {
-            BackingKind::Dvd2048 | BackingKind::Raw2048 => {
-                let start = offset;
+    panics::install(
+        mounts
+            .iter()
+            .map(|fs| fs.args().mountpoint().to_path_buf())
+            .collect(),
+    );
 
-                if start >= ent.iso_size {
-                    reply.data(&[]);
-                    return;
-                }
+    // Held until every mount is gone; dropping it releases the bus name.
+    #[cfg(feature = "dbus")]
+    let _dbus = match args.dbus {
+        Some(bus) => Some(dbus::serve(bus, mounts.clone()).context("D-Bus service")?),
+        None => None,
+    };
 
-                let end = start.saturating_add(size as u64).min(ent.iso_size);
-                let to_read = (end - start) as usize;
+    memory::govern(Arc::clone(&frame_cache), mounts.clone()).context("memory governor")?;
+    if let Some(path) = &args.control_socket {
+        control::serve(path, mounts.clone()).context("control socket")?;
+    }
+    if let Some(addr) = args.web_ui {
+        webui::serve(addr, mounts.clone())?;
+    }
+    #[cfg(feature = "otel")]
+    if let Some(endpoint) = &args.otlp_endpoint {
+        let interval = Duration::from_secs(args.otlp_interval);
+        otel::serve(endpoint, interval, mounts.clone()).context("OTLP export")?;
+    }
 
-                let f = match File::open(&chd_path) {
-                    Ok(f) => f,
-                    Err(_) => {
-                        reply.error(Errno::from_i32(libc::EIO));
-                        return;
-                    }
-                };
+    #[cfg(feature = "smb")]
+    if args.serve == Some(Serve::Smb) {
+        smb::serve(args.serve_addr, &mounts)?;
+    }
 
-                let mut chd = match Chd::open(BufReader::new(f), None) {
-                    Ok(c) => c,
-                    Err(_) => {
-                        reply.error(Errno::from_i32(libc::EIO));
-                        return;
-                    }
-                };
+    spawn_reload_on_sighup(Reload {
+        cli: args,
+        mounts: mounts.clone(),
+        frame_cache,
+        set_log_filter: Box::new(move |directive| Ok(log.reload(parse_log_filter(directive)?)?)),
+    })?;
 
-                let hunk_size = chd.header().hunk_size() as u64;
-                let mut buf = vec![0u8; to_read];
-                let mut out_off = 0usize;
-                let mut left = to_read as u64;
-                let mut pos = start;
-
-                while left > 0 {
-                    let hunk_idx = (pos / hunk_size) as u32;
-                    let in_hunk_off = (pos % hunk_size) as usize;
-                    let take = ((hunk_size as usize) - in_hunk_off).min(left as usize);
-
-                    let mut hunk_buf = chd.get_hunksized_buffer();
-                    let mut cmp = Vec::new();
-
-                    let mut hk = match chd.hunk(hunk_idx) {
-                        Ok(h) => h,
-                        Err(_) => {
-                            reply.error(Errno::from_i32(libc::EIO));
-                            return;
-                        }
-                    };
+    #[cfg(feature = "async")]
+    if backend == Backend::Async {
+        return async_fs::mount(mounts);
+    }
 
-                    if hk.read_hunk_in(&mut cmp, &mut hunk_buf).is_err() {
-                        reply.error(Errno::from_i32(libc::EIO));
-                        return;
-                    }
+    if mounts.len() == 1 {
+        return serve(mounts.remove(0));
+    }
 
-                    buf[out_off..out_off + take]
-                        .copy_from_slice(&hunk_buf[in_hunk_off..in_hunk_off + take]);
+    // Supervisor mode: one session thread per mount, sharing the frame cache.
+    let threads = mounts
+        .into_iter()
+        .map(|fs| {
+            let name = format!("mount:{}", fs.args().mountpoint().display());
+            thread::Builder::new()
+                .name(name)
+                .spawn(move || (fs.args().mountpoint().to_path_buf(), serve(fs)))
+        })
+        .collect::<std::io::Result<Vec<_>>>()?;
+
+    let mut failed = 0;
+    for t in threads {
+        match t.join() {
+            Ok((_, Ok(()))) => {}
+            Ok((mountpoint, Err(e))) => {
+                error!("{:?}: {}", mountpoint, e);
+                failed += 1;
+            }
+            Err(_) => failed += 1,
+        }
+    }
 
-                    out_off += take;
-                    left -= take as u64;
-                    pos += take as u64;
-                }
+    if failed > 0 {
+        return Err(anyhow!("{failed} mount(s) failed"));
+    }
+    Ok(())
+}
 
-                reply.data(&buf);
+impl Args {
+    /// Map flags kept for old command lines onto their replacements.
+    fn upgrade_deprecated(&mut self) {
+        if self.cd_allow_form2 {
+            warn!("--cd-allow-form2 is deprecated; use --form2-policy bin");
+            if self.form2_policy == Form2Policy::Hide {
+                self.form2_policy = Form2Policy::Bin;
             }
-            BackingKind::Cd2352 {
-                first_data_lba,
-                payload_kind,
-                track_frames,
-            } => {
-                let per_sector = match payload_kind {
-                    CdPayloadKind::Mode1_2048 | CdPayloadKind::Mode2Form1_2048 => 2048u64,
-                    CdPayloadKind::Mode2Form2_2324 => 2324u64,
-                };
+        }
+    }
 
-                let max_len = if let Some(fr) = track_frames {
-                    fr * per_sector
-                } else {
-                    ent.iso_size
-                };
+    /// `--mtime`, with `sha1` in place of `source` under `--reproducible`.
+    fn mtime_policy(&self) -> MtimePolicy {
+        match self.mtime {
+            MtimePolicy::Source if self.reproducible => MtimePolicy::Sha1,
+            policy => policy,
+        }
+    }
 
-                self.read_iso_from_cd(
-                    file_id,
-                    &chd_path,
-                    first_data_lba,
-                    payload_kind,
-                    offset,
-                    size,
-                    max_len,
-                    reply,
-                );
-            }
+    /// The `--trace-reads-*` rule, if any of them is set.
+    fn trace_rule(&self) -> Option<TraceRule> {
+        let on = self.trace_reads_every.is_some()
+            || self.trace_reads_slower_ms.is_some()
+            || self.trace_reads_file.is_some();
+        on.then(|| TraceRule {
+            every: self.trace_reads_every.unwrap_or(1).max(1),
+            slower_ms: self.trace_reads_slower_ms.unwrap_or(0),
+            file: self.trace_reads_file.clone(),
+        })
+    }
+
+    /// Set for every resolved mount (see `resolve_mounts`).
+    fn mountpoint(&self) -> &Path {
+        self.mountpoint
+            .as_deref()
+            .expect("mountpoint resolved at startup")
+    }
+}
+
+/// A `--probe-isolation` child: probe `path` with the settings of the
+/// mount the parent names and exit.
+fn probe_child(args: &Args, file: Option<&ConfigFile>, path: &Path) -> Result<()> {
+    isolation::confine();
+    let args = match std::env::var_os(isolation::MOUNT_ENV) {
+        Some(mount) => resolve_mounts(args, file)?
+            .into_iter()
+            .find(|a| a.mountpoint() == Path::new(&mount))
+            .with_context(|| format!("no mount at {mount:?}"))?,
+        None => args.clone(),
+    };
+    #[cfg(any(feature = "http", feature = "sftp"))]
+    remote::configure(&args.http_cache, args.http_cache_mib);
+
+    let frame_cache = Arc::new(FrameCache::new(64, 16 << 20));
+    Registry::with_builtin().probe(path, &probe_context(&args, &frame_cache))?;
+    Ok(())
+}
+
+fn probe_context<'a>(args: &'a Args, frame_cache: &'a Arc<FrameCache>) -> ProbeContext<'a> {
+    ProbeContext {
+        form2: args.form2_policy,
+        audio_tracks: args.audio_tracks,
+        pregap_audio: args.pregap_audio,
+        audio_byteswap: args.audio_byteswap,
+        subchannel: args.export_subchannel,
+        audio_cd: args.audio_cd,
+        toc: args.toc,
+        full_disc: args.full_disc_image,
+        merged_iso: args.merged_iso,
+        spill_dir: &args.spill_dir,
+        frame_cache,
+        serials: args.title_db.is_some() || args.layout == Layout::Opl,
+        consoles: args.console_dirs,
+        tail: args.tail_policy,
+        strict: args.strict,
+        extensions: &args.extension_map,
+    }
+}
+
+/// `--title-db`, read afresh; none (with a warning) if it cannot be read.
+fn load_titles(args: &Args) -> Option<TitleDb> {
+    let file = args.title_db.as_deref()?;
+    match TitleDb::load(file) {
+        Ok(db) if db.is_empty() => {
+            warn!("no titles in {:?}", file);
+            None
+        }
+        Ok(db) => {
+            info!("{} titles from {:?}", db.len(), file);
+            Some(db)
+        }
+        Err(e) => {
+            warn!("not naming by title: {:#}", e);
+            None
         }
     }
 }
 
-fn default_file_attr(e: &IndexEntry) -> FileAttr {
-    FileAttr {
-        ino: INodeNo(e.ino),
-        size: e.iso_size,
-        blocks: e.iso_size.div_ceil(512),
-        atime: SystemTime::now(),
-        mtime: SystemTime::now(),
-        ctime: SystemTime::now(),
-        crtime: SystemTime::UNIX_EPOCH,
-        kind: FileType::RegularFile,
-        perm: 0o444,
-        nlink: 1,
-        uid: unsafe { libc::geteuid() },
-        gid: unsafe { libc::getegid() },
-        rdev: 0,
-        flags: 0,
-        blksize: 4096,
+/// `name` from a source whose main entry is named `stem`.*, with the stem
+/// swapped for `title`: `game_v2.cue` -> `Ico.cue`.
+fn titled_name(name: &str, stem: &str, title: &str) -> String {
+    match name.strip_prefix(stem) {
+        Some(rest) => format!("{}{rest}", title.replace('/', "-")),
+        None => name.to_string(),
     }
 }
 
-fn file_attr_for(e: &IndexEntry) -> Result<FileAttr> {
-    let meta = e.chd_path.metadata()?;
+/// Longest game name Open PS2 Loader shows in a `SERIAL.Title.iso` name.
+const OPL_TITLE_MAX: usize = 32;
 
-    Ok(FileAttr {
-        ino: INodeNo(e.ino),
-        size: e.iso_size,
-        blocks: e.iso_size.div_ceil(512),
-        atime: SystemTime::now(),
-        mtime: SystemTime::UNIX_EPOCH + Duration::from_secs(meta.mtime() as u64),
-        ctime: SystemTime::UNIX_EPOCH + Duration::from_secs(meta.ctime() as u64),
-        crtime: SystemTime::UNIX_EPOCH,
-        kind: FileType::RegularFile,
-        perm: 0o444,
-        nlink: 1,
-        uid: meta.uid(),
-        gid: meta.gid(),
-        rdev: 0,
-        flags: 0,
-        blksize: 4096,
-    })
+/// `SLUS-20062` as OPL spells it, `SLUS_200.62`; none for serials of
+/// another shape.
+fn opl_serial(serial: &str) -> Option<String> {
+    let (prefix, digits) = serial.split_once('-')?;
+    (prefix.len() == 4 && digits.len() == 5 && digits.bytes().all(|b| b.is_ascii_digit()))
+        .then(|| format!("{prefix}_{}.{}", &digits[..3], &digits[3..]))
 }
 
-fn main() -> Result<()> {
-    #[cfg(feature = "doccheck")]
-    if std::env::args().any(|a| a == "--dump-flags") {
-        dump_all_flags_and_exit();
-    }
+/// `SLUS_200.62.Title.iso`, the title cut to what OPL displays.
+fn opl_name(serial: &str, title: &str) -> String {
+    let title: String = title
+        .chars()
+        .map(|c| if c == '/' { '-' } else { c })
+        .take(OPL_TITLE_MAX)
+        .collect();
+    format!("{serial}.{}.iso", title.trim_end())
+}
 
-    let args = Args::parse();
+/// Records of `--import-index` still matching their sources, by path; none
+/// (with a warning) if the file cannot be used.
+fn imported_index(args: &Args, fingerprint: &str) -> HashMap<PathBuf, SourceRecord> {
+    let Some(file) = &args.import_index else {
+        return HashMap::new();
+    };
 
-    let filter = if args.verbose {
-        EnvFilter::new("info")
-    } else {
-        EnvFilter::new("warn")
+    match Snapshot::load(file).and_then(|s| s.fresh(args.source_dir.as_deref(), fingerprint)) {
+        Ok(fresh) => {
+            info!(
+                "{:?}: reusing the probe results of {} sources",
+                file,
+                fresh.len()
+            );
+            fresh
+        }
+        Err(e) => {
+            warn!("not importing the index: {:#}", e);
+            HashMap::new()
+        }
+    }
+}
+
+/// Per-mount settings: the command line alone, or one entry per `[[mount]]`
+/// table of `--config` layered over it.
+fn resolve_mounts(args: &Args, file: Option<&ConfigFile>) -> Result<Vec<Args>> {
+    let Some(file) = file.filter(|f| !f.mount.is_empty()) else {
+        if args.mountpoint.is_none() {
+            return Err(anyhow!("{:?} defines no [[mount]] tables", args.config));
+        }
+        return Ok(vec![args.clone()]);
     };
 
-    tracing_subscriber::fmt().with_env_filter(filter).init();
+    file.mount
+        .iter()
+        .map(|m| {
+            let mut a = args.clone();
+
+            match (&m.source, &m.source_list) {
+                (Some(dir), None) => (a.source_dir, a.source_list) = (Some(dir.clone()), None),
+                (None, Some(list)) => (a.source_dir, a.source_list) = (None, Some(list.clone())),
+                _ => {
+                    return Err(anyhow!(
+                        "[[mount]] for {:?} needs exactly one of source / source_list",
+                        m.mount
+                    ))
+                }
+            }
 
-    if args.mountpoint.metadata().is_err() {
-        return Err(anyhow!(
-            "Mountpoint {:?} does not exist or is not accessible",
-            args.mountpoint
-        ));
+            a.mountpoint = Some(m.mount.clone());
+            a.allow_other = m.allow_other.unwrap_or(a.allow_other);
+            a.visible_to = m.visible_to.clone().unwrap_or(a.visible_to);
+            a.audit_log = m.audit_log.clone().or(a.audit_log);
+            a.only = m.only.clone().unwrap_or(a.only);
+            a.nfs_export = m.nfs_export.unwrap_or(a.nfs_export);
+            a.smb_mode = m.smb_mode.unwrap_or(a.smb_mode);
+            a.form2_policy = m.form2_policy.unwrap_or(a.form2_policy);
+            if m.cd_allow_form2 == Some(true) && m.form2_policy.is_none() {
+                warn!(
+                    "[[mount]] for {:?}: cd_allow_form2 is deprecated; use form2_policy = \"bin\"",
+                    m.mount
+                );
+                a.form2_policy = Form2Policy::Bin;
+            }
+            a.audio_tracks = m.audio_tracks.unwrap_or(a.audio_tracks);
+            a.pregap_audio = m.pregap_audio.unwrap_or(a.pregap_audio);
+            a.audio_byteswap = m.audio_byteswap.unwrap_or(a.audio_byteswap);
+            a.export_subchannel = m.export_subchannel.unwrap_or(a.export_subchannel);
+            a.audio_cd = m.audio_cd.unwrap_or(a.audio_cd);
+            a.tail_policy = m.tail_policy.unwrap_or(a.tail_policy);
+            a.strict = m.strict.unwrap_or(a.strict);
+            a.reproducible = m.reproducible.unwrap_or(a.reproducible);
+            a.probe_isolation = m.probe_isolation.unwrap_or(a.probe_isolation);
+            a.toc = m.toc.unwrap_or(a.toc);
+            a.full_disc_image = m.full_disc_image.unwrap_or(a.full_disc_image);
+            a.merged_iso = m.merged_iso.unwrap_or(a.merged_iso);
+            a.extension_map = m.extension_map.clone().unwrap_or(a.extension_map);
+            a.follow_symlinks = m.follow_symlinks.unwrap_or(a.follow_symlinks);
+            a.skip_hidden = m.skip_hidden.unwrap_or(a.skip_hidden);
+            a.dedupe = m.dedupe.unwrap_or(a.dedupe);
+            a.expose_sources = m.expose_sources.unwrap_or(a.expose_sources);
+            a.title_db = m.title_db.clone().or(a.title_db);
+            a.title_region_dirs = m.title_region_dirs.unwrap_or(a.title_region_dirs);
+            a.console_dirs = m.console_dirs.unwrap_or(a.console_dirs);
+            a.sidecar_dir = m.sidecar_dir.clone().or(a.sidecar_dir);
+            a.layout = m.layout.unwrap_or(a.layout);
+            a.checksum_files = m.checksum_files.or(a.checksum_files);
+            a.name_from_label = m.name_from_label.unwrap_or(a.name_from_label);
+            a.blocks_report = m.blocks_report.unwrap_or(a.blocks_report);
+            a.mtime = m.mtime.unwrap_or(a.mtime);
+            a.attr_refresh = m.attr_refresh.unwrap_or(a.attr_refresh);
+            a.deny_delete_silently = m.deny_delete_silently.unwrap_or(a.deny_delete_silently);
+            a.overlay = m.overlay.clone().or(a.overlay);
+            a.health = m.health.unwrap_or(a.health);
+            a.error_budget = m.error_budget.unwrap_or(a.error_budget);
+            a.quarantine = m.quarantine.unwrap_or(a.quarantine);
+            a.export_index = m.export_index.clone().or(a.export_index);
+            a.import_index = m.import_index.clone().or(a.import_index);
+            a.compressed_view = m.compressed_view.or(a.compressed_view);
+            a.spill_dir = m.spill_dir.clone().unwrap_or(a.spill_dir);
+            a.header_cache = m.header_cache.clone().or(a.header_cache);
+            a.pin_mib = m.pin_mib.unwrap_or(a.pin_mib);
+            a.warmup = m.warmup.unwrap_or(a.warmup);
+            a.maintenance = m.maintenance.clone().unwrap_or(a.maintenance);
+            a.maintenance_workers = m.maintenance_workers.unwrap_or(a.maintenance_workers);
+            a.maintenance_throughput = m.maintenance_throughput.unwrap_or(a.maintenance_throughput);
+            a.max_background = m.max_background.unwrap_or(a.max_background);
+            a.congestion_threshold = m.congestion_threshold.unwrap_or(a.congestion_threshold);
+            a.drop_source_cache = m.drop_source_cache.unwrap_or(a.drop_source_cache);
+            a.max_throughput = m.max_throughput.unwrap_or(a.max_throughput);
+            a.max_handle_throughput = m.max_handle_throughput.unwrap_or(a.max_handle_throughput);
+            a.slow_read_ms = m.slow_read_ms.unwrap_or(a.slow_read_ms);
+            a.trace_reads_every = m.trace_reads_every.or(a.trace_reads_every);
+            a.trace_reads_slower_ms = m.trace_reads_slower_ms.or(a.trace_reads_slower_ms);
+            a.trace_reads_file = m.trace_reads_file.clone().or(a.trace_reads_file);
+            a.hot = m.hot.clone().unwrap_or(a.hot);
+            a.hot_mib = m.hot_mib.unwrap_or(a.hot_mib);
+            Ok(a)
+        })
+        .collect()
+}
+
+/// Check that no mount's source directory is, or lies inside, a mountpoint:
+/// the mount would hide it and re-indexing would read the mount itself.
+/// `mounts` are (source directory, mountpoint) pairs. Returns, per mount,
+/// the (canonical) mountpoints inside its source, which its scans skip.
+fn nested_mountpoints(mounts: &[(Option<&Path>, &Path)]) -> Result<Vec<Vec<PathBuf>>> {
+    let canonical = |p: &Path| fs::canonicalize(p).unwrap_or_else(|_| p.to_path_buf());
+    let points: Vec<PathBuf> = mounts.iter().map(|(_, m)| canonical(m)).collect();
+
+    mounts
+        .iter()
+        .map(|(source, _)| {
+            let Some(dir) = source.filter(|d| !vfs::is_url(d)) else {
+                return Ok(Vec::new());
+            };
+            let source = canonical(dir);
+            if let Some(point) = points.iter().find(|p| source.starts_with(p)) {
+                return Err(anyhow!(
+                    "Source {:?} is the mountpoint {:?} or inside it; mount elsewhere",
+                    dir,
+                    point
+                ));
+            }
+            Ok(points
+                .iter()
+                .filter(|p| p.starts_with(&source))
+                .cloned()
+                .collect())
+        })
+        .collect()
+}
+
+/// Frame cache (entries, bytes): top-level `--config` keys over the flags.
+fn cache_budget(args: &Args, file: Option<&ConfigFile>) -> (usize, usize) {
+    (
+        file.and_then(|f| f.cache_hunks).unwrap_or(args.cache_hunks),
+        file.and_then(|f| f.cache_bytes).unwrap_or(args.cache_bytes),
+    )
+}
+
+/// `max_memory` from `--config`, else `--max-memory`, in bytes.
+fn max_memory(args: &Args, file: Option<&ConfigFile>) -> u64 {
+    file.and_then(|f| f.max_memory)
+        .unwrap_or(args.max_memory)
+        .saturating_mul(1 << 20)
+}
+
+/// `log_level` from `--config`, else `info` with `--verbose`, else `warn`.
+fn log_filter(args: &Args, file: Option<&ConfigFile>) -> String {
+    match file.and_then(|f| f.log_level.clone()) {
+        Some(level) => level,
+        None if args.verbose => "info".into(),
+        None => "warn".into(),
     }
+}
 
-    let mut fs = FsState::new(args)?;
-    fs.build_index()?;
+fn parse_log_filter(directive: &str) -> Result<EnvFilter> {
+    EnvFilter::try_new(directive).with_context(|| format!("invalid log_level {directive:?}"))
+}
 
+/// Mount `fs` with fuser and serve until unmounted.
+fn serve(fs: Arc<FsState>) -> Result<()> {
     let mut config = Config::default();
     config.mount_options = vec![
         MountOption::FSName("chd2iso".into()),
-        MountOption::RO,
         MountOption::DefaultPermissions,
     ];
+    if fs.read_only_mount() {
+        config.mount_options.push(MountOption::RO);
+    }
 
-    if fs.args.allow_other {
+    let allow_other = fs.args().allow_other.resolve(fs.args().mountpoint());
+    if allow_other {
         config.acl = SessionACL::All;
-        config.mount_options.push(MountOption::AutoUnmount);
+        if !fs.args().no_auto_unmount {
+            config.mount_options.push(MountOption::AutoUnmount);
+        }
     }
 
-    info!(
-        "mounting {:?} -> {:?} (entries: {})",
-        fs.args.source_dir,
-        fs.args.mountpoint,
-        fs.entries.len()
-    );
-
-    let mountpoint = fs.args.mountpoint.clone();
-    fuser::mount2(fs, &mountpoint, &config).map_err(|e| anyhow!("mount failed: {e}"))
+    let mountpoint = fs.args().mountpoint().to_path_buf();
+    let mut session = Session::new(ChdFs(Arc::clone(&fs)), &mountpoint, &config).map_err(|e| {
+        anyhow!(
+            "mount failed: {e}{}{}",
+            fusedev::mount_hint(&e),
+            fusedev::allow_other_hint(allow_other)
+        )
+    })?;
+    *fs.notifier.lock().expect("notifier mutex poisoned") = Some(session.notifier());
+
+    // A panicking handler unwinds out of `run`; its request has been answered
+    // with EIO, so pick the loop up again (see `panics`).
+    loop {
+        match panic::catch_unwind(AssertUnwindSafe(|| session.run())) {
+            Ok(res) => return res.map_err(|e| anyhow!("mount failed: {e}")),
+            Err(_) => warn!("{:?}: request handler panicked, still serving", mountpoint),
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[derive(Debug)]
+    struct Empty;
+
+    impl BackingProvider for Empty {
+        fn size(&self) -> u64 {
+            0
+        }
+
+        fn kind(&self) -> ImageKind {
+            ImageKind::Dvd
+        }
+
+        fn read_at(&self, _offset: u64, _buf: &mut [u8]) -> Result<usize> {
+            Ok(0)
+        }
+    }
+
+    fn entry(name: &str, path: &str, sha1: Option<[u8; 20]>) -> IndexEntry {
+        IndexEntry {
+            ino: 0,
+            parent: 1,
+            dir: String::new(),
+            name: name.to_string(),
+            chd_path: PathBuf::from(path),
+            provider: Arc::new(Empty),
+            sha1,
+            layer_break: None,
+            original_name: None,
+            label: None,
+            source_bytes: 0,
+        }
+    }
+
+    #[test]
+    fn parses_mtime_policy() {
+        assert_eq!("source".parse(), Ok(MtimePolicy::Source));
+        assert_eq!("fixed:0".parse(), Ok(MtimePolicy::Fixed(0)));
+        assert_eq!(
+            "fixed:1700000000".parse(),
+            Ok(MtimePolicy::Fixed(1_700_000_000))
+        );
+        assert!("fixed:".parse::<MtimePolicy>().is_err());
+        assert!("chd".parse::<MtimePolicy>().is_err());
+
+        let sha1 = [0x12, 0x34, 0x56, 0x78, 0x9a];
+        let sha1 = std::array::from_fn(|i| sha1[i % 5]);
+        let t = MtimePolicy::Sha1.fixed(Some(&sha1)).unwrap();
+        let secs = t.duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+        assert_eq!(secs, SHA1_MTIME_BASE + (0x1234_5678 % (1 << 28)));
+        assert_eq!(MtimePolicy::Sha1.fixed(None), None);
+        assert_eq!(MtimePolicy::Source.fixed(Some(&sha1)), None);
+    }
+
+    #[test]
+    fn cd_allow_form2_still_means_bin() {
+        let parse = |extra: &[&str]| {
+            let argv = [
+                "chd2iso-fuse",
+                "-s",
+                "/srv/chd",
+                "-m",
+                "/srv/iso",
+                "--cd-allow-form2",
+            ];
+            let mut args = Args::try_parse_from(argv.iter().chain(extra)).unwrap();
+            args.upgrade_deprecated();
+            args.form2_policy
+        };
+        assert_eq!(parse(&[]), Form2Policy::Bin);
+        assert_eq!(parse(&["--form2-policy", "xa-view"]), Form2Policy::XaView);
+    }
+
+    #[test]
+    fn pro_rates_blocks_by_logical_share() {
+        assert_eq!(pro_rate(1000, 700, 700), 1000);
+        assert_eq!(pro_rate(1000, 600, 700), 858);
+        assert_eq!(pro_rate(1000, 100, 700), 143);
+        assert_eq!(pro_rate(1000, 5, 0), 0);
+        assert_eq!(pro_rate(u64::MAX / 512, 8 << 30, 8 << 30), u64::MAX / 512);
+    }
+
+    #[test]
+    fn duplicates_move_to_subdir_and_get_unique_names() {
+        let mut entries = vec![
+            entry("Game.iso", "a/Game.chd", Some([1; 20])),
+            entry("Game.iso", "b/Game.chd", Some([1; 20])),
+            entry("Game.iso", "c/Game.chd", Some([1; 20])),
+            entry("Other.iso", "Other.chd", None),
+        ];
+
+        move_duplicates(&mut entries);
+        disambiguate_names(&mut entries);
+
+        assert_eq!(entries[0].dir, "");
+        assert_eq!(entries[0].name, "Game.iso");
+        assert_eq!(entries[1].dir, DUPLICATES_DIR);
+        assert_eq!(entries[1].name, "Game.iso");
+        assert_eq!(entries[2].dir, DUPLICATES_DIR);
+        assert_eq!(entries[2].name, "Game (2).iso");
+        assert_eq!(entries[3].dir, "");
+    }
+
+    #[test]
+    fn sha1_layout_links_names_to_content() {
+        let mut nested = entry("Copy.iso", "b/Copy.chd", Some([0xab; 20]));
+        nested.dir = "PS2/USA".into();
+        let mut entries = vec![
+            entry("Game.iso", "a/Game.chd", Some([0xab; 20])),
+            nested,
+            entry("Other.iso", "Other.chd", None),
+        ];
+
+        let links = content_address(&mut entries);
+
+        let stored = format!("{}.iso", "ab".repeat(19));
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].dir, "sha1/ab");
+        assert_eq!(entries[0].name, stored);
+        assert_eq!(entries[1].name, "Other.iso");
+        assert_eq!(links.len(), 2);
+        assert_eq!((&*links[0].dir, &*links[0].name), ("", "Game.iso"));
+        assert_eq!(links[0].target, format!("sha1/ab/{stored}"));
+        assert_eq!((&*links[1].dir, &*links[1].name), ("PS2/USA", "Copy.iso"));
+        assert_eq!(links[1].target, format!("../../sha1/ab/{stored}"));
+    }
+
+    #[test]
+    fn sidecars_sit_beside_their_image() {
+        let dir = std::env::temp_dir().join(format!("chd2iso-sidecars-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for name in ["Game.png", "Game.iso", "Old Name.cfg", "Other.png"] {
+            fs::write(dir.join(name), b"x").unwrap();
+        }
+
+        let entries = vec![
+            entry("Game.iso", "Game.chd", None),
+            entry("Ico (USA).iso", "Old Name.chd", None),
+        ];
+        let mut names: Vec<String> = sidecar_entries(&dir, &entries)
+            .into_iter()
+            .map(|e| e.name)
+            .collect();
+        names.sort();
+        assert_eq!(names, ["Game.png", "Ico (USA).cfg"]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
     #[test]
-    fn parse_mode1_track_line() {
-        let line = "TRACK:1 TYPE:MODE1 SUBTYPE:NONE FRAMES:26888 PREGAP:0 PGTYPE:MODE1 PGSUB:RW_RAW POSTGAP:0";
-        let ti = parse_track_line(line).expect("should parse MODE1 track");
+    fn mountpoints_nested_in_sources() {
+        let dir = std::env::temp_dir().join(format!("chd2iso-nested-{}", std::process::id()));
+        let [chd, iso, other, mnt] = ["chd", "chd/iso", "other", "mnt"].map(|d| dir.join(d));
+        for d in [&iso, &other, &mnt] {
+            fs::create_dir_all(d).unwrap();
+        }
+        let nested = |pairs: &[(&PathBuf, &PathBuf)]| {
+            let pairs: Vec<_> = pairs
+                .iter()
+                .map(|(s, m)| (Some(s.as_path()), m.as_path()))
+                .collect();
+            nested_mountpoints(&pairs)
+        };
 
-        assert_eq!(ti.number, 1);
-        assert_eq!(ti.kind, TrackKind::Mode1);
-        assert_eq!(ti.frames, 26888);
-        assert_eq!(ti.pregap, 0);
-        assert_eq!(ti.postgap, 0);
+        assert_eq!(
+            nested(&[(&chd, &iso)]).unwrap(),
+            [[fs::canonicalize(&iso).unwrap()]]
+        );
+        assert!(nested(&[(&chd, &chd)]).is_err());
+        assert!(nested(&[(&iso, &chd)]).is_err());
+        // Other mounts' mountpoints count too.
+        assert!(nested(&[(&chd, &mnt), (&other, &chd)]).is_err());
+        assert_eq!(nested(&[(&chd, &mnt), (&other, &iso)]).unwrap()[0].len(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
     }
 
     #[test]
-    fn parse_mode2_2048_track_line() {
-        let line = "TRACK:2 TYPE:MODE2/2048 FRAMES:1234 PREGAP:5 POSTGAP:6";
-        let ti = parse_track_line(line).expect("should parse MODE2/2048 track");
+    fn dot_lookups_resolve_by_inode() {
+        let mut game = entry("Game.iso", "Game.chd", None);
+        (game.ino, game.parent) = (7, 5);
+        let index = Index {
+            dirs: vec![VirtualDir {
+                ino: 5,
+                parent: 1,
+                path: "DVD".into(),
+                name: "DVD".into(),
+            }],
+            entries: vec![game],
+            links: vec![VirtualLink {
+                ino: 8,
+                parent: 1,
+                dir: String::new(),
+                name: "Game.iso".into(),
+                target: "DVD/Game.iso".into(),
+            }],
+        };
 
-        assert_eq!(ti.number, 2);
-        assert_eq!(ti.kind, TrackKind::Mode2Form1);
-        assert_eq!(ti.frames, 1234);
-        assert_eq!(ti.pregap, 5);
-        assert_eq!(ti.postgap, 6);
+        let ino = |found: Option<Lookup>| match found {
+            Some(Lookup::Dir(ino) | Lookup::Link(ino)) => Some(ino),
+            Some(Lookup::Entry(e)) => Some(e.ino),
+            None => None,
+        };
+        assert_eq!(ino(index.lookup(7, ".")), Some(7));
+        assert_eq!(ino(index.lookup(8, ".")), Some(8));
+        assert_eq!(ino(index.lookup(1, "Game.iso")), Some(8));
+        assert_eq!(ino(index.lookup(5, ".")), Some(5));
+        assert_eq!(ino(index.lookup(5, "..")), Some(1));
+        assert_eq!(ino(index.lookup(1, "..")), Some(1));
+        assert_eq!(ino(index.lookup(9, ".")), None);
     }
 
     #[test]
-    fn parse_mode2_2324_track_line() {
-        let line = "TRACK:3 TYPE:MODE2/2324 FRAMES:567 PREGAP:0 POSTGAP:0";
-        let ti = parse_track_line(line).expect("should parse MODE2/2324 track");
+    fn opl_names_follow_the_loader() {
+        assert_eq!(opl_serial("SLUS-20062").as_deref(), Some("SLUS_200.62"));
+        assert_eq!(opl_serial("SLPM-650512"), None);
+        assert_eq!(
+            opl_name("SLUS_200.62", "Grand Theft Auto - Vice City"),
+            "SLUS_200.62.Grand Theft Auto - Vice City.iso"
+        );
+        assert_eq!(
+            opl_name("SCUS_971.13", "An Overly Long Title That OPL Would Cut"),
+            "SCUS_971.13.An Overly Long Title That OPL Wo.iso"
+        );
+    }
 
-        assert_eq!(ti.number, 3);
-        assert_eq!(ti.kind, TrackKind::Mode2Form2);
-        assert_eq!(ti.frames, 567);
+    /// Read a listing of `names` the way getdents64 does, `per_call`
+    /// entries to a buffer, from `offset`; (offset, name) of each entry.
+    fn list_dir(names: &[String], offset: u64, per_call: usize) -> Vec<(u64, String)> {
+        let listing = || {
+            [
+                (1, FileType::Directory, "."),
+                (1, FileType::Directory, ".."),
+            ]
+            .into_iter()
+            .chain(names.iter().map(|n| (2, FileType::RegularFile, n.as_str())))
+        };
+        let (mut out, mut offset) = (Vec::new(), offset);
+        loop {
+            let mut batch = Vec::new();
+            fill_dir(listing(), offset, |_, next, _, name| {
+                if batch.len() == per_call {
+                    return true;
+                }
+                batch.push((next, name.to_string()));
+                false
+            });
+            let Some(&(last, _)) = batch.last() else {
+                return out;
+            };
+            offset = last;
+            out.extend(batch);
+        }
     }
 
     #[test]
-    fn parse_malformed_track_line() {
-        let line = "TRACK:4 FRAMES:100";
-        assert!(parse_track_line(line).is_none());
+    fn readdir_offsets_resume_and_seek() {
+        let names: Vec<String> = (0..5000).map(|i| format!("Game {i:04}.iso")).collect();
+        let expected: Vec<String> = [".", ".."]
+            .into_iter()
+            .map(String::from)
+            .chain(names.iter().cloned())
+            .collect();
+
+        for per_call in [1, 2, 37, 4096, 10_000] {
+            let listed = list_dir(&names, 0, per_call);
+            let offsets: Vec<u64> = listed.iter().map(|(o, _)| *o).collect();
+            assert_eq!(offsets, (1..=expected.len() as u64).collect::<Vec<_>>());
+            let listed: Vec<String> = listed.into_iter().map(|(_, n)| n).collect();
+            assert_eq!(listed, expected, "{per_call} entries per buffer");
+        }
+
+        // seekdir to an offset handed out earlier, including one between
+        // "." and ".."; rewinddir is offset 0 again.
+        let all = list_dir(&names, 0, 100);
+        for (at, _) in [all[0].clone(), all[1].clone(), all[2500].clone()] {
+            let rest: Vec<String> = list_dir(&names, at, 64)
+                .into_iter()
+                .map(|(_, n)| n)
+                .collect();
+            assert_eq!(rest, expected[at as usize..]);
+        }
+        assert_eq!(list_dir(&names, 0, 64), all);
+        assert!(list_dir(&names, all.len() as u64, 64).is_empty());
+        assert!(list_dir(&names, u64::MAX, 64).is_empty());
     }
 }
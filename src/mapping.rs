@@ -0,0 +1,158 @@
+//! Offset math between the files a mount exposes and the CHD frame and hunk
+//! stream behind them. Kept pure and allocation-free so every mode can be
+//! pinned by golden vectors; an off-by-one here silently corrupts output
+//! rather than failing.
+//!
+//! Golden vectors (CD CHD, 2448-byte frames in 19584-byte hunks, data from
+//! frame 150):
+//!
+//! | payload         | byte offset | frame | byte in frame | hunk | byte in hunk |
+//! |-----------------|-------------|-------|---------------|------|--------------|
+//! | Mode 1 / 2048   | 0           | 150   | 16            | 18   | 14688        |
+//! | Mode 1 / 2048   | 2047        | 150   | 2063          | 18   | 14688        |
+//! | Mode 1 / 2048   | 2048        | 151   | 16            | 18   | 17136        |
+//! | Form 1 / 2048   | 0           | 150   | 24            | 18   | 14688        |
+//! | Form 2 / 2324   | 2324        | 151   | 24            | 18   | 17136        |
+//! | Mode 2 / 2336   | 2336        | 151   | 16            | 18   | 17136        |
+//! | audio / 2352    | 2352 * 2    | 152   | 0             | 19   | 0            |
+
+use crate::chd_image::CdPayloadKind;
+
+/// Where a byte of a CD payload view lies in the frame stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FramePos {
+    /// Frame number in the CHD
+    pub frame: u64,
+    /// Offset of the byte within the frame
+    pub byte: usize,
+    /// Payload bytes from there to the end of the sector
+    pub left: usize,
+}
+
+/// Byte `offset` of a view of `kind` sectors starting at frame
+/// `first_data_lba`.
+pub const fn cd_byte(offset: u64, first_data_lba: u64, kind: CdPayloadKind) -> FramePos {
+    let per_sector = kind.sector_size() as u64;
+    let in_sector = (offset % per_sector) as usize;
+    FramePos {
+        frame: first_data_lba + offset / per_sector,
+        byte: kind.payload_offset() + in_sector,
+        left: kind.sector_size() - in_sector,
+    }
+}
+
+/// Bytes in a view of `frames` sectors of `kind`.
+pub const fn cd_size(frames: u64, kind: CdPayloadKind) -> u64 {
+    frames * kind.sector_size() as u64
+}
+
+/// (hunk number, position within it) of unit `n` (a byte or a frame) with
+/// `per_hunk` units per hunk; None past the u32 hunk numbers of a CHD or
+/// for an empty hunk. In u64 so images past 4 GiB map where usize is 32
+/// bits.
+pub const fn hunk_of(n: u64, per_hunk: u64) -> Option<(u32, u64)> {
+    if per_hunk == 0 || n / per_hunk > u32::MAX as u64 {
+        return None;
+    }
+    Some(((n / per_hunk) as u32, n % per_hunk))
+}
+
+/// (hunk number, byte offset within it) of frame `frame`, with frames of
+/// `frame_stride` bytes packed into hunks of `hunk_size`; None unless a hunk
+/// holds a whole number of frames, as chdman writes them.
+pub const fn frame_in_hunk(
+    frame: u64,
+    frame_stride: usize,
+    hunk_size: usize,
+) -> Option<(u32, usize)> {
+    if frame_stride == 0 || hunk_size % frame_stride != 0 {
+        return None;
+    }
+    match hunk_of(frame, (hunk_size / frame_stride) as u64) {
+        Some((hunk, n)) => Some((hunk, n as usize * frame_stride)),
+        None => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chd_image::{CD_FRAME_2352, CD_FRAME_2448};
+
+    const HUNK: usize = 8 * CD_FRAME_2448;
+
+    /// (payload, byte offset) -> (frame, byte in frame, bytes left, hunk,
+    /// byte in hunk), as in the module docs.
+    #[rustfmt::skip]
+    const GOLDEN: &[(CdPayloadKind, u64, u64, usize, usize, u32, usize)] = &[
+        (CdPayloadKind::Mode1_2048, 0, 150, 16, 2048, 18, 14688),
+        (CdPayloadKind::Mode1_2048, 2047, 150, 2063, 1, 18, 14688),
+        (CdPayloadKind::Mode1_2048, 2048, 151, 16, 2048, 18, 17136),
+        (CdPayloadKind::Mode2Form1_2048, 0, 150, 24, 2048, 18, 14688),
+        (CdPayloadKind::Mode2Form1_2048, 4095, 151, 2071, 1, 18, 17136),
+        (CdPayloadKind::Mode2Form2_2324, 2323, 150, 2347, 1, 18, 14688),
+        (CdPayloadKind::Mode2Form2_2324, 2324, 151, 24, 2324, 18, 17136),
+        (CdPayloadKind::Mode2_2336, 2336, 151, 16, 2336, 18, 17136),
+        (CdPayloadKind::Audio2352, 2352 * 2, 152, 0, 2352, 19, 0),
+        (CdPayloadKind::Subcode96, 95, 150, 2447, 1, 18, 14688),
+    ];
+
+    #[test]
+    fn golden_vectors() {
+        for &(kind, offset, frame, byte, left, hunk, in_hunk) in GOLDEN {
+            let pos = cd_byte(offset, 150, kind);
+            assert_eq!(pos, FramePos { frame, byte, left }, "{kind:?} at {offset}");
+            assert_eq!(
+                frame_in_hunk(pos.frame, CD_FRAME_2448, HUNK),
+                Some((hunk, in_hunk)),
+                "{kind:?} at {offset}"
+            );
+        }
+    }
+
+    #[test]
+    fn every_byte_of_a_sector_stays_in_its_payload() {
+        let kinds = [
+            CdPayloadKind::Mode1_2048,
+            CdPayloadKind::Mode2Form1_2048,
+            CdPayloadKind::Mode2Form2_2324,
+            CdPayloadKind::Mode2_2336,
+            CdPayloadKind::Audio2352,
+            CdPayloadKind::Subcode96,
+        ];
+        for kind in kinds {
+            let size = kind.sector_size();
+            assert!(kind.payload_offset() + size <= CD_FRAME_2448, "{kind:?}");
+            if kind != CdPayloadKind::Subcode96 {
+                assert!(kind.payload_offset() + size <= CD_FRAME_2352, "{kind:?}");
+            }
+            for offset in 0..3 * size as u64 {
+                let pos = cd_byte(offset, 0, kind);
+                let in_sector = (offset % size as u64) as usize;
+                assert_eq!(pos.frame, offset / size as u64);
+                assert_eq!(pos.byte, kind.payload_offset() + in_sector);
+                assert_eq!(pos.byte + pos.left, kind.payload_offset() + size);
+            }
+            assert_eq!(cd_size(3, kind), 3 * size as u64);
+        }
+    }
+
+    #[test]
+    fn hunk_edges() {
+        assert_eq!(hunk_of(0, 4096), Some((0, 0)));
+        assert_eq!(hunk_of(4095, 4096), Some((0, 4095)));
+        assert_eq!(hunk_of(4096, 4096), Some((1, 0)));
+        assert_eq!(hunk_of(5 << 30, 4096), Some((1_310_720, 0)));
+        assert_eq!(hunk_of(u32::MAX as u64, 1), Some((u32::MAX, 0)));
+        assert_eq!(hunk_of(u32::MAX as u64 + 1, 1), None);
+        assert_eq!(hunk_of(1, 0), None);
+
+        assert_eq!(
+            frame_in_hunk(8, CD_FRAME_2352, 8 * CD_FRAME_2352),
+            Some((1, 0))
+        );
+        // A frame would straddle two 19584-byte hunks of 2352-byte frames.
+        assert_eq!(frame_in_hunk(7, CD_FRAME_2352, HUNK), None);
+        assert_eq!(frame_in_hunk(0, CD_FRAME_2448, CD_FRAME_2352), None);
+    }
+}
@@ -0,0 +1,83 @@
+//! Removable sources. When the drive holding `--source` goes away the mount
+//! goes offline: requests fail with `ENODEV` instead of a flood of `EIO`
+//! until a probe finds the drive back and re-indexes.
+
+use std::{
+    os::unix::fs::MetadataExt,
+    path::Path,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// How often an offline mount looks for its drive.
+pub const PROBE_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(Default)]
+pub struct Media {
+    offline: AtomicBool,
+    /// st_dev of the source directory at the last index (0 = unknown)
+    dev: AtomicU64,
+}
+
+impl Media {
+    pub fn is_offline(&self) -> bool {
+        self.offline.load(Ordering::Relaxed)
+    }
+
+    /// Remember the device `root` was indexed on.
+    pub fn indexed(&self, root: &Path) {
+        if let Ok(meta) = root.metadata() {
+            self.dev.store(meta.dev(), Ordering::Relaxed);
+        }
+    }
+
+    /// Whether the drive `root` was indexed on is there. A directory on
+    /// another device counts only if it is a mount point again (the drive
+    /// may come back under a new device number); otherwise it is the bare
+    /// directory the drive was mounted on.
+    pub fn present(&self, root: &Path) -> bool {
+        let Ok(meta) = root.metadata() else {
+            return false;
+        };
+        let dev = self.dev.load(Ordering::Relaxed);
+        if dev == 0 || meta.dev() == dev {
+            return true;
+        }
+
+        root.parent()
+            .and_then(|p| p.metadata().ok())
+            .is_some_and(|p| p.dev() != meta.dev())
+    }
+
+    /// Mark the mount offline; true only for the call that took it there.
+    pub fn go_offline(&self) -> bool {
+        !self.offline.swap(true, Ordering::Relaxed)
+    }
+
+    pub fn go_online(&self) {
+        self.offline.store(false, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_root_is_absent() {
+        let root = std::env::temp_dir().join(format!("chd2iso-media-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+
+        let media = Media::default();
+        media.indexed(&root);
+        assert!(media.present(&root));
+
+        std::fs::remove_dir(&root).unwrap();
+        assert!(!media.present(&root));
+
+        assert!(media.go_offline());
+        assert!(!media.go_offline());
+        media.go_online();
+        assert!(!media.is_offline());
+    }
+}
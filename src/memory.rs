@@ -0,0 +1,202 @@
+//! `--max-memory MIB`: what the process holds in caches and buffers, and a
+//! cap on it for small boards. Every few seconds a thread totals the frame
+//! cache, spare hunk buffers, readahead buffers, pinned heads, `--hot`
+//! titles and the indexes. Over the cap it holds the frame cache to what the
+//! rest leaves, and if that is too little stops readahead and spare hunk
+//! buffers as well, logging each step, until usage is back well below.
+//!
+//! Pinned heads and `--hot` titles are never dropped: the user asked for
+//! them. The total leaves out code, stacks and allocator slack, so the RSS
+//! reported next to it is higher.
+
+use anyhow::Result;
+use std::{
+    fs,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+use tracing::{info, warn};
+
+use crate::{chd_image, handles, provider, FrameCache, FsState};
+
+const INTERVAL: Duration = Duration::from_secs(2);
+/// A frame cache held below this also stops readahead and spare buffers.
+const MIN_CACHE: u64 = 16 << 20;
+/// Caps lift once usage would be this share of the cap, in percent.
+const RELEASE_PERCENT: u64 = 90;
+
+/// `--max-memory` in bytes; 0 = no cap.
+static CAP: AtomicU64 = AtomicU64::new(0);
+static CAPPED: AtomicBool = AtomicBool::new(false);
+
+pub fn set_cap(bytes: u64) {
+    CAP.store(bytes, Ordering::Relaxed);
+}
+
+/// Whether the cap is holding anything back now.
+pub fn capped() -> bool {
+    CAPPED.load(Ordering::Relaxed)
+}
+
+/// Resident set size of the process, from /proc/self/statm.
+pub fn rss() -> Option<u64> {
+    let statm = fs::read_to_string("/proc/self/statm").ok()?;
+    let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    Some(pages * u64::try_from(page).ok()?)
+}
+
+/// Bytes held, by what holds them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Usage {
+    pub frame_cache: u64,
+    pub hunk_buffers: u64,
+    pub readahead: u64,
+    pub pinned: u64,
+    pub hot: u64,
+    pub index: u64,
+}
+
+impl Usage {
+    pub fn total(&self) -> u64 {
+        self.frame_cache + self.besides_cache()
+    }
+
+    fn besides_cache(&self) -> u64 {
+        self.hunk_buffers + self.readahead + self.pinned + self.hot + self.index
+    }
+}
+
+pub fn usage(frame_cache: &FrameCache, mounts: &[Arc<FsState>]) -> Usage {
+    let mut usage = Usage {
+        frame_cache: frame_cache.usage().1 as u64,
+        hunk_buffers: chd_image::spare_hunk_bytes() as u64,
+        pinned: provider::pinned_bytes() as u64,
+        ..Usage::default()
+    };
+    for fs in mounts {
+        usage.readahead += fs.handles.readahead_bytes() as u64;
+        usage.hot += fs.hot.usage().1;
+        usage.index += fs.index_bytes();
+    }
+    usage
+}
+
+/// What the cap calls for.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct Plan {
+    /// Frame cache bytes allowed, if fewer than configured
+    cache: Option<u64>,
+    /// No readahead or spare hunk buffers
+    lean: bool,
+}
+
+/// The plan for `usage` under `cap` with a frame cache configured for
+/// `cache_budget` bytes, after `prev`.
+fn plan(cap: u64, usage: &Usage, cache_budget: u64, prev: Plan) -> Plan {
+    let others = usage.besides_cache();
+    let limit = match prev.cache {
+        Some(_) => cap / 100 * RELEASE_PERCENT,
+        None => cap,
+    };
+    if others + cache_budget <= limit {
+        return Plan::default();
+    }
+    let room = cap.saturating_sub(others);
+    let min = if prev.lean { 2 * MIN_CACHE } else { MIN_CACHE };
+    Plan {
+        cache: Some(room.min(cache_budget)),
+        lean: room < min,
+    }
+}
+
+/// Check usage against `--max-memory` from a background thread for as
+/// long as the process runs.
+pub fn govern(frame_cache: Arc<FrameCache>, mounts: Vec<Arc<FsState>>) -> Result<()> {
+    thread::Builder::new()
+        .name("memory".into())
+        .spawn(move || {
+            let mut prev = Plan::default();
+            loop {
+                thread::sleep(INTERVAL);
+                let cap = CAP.load(Ordering::Relaxed);
+                let usage = usage(&frame_cache, &mounts);
+                let next = match cap {
+                    0 => Plan::default(),
+                    _ => plan(cap, &usage, frame_cache.max_bytes() as u64, prev),
+                };
+                if next != prev {
+                    report(cap, &usage, prev, next);
+                    frame_cache.cap(next.cache.map(|b| b as usize));
+                    chd_image::keep_spare_hunks(!next.lean);
+                    handles::set_readahead(!next.lean);
+                    CAPPED.store(next.cache.is_some(), Ordering::Relaxed);
+                    prev = next;
+                }
+            }
+        })?;
+    Ok(())
+}
+
+fn report(cap: u64, usage: &Usage, prev: Plan, next: Plan) {
+    let mib = |b: u64| b >> 20;
+    match (prev.cache, next.cache) {
+        (None, Some(cache)) => warn!(
+            "memory: {} MiB in caches and buffers; frame cache held to {} MiB to stay under \
+             --max-memory {} MiB",
+            mib(usage.total()),
+            mib(cache),
+            mib(cap)
+        ),
+        (Some(_), None) => info!("memory: back under --max-memory, frame cache as configured"),
+        _ => {}
+    }
+    match (prev.lean, next.lean) {
+        (false, true) => warn!(
+            "memory: readahead and spare hunk buffers off to stay under --max-memory {} MiB; \
+             sequential reads will be slower",
+            mib(cap)
+        ),
+        (true, false) => info!("memory: readahead and spare hunk buffers on again"),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn caps_cache_then_readahead_and_lifts_with_margin() {
+        const MIB: u64 = 1 << 20;
+        let cap = 256 * MIB;
+        let usage = |others| Usage {
+            index: others,
+            ..Usage::default()
+        };
+
+        let free = plan(cap, &usage(100 * MIB), 128 * MIB, Plan::default());
+        assert_eq!(free, Plan::default());
+
+        let held = plan(cap, &usage(200 * MIB), 128 * MIB, free);
+        assert_eq!(held.cache, Some(56 * MIB));
+        assert!(!held.lean);
+
+        let lean = plan(cap, &usage(250 * MIB), 128 * MIB, held);
+        assert_eq!(lean.cache, Some(6 * MIB));
+        assert!(lean.lean);
+
+        // Under the cap, but not by the margin: stays held.
+        assert!(plan(cap, &usage(120 * MIB), 128 * MIB, held)
+            .cache
+            .is_some());
+        assert_eq!(
+            plan(cap, &usage(90 * MIB), 128 * MIB, held),
+            Plan::default()
+        );
+    }
+}
@@ -0,0 +1,478 @@
+//! `--otlp-endpoint URL`: export spans of client reads, source opens and
+//! index builds, and the counters of D-Bus `Stats`, to an OpenTelemetry
+//! collector over OTLP/HTTP with JSON encoding, every `--otlp-interval`.
+//! Each span is its own trace, so a collector shows where a stutter went
+//! (source I/O, hunk decodes, frame cache) next to the emulator's own.
+//!
+//! Spans are queued here whether or not the exporter is built, as a no-op
+//! until it is started; the exporter itself needs the `otel` feature.
+
+#![cfg_attr(not(feature = "otel"), allow(dead_code))]
+
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+/// Spans held between exports; later ones are dropped.
+const MAX_QUEUED: usize = 4096;
+/// Counters exported as cumulative sums; the rest are gauges.
+const MONOTONIC: &[&str] = &["reads", "bytes_read"];
+const SPAN_KIND_SERVER: u8 = 2;
+const STATUS_OK: u8 = 1;
+const STATUS_ERROR: u8 = 2;
+const TEMPORALITY_CUMULATIVE: u8 = 2;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static QUEUE: Mutex<Vec<Recorded>> = Mutex::new(Vec::new());
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// A span attribute value.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Attr {
+    Str(String),
+    Int(i64),
+}
+
+/// A finished operation waiting for export.
+#[derive(Debug)]
+struct Recorded {
+    name: &'static str,
+    start: SystemTime,
+    took: Duration,
+    ok: bool,
+    attrs: Vec<(&'static str, Attr)>,
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Queue a span for `name` that began at `start` and took `took`.
+pub fn span(
+    name: &'static str,
+    start: SystemTime,
+    took: Duration,
+    ok: bool,
+    attrs: Vec<(&'static str, Attr)>,
+) {
+    if !enabled() {
+        return;
+    }
+    let mut queue = QUEUE.lock().expect("otel queue poisoned");
+    if queue.len() >= MAX_QUEUED {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+    queue.push(Recorded {
+        name,
+        start,
+        took,
+        ok,
+        attrs,
+    });
+}
+
+/// Run `op` and, when exporting, queue a span for it with `attrs`.
+pub fn timed<T, E>(
+    name: &'static str,
+    attrs: impl FnOnce() -> Vec<(&'static str, Attr)>,
+    op: impl FnOnce() -> Result<T, E>,
+) -> Result<T, E> {
+    if !enabled() {
+        return op();
+    }
+    let start = SystemTime::now();
+    let t = Instant::now();
+    let res = op();
+    span(name, start, t.elapsed(), res.is_ok(), attrs());
+    res
+}
+
+fn take() -> Vec<Recorded> {
+    std::mem::take(&mut *QUEUE.lock().expect("otel queue poisoned"))
+}
+
+/// 64 bits that differ for every call in this process and between
+/// processes: a counter through splitmix64, seeded by the pid and clock.
+fn next_id() -> u64 {
+    let seed = u64::from(std::process::id()) << 32 ^ unix_nanos(SystemTime::now());
+    let mut z = seed.wrapping_add(
+        NEXT_ID
+            .fetch_add(1, Ordering::Relaxed)
+            .wrapping_mul(0x9E37_79B9_7F4A_7C15),
+    );
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+fn unix_nanos(t: SystemTime) -> u64 {
+    t.duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos() as u64)
+}
+
+// OTLP/JSON (opentelemetry-proto, JSON mapping): 64-bit integers are
+// strings, ids are hex.
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TracesRequest {
+    resource_spans: Vec<ResourceSpans>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ResourceSpans {
+    resource: Resource,
+    scope_spans: Vec<ScopeSpans>,
+}
+
+#[derive(Serialize)]
+struct Resource {
+    attributes: Vec<KeyValue>,
+}
+
+#[derive(Serialize)]
+struct Scope {
+    name: &'static str,
+    version: &'static str,
+}
+
+#[derive(Serialize)]
+struct ScopeSpans {
+    scope: Scope,
+    spans: Vec<Span>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Span {
+    trace_id: String,
+    span_id: String,
+    name: &'static str,
+    kind: u8,
+    start_time_unix_nano: String,
+    end_time_unix_nano: String,
+    attributes: Vec<KeyValue>,
+    status: Status,
+}
+
+#[derive(Serialize)]
+struct Status {
+    code: u8,
+}
+
+#[derive(Serialize)]
+struct KeyValue {
+    key: String,
+    value: AnyValue,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+enum AnyValue {
+    StringValue(String),
+    IntValue(String),
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct MetricsRequest {
+    resource_metrics: Vec<ResourceMetrics>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ResourceMetrics {
+    resource: Resource,
+    scope_metrics: Vec<ScopeMetrics>,
+}
+
+#[derive(Serialize)]
+struct ScopeMetrics {
+    scope: Scope,
+    metrics: Vec<Metric>,
+}
+
+#[derive(Serialize)]
+struct Metric {
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    gauge: Option<Gauge>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sum: Option<Sum>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Gauge {
+    data_points: Vec<DataPoint>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Sum {
+    data_points: Vec<DataPoint>,
+    aggregation_temporality: u8,
+    is_monotonic: bool,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct DataPoint {
+    attributes: Vec<KeyValue>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    start_time_unix_nano: Option<String>,
+    time_unix_nano: String,
+    as_int: String,
+}
+
+fn key_value(key: &str, value: &Attr) -> KeyValue {
+    KeyValue {
+        key: key.to_string(),
+        value: match value {
+            Attr::Str(s) => AnyValue::StringValue(s.clone()),
+            Attr::Int(n) => AnyValue::IntValue(n.to_string()),
+        },
+    }
+}
+
+fn resource() -> Resource {
+    Resource {
+        attributes: vec![key_value(
+            "service.name",
+            &Attr::Str(env!("CARGO_PKG_NAME").into()),
+        )],
+    }
+}
+
+fn scope() -> Scope {
+    Scope {
+        name: env!("CARGO_PKG_NAME"),
+        version: env!("CARGO_PKG_VERSION"),
+    }
+}
+
+fn traces(recorded: &[Recorded]) -> TracesRequest {
+    let spans = recorded
+        .iter()
+        .map(|r| {
+            let start = unix_nanos(r.start);
+            Span {
+                trace_id: format!("{:016x}{:016x}", next_id(), next_id()),
+                span_id: format!("{:016x}", next_id()),
+                name: r.name,
+                kind: SPAN_KIND_SERVER,
+                start_time_unix_nano: start.to_string(),
+                end_time_unix_nano: (start + r.took.as_nanos() as u64).to_string(),
+                attributes: r.attrs.iter().map(|(k, v)| key_value(k, v)).collect(),
+                status: Status {
+                    code: if r.ok { STATUS_OK } else { STATUS_ERROR },
+                },
+            }
+        })
+        .collect();
+    TracesRequest {
+        resource_spans: vec![ResourceSpans {
+            resource: resource(),
+            scope_spans: vec![ScopeSpans {
+                scope: scope(),
+                spans,
+            }],
+        }],
+    }
+}
+
+/// One metric per counter name, a data point per mount.
+fn metrics(stats: &[(String, BTreeMap<String, u64>)], started: SystemTime) -> MetricsRequest {
+    let now = unix_nanos(SystemTime::now()).to_string();
+    let mut by_name: BTreeMap<&str, Vec<DataPoint>> = BTreeMap::new();
+    for (mount, counters) in stats {
+        for (name, value) in counters {
+            let monotonic = MONOTONIC.contains(&name.as_str());
+            by_name.entry(name).or_default().push(DataPoint {
+                attributes: vec![key_value("mount", &Attr::Str(mount.clone()))],
+                start_time_unix_nano: monotonic.then(|| unix_nanos(started).to_string()),
+                time_unix_nano: now.clone(),
+                as_int: value.to_string(),
+            });
+        }
+    }
+
+    let mut metrics: Vec<Metric> = by_name
+        .into_iter()
+        .map(|(name, data_points)| {
+            let (gauge, sum) = if MONOTONIC.contains(&name) {
+                let sum = Sum {
+                    data_points,
+                    aggregation_temporality: TEMPORALITY_CUMULATIVE,
+                    is_monotonic: true,
+                };
+                (None, Some(sum))
+            } else {
+                (Some(Gauge { data_points }), None)
+            };
+            Metric {
+                name: format!("chd2iso.{name}"),
+                gauge,
+                sum,
+            }
+        })
+        .collect();
+    metrics.push(Metric {
+        name: "chd2iso.otel_spans_dropped".into(),
+        gauge: None,
+        sum: Some(Sum {
+            data_points: vec![DataPoint {
+                attributes: Vec::new(),
+                start_time_unix_nano: Some(unix_nanos(started).to_string()),
+                time_unix_nano: now,
+                as_int: DROPPED.load(Ordering::Relaxed).to_string(),
+            }],
+            aggregation_temporality: TEMPORALITY_CUMULATIVE,
+            is_monotonic: true,
+        }),
+    });
+
+    MetricsRequest {
+        resource_metrics: vec![ResourceMetrics {
+            resource: resource(),
+            scope_metrics: vec![ScopeMetrics {
+                scope: scope(),
+                metrics,
+            }],
+        }],
+    }
+}
+
+#[cfg(feature = "otel")]
+pub use exporter::serve;
+
+#[cfg(feature = "otel")]
+mod exporter {
+    use anyhow::Result;
+    use serde::Serialize;
+    use std::{
+        sync::{atomic::Ordering, Arc},
+        thread,
+        time::{Duration, SystemTime},
+    };
+    use tracing::{info, warn};
+
+    use super::{metrics, take, traces, ENABLED};
+    use crate::FsState;
+
+    /// Start queuing spans and export them and the counters of `mounts` to
+    /// the collector at `endpoint` every `interval`.
+    pub fn serve(endpoint: &str, interval: Duration, mounts: Vec<Arc<FsState>>) -> Result<()> {
+        let agent = ureq::AgentBuilder::new()
+            .timeout(Duration::from_secs(10))
+            .user_agent(concat!(
+                env!("CARGO_PKG_NAME"),
+                "/",
+                env!("CARGO_PKG_VERSION")
+            ))
+            .build();
+        let base = endpoint.trim_end_matches('/').to_string();
+        let started = SystemTime::now();
+        ENABLED.store(true, Ordering::Relaxed);
+        info!("OTLP: exporting to {} every {:?}", base, interval);
+
+        thread::Builder::new().name("otlp".into()).spawn(move || {
+            let mut failing = false;
+            loop {
+                thread::sleep(interval);
+                let spans = take();
+                let stats: Vec<_> = mounts
+                    .iter()
+                    .map(|fs| (fs.args().mountpoint().display().to_string(), fs.stats()))
+                    .collect();
+
+                let mut res = post(
+                    &agent,
+                    &format!("{base}/v1/metrics"),
+                    &metrics(&stats, started),
+                );
+                if res.is_ok() && !spans.is_empty() {
+                    res = post(&agent, &format!("{base}/v1/traces"), &traces(&spans));
+                }
+                match res {
+                    Err(e) if !failing => {
+                        warn!("OTLP: export to {} failed: {:#}", base, e);
+                        failing = true;
+                    }
+                    Ok(()) if failing => {
+                        info!("OTLP: exporting to {} again", base);
+                        failing = false;
+                    }
+                    _ => {}
+                }
+            }
+        })?;
+        Ok(())
+    }
+
+    fn post(agent: &ureq::Agent, url: &str, body: &impl Serialize) -> Result<()> {
+        agent
+            .post(url)
+            .set("Content-Type", "application/json")
+            .send_bytes(&serde_json::to_vec(body)?)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counters_group_by_name_and_ids_differ() {
+        let stats = vec![
+            (
+                "/a".to_string(),
+                BTreeMap::from([("reads".into(), 5), ("entries".into(), 2)]),
+            ),
+            ("/b".to_string(), BTreeMap::from([("reads".into(), 7)])),
+        ];
+        let req = metrics(&stats, SystemTime::UNIX_EPOCH);
+        let metrics = &req.resource_metrics[0].scope_metrics[0].metrics;
+        let names: Vec<&str> = metrics.iter().map(|m| &*m.name).collect();
+        assert_eq!(
+            names,
+            [
+                "chd2iso.entries",
+                "chd2iso.reads",
+                "chd2iso.otel_spans_dropped"
+            ]
+        );
+        assert!(metrics[0].gauge.is_some());
+        let reads = metrics[1].sum.as_ref().unwrap();
+        assert_eq!(reads.data_points.len(), 2);
+        assert_eq!(reads.data_points[1].as_int, "7");
+        assert_eq!(
+            reads.data_points[0].start_time_unix_nano.as_deref(),
+            Some("0")
+        );
+
+        let recorded = [Recorded {
+            name: "read",
+            start: SystemTime::UNIX_EPOCH + Duration::from_secs(1),
+            took: Duration::from_micros(1500),
+            ok: false,
+            attrs: vec![("offset", Attr::Int(4096))],
+        }];
+        let req = traces(&recorded);
+        let span = &req.resource_spans[0].scope_spans[0].spans[0];
+        assert_eq!(span.end_time_unix_nano, "1001500000");
+        assert_eq!(span.status.code, STATUS_ERROR);
+        assert_eq!(span.trace_id.len(), 32);
+        assert_ne!(next_id(), next_id());
+    }
+}
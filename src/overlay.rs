@@ -0,0 +1,156 @@
+//! `--overlay DIR`: a writable layer for sidecar files (emulator configs,
+//! artwork, save metadata) next to the read-only images. A file written as
+//! `sub/dir/Name.cfg` in the mount is stored as `DIR/sub/dir/Name.cfg`.
+//! Names of index entries, and names with an image extension, cannot be
+//! created here, so images always come from the index.
+
+use anyhow::{Context, Result};
+use std::{
+    collections::HashMap,
+    ffi::{OsStr, OsString},
+    fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+/// Overlay inodes are allocated from here up, far above the index's.
+const FIRST_INO: u64 = 1 << 62;
+
+/// Extensions the index serves; never stored in the overlay.
+const IMAGE_EXTENSIONS: &[&str] = &[
+    "bin", "chd", "cso", "cue", "gz", "iso", "sub", "toc", "wav", "zso",
+];
+
+#[derive(Default)]
+struct Inodes {
+    by_name: HashMap<(u64, OsString), u64>,
+    by_ino: HashMap<u64, (u64, OsString)>,
+    next: u64,
+}
+
+pub struct Overlay {
+    root: PathBuf,
+    inodes: Mutex<Inodes>,
+}
+
+impl Overlay {
+    pub fn new(root: &Path) -> Result<Self> {
+        fs::create_dir_all(root).with_context(|| format!("creating overlay {root:?}"))?;
+        Ok(Self {
+            root: root.to_path_buf(),
+            inodes: Mutex::new(Inodes {
+                next: FIRST_INO,
+                ..Inodes::default()
+            }),
+        })
+    }
+
+    fn inodes(&self) -> std::sync::MutexGuard<'_, Inodes> {
+        self.inodes.lock().expect("overlay inodes mutex poisoned")
+    }
+
+    pub fn owns(ino: u64) -> bool {
+        ino >= FIRST_INO
+    }
+
+    /// Whether a file called `name` may be created in the overlay.
+    pub fn allows(name: &OsStr) -> bool {
+        let name = Path::new(name);
+        let image = name
+            .extension()
+            .and_then(OsStr::to_str)
+            .is_some_and(|ext| IMAGE_EXTENSIONS.contains(&ext.to_ascii_lowercase().as_str()));
+        !image && !name.to_string_lossy().contains('/')
+    }
+
+    /// On-disk path of `name` in the virtual directory `dir` ("" = root).
+    pub fn path_in(&self, dir: &str, name: &OsStr) -> PathBuf {
+        self.root.join(dir).join(name)
+    }
+
+    /// Inode of `name` in directory `parent`, allocated on first use.
+    pub fn ino(&self, parent: u64, name: &OsStr) -> u64 {
+        let mut inodes = self.inodes();
+        let key = (parent, name.to_os_string());
+        if let Some(&ino) = inodes.by_name.get(&key) {
+            return ino;
+        }
+
+        let ino = inodes.next;
+        inodes.next += 1;
+        inodes.by_name.insert(key.clone(), ino);
+        inodes.by_ino.insert(ino, key);
+        ino
+    }
+
+    /// (directory inode, name) of overlay inode `ino`.
+    pub fn name_of(&self, ino: u64) -> Option<(u64, OsString)> {
+        self.inodes().by_ino.get(&ino).cloned()
+    }
+
+    /// Keep the inode of a renamed file, dropping whatever `to` had.
+    pub fn rename(&self, from: (u64, &OsStr), to: (u64, &OsStr)) {
+        let mut inodes = self.inodes();
+        let to = (to.0, to.1.to_os_string());
+        if let Some(old) = inodes.by_name.remove(&to) {
+            inodes.by_ino.remove(&old);
+        }
+        if let Some(ino) = inodes.by_name.remove(&(from.0, from.1.to_os_string())) {
+            inodes.by_name.insert(to.clone(), ino);
+            inodes.by_ino.insert(ino, to);
+        }
+    }
+
+    pub fn forget(&self, parent: u64, name: &OsStr) {
+        let mut inodes = self.inodes();
+        if let Some(ino) = inodes.by_name.remove(&(parent, name.to_os_string())) {
+            inodes.by_ino.remove(&ino);
+        }
+    }
+
+    /// Files stored for the virtual directory `dir`.
+    pub fn list(&self, dir: &str) -> Vec<OsString> {
+        let Ok(rd) = fs::read_dir(self.root.join(dir)) else {
+            return Vec::new();
+        };
+
+        let mut names: Vec<OsString> = rd
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_type().is_ok_and(|t| t.is_file()))
+            .map(|e| e.file_name())
+            .collect();
+        names.sort();
+        names
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuses_image_names() {
+        assert!(Overlay::allows(OsStr::new("Game.cfg")));
+        assert!(Overlay::allows(OsStr::new("Game.png")));
+        assert!(!Overlay::allows(OsStr::new("Game.iso")));
+        assert!(!Overlay::allows(OsStr::new("Game (Track 02).BIN")));
+        assert!(!Overlay::allows(OsStr::new("Game.cue")));
+    }
+
+    #[test]
+    fn inodes_are_stable_until_forgotten() {
+        let root = std::env::temp_dir().join(format!("chd2iso-overlay-{}", std::process::id()));
+        let overlay = Overlay::new(&root).unwrap();
+
+        let a = overlay.ino(1, OsStr::new("a.cfg"));
+        assert!(Overlay::owns(a));
+        assert_eq!(overlay.ino(1, OsStr::new("a.cfg")), a);
+        assert_eq!(overlay.name_of(a), Some((1, OsString::from("a.cfg"))));
+
+        overlay.forget(1, OsStr::new("a.cfg"));
+        assert_eq!(overlay.name_of(a), None);
+        assert_ne!(overlay.ino(1, OsStr::new("a.cfg")), a);
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}
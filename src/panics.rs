@@ -0,0 +1,62 @@
+//! Panics in request handlers. With the sync backend a panic unwinds out of
+//! the session loop; fuser answers the request being served with `EIO` as its
+//! reply is dropped, and `serve` resumes the loop. Every panic is logged and
+//! counted here, and past `MAX_PANICS` the process unmounts every mount and
+//! exits: a mount that keeps panicking (a poisoned lock, say) is better
+//! restarted by its supervisor than left half-working.
+
+use std::{
+    panic,
+    path::PathBuf,
+    process,
+    sync::atomic::{AtomicU32, Ordering},
+};
+use tracing::error;
+
+use crate::fusedev;
+
+/// Panics tolerated before giving up.
+const MAX_PANICS: u32 = 3;
+
+/// Exit status after too many panics (EX_SOFTWARE).
+const EXIT_STATUS: i32 = 70;
+
+static PANICS: AtomicU32 = AtomicU32::new(0);
+
+/// Log and count every panic; once there are more than `MAX_PANICS`,
+/// lazily unmount `mountpoints` and exit with `EXIT_STATUS`.
+pub fn install(mountpoints: Vec<PathBuf>) {
+    panic::set_hook(Box::new(move |info| {
+        let thread = std::thread::current();
+        error!("thread {:?} {}", thread.name().unwrap_or("<unnamed>"), info);
+
+        let n = PANICS.fetch_add(1, Ordering::SeqCst) + 1;
+        if !over_limit(n) {
+            return;
+        }
+
+        error!("{} panics, unmounting and exiting", n);
+        for m in &mountpoints {
+            if let Err(e) = fusedev::unmount(m, true) {
+                error!("unmount {:?}: {}", m, e);
+            }
+        }
+        process::exit(EXIT_STATUS);
+    }));
+}
+
+fn over_limit(panics: u32) -> bool {
+    panics > MAX_PANICS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tolerates_up_to_the_limit() {
+        assert!(!over_limit(1));
+        assert!(!over_limit(MAX_PANICS));
+        assert!(over_limit(MAX_PANICS + 1));
+    }
+}
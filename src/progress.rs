@@ -0,0 +1,240 @@
+//! `--progress` of the long-running subcommands (`check`, `cat`): a bar on
+//! stderr for people, or JSON lines on stderr for frontends and scripts,
+//! with an ETA from the bytes done so far and one line per finished file.
+
+use clap::ValueEnum;
+use serde::Serialize;
+use std::{
+    io::{self, IsTerminal, Write},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// Updates are written at most this often; file lines always are.
+const INTERVAL: Duration = Duration::from_millis(250);
+const BAR_WIDTH: usize = 30;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum ProgressMode {
+    /// Nothing beyond the command's own output
+    #[default]
+    None,
+    /// A progress bar, redrawn in place
+    Bar,
+    /// One JSON object per line
+    Json,
+}
+
+#[derive(Serialize)]
+struct Event<'a> {
+    event: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    file: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'a str>,
+    bytes_done: u64,
+    bytes_total: u64,
+    files_done: usize,
+    files_total: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    eta_secs: Option<u64>,
+    elapsed_secs: u64,
+}
+
+pub struct Progress {
+    mode: ProgressMode,
+    bytes_total: u64,
+    files_total: usize,
+    bytes_done: AtomicU64,
+    files_done: AtomicUsize,
+    start: Instant,
+    /// Last update written, and what the bar shows as current
+    last: Mutex<(Option<Instant>, String)>,
+}
+
+impl Progress {
+    /// `bar` falls back to none when stderr is not a terminal.
+    pub fn new(mode: ProgressMode, files_total: usize, bytes_total: u64) -> Self {
+        let mode = match mode {
+            ProgressMode::Bar if !io::stderr().is_terminal() => ProgressMode::None,
+            m => m,
+        };
+        Self {
+            mode,
+            bytes_total,
+            files_total,
+            bytes_done: AtomicU64::new(0),
+            files_done: AtomicUsize::new(0),
+            start: Instant::now(),
+            last: Mutex::new((None, String::new())),
+        }
+    }
+
+    /// `bytes` more of `file` are done.
+    pub fn advance(&self, file: &str, bytes: u64) {
+        self.bytes_done.fetch_add(bytes, Ordering::Relaxed);
+        if self.mode == ProgressMode::None {
+            return;
+        }
+        let mut last = self.last.lock().expect("progress mutex poisoned");
+        if last.0.is_some_and(|t| t.elapsed() < INTERVAL) {
+            return;
+        }
+        *last = (Some(Instant::now()), file.to_string());
+        self.write("progress", Some(file), None, None, &last.1);
+    }
+
+    /// `file` is done with `status` ("ok", "failed", ...); `line` is the
+    /// command's own output for it, printed to stdout around the bar.
+    pub fn finish_file(&self, file: &str, status: &str, error: Option<&str>, line: &str) {
+        self.files_done.fetch_add(1, Ordering::Relaxed);
+        let last = self.last.lock().expect("progress mutex poisoned");
+        if self.mode == ProgressMode::Bar {
+            eprint!("\r\x1b[K");
+        }
+        if !line.is_empty() {
+            println!("{line}");
+        }
+        match self.mode {
+            ProgressMode::None => {}
+            ProgressMode::Bar => self.write("progress", None, None, None, &last.1),
+            ProgressMode::Json => self.write("file", Some(file), Some(status), error, &last.1),
+        }
+    }
+
+    /// The final update; ends the bar's line.
+    pub fn done(&self) {
+        let last = self.last.lock().expect("progress mutex poisoned");
+        self.write("done", None, None, None, &last.1);
+        if self.mode == ProgressMode::Bar {
+            eprintln!();
+        }
+    }
+
+    fn write(
+        &self,
+        event: &'static str,
+        file: Option<&str>,
+        status: Option<&str>,
+        error: Option<&str>,
+        current: &str,
+    ) {
+        let bytes_done = self.bytes_done.load(Ordering::Relaxed);
+        let elapsed = self.start.elapsed();
+        let eta = eta(elapsed, bytes_done, self.bytes_total);
+        let mut err = io::stderr().lock();
+        match self.mode {
+            ProgressMode::None => {}
+            ProgressMode::Bar => {
+                let _ = write!(
+                    err,
+                    "\r\x1b[K{} {}/{} files  {}/{}  ETA {}  {}",
+                    bar(bytes_done, self.bytes_total),
+                    self.files_done.load(Ordering::Relaxed),
+                    self.files_total,
+                    human_bytes(bytes_done),
+                    human_bytes(self.bytes_total),
+                    eta.map_or_else(|| "--".into(), human_duration),
+                    current
+                );
+            }
+            ProgressMode::Json => {
+                let line = serde_json::to_string(&Event {
+                    event,
+                    file,
+                    status,
+                    error,
+                    bytes_done,
+                    bytes_total: self.bytes_total,
+                    files_done: self.files_done.load(Ordering::Relaxed),
+                    files_total: self.files_total,
+                    eta_secs: eta.map(|d| d.as_secs()),
+                    elapsed_secs: elapsed.as_secs(),
+                });
+                if let Ok(line) = line {
+                    let _ = writeln!(err, "{line}");
+                }
+            }
+        }
+        let _ = err.flush();
+    }
+}
+
+/// Time left at the rate so far; None until something is done.
+fn eta(elapsed: Duration, done: u64, total: u64) -> Option<Duration> {
+    if done == 0 {
+        return None;
+    }
+    let left = total.saturating_sub(done) as f64;
+    Some(Duration::from_secs_f64(
+        elapsed.as_secs_f64() * left / done as f64,
+    ))
+}
+
+fn bar(done: u64, total: u64) -> String {
+    let frac = if total == 0 {
+        1.0
+    } else {
+        (done as f64 / total as f64).min(1.0)
+    };
+    let filled = (frac * BAR_WIDTH as f64) as usize;
+    format!(
+        "[{}{}] {:3.0}%",
+        "#".repeat(filled),
+        " ".repeat(BAR_WIDTH - filled),
+        frac * 100.0
+    )
+}
+
+fn human_bytes(n: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut v = n as f64;
+    let mut unit = 0;
+    while v >= 1024.0 && unit + 1 < UNITS.len() {
+        v /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{n} B")
+    } else {
+        format!("{v:.1} {}", UNITS[unit])
+    }
+}
+
+fn human_duration(d: Duration) -> String {
+    let s = d.as_secs();
+    match s {
+        0..=59 => format!("{s}s"),
+        60..=3599 => format!("{}m{:02}s", s / 60, s % 60),
+        _ => format!("{}h{:02}m", s / 3600, s % 3600 / 60),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn eta_and_bar_formatting() {
+        assert_eq!(eta(Duration::from_secs(10), 0, 100), None);
+        assert_eq!(
+            eta(Duration::from_secs(10), 25, 100),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(eta(Duration::from_secs(10), 100, 100), Some(Duration::ZERO));
+
+        assert_eq!(
+            bar(1, 2),
+            format!("[{}{}]  50%", "#".repeat(15), " ".repeat(15))
+        );
+        assert_eq!(human_bytes(512), "512 B");
+        assert_eq!(human_bytes(3 << 29), "1.5 GiB");
+        assert_eq!(human_duration(Duration::from_secs(192)), "3m12s");
+        assert_eq!(human_duration(Duration::from_secs(7260)), "2h01m");
+    }
+}
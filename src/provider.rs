@@ -0,0 +1,1274 @@
+//! Backing providers and the registry that picks one for each source file.
+//!
+//! Each provider exposes a decompressed image stream through
+//! [`BackingProvider::read_at`], so the FUSE read path does not need to know
+//! the container format. Factories in the [`Registry`] are tried in order;
+//! the first whose [`ProviderFactory::matches`] accepts a path probes it.
+
+use anyhow::{anyhow, Context, Result};
+use flate2::read::{DeflateDecoder, MultiGzDecoder};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    fs::{self, File},
+    io::{self, Read},
+    os::unix::fs::FileExt,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard, OnceLock,
+    },
+    thread,
+};
+use tracing::{debug, info, warn};
+
+use crate::{
+    chd_image::{AudioCdMode, ChdFactory, Form2Policy, FrameCache, TailPolicy},
+    md5, sha1,
+    vfs::{self, RangedRead},
+};
+
+pub trait BackingProvider: Send + Sync + fmt::Debug {
+    /// Size of the exposed image in bytes.
+    fn size(&self) -> u64;
+
+    /// What the stream holds; decides which derived views apply.
+    fn kind(&self) -> ImageKind;
+
+    /// Fill `buf` from `offset`; returns bytes read (0 at or past the end).
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize>;
+
+    /// Do now what the first read would, calling `pace` with the bytes of
+    /// each read it makes (`--maintenance hash`). False if there is nothing
+    /// to do.
+    fn prepare(&self, _pace: &dyn Fn(usize)) -> Result<bool> {
+        Ok(false)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageKind {
+    /// 2048-byte DVD CHD passthrough
+    Dvd,
+    /// User data of a CD's first data track
+    Cd,
+    /// Raw 2352-byte CDDA frames of one audio track
+    Audio,
+    /// CHD with an unrecognized unit size, passed through as-is
+    Raw,
+    /// 2048-byte ISO decoded from another container (CSO/ZSO/gzip)
+    Decoded,
+    /// Container view synthesized over another provider
+    View,
+    /// Original source file, served byte-for-byte
+    Source,
+    /// Generated text, e.g. a cue sheet
+    Sheet,
+    /// Every track of a CD as raw 2352-byte frames (`--full-disc-image`)
+    Disc,
+}
+
+/// Shared state a factory may need while probing.
+pub struct ProbeContext<'a> {
+    pub form2: Form2Policy,
+    pub audio_tracks: bool,
+    /// `--pregap-audio`: `audio_tracks` files include stored pregaps
+    pub pregap_audio: bool,
+    pub audio_byteswap: bool,
+    pub subchannel: bool,
+    pub audio_cd: AudioCdMode,
+    pub toc: bool,
+    /// `--full-disc-image`
+    pub full_disc: bool,
+    /// `--merged-iso`
+    pub merged_iso: bool,
+    pub spill_dir: &'a Path,
+    pub frame_cache: &'a Arc<FrameCache>,
+    /// Read disc serials (for `--title-db`)
+    pub serials: bool,
+    /// Tell consoles apart (`--console-dirs`)
+    pub consoles: bool,
+    pub tail: TailPolicy,
+    /// `--strict`: refuse sources whose exposure would be a best guess
+    pub strict: bool,
+    pub extensions: &'a ExtensionMap,
+}
+
+impl ProbeContext<'_> {
+    /// The settings that change what probing exposes, as text.
+    pub fn fingerprint(&self) -> String {
+        let mut s = format!(
+            "form2={:?} audio_tracks={} byteswap={} subchannel={} audio_cd={:?} toc={}",
+            self.form2,
+            self.audio_tracks,
+            self.audio_byteswap,
+            self.subchannel,
+            self.audio_cd,
+            self.toc
+        );
+        if self.serials {
+            s.push_str(" serials");
+        }
+        if self.consoles {
+            s.push_str(" consoles");
+        }
+        if self.pregap_audio {
+            s.push_str(" pregap_audio");
+        }
+        if self.full_disc {
+            s.push_str(" full_disc");
+        }
+        if self.merged_iso {
+            s.push_str(" merged_iso");
+        }
+        if self.strict {
+            s.push_str(" strict");
+        }
+        if *self.extensions != ExtensionMap::default() {
+            s.push_str(&format!(" extensions={:?}", self.extensions));
+        }
+        s
+    }
+
+    /// `path` is exposed on a guess (`what`): an `Ambiguous` error with
+    /// `--strict`, else a warning.
+    pub fn ambiguous(&self, path: &Path, what: impl fmt::Display) -> Result<()> {
+        if self.strict {
+            return Err(Ambiguous(what.to_string()).into());
+        }
+        warn!("{:?}: {}", path, what);
+        Ok(())
+    }
+}
+
+/// A source `--strict` hides: how to expose it could only be guessed.
+#[derive(Debug)]
+pub struct Ambiguous(pub String);
+
+impl fmt::Display for Ambiguous {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Ambiguous {}
+
+/// `--extension-map`: the extension each kind of image is exposed with,
+/// for frontends that only look for some.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct ExtensionMap {
+    pub dvd: String,
+    /// Mode 1 and Mode 2 Form 1 CD data
+    pub cd_data: String,
+    pub form2: String,
+    /// Mode 2 sectors with their XA subheader (`--form2-policy xa-view`)
+    pub xa: String,
+    /// CHDs of another unit size, such as hard disks, passed through
+    pub hdd: String,
+}
+
+impl Default for ExtensionMap {
+    fn default() -> Self {
+        Self {
+            dvd: "iso".into(),
+            cd_data: "iso".into(),
+            form2: "bin".into(),
+            xa: "bin".into(),
+            hdd: "iso".into(),
+        }
+    }
+}
+
+impl std::str::FromStr for ExtensionMap {
+    type Err = String;
+
+    /// `dvd=iso,cd-data=iso,form2=bin,hdd=img`; kinds left out keep their
+    /// default.
+    fn from_str(s: &str) -> Result<Self, String> {
+        let mut map = Self::default();
+        for pair in s.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let (kind, ext) = pair
+                .split_once('=')
+                .ok_or_else(|| format!("expected KIND=EXT, got {pair:?}"))?;
+            let ext = ext.trim_start_matches('.');
+            if ext.is_empty() || !ext.bytes().all(|b| b.is_ascii_alphanumeric()) {
+                return Err(format!("invalid extension {ext:?} for {kind}"));
+            }
+            let slot = match kind.trim() {
+                "dvd" => &mut map.dvd,
+                "cd-data" => &mut map.cd_data,
+                "form2" => &mut map.form2,
+                "xa" => &mut map.xa,
+                "hdd" => &mut map.hdd,
+                other => {
+                    return Err(format!(
+                        "unknown image kind {other:?} (dvd, cd-data, form2, xa or hdd)"
+                    ))
+                }
+            };
+            *slot = ext.to_string();
+        }
+        Ok(map)
+    }
+}
+
+impl TryFrom<String> for ExtensionMap {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, String> {
+        s.parse()
+    }
+}
+
+/// A successfully probed source: the exposed file name and its provider.
+pub struct Probed {
+    pub name: String,
+    pub provider: Arc<dyn BackingProvider>,
+    pub sha1: Option<[u8; 20]>,
+    /// Further files from the same source (e.g. audio tracks), listed
+    /// alongside the main one
+    pub extras: Vec<(String, Arc<dyn BackingProvider>)>,
+}
+
+pub trait ProviderFactory: Send + Sync {
+    /// Cheap check (by name) whether this factory handles `path`.
+    fn matches(&self, path: &Path) -> bool;
+
+    /// Open `path`; `Ok(None)` means it is valid but has nothing to expose.
+    fn probe(&self, path: &Path, ctx: &ProbeContext) -> Result<Option<Probed>>;
+}
+
+pub struct Registry {
+    factories: Vec<Box<dyn ProviderFactory>>,
+}
+
+impl Registry {
+    pub fn with_builtin() -> Self {
+        Self {
+            factories: vec![
+                Box::new(ChdFactory),
+                Box::new(ContainerFactory(InputFormat::Cso)),
+                Box::new(ContainerFactory(InputFormat::Zso)),
+                Box::new(ContainerFactory(InputFormat::IsoGz)),
+                #[cfg(feature = "archive")]
+                Box::new(crate::archive::ArchiveFactory),
+            ],
+        }
+    }
+
+    pub fn matches(&self, path: &Path) -> bool {
+        self.factories.iter().any(|f| f.matches(path))
+    }
+
+    pub fn probe(&self, path: &Path, ctx: &ProbeContext) -> Result<Option<Probed>> {
+        match self.factories.iter().find(|f| f.matches(path)) {
+            Some(f) => f.probe(path, ctx),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Registry entry for the CISO/ZISO/gzip inputs.
+struct ContainerFactory(InputFormat);
+
+impl ProviderFactory for ContainerFactory {
+    fn matches(&self, path: &Path) -> bool {
+        InputFormat::detect(path) == Some(self.0)
+    }
+
+    fn probe(&self, path: &Path, ctx: &ProbeContext) -> Result<Option<Probed>> {
+        let provider: Arc<dyn BackingProvider> = match self.0 {
+            InputFormat::Cso | InputFormat::Zso => Arc::new(CisoProvider::open(path)?),
+            InputFormat::IsoGz => Arc::new(GzSpillProvider::open(path, ctx.spill_dir)?),
+        };
+
+        Ok(Some(Probed {
+            name: format!("{}.iso", self.0.stem(path)),
+            provider,
+            sha1: None,
+            extras: Vec::new(),
+        }))
+    }
+}
+
+/// Sequential [`Read`] over a provider, for consumers that stream the whole
+/// image (layout builders, hashing). Reads are batched so providers that
+/// reopen their source per call stay cheap.
+pub struct ProviderReader<'a> {
+    provider: &'a dyn BackingProvider,
+    pos: u64,
+    buf: Vec<u8>,
+    start: usize,
+}
+
+const READER_CHUNK: usize = 1024 * 1024;
+
+impl<'a> ProviderReader<'a> {
+    pub fn new(provider: &'a dyn BackingProvider) -> Self {
+        Self {
+            provider,
+            pos: 0,
+            buf: Vec::new(),
+            start: 0,
+        }
+    }
+}
+
+impl Read for ProviderReader<'_> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if self.start == self.buf.len() {
+            self.buf.resize(READER_CHUNK, 0);
+            let n = self
+                .provider
+                .read_at(self.pos, &mut self.buf)
+                .map_err(io::Error::other)?;
+            self.buf.truncate(n);
+            self.pos += n as u64;
+            self.start = 0;
+        }
+
+        let take = out.len().min(self.buf.len() - self.start);
+        out[..take].copy_from_slice(&self.buf[self.start..self.start + take]);
+        self.start += take;
+        Ok(take)
+    }
+}
+
+/// Keeps the first `pin_bytes` of an image decoded in memory once touched.
+/// That is where ISO9660 puts the volume descriptors, path table and most
+/// directory records, which emulators re-read constantly; the frame cache's
+/// LRU would otherwise evict them between file loads.
+pub struct PinnedProvider {
+    inner: Arc<dyn BackingProvider>,
+    pin_bytes: u64,
+    head: Mutex<Option<Arc<Vec<u8>>>>,
+}
+
+impl fmt::Debug for PinnedProvider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PinnedProvider")
+            .field("inner", &self.inner)
+            .field("pin_bytes", &self.pin_bytes)
+            .finish()
+    }
+}
+
+impl PinnedProvider {
+    pub fn new(inner: Arc<dyn BackingProvider>, pin_bytes: u64) -> Self {
+        Self {
+            // The pinned head is one buffer, so it must fit in usize.
+            pin_bytes: pin_bytes.min(inner.size()).min(usize::MAX as u64),
+            inner,
+            head: Mutex::new(None),
+        }
+    }
+
+    fn head(&self) -> Result<Arc<Vec<u8>>> {
+        let mut slot = self.head.lock().expect("pinned head mutex poisoned");
+        if let Some(h) = &*slot {
+            return Ok(Arc::clone(h));
+        }
+
+        let mut buf = vec![0u8; self.pin_bytes as usize];
+        let mut done = 0;
+        while done < buf.len() {
+            let n = self.inner.read_at(done as u64, &mut buf[done..])?;
+            if n == 0 {
+                break;
+            }
+            done += n;
+        }
+        buf.truncate(done);
+        PINNED_BYTES.fetch_add(buf.len(), Ordering::Relaxed);
+
+        let head = Arc::new(buf);
+        *slot = Some(Arc::clone(&head));
+        Ok(head)
+    }
+}
+
+impl Drop for PinnedProvider {
+    fn drop(&mut self) {
+        if let Some(head) = self.head.get_mut().ok().and_then(Option::take) {
+            PINNED_BYTES.fetch_sub(head.len(), Ordering::Relaxed);
+        }
+    }
+}
+
+/// Bytes of pinned heads loaded across every mount.
+static PINNED_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+pub fn pinned_bytes() -> usize {
+    PINNED_BYTES.load(Ordering::Relaxed)
+}
+
+impl BackingProvider for PinnedProvider {
+    fn size(&self) -> u64 {
+        self.inner.size()
+    }
+
+    fn kind(&self) -> ImageKind {
+        self.inner.kind()
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        if offset >= self.pin_bytes {
+            return self.inner.read_at(offset, buf);
+        }
+
+        let head = self.head()?;
+        if offset >= head.len() as u64 {
+            // Inner stream ended early; nothing past it either.
+            return Ok(0);
+        }
+
+        let from_head = (head.len() - offset as usize).min(buf.len());
+        buf[..from_head].copy_from_slice(&head[offset as usize..offset as usize + from_head]);
+
+        if from_head == buf.len() || (head.len() as u64) < self.pin_bytes {
+            return Ok(from_head);
+        }
+
+        let rest = self
+            .inner
+            .read_at(offset + from_head as u64, &mut buf[from_head..])?;
+        Ok(from_head + rest)
+    }
+}
+
+/// Bytes of each image `--header-cache` keeps: as far as frontend scanners
+/// read when sniffing a library.
+pub const HEADER_BYTES: u64 = 1 << 20;
+
+/// `--header-cache`: the first [`HEADER_BYTES`] of an image decoded once
+/// into a file keyed by the CHD's SHA-1 and the settings that shape its
+/// bytes, so the header sniffing of a library
+/// scan is served without decoding, after restarts too. Unlike
+/// [`PinnedProvider`] nothing stays in memory but the page cache.
+pub struct HeaderCacheProvider {
+    inner: Arc<dyn BackingProvider>,
+    path: PathBuf,
+    len: u64,
+    file: Mutex<Option<Arc<File>>>,
+}
+
+impl fmt::Debug for HeaderCacheProvider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HeaderCacheProvider")
+            .field("inner", &self.inner)
+            .field("path", &self.path)
+            .finish()
+    }
+}
+
+impl HeaderCacheProvider {
+    /// `settings` is what, besides the CHD, decides the image's bytes, such
+    /// as [`ProbeContext::fingerprint`].
+    pub fn new(
+        inner: Arc<dyn BackingProvider>,
+        dir: &Path,
+        sha1: &[u8; 20],
+        settings: &str,
+    ) -> Self {
+        let kind = format!("{:?}", inner.kind()).to_lowercase();
+        let settings = &sha1::hex(&sha1::digest(settings.as_bytes()))[..8];
+        Self {
+            path: dir.join(format!(
+                "{}-{settings}-{kind}-{}.head",
+                sha1::hex(sha1),
+                inner.size()
+            )),
+            len: HEADER_BYTES.min(inner.size()),
+            inner,
+            file: Mutex::new(None),
+        }
+    }
+
+    /// The cache file, written from `inner` if missing or the wrong size.
+    fn head(&self) -> Result<Arc<File>> {
+        let mut slot = self.file.lock().expect("header cache mutex poisoned");
+        if let Some(f) = &*slot {
+            return Ok(Arc::clone(f));
+        }
+
+        let file = match File::open(&self.path) {
+            Ok(f) if f.metadata()?.len() == self.len => f,
+            _ => {
+                let mut buf = vec![0u8; self.len as usize];
+                let mut done = 0;
+                while done < buf.len() {
+                    match self.inner.read_at(done as u64, &mut buf[done..])? {
+                        0 => return Err(anyhow!("{:?}: image ended at {}", self.path, done)),
+                        n => done += n,
+                    }
+                }
+                if let Some(dir) = self.path.parent() {
+                    fs::create_dir_all(dir)
+                        .with_context(|| format!("creating header cache {dir:?}"))?;
+                }
+                let tmp = self.path.with_extension("part");
+                fs::write(&tmp, &buf).with_context(|| format!("writing {tmp:?}"))?;
+                fs::rename(&tmp, &self.path)?;
+                File::open(&self.path)?
+            }
+        };
+
+        let file = Arc::new(file);
+        *slot = Some(Arc::clone(&file));
+        Ok(file)
+    }
+}
+
+impl BackingProvider for HeaderCacheProvider {
+    fn size(&self) -> u64 {
+        self.inner.size()
+    }
+
+    fn kind(&self) -> ImageKind {
+        self.inner.kind()
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        if offset >= self.len {
+            return self.inner.read_at(offset, buf);
+        }
+
+        let file = match self.head() {
+            Ok(f) => f,
+            Err(e) => {
+                debug!("header cache: {:#}", e);
+                return self.inner.read_at(offset, buf);
+            }
+        };
+
+        let from_head = ((self.len - offset) as usize).min(buf.len());
+        file.read_exact_at(&mut buf[..from_head], offset)?;
+        if from_head == buf.len() {
+            return Ok(from_head);
+        }
+
+        let rest = self
+            .inner
+            .read_at(offset + from_head as u64, &mut buf[from_head..])?;
+        Ok(from_head + rest)
+    }
+}
+
+/// Input formats recognized besides `.chd`, by file name.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InputFormat {
+    Cso,
+    Zso,
+    IsoGz,
+}
+
+impl InputFormat {
+    pub fn detect(path: &Path) -> Option<Self> {
+        let name = path.file_name()?.to_str()?.to_ascii_lowercase();
+
+        if name.ends_with(".cso") {
+            Some(InputFormat::Cso)
+        } else if name.ends_with(".zso") {
+            Some(InputFormat::Zso)
+        } else if name.ends_with(".iso.gz") {
+            Some(InputFormat::IsoGz)
+        } else {
+            None
+        }
+    }
+
+    /// File name without the container suffix(es): `Game.iso.gz` -> `Game`.
+    pub fn stem(self, path: &Path) -> String {
+        let name = path
+            .file_name()
+            .and_then(|s| s.to_str())
+            .unwrap_or("unknown");
+        let cut = match self {
+            InputFormat::Cso | InputFormat::Zso => 4,
+            InputFormat::IsoGz => 7,
+        };
+
+        name[..name.len().saturating_sub(cut)].to_string()
+    }
+}
+
+/// Reader for CISO (deflate) and ZISO (LZ4) v1 files.
+pub struct CisoProvider {
+    file: Box<dyn RangedRead>,
+    lz4: bool,
+    total_bytes: u64,
+    block_size: u32,
+    align: u8,
+    table: Vec<u32>,
+    /// Last decoded block, for sequential readers
+    last: Mutex<Option<(u64, Vec<u8>)>>,
+}
+
+const CISO_PLAIN: u32 = 0x8000_0000;
+
+/// Largest block size taken from a CISO header.
+const CISO_MAX_BLOCK: u32 = 1 << 24;
+
+/// Most a block of `block_size` bytes can take stored, deflated or LZ4
+/// compressed at worst, before alignment padding.
+fn ciso_max_stored(block_size: u32) -> u64 {
+    let n = block_size as u64;
+    n + n / 255 + 64
+}
+
+impl fmt::Debug for CisoProvider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CisoProvider")
+            .field("lz4", &self.lz4)
+            .field("total_bytes", &self.total_bytes)
+            .field("block_size", &self.block_size)
+            .finish()
+    }
+}
+
+impl CisoProvider {
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = vfs::open(path)?;
+
+        let mut hdr = [0u8; 24];
+        file.read_exact_at(&mut hdr, 0)?;
+
+        let lz4 = match &hdr[0..4] {
+            b"CISO" => false,
+            b"ZISO" => true,
+            _ => return Err(anyhow!("not a CISO/ZISO file")),
+        };
+
+        let header_size = u32::from_le_bytes(hdr[4..8].try_into()?) as u64;
+        let total_bytes = u64::from_le_bytes(hdr[8..16].try_into()?);
+        let block_size = u32::from_le_bytes(hdr[16..20].try_into()?);
+        let align = hdr[21];
+
+        if block_size == 0 || block_size > CISO_MAX_BLOCK || align > 24 {
+            return Err(anyhow!(
+                "bad CISO header (block size {block_size}, align {align})"
+            ));
+        }
+
+        let blocks = total_bytes.div_ceil(block_size as u64);
+        let table_len = blocks
+            .checked_add(1)
+            .and_then(|n| n.checked_mul(4))
+            .and_then(|n| usize::try_from(n).ok())
+            .ok_or_else(|| anyhow!("CISO block table too large ({blocks} blocks)"))?;
+        let mut raw = vec![0u8; table_len];
+        // Some writers put 0 in header_size; the table always follows 24 bytes.
+        file.read_exact_at(&mut raw, header_size.max(24))?;
+
+        let table: Vec<u32> = raw
+            .chunks_exact(4)
+            .map(|c| u32::from_le_bytes([c[0], c[1], c[2], c[3]]))
+            .collect();
+        let offset = |w: u32| ((w & !CISO_PLAIN) as u64) << align;
+        if let Some(b) = table.windows(2).position(|w| offset(w[1]) < offset(w[0])) {
+            return Err(anyhow!("CISO block table goes backwards at block {b}"));
+        }
+        let end = table.last().map_or(0, |&w| offset(w));
+        if end > file.size() {
+            return Err(anyhow!(
+                "CISO block table ends at {end}, past the file's {} bytes",
+                file.size()
+            ));
+        }
+
+        Ok(Self {
+            file,
+            lz4,
+            total_bytes,
+            block_size,
+            align,
+            table,
+            last: Mutex::new(None),
+        })
+    }
+
+    fn block(&self, b: u64) -> Result<Vec<u8>> {
+        if let Some((n, data)) = &*self.last.lock().expect("ciso cache mutex poisoned") {
+            if *n == b {
+                return Ok(data.clone());
+            }
+        }
+
+        let word = self.table[b as usize];
+        let start = ((word & !CISO_PLAIN) as u64) << self.align;
+        let end = ((self.table[b as usize + 1] & !CISO_PLAIN) as u64) << self.align;
+        let want = (self.total_bytes - b * self.block_size as u64).min(self.block_size as u64);
+
+        let max = ciso_max_stored(self.block_size).next_multiple_of(1 << self.align);
+        let stored_len = end.saturating_sub(start);
+        if stored_len > max {
+            return Err(anyhow!(
+                "CISO block {b} stored in {stored_len} bytes, more than {max}"
+            ));
+        }
+        let stored_len = stored_len as usize;
+        let mut stored = vec![0u8; stored_len];
+        self.file.read_exact_at(&mut stored, start)?;
+
+        let data = if word & CISO_PLAIN != 0 {
+            stored.truncate(want as usize);
+            stored
+        } else if self.lz4 {
+            lz4_flex::block::decompress(&stored, want as usize)?
+        } else {
+            let mut out = Vec::with_capacity(want as usize);
+            DeflateDecoder::new(&stored[..])
+                .take(want)
+                .read_to_end(&mut out)?;
+            out
+        };
+
+        if (data.len() as u64) < want {
+            return Err(anyhow!("block {b} decoded short ({} < {want})", data.len()));
+        }
+
+        *self.last.lock().expect("ciso cache mutex poisoned") = Some((b, data.clone()));
+        Ok(data)
+    }
+}
+
+impl BackingProvider for CisoProvider {
+    fn size(&self) -> u64 {
+        self.total_bytes
+    }
+
+    fn kind(&self) -> ImageKind {
+        ImageKind::Decoded
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        let bs = self.block_size as u64;
+        let mut done = 0usize;
+        let mut pos = offset;
+
+        while done < buf.len() && pos < self.total_bytes {
+            let data = self.block(pos / bs)?;
+            let in_block = (pos % bs) as usize;
+            let take = (data.len() - in_block).min(buf.len() - done);
+
+            buf[done..done + take].copy_from_slice(&data[in_block..in_block + take]);
+            done += take;
+            pos += take as u64;
+        }
+
+        Ok(done)
+    }
+}
+
+/// Name for spilled copies of `path`: its device and inode (a remote one's
+/// URL), size and mtime, so they are reused across restarts and never served
+/// stale.
+pub fn spill_key(path: &Path) -> Result<String> {
+    let meta = vfs::stat(path)?;
+    let id = match meta.local {
+        Some(local) => format!("{:x}-{:x}", local.dev, local.ino),
+        None => sha1::hex(&sha1::digest(path.as_os_str().as_encoded_bytes())),
+    };
+    Ok(format!(
+        "{id}-{:x}-{:x}",
+        meta.size,
+        meta.mtime.unwrap_or(0)
+    ))
+}
+
+/// `.iso.gz` inflated once into the spill directory and served from there.
+#[derive(Debug)]
+pub struct GzSpillProvider {
+    spill: File,
+    len: u64,
+}
+
+impl GzSpillProvider {
+    pub fn open(path: &Path, spill_dir: &Path) -> Result<Self> {
+        let spill_path = spill_dir.join(format!("{}.iso", spill_key(path)?));
+
+        if !spill_path.is_file() {
+            fs::create_dir_all(spill_dir)
+                .with_context(|| format!("creating spill dir {spill_dir:?}"))?;
+            info!("inflating {:?} -> {:?}", path, spill_path);
+            inflate_to(path, &spill_path)?;
+        }
+
+        let spill = File::open(&spill_path)?;
+        let len = spill.metadata()?.len();
+
+        Ok(Self { spill, len })
+    }
+}
+
+fn inflate_to(src: &Path, dst: &Path) -> Result<()> {
+    let tmp: PathBuf = dst.with_extension("part");
+    let mut out = File::create(&tmp)?;
+    let mut dec = MultiGzDecoder::new(vfs::reader(src)?);
+
+    io::copy(&mut dec, &mut out).with_context(|| format!("inflating {src:?}"))?;
+    out.sync_all()?;
+    fs::rename(&tmp, dst)?;
+    Ok(())
+}
+
+impl BackingProvider for GzSpillProvider {
+    fn size(&self) -> u64 {
+        self.len
+    }
+
+    fn kind(&self) -> ImageKind {
+        ImageKind::Decoded
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        if offset >= self.len {
+            return Ok(0);
+        }
+
+        let want = (buf.len() as u64).min(self.len - offset) as usize;
+        self.spill.read_exact_at(&mut buf[..want], offset)?;
+        Ok(want)
+    }
+}
+
+/// A small generated file held in memory.
+#[derive(Debug)]
+pub struct SheetProvider(Vec<u8>);
+
+impl SheetProvider {
+    pub fn new(text: String) -> Self {
+        Self(text.into_bytes())
+    }
+}
+
+impl BackingProvider for SheetProvider {
+    fn size(&self) -> u64 {
+        self.0.len() as u64
+    }
+
+    fn kind(&self) -> ImageKind {
+        ImageKind::Sheet
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        let start = usize::try_from(offset).map_or(self.0.len(), |o| o.min(self.0.len()));
+        let n = buf.len().min(self.0.len() - start);
+        buf[..n].copy_from_slice(&self.0[start..start + n]);
+        Ok(n)
+    }
+}
+
+/// Digest of a [`ChecksumProvider`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Checksum {
+    Sha1,
+    Md5,
+}
+
+impl Checksum {
+    pub fn extension(self) -> &'static str {
+        match self {
+            Checksum::Sha1 => "sha1",
+            Checksum::Md5 => "md5",
+        }
+    }
+
+    fn hex_len(self) -> usize {
+        match self {
+            Checksum::Sha1 => 40,
+            Checksum::Md5 => 32,
+        }
+    }
+}
+
+/// `Name.iso.sha1` (`--checksum-files`): a `sha1sum`-style line for an
+/// image, so `sha1sum -c` runs against the mount. The first read starts
+/// hashing the image in the background and fails with [`NotReady`], as do
+/// reads until the line is kept; its length is known up front.
+#[derive(Debug)]
+pub struct ChecksumProvider {
+    image: Arc<dyn BackingProvider>,
+    image_name: String,
+    algo: Checksum,
+    digest: Arc<Mutex<Digest>>,
+}
+
+/// How far a [`ChecksumProvider`] has got.
+#[derive(Debug)]
+enum Digest {
+    Idle,
+    /// Being hashed, in the background or by `prepare`
+    Running,
+    Done(Arc<Vec<u8>>),
+    /// Hashing failed; the next read says so, the one after tries again
+    Failed(String),
+}
+
+/// A read of something still being worked out in the background; try
+/// again later.
+#[derive(Debug)]
+pub struct NotReady;
+
+impl fmt::Display for NotReady {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("not ready yet")
+    }
+}
+
+impl std::error::Error for NotReady {}
+
+impl ChecksumProvider {
+    pub fn new(image: Arc<dyn BackingProvider>, image_name: &str, algo: Checksum) -> Self {
+        Self {
+            image,
+            image_name: image_name.to_string(),
+            algo,
+            digest: Arc::new(Mutex::new(Digest::Idle)),
+        }
+    }
+
+    fn digest(&self) -> MutexGuard<'_, Digest> {
+        self.digest.lock().expect("checksum lock poisoned")
+    }
+
+    /// The line, once hashed; until then [`NotReady`], hashing in the
+    /// background from the first call.
+    fn text(&self) -> Result<Arc<Vec<u8>>> {
+        let mut digest = self.digest();
+        match &*digest {
+            Digest::Done(t) => return Ok(Arc::clone(t)),
+            Digest::Running => return Err(NotReady.into()),
+            Digest::Failed(e) => {
+                let e = anyhow!("{e}");
+                *digest = Digest::Idle;
+                return Err(e);
+            }
+            Digest::Idle => {}
+        }
+
+        let (image, name, algo) = (Arc::clone(&self.image), self.image_name.clone(), self.algo);
+        let state = Arc::clone(&self.digest);
+        thread::Builder::new()
+            .name("checksum".into())
+            .spawn(move || {
+                let done = match checksum_line(&*image, &name, algo, &|_| {}) {
+                    Ok(t) => Digest::Done(Arc::new(t)),
+                    Err(e) => {
+                        warn!("hashing {} for its .{}: {:#}", name, algo.extension(), e);
+                        Digest::Failed(format!("{e:#}"))
+                    }
+                };
+                *state.lock().expect("checksum lock poisoned") = done;
+            })?;
+        *digest = Digest::Running;
+        Err(NotReady.into())
+    }
+}
+
+/// `sha1sum`/`md5sum` line for `image`, read through with `pace` called
+/// before each read.
+fn checksum_line(
+    image: &dyn BackingProvider,
+    name: &str,
+    algo: Checksum,
+    pace: &dyn Fn(usize),
+) -> Result<Vec<u8>> {
+    info!("hashing {} for its .{}", name, algo.extension());
+    let (mut s1, mut m5) = (sha1::Sha1::default(), md5::Md5::default());
+    let mut buf = vec![0u8; 1 << 20];
+    let mut pos = 0;
+    while pos < image.size() {
+        pace(buf.len());
+        let n = image.read_at(pos, &mut buf)?;
+        if n == 0 {
+            return Err(anyhow!("{}: short read at {}", name, pos));
+        }
+        match algo {
+            Checksum::Sha1 => s1.update(&buf[..n]),
+            Checksum::Md5 => m5.update(&buf[..n]),
+        }
+        pos += n as u64;
+    }
+
+    let hex = match algo {
+        Checksum::Sha1 => sha1::hex(&s1.finish()),
+        Checksum::Md5 => md5::hex(&m5.finish()),
+    };
+    Ok(format!("{hex}  {name}\n").into_bytes())
+}
+
+impl BackingProvider for ChecksumProvider {
+    fn size(&self) -> u64 {
+        (self.algo.hex_len() + 2 + self.image_name.len() + 1) as u64
+    }
+
+    fn kind(&self) -> ImageKind {
+        ImageKind::Sheet
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        if offset >= self.size() || buf.is_empty() {
+            return Ok(0);
+        }
+        let text = self.text()?;
+        let start = offset as usize;
+        let n = buf.len().min(text.len() - start);
+        buf[..n].copy_from_slice(&text[start..start + n]);
+        Ok(n)
+    }
+
+    fn prepare(&self, pace: &dyn Fn(usize)) -> Result<bool> {
+        {
+            let mut digest = self.digest();
+            if matches!(*digest, Digest::Running | Digest::Done(_)) {
+                return Ok(false);
+            }
+            *digest = Digest::Running;
+        }
+        let line = checksum_line(&*self.image, &self.image_name, self.algo, pace);
+        let mut digest = self.digest();
+        match line {
+            Ok(t) => {
+                *digest = Digest::Done(Arc::new(t));
+                Ok(true)
+            }
+            Err(e) => {
+                *digest = Digest::Idle;
+                Err(e)
+            }
+        }
+    }
+}
+
+/// The original input file itself (`--expose-sources`). Opened on first
+/// read so an index of thousands of sources does not hold as many fds.
+#[derive(Debug)]
+pub struct SourceFileProvider {
+    path: PathBuf,
+    len: u64,
+    file: OnceLock<Box<dyn RangedRead>>,
+}
+
+impl SourceFileProvider {
+    pub fn new(path: &Path) -> Result<Self> {
+        let len = vfs::stat(path)?.size;
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            len,
+            file: OnceLock::new(),
+        })
+    }
+}
+
+impl BackingProvider for SourceFileProvider {
+    fn size(&self) -> u64 {
+        self.len
+    }
+
+    fn kind(&self) -> ImageKind {
+        ImageKind::Source
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        if offset >= self.len {
+            return Ok(0);
+        }
+
+        let file = match self.file.get() {
+            Some(f) => f,
+            None => {
+                let f = vfs::open(&self.path)?;
+                self.file.get_or_init(|| f)
+            }
+        };
+
+        let want = (buf.len() as u64).min(self.len - offset) as usize;
+        file.read_exact_at(&mut buf[..want], offset)?;
+        Ok(want)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug)]
+    struct Mem(Vec<u8>);
+
+    impl BackingProvider for Mem {
+        fn size(&self) -> u64 {
+            self.0.len() as u64
+        }
+
+        fn kind(&self) -> ImageKind {
+            ImageKind::Dvd
+        }
+
+        fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize> {
+            let data = self.0.get(offset as usize..).unwrap_or_default();
+            let n = data.len().min(buf.len());
+            buf[..n].copy_from_slice(&data[..n]);
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn extension_map_overrides_some_kinds() {
+        let map: ExtensionMap = "hdd=img, form2=.BIN2,".parse().unwrap();
+        assert_eq!(map.hdd, "img");
+        assert_eq!(map.form2, "BIN2");
+        assert_eq!(map.dvd, "iso");
+        assert_eq!("".parse::<ExtensionMap>().unwrap(), ExtensionMap::default());
+
+        assert!("floppy=img".parse::<ExtensionMap>().is_err());
+        assert!("dvd".parse::<ExtensionMap>().is_err());
+        assert!("dvd=is/o".parse::<ExtensionMap>().is_err());
+    }
+
+    #[test]
+    fn strict_turns_guesses_into_errors() {
+        let frame_cache = Arc::new(FrameCache::new(8, 1 << 20));
+        let mut ctx = ProbeContext {
+            form2: Form2Policy::Hide,
+            audio_tracks: false,
+            pregap_audio: false,
+            audio_byteswap: false,
+            subchannel: false,
+            audio_cd: AudioCdMode::Hide,
+            toc: false,
+            full_disc: false,
+            merged_iso: false,
+            spill_dir: Path::new("/tmp"),
+            frame_cache: &frame_cache,
+            serials: false,
+            consoles: false,
+            tail: TailPolicy::Eio,
+            strict: false,
+            extensions: &ExtensionMap::default(),
+        };
+        let path = Path::new("Game.chd");
+        assert!(ctx.ambiguous(path, "scanned").is_ok());
+        let lenient = ctx.fingerprint();
+
+        ctx.strict = true;
+        let err = ctx.ambiguous(path, "scanned").unwrap_err();
+        assert_eq!(err.downcast_ref::<Ambiguous>().unwrap().0, "scanned");
+        assert_ne!(ctx.fingerprint(), lenient);
+    }
+
+    #[test]
+    fn checksum_lines_for_sha1sum() {
+        let image: Arc<dyn BackingProvider> = Arc::new(Mem(b"abc".to_vec()));
+        for (algo, line) in [
+            (
+                Checksum::Sha1,
+                "a9993e364706816aba3e25717850c26c9cd0d89d  Game.iso\n",
+            ),
+            (
+                Checksum::Md5,
+                "900150983cd24fb0d6963f7d28e17f72  Game.iso\n",
+            ),
+        ] {
+            let sum = ChecksumProvider::new(Arc::clone(&image), "Game.iso", algo);
+            assert_eq!(sum.size(), line.len() as u64);
+            let mut buf = vec![0u8; 100];
+            // Hashed in the background, not by the first read.
+            assert!(sum.read_at(0, &mut buf).unwrap_err().is::<NotReady>());
+            let n = loop {
+                match sum.read_at(0, &mut buf) {
+                    Err(e) if e.is::<NotReady>() => {
+                        thread::sleep(std::time::Duration::from_millis(5))
+                    }
+                    res => break res.unwrap(),
+                }
+            };
+            assert_eq!(&buf[..n], line.as_bytes());
+            assert_eq!(sum.read_at(n as u64, &mut buf).unwrap(), 0);
+
+            let sum = ChecksumProvider::new(Arc::clone(&image), "Game.iso", algo);
+            assert!(sum.prepare(&|_| {}).unwrap());
+            assert!(!sum.prepare(&|_| {}).unwrap());
+            assert_eq!(sum.read_at(0, &mut buf).unwrap(), n);
+        }
+    }
+
+    #[test]
+    fn header_cache_survives_the_provider() {
+        let dir = std::env::temp_dir().join(format!("chd2iso-heads-{}", std::process::id()));
+        let data: Vec<u8> = (0..HEADER_BYTES as usize + 4096).map(|i| i as u8).collect();
+        let sha1 = [7u8; 20];
+
+        let cached = HeaderCacheProvider::new(Arc::new(Mem(data.clone())), &dir, &sha1, "");
+        let mut buf = vec![0u8; 8192];
+        let at = HEADER_BYTES - 100;
+        assert_eq!(cached.read_at(at, &mut buf).unwrap(), 4196);
+        assert_eq!(buf[..4196], data[at as usize..]);
+
+        // A fresh provider over other data of the same size and kind (a
+        // restart) serves the head from the file.
+        let stale = vec![0u8; data.len()];
+        let cached = HeaderCacheProvider::new(Arc::new(Mem(stale.clone())), &dir, &sha1, "");
+        assert_eq!(cached.read_at(10, &mut buf[..10]).unwrap(), 10);
+        assert_eq!(buf[..10], data[10..20]);
+
+        // Other settings do not share it.
+        let cached = HeaderCacheProvider::new(Arc::new(Mem(stale)), &dir, &sha1, "byteswap");
+        assert_eq!(cached.read_at(10, &mut buf[..10]).unwrap(), 10);
+        assert_eq!(buf[..10], [0; 10]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn pinned_reads_straddle_the_head() {
+        let data: Vec<u8> = (0..10_000u32).map(|i| i as u8).collect();
+        let p = PinnedProvider::new(Arc::new(Mem(data.clone())), 4096);
+
+        let mut buf = vec![0u8; 2000];
+        assert_eq!(p.read_at(3000, &mut buf).unwrap(), 2000);
+        assert_eq!(buf, data[3000..5000]);
+
+        assert_eq!(p.read_at(9000, &mut buf).unwrap(), 1000);
+        assert_eq!(buf[..1000], data[9000..]);
+    }
+
+    #[test]
+    fn ciso_table_must_fit_the_file() {
+        let path = std::env::temp_dir().join(format!("ciso-table-{}.cso", std::process::id()));
+        let open = |table: &[u32]| {
+            let mut file = b"CISO".to_vec();
+            file.extend(24u32.to_le_bytes());
+            file.extend(4096u64.to_le_bytes());
+            file.extend(2048u32.to_le_bytes());
+            file.extend([1, 0, 0, 0]);
+            for w in table {
+                file.extend(w.to_le_bytes());
+            }
+            file.resize(64, 0);
+            fs::write(&path, file).unwrap();
+            CisoProvider::open(&path).map(|_| ())
+        };
+
+        assert!(open(&[36, 40, 64]).is_ok());
+        assert!(open(&[40, 36, 64]).is_err());
+        assert!(open(&[36, 40, 1 << 30]).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn detects_inputs_and_strips_suffixes() {
+        let p = Path::new("/lib/Game (USA).iso.gz");
+        assert_eq!(InputFormat::detect(p), Some(InputFormat::IsoGz));
+        assert_eq!(InputFormat::IsoGz.stem(p), "Game (USA)");
+
+        let p = Path::new("Game.ZSO");
+        assert_eq!(InputFormat::detect(p), Some(InputFormat::Zso));
+        assert_eq!(InputFormat::Zso.stem(p), "Game");
+
+        assert_eq!(InputFormat::detect(Path::new("Game.gz")), None);
+    }
+}
@@ -0,0 +1,322 @@
+//! Remote sources: a CHD library on an HTTP server (`--source
+//! http(s)://...`, `http` feature) or behind SSH (`--source sftp://...`,
+//! `sftp` feature), for thin clients without NFS. Each scheme is a
+//! [`Transport`] that lists the library and reads byte ranges, served to the
+//! rest of the tree as [`RemoteVfs`]; files are
+//! read in `CHUNK`-sized pieces of the compressed CHD, kept on disk under
+//! `--http-cache` and the most recent in memory, so each piece crosses the
+//! network once.
+
+use anyhow::{anyhow, bail, Result};
+use lru::LruCache;
+use std::{
+    collections::HashMap,
+    fmt, fs, io,
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    time::{Duration, SystemTime},
+};
+use tracing::{debug, warn};
+
+use crate::{
+    sha1,
+    vfs::{RangedRead, ScanOptions, SourceMeta, SourceVfs},
+};
+
+#[cfg(feature = "http")]
+mod http;
+#[cfg(feature = "sftp")]
+mod sftp;
+
+const CHUNK: u64 = 1 << 20;
+
+/// Chunks kept in memory, shared by every remote file.
+const MEMORY_CHUNKS: usize = 32;
+
+/// Subdirectory levels followed below the source URL.
+const MAX_DEPTH: usize = 8;
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// One URL scheme. Paths are the URLs the index holds, undecorated (HTTP
+/// ones percent-decoded).
+trait Transport: Send + Sync {
+    /// Files under `base` that `opts` wants, following subdirectories.
+    fn list(&self, base: &str, opts: &ScanOptions) -> Result<Vec<PathBuf>>;
+
+    /// A file, with its length, or a directory.
+    fn stat(&self, url: &str) -> Result<SourceMeta>;
+
+    /// `len` bytes of `url` from `start`.
+    fn read(&self, url: &str, start: u64, len: u64) -> Result<Vec<u8>>;
+}
+
+fn transport(url: &str) -> Result<&'static dyn Transport> {
+    #[cfg(feature = "http")]
+    if url.starts_with("http://") || url.starts_with("https://") {
+        return Ok(http::transport());
+    }
+    #[cfg(feature = "sftp")]
+    if url.starts_with("sftp://") {
+        return Ok(sftp::transport());
+    }
+    Err(anyhow!(
+        "{url}: unsupported scheme (this build has: {})",
+        SCHEMES.join(", ")
+    ))
+}
+
+const SCHEMES: &[&str] = &[
+    #[cfg(feature = "http")]
+    "http",
+    #[cfg(feature = "http")]
+    "https",
+    #[cfg(feature = "sftp")]
+    "sftp",
+];
+
+type Chunk = Arc<Vec<u8>>;
+
+/// Chunks on disk, as `<dir>/<file key>/<chunk index>`, evicted oldest
+/// first once they pass `budget` bytes.
+struct DiskCache {
+    dir: PathBuf,
+    budget: u64,
+    used: AtomicU64,
+    evicting: Mutex<()>,
+}
+
+impl DiskCache {
+    fn get(&self, key: &str, idx: u64) -> Option<Vec<u8>> {
+        fs::read(self.dir.join(key).join(idx.to_string())).ok()
+    }
+
+    fn put(&self, key: &str, idx: u64, data: &[u8]) {
+        let dir = self.dir.join(key);
+        let path = dir.join(idx.to_string());
+        let tmp = path.with_extension("part");
+        let res = fs::create_dir_all(&dir)
+            .and_then(|()| fs::write(&tmp, data))
+            .and_then(|()| fs::rename(&tmp, &path));
+        if let Err(e) = res {
+            warn!("caching {:?}: {}", path, e);
+            return;
+        }
+
+        let used = self.used.fetch_add(data.len() as u64, Ordering::Relaxed) + data.len() as u64;
+        if used > self.budget {
+            self.evict();
+        }
+    }
+
+    /// Remove the oldest chunks down to 90% of the budget.
+    fn evict(&self) {
+        let Ok(_guard) = self.evicting.try_lock() else {
+            return;
+        };
+
+        let mut files = chunk_files(&self.dir);
+        files.sort_by_key(|(_, _, mtime)| *mtime);
+        let mut used: u64 = files.iter().map(|(_, size, _)| size).sum();
+        let target = self.budget / 10 * 9;
+        for (path, size, _) in files {
+            if used <= target {
+                break;
+            }
+            if fs::remove_file(&path).is_ok() {
+                used -= size;
+            }
+        }
+        self.used.store(used, Ordering::Relaxed);
+    }
+}
+
+/// (path, size, mtime) of every chunk under `dir`.
+fn chunk_files(dir: &Path) -> Vec<(PathBuf, u64, SystemTime)> {
+    let Ok(keys) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    keys.flatten()
+        .filter_map(|k| fs::read_dir(k.path()).ok())
+        .flat_map(|chunks| chunks.flatten())
+        .filter_map(|c| {
+            let meta = c.metadata().ok()?;
+            Some((c.path(), meta.len(), meta.modified().ok()?))
+        })
+        .collect()
+}
+
+struct Caches {
+    disk: Option<DiskCache>,
+    memory: Mutex<LruCache<(String, u64), Chunk>>,
+    /// Length by URL, so reopening a file costs no request
+    lengths: Mutex<HashMap<String, u64>>,
+}
+
+static CACHES: OnceLock<Caches> = OnceLock::new();
+
+/// Set up the chunk cache in `dir`, holding up to `mib` MiB (0: memory
+/// only). Call before the first index.
+pub fn configure(dir: &Path, mib: u64) {
+    let disk = (mib > 0).then(|| DiskCache {
+        dir: dir.to_path_buf(),
+        budget: mib.saturating_mul(1 << 20),
+        used: AtomicU64::new(chunk_files(dir).iter().map(|(_, size, _)| size).sum()),
+        evicting: Mutex::new(()),
+    });
+
+    let _ = CACHES.set(Caches {
+        disk,
+        memory: Mutex::new(LruCache::new(
+            NonZeroUsize::new(MEMORY_CHUNKS).expect("non-zero"),
+        )),
+        lengths: Mutex::new(HashMap::new()),
+    });
+}
+
+fn caches() -> &'static Caches {
+    CACHES.get().expect("remote::configure not called")
+}
+
+fn lengths() -> std::sync::MutexGuard<'static, HashMap<String, u64>> {
+    caches().lengths.lock().expect("lengths mutex poisoned")
+}
+
+fn url_of(path: &Path) -> Result<&str> {
+    path.to_str()
+        .ok_or_else(|| anyhow!("{path:?}: not a valid URL"))
+}
+
+/// Whether the last segment of `url` starts with `.`.
+fn is_hidden(url: &str) -> bool {
+    url.trim_end_matches('/')
+        .rsplit('/')
+        .next()
+        .is_some_and(|name| name.starts_with('.'))
+}
+
+/// Remote libraries, by URL scheme.
+pub struct RemoteVfs;
+
+impl SourceVfs for RemoteVfs {
+    fn list(&self, root: &Path, opts: &ScanOptions) -> Result<Vec<PathBuf>> {
+        let base = url_of(root)?;
+        // Lengths are taken afresh on each index.
+        lengths().clear();
+        let mut out = transport(base)?.list(base, opts)?;
+        out.sort();
+        Ok(out)
+    }
+
+    fn stat(&self, path: &Path) -> Result<SourceMeta> {
+        let url = url_of(path)?;
+        let meta = transport(url)?.stat(url)?;
+        if !meta.is_dir {
+            lengths().insert(url.to_string(), meta.size);
+        }
+        Ok(meta)
+    }
+
+    fn open(&self, path: &Path) -> Result<Box<dyn RangedRead>> {
+        Ok(Box::new(RemoteFile::open(path)?))
+    }
+}
+
+/// A remote file, read through the chunk caches.
+struct RemoteFile {
+    transport: &'static dyn Transport,
+    url: String,
+    /// Cache key: the URL and length, so a replaced file is fetched afresh
+    key: String,
+    len: u64,
+}
+
+impl fmt::Debug for RemoteFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RemoteFile")
+            .field("url", &self.url)
+            .field("len", &self.len)
+            .finish()
+    }
+}
+
+impl RemoteFile {
+    fn open(path: &Path) -> Result<Self> {
+        let url = url_of(path)?;
+        let transport = transport(url)?;
+        let known = lengths().get(url).copied();
+        let len = match known {
+            Some(len) => len,
+            None => {
+                let meta = transport.stat(url)?;
+                if meta.is_dir {
+                    bail!("{url}: is a directory");
+                }
+                lengths().insert(url.to_string(), meta.size);
+                meta.size
+            }
+        };
+
+        Ok(Self {
+            transport,
+            key: sha1::hex(&sha1::digest(format!("{url} {len}").as_bytes())),
+            url: url.to_string(),
+            len,
+        })
+    }
+
+    fn fetch(&self, idx: u64) -> Result<Chunk> {
+        let c = caches();
+        let mem_key = (self.key.clone(), idx);
+        if let Some(chunk) = c
+            .memory
+            .lock()
+            .expect("chunk cache mutex poisoned")
+            .get(&mem_key)
+        {
+            return Ok(Arc::clone(chunk));
+        }
+
+        let chunk = match c.disk.as_ref().and_then(|d| d.get(&self.key, idx)) {
+            Some(data) => Arc::new(data),
+            None => {
+                let start = idx * CHUNK;
+                let want = CHUNK.min(self.len - start);
+                let data = self.transport.read(&self.url, start, want)?;
+                debug!("fetched {} bytes at {} of {}", want, start, self.url);
+                if let Some(d) = &c.disk {
+                    d.put(&self.key, idx, &data);
+                }
+                Arc::new(data)
+            }
+        };
+        c.memory
+            .lock()
+            .expect("chunk cache mutex poisoned")
+            .put(mem_key, Arc::clone(&chunk));
+        Ok(chunk)
+    }
+}
+
+impl RangedRead for RemoteFile {
+    fn size(&self) -> u64 {
+        self.len
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        if offset >= self.len || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let idx = offset / CHUNK;
+        let data = self.fetch(idx).map_err(io::Error::other)?;
+        let off = (offset - idx * CHUNK) as usize;
+        let n = (data.len() - off).min(buf.len());
+        buf[..n].copy_from_slice(&data[off..off + n]);
+        Ok(n)
+    }
+}
@@ -0,0 +1,252 @@
+//! `http(s)://` sources. The library is listed from the server's directory
+//! index pages (an nginx or Apache autoindex: every link to a source file,
+//! following links to subdirectories), and files are read with range
+//! requests.
+
+use anyhow::{anyhow, bail, Context, Result};
+use std::{collections::HashSet, io::Read, path::PathBuf, sync::OnceLock};
+use tracing::warn;
+
+use super::{is_hidden, Transport, MAX_DEPTH, TIMEOUT};
+use crate::vfs::{ScanOptions, SourceMeta};
+
+struct Http {
+    agent: ureq::Agent,
+}
+
+pub(super) fn transport() -> &'static dyn Transport {
+    static HTTP: OnceLock<Http> = OnceLock::new();
+    HTTP.get_or_init(|| Http {
+        agent: ureq::AgentBuilder::new().timeout(TIMEOUT).build(),
+    })
+}
+
+impl Http {
+    fn walk(
+        &self,
+        base: &str,
+        page: &str,
+        depth: usize,
+        opts: &ScanOptions,
+        seen: &mut HashSet<String>,
+        out: &mut Vec<PathBuf>,
+    ) -> Result<()> {
+        let html = self
+            .agent
+            .get(page)
+            .call()
+            .with_context(|| format!("listing {page}"))?
+            .into_string()?;
+
+        for href in links(&html) {
+            let Some(url) = resolve(page, &href) else {
+                continue;
+            };
+            if url.len() <= base.len() || !url.starts_with(base) || !seen.insert(url.clone()) {
+                continue;
+            }
+            if opts.skip_hidden && is_hidden(&decode(&url)) {
+                continue;
+            }
+
+            if url.ends_with('/') {
+                if depth >= MAX_DEPTH {
+                    continue;
+                }
+                if let Err(e) = self.walk(base, &url, depth + 1, opts, seen, out) {
+                    warn!("skipping {}: {:#}", url, e);
+                }
+            } else {
+                let path = PathBuf::from(decode(&url));
+                if (opts.wanted)(&path) {
+                    out.push(path);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Transport for Http {
+    fn list(&self, base: &str, opts: &ScanOptions) -> Result<Vec<PathBuf>> {
+        let mut base = encode(&decode(base));
+        if !base.ends_with('/') {
+            base.push('/');
+        }
+
+        let mut seen = HashSet::new();
+        let mut out = Vec::new();
+        self.walk(&base, &base, 0, opts, &mut seen, &mut out)?;
+        Ok(out)
+    }
+
+    /// An HTML page in answer to a range request is a directory index; files
+    /// can only be read from servers that support ranges.
+    fn stat(&self, url: &str) -> Result<SourceMeta> {
+        let url = encode(&decode(url));
+        let resp = self
+            .agent
+            .get(&url)
+            .set("Range", "bytes=0-0")
+            .call()
+            .with_context(|| format!("opening {url}"))?;
+        if resp.status() != 206 {
+            if !resp.content_type().starts_with("text/html") {
+                bail!("{url}: the server does not support range requests");
+            }
+            return Ok(SourceMeta {
+                is_dir: true,
+                size: 0,
+                mtime: None,
+                local: None,
+            });
+        }
+        let size = resp
+            .header("Content-Range")
+            .and_then(|r| r.rsplit('/').next()?.parse().ok())
+            .ok_or_else(|| anyhow!("{url}: no length in Content-Range"))?;
+        Ok(SourceMeta {
+            is_dir: false,
+            size,
+            mtime: None,
+            local: None,
+        })
+    }
+
+    fn read(&self, url: &str, start: u64, len: u64) -> Result<Vec<u8>> {
+        let url = encode(url);
+        let resp = self
+            .agent
+            .get(&url)
+            .set("Range", &format!("bytes={}-{}", start, start + len - 1))
+            .call()
+            .with_context(|| format!("fetching {url} at {start}"))?;
+        if resp.status() != 206 {
+            bail!("{url}: range request answered with {}", resp.status());
+        }
+
+        let mut data = Vec::with_capacity(len as usize);
+        resp.into_reader().take(len).read_to_end(&mut data)?;
+        if data.len() as u64 != len {
+            bail!("{url}: short read at {start}");
+        }
+        Ok(data)
+    }
+}
+
+/// Targets of the `href` attributes in `html`.
+fn links(html: &str) -> Vec<String> {
+    let lower = html.to_ascii_lowercase();
+    let mut out = Vec::new();
+    let mut from = 0;
+    while let Some(i) = lower[from..].find("href=") {
+        let start = from + i + "href=".len();
+        from = start;
+        let Some(quote) = html[start..]
+            .chars()
+            .next()
+            .filter(|c| *c == '"' || *c == '\'')
+        else {
+            continue;
+        };
+        let Some(len) = html[start + 1..].find(quote) else {
+            break;
+        };
+        out.push(html[start + 1..start + 1 + len].replace("&amp;", "&"));
+        from = start + 1 + len;
+    }
+    out
+}
+
+/// `href` as an absolute URL, relative to `page`; None for links that are
+/// not plain files or directories (sorting queries, parents, other schemes).
+fn resolve(page: &str, href: &str) -> Option<String> {
+    if href.is_empty() || href.contains(['?', '#']) || href.split('/').any(|seg| seg == "..") {
+        return None;
+    }
+    if href.starts_with("http://") || href.starts_with("https://") {
+        return Some(href.to_string());
+    }
+    if href.split('/').next()?.contains(':') {
+        return None;
+    }
+
+    if let Some(path) = href.strip_prefix('/') {
+        let host = page.find("://")? + 3;
+        let origin = host + page[host..].find('/')?;
+        return Some(format!("{}/{}", &page[..origin], path));
+    }
+    let dir = &page[..=page.rfind('/')?];
+    Some(format!("{dir}{}", href.trim_start_matches("./")))
+}
+
+fn decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|h| std::str::from_utf8(h).ok())
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(b)) => {
+                out.push(b);
+                i += 3;
+            }
+            (b, _) => {
+                out.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+fn encode(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for b in s.bytes() {
+        if b.is_ascii_alphanumeric() || b"-._~/:@!$&'()*+,;=".contains(&b) {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("%{b:02X}"));
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn follows_autoindex_links() {
+        let page = "https://nas/chds/";
+        let html = r#"<a href="?C=N;O=D">Name</a> <a href="../">Parent</a>
+            <a href="ps2/">ps2/</a> <a HREF='Game%20(USA).chd'>Game (USA).chd</a>
+            <a href="/chds/Other.chd">x</a> <a href="mailto:admin@nas">mail</a>"#;
+
+        let urls: Vec<String> = links(html)
+            .iter()
+            .filter_map(|h| resolve(page, h))
+            .collect();
+        assert_eq!(
+            urls,
+            vec![
+                "https://nas/chds/ps2/",
+                "https://nas/chds/Game%20(USA).chd",
+                "https://nas/chds/Other.chd",
+            ]
+        );
+    }
+
+    #[test]
+    fn encoding_round_trips() {
+        let url = "https://nas/chds/Game (USA) [100%].chd";
+        assert_eq!(
+            encode(url),
+            "https://nas/chds/Game%20(USA)%20%5B100%25%5D.chd"
+        );
+        assert_eq!(decode(&encode(url)), url);
+    }
+}
@@ -0,0 +1,304 @@
+//! `sftp://[user@]host[:port]/path` sources, for a NAS that only offers
+//! SSH. Sessions log in as `user` (default `$USER`) with the SSH agent or
+//! an unencrypted default key (`~/.ssh/id_ed25519`, `id_ecdsa`, `id_rsa`),
+//! and the server's host key must already be in `~/.ssh/known_hosts`. Up
+//! to `POOL_SIZE` idle sessions per server are kept for the ranged reads.
+//! Paths are taken as written, without percent-decoding.
+
+use anyhow::{anyhow, bail, Context, Result};
+use ssh2::{CheckResult, KnownHostFileKind, Session, Sftp};
+use std::{
+    collections::HashMap,
+    env,
+    io::{Read, Seek, SeekFrom},
+    net::TcpStream,
+    path::{Path, PathBuf},
+    sync::{Mutex, OnceLock},
+};
+use tracing::{info, warn};
+
+use super::{is_hidden, Transport, MAX_DEPTH, TIMEOUT};
+use crate::vfs::{ScanOptions, SourceMeta};
+
+/// Idle sessions kept per server.
+const POOL_SIZE: usize = 4;
+
+const DEFAULT_PORT: u16 = 22;
+
+const KEY_FILES: &[&str] = &["id_ed25519", "id_ecdsa", "id_rsa"];
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct Server {
+    user: String,
+    host: String,
+    port: u16,
+}
+
+/// `url` split into its server and absolute path.
+fn parse(url: &str) -> Result<(Server, &str)> {
+    let rest = url
+        .strip_prefix("sftp://")
+        .ok_or_else(|| anyhow!("{url}: not an sftp URL"))?;
+    let (authority, path) = match rest.find('/') {
+        Some(i) => rest.split_at(i),
+        None => (rest, "/"),
+    };
+
+    let (user, host_port) = match authority.rsplit_once('@') {
+        Some((user, host_port)) => (user.to_string(), host_port),
+        None => (
+            env::var("USER").map_err(|_| anyhow!("{url}: no user, and $USER is unset"))?,
+            authority,
+        ),
+    };
+    let (host, port) = match host_port.strip_prefix('[').and_then(|h| h.split_once(']')) {
+        Some((host, rest)) => (host, rest.strip_prefix(':')),
+        None => match host_port.split_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (host_port, None),
+        },
+    };
+    if host.is_empty() {
+        bail!("{url}: no host");
+    }
+    let port = port
+        .map(str::parse::<u16>)
+        .transpose()
+        .with_context(|| format!("{url}: bad port"))?
+        .unwrap_or(DEFAULT_PORT);
+
+    Ok((
+        Server {
+            user,
+            host: host.to_string(),
+            port,
+        },
+        path,
+    ))
+}
+
+struct Conn {
+    sftp: Sftp,
+    _session: Session,
+}
+
+fn ssh_dir() -> Result<PathBuf> {
+    env::var_os("HOME")
+        .map(|home| PathBuf::from(home).join(".ssh"))
+        .ok_or_else(|| anyhow!("$HOME is unset, so there is no ~/.ssh"))
+}
+
+fn connect(server: &Server) -> Result<Conn> {
+    let tcp = TcpStream::connect((server.host.as_str(), server.port))
+        .with_context(|| format!("connecting to {}:{}", server.host, server.port))?;
+    let mut session = Session::new()?;
+    session.set_tcp_stream(tcp);
+    session.set_timeout(TIMEOUT.as_millis() as u32);
+    session
+        .handshake()
+        .with_context(|| format!("SSH handshake with {}", server.host))?;
+
+    verify_host(&session, server)?;
+    authenticate(&session, server)?;
+    info!("SSH session to {}@{}", server.user, server.host);
+
+    Ok(Conn {
+        sftp: session.sftp()?,
+        _session: session,
+    })
+}
+
+fn verify_host(session: &Session, server: &Server) -> Result<()> {
+    let (key, _) = session
+        .host_key()
+        .ok_or_else(|| anyhow!("{}: no host key offered", server.host))?;
+    let file = ssh_dir()?.join("known_hosts");
+    let mut known = session.known_hosts()?;
+    known
+        .read_file(&file, KnownHostFileKind::OpenSSH)
+        .with_context(|| format!("reading {file:?}"))?;
+
+    match known.check_port(&server.host, server.port, key) {
+        CheckResult::Match => Ok(()),
+        CheckResult::Mismatch => bail!(
+            "{}: host key does NOT match the one in {:?}",
+            server.host,
+            file
+        ),
+        CheckResult::NotFound => bail!(
+            "{}: host key not in {:?}; connect once with ssh to add it",
+            server.host,
+            file
+        ),
+        CheckResult::Failure => bail!("{}: cannot check the host key", server.host),
+    }
+}
+
+fn authenticate(session: &Session, server: &Server) -> Result<()> {
+    if session.userauth_agent(&server.user).is_ok() && session.authenticated() {
+        return Ok(());
+    }
+
+    let dir = ssh_dir()?;
+    for name in KEY_FILES {
+        let key = dir.join(name);
+        if key.is_file()
+            && session
+                .userauth_pubkey_file(&server.user, None, &key, None)
+                .is_ok()
+            && session.authenticated()
+        {
+            return Ok(());
+        }
+    }
+    bail!(
+        "{}@{}: neither the SSH agent nor an unencrypted key in {:?} was accepted",
+        server.user,
+        server.host,
+        dir
+    )
+}
+
+#[derive(Default)]
+struct Pool {
+    idle: Mutex<HashMap<Server, Vec<Conn>>>,
+}
+
+pub(super) fn transport() -> &'static dyn Transport {
+    static POOL: OnceLock<Pool> = OnceLock::new();
+    POOL.get_or_init(Pool::default)
+}
+
+impl Pool {
+    /// Run `f` on an idle session to `server`, or a new one. Sessions go
+    /// back to the pool only after success, as an error may have broken them.
+    fn with<T>(&self, server: &Server, f: impl FnOnce(&Sftp) -> Result<T>) -> Result<T> {
+        let idle = self
+            .idle
+            .lock()
+            .expect("session pool mutex poisoned")
+            .get_mut(server)
+            .and_then(Vec::pop);
+        let conn = match idle {
+            Some(conn) => conn,
+            None => connect(server)?,
+        };
+
+        let res = f(&conn.sftp);
+        if res.is_ok() {
+            let mut idle = self.idle.lock().expect("session pool mutex poisoned");
+            let conns = idle.entry(server.clone()).or_default();
+            if conns.len() < POOL_SIZE {
+                conns.push(conn);
+            }
+        }
+        res
+    }
+}
+
+/// Files under `dir`, as URLs starting with `prefix`.
+fn walk(
+    sftp: &Sftp,
+    prefix: &str,
+    dir: &Path,
+    depth: usize,
+    opts: &ScanOptions,
+    out: &mut Vec<PathBuf>,
+) -> Result<()> {
+    for (path, stat) in sftp
+        .readdir(dir)
+        .with_context(|| format!("listing {dir:?}"))?
+    {
+        let url = format!("{prefix}{}", path.display());
+        if opts.skip_hidden && is_hidden(&url) {
+            continue;
+        }
+
+        if stat.is_dir() {
+            if depth >= MAX_DEPTH {
+                continue;
+            }
+            if let Err(e) = walk(sftp, prefix, &path, depth + 1, opts, out) {
+                warn!("skipping {:?}: {:#}", path, e);
+            }
+        } else {
+            let url = PathBuf::from(url);
+            if (opts.wanted)(&url) {
+                out.push(url);
+            }
+        }
+    }
+    Ok(())
+}
+
+impl Transport for Pool {
+    fn list(&self, base: &str, opts: &ScanOptions) -> Result<Vec<PathBuf>> {
+        let (server, root) = parse(base)?;
+        // URLs keep the server as written.
+        let prefix = &base[..base.len() - root.len()];
+        self.with(&server, |sftp| {
+            let mut out = Vec::new();
+            walk(sftp, prefix, Path::new(root), 0, opts, &mut out)?;
+            Ok(out)
+        })
+    }
+
+    fn stat(&self, url: &str) -> Result<SourceMeta> {
+        let (server, path) = parse(url)?;
+        let stat = self.with(&server, |sftp| {
+            sftp.stat(Path::new(path))
+                .with_context(|| format!("stat {url}"))
+        })?;
+        let is_dir = stat.is_dir();
+        Ok(SourceMeta {
+            is_dir,
+            size: match stat.size {
+                Some(size) => size,
+                None if is_dir => 0,
+                None => bail!("{url}: no size"),
+            },
+            mtime: stat.mtime.map(|t| t as i64),
+            local: None,
+        })
+    }
+
+    fn read(&self, url: &str, start: u64, len: u64) -> Result<Vec<u8>> {
+        let (server, path) = parse(url)?;
+        self.with(&server, |sftp| {
+            let mut file = sftp
+                .open(Path::new(path))
+                .with_context(|| format!("opening {url}"))?;
+            file.seek(SeekFrom::Start(start))?;
+            let mut data = vec![0; len as usize];
+            file.read_exact(&mut data)
+                .with_context(|| format!("reading {url} at {start}"))?;
+            Ok(data)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_urls() {
+        let (server, path) = parse("sftp://pi@nas.local:2222/srv/chds").unwrap();
+        assert_eq!(
+            server,
+            Server {
+                user: "pi".into(),
+                host: "nas.local".into(),
+                port: 2222
+            }
+        );
+        assert_eq!(path, "/srv/chds");
+
+        let (server, path) = parse("sftp://pi@[fe80::1]/").unwrap();
+        assert_eq!((server.host.as_str(), server.port), ("fe80::1", 22));
+        assert_eq!(path, "/");
+
+        assert!(parse("sftp://pi@/srv").is_err());
+        assert!(parse("sftp://pi@nas:ssh/srv").is_err());
+    }
+}
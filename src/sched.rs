@@ -0,0 +1,145 @@
+//! Process scheduling controls (`--nice`, `--ionice`, `--decode-cpu-affinity`).
+//!
+//! All three are applied to the main thread before any other thread starts.
+//! Linux threads inherit nice value, I/O priority and CPU affinity from their
+//! creator, so the FUSE session threads, the reload thread and the async
+//! backend's decode pool all pick them up.
+
+use anyhow::{anyhow, Result};
+use std::{io, mem, str::FromStr};
+
+/// Not exported by libc; see ioprio_set(2).
+const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+const IOPRIO_CLASS_SHIFT: u32 = 13;
+
+/// `--ionice CLASS[:PRIO]`, as in ionice(1).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IoPrio {
+    class: u32,
+    level: u32,
+}
+
+impl FromStr for IoPrio {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let (class, level) = match s.split_once(':') {
+            Some((c, p)) => (c, Some(p)),
+            None => (s, None),
+        };
+
+        let class = match class.to_ascii_lowercase().as_str() {
+            "realtime" | "rt" | "1" => 1,
+            "best-effort" | "be" | "2" => 2,
+            "idle" | "3" => 3,
+            other => return Err(format!("unknown I/O class {other:?}")),
+        };
+
+        let level = match level {
+            Some(p) => p
+                .parse::<u32>()
+                .ok()
+                .filter(|p| *p <= 7)
+                .ok_or_else(|| format!("I/O priority must be 0-7, got {p:?}"))?,
+            None => 4,
+        };
+
+        Ok(Self { class, level })
+    }
+}
+
+/// `--decode-cpu-affinity LIST`, e.g. `2-3,6`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CpuList(Vec<usize>);
+
+impl FromStr for CpuList {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let bad = || format!("invalid CPU list {s:?}");
+        let mut cpus = Vec::new();
+
+        for part in s.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let (lo, hi) = match part.split_once('-') {
+                Some((lo, hi)) => (lo, hi),
+                None => (part, part),
+            };
+            let lo: usize = lo.parse().map_err(|_| bad())?;
+            let hi: usize = hi.parse().map_err(|_| bad())?;
+
+            if lo > hi || hi >= libc::CPU_SETSIZE as usize {
+                return Err(bad());
+            }
+            cpus.extend(lo..=hi);
+        }
+
+        if cpus.is_empty() {
+            return Err(bad());
+        }
+
+        cpus.sort_unstable();
+        cpus.dedup();
+        Ok(Self(cpus))
+    }
+}
+
+pub fn set_nice(n: i32) -> Result<()> {
+    if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, n) } != 0 {
+        return Err(anyhow!("--nice {n}: {}", io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+pub fn set_ionice(p: IoPrio) -> Result<()> {
+    let value = (p.class << IOPRIO_CLASS_SHIFT) | p.level;
+    if unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, value) } != 0 {
+        return Err(anyhow!("--ionice: {}", io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+/// Drop the calling thread to nice 19 and the idle I/O class, for
+/// maintenance work. On Linux both apply to the thread alone.
+pub fn background_thread() {
+    unsafe {
+        libc::setpriority(libc::PRIO_PROCESS, 0, 19);
+    }
+    if let Err(e) = set_ionice(IoPrio { class: 3, level: 7 }) {
+        tracing::debug!("idle I/O class for a worker: {:#}", e);
+    }
+}
+
+pub fn set_affinity(cpus: &CpuList) -> Result<()> {
+    let rc = unsafe {
+        let mut set: libc::cpu_set_t = mem::zeroed();
+        libc::CPU_ZERO(&mut set);
+        for &cpu in &cpus.0 {
+            libc::CPU_SET(cpu, &mut set);
+        }
+        libc::sched_setaffinity(0, mem::size_of::<libc::cpu_set_t>(), &set)
+    };
+
+    if rc != 0 {
+        return Err(anyhow!(
+            "--decode-cpu-affinity: {}",
+            io::Error::last_os_error()
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_ionice_and_cpu_lists() {
+        assert_eq!("idle".parse::<IoPrio>(), Ok(IoPrio { class: 3, level: 4 }));
+        assert_eq!("be:7".parse::<IoPrio>(), Ok(IoPrio { class: 2, level: 7 }));
+        assert!("be:8".parse::<IoPrio>().is_err());
+
+        assert_eq!("2-3,6,3".parse::<CpuList>(), Ok(CpuList(vec![2, 3, 6])));
+        assert!("3-1".parse::<CpuList>().is_err());
+        assert!("".parse::<CpuList>().is_err());
+    }
+}
@@ -0,0 +1,108 @@
+//! `chd2iso-fuse share`: print a `[[mount]]` table for `--config` that
+//! serves a curated subset of a library at a second mountpoint, for sharing
+//! over the network without exposing everything. Added to the same config
+//! as the full mount, both are served by one process and share its frame
+//! cache; with `--header-cache` or `--import-index` the share's index costs
+//! little on top. The table restricts the share with `only`, the same
+//! filter as `--only`.
+
+use anyhow::{bail, Context, Result};
+use serde::Serialize;
+use std::{collections::HashSet, fs, path::PathBuf};
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct ShareArgs {
+    /// Library the share picks from
+    #[arg(short = 's', long = "source", value_name = "DIR")]
+    source: PathBuf,
+
+    /// Mountpoint of the share
+    #[arg(short = 'm', long = "mount", value_name = "DIR")]
+    mount: PathBuf,
+
+    /// Titles to share: image or CHD names without extension, serials or --title-db names
+    #[arg(value_name = "TITLE")]
+    titles: Vec<String>,
+
+    /// Read more titles from FILE, one per line (# starts a comment)
+    #[arg(long = "from", value_name = "FILE")]
+    from: Option<PathBuf>,
+}
+
+#[derive(Serialize)]
+struct Profile {
+    mount: Vec<Table>,
+}
+
+#[derive(Serialize)]
+struct Table {
+    source: PathBuf,
+    mount: PathBuf,
+    only: Vec<String>,
+}
+
+pub fn run(args: &ShareArgs) -> Result<()> {
+    let mut titles = args.titles.clone();
+    if let Some(file) = &args.from {
+        let text = fs::read_to_string(file).with_context(|| format!("reading {file:?}"))?;
+        titles.extend(
+            text.lines()
+                .map(str::trim)
+                .filter(|l| !l.is_empty() && !l.starts_with('#'))
+                .map(str::to_string),
+        );
+    }
+    if titles.is_empty() {
+        bail!("no titles to share; list them or pass --from FILE");
+    }
+    if !args.source.is_dir() {
+        bail!("{:?} is not a directory", args.source);
+    }
+
+    let profile = Profile {
+        mount: vec![Table {
+            source: args.source.clone(),
+            mount: args.mount.clone(),
+            only: titles,
+        }],
+    };
+    print!("{}", toml::to_string(&profile)?);
+    Ok(())
+}
+
+/// `--only`: the titles a mount exposes, compared case-insensitively with
+/// every name an image goes by.
+pub struct Only(HashSet<String>);
+
+impl Only {
+    /// None (everything) for an empty list.
+    pub fn new(titles: &[String]) -> Option<Self> {
+        if titles.is_empty() {
+            return None;
+        }
+        Some(Self(
+            titles.iter().map(|t| t.trim().to_lowercase()).collect(),
+        ))
+    }
+
+    pub fn matches<'a>(&self, names: impl IntoIterator<Item = &'a str>) -> bool {
+        names
+            .into_iter()
+            .any(|n| self.0.contains(&n.to_lowercase()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_matches_any_name() {
+        assert!(Only::new(&[]).is_none());
+
+        let only = Only::new(&["ico (usa)".into(), " SLUS-20062".into()]).unwrap();
+        assert!(only.matches(["Ico (USA)"]));
+        assert!(only.matches(["Grand Theft Auto - Vice City", "slus-20062"]));
+        assert!(!only.matches(["Ico (Europe)", "SCES-50760"]));
+    }
+}
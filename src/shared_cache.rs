@@ -0,0 +1,758 @@
+//! `--shared-cache PATH`: decoded hunks shared between instances serving the
+//! same CHDs (per-user mounts, say), so a popular title is decompressed once
+//! rather than once per instance. The first instance to bind the Unix socket
+//! at PATH creates a memfd segment of `--shared-cache-mib` in fixed slots and
+//! keeps the index of what each slot holds; the others receive the segment
+//! over the socket and ask the owner where a hunk is, or where to put one.
+//! CHDs are matched by the SHA-1 of their data and their hunk and unit sizes,
+//! since the same data in other hunks lays out differently; those without a
+//! SHA-1 are not shared.
+//!
+//! Only the owner writes slot headers. A slot's sequence number is odd while
+//! its data is being written and changes whenever the slot is reused, so a
+//! reader that sees the same even number before and after copying got whole
+//! data. A slot is not handed out again until its writer says it is done or
+//! goes away, and the header keeps the CRC-32 of the data, which readers
+//! check, in case a writer that was given up on is still copying. When the
+//! owner exits the others decode locally, and a while later one of them
+//! takes the socket over; `PATH.lock` keeps it to one.
+//!
+//! Anyone who can connect to the socket can read and write the cache; it is
+//! created group-writable, so put it in a directory only the sharing users
+//! can reach.
+
+use anyhow::{anyhow, Context, Result};
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, BufRead, BufReader, Write},
+    mem::size_of,
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+        unix::{
+            fs::{OpenOptionsExt, PermissionsExt},
+            net::{UnixListener, UnixStream},
+        },
+    },
+    path::{Path, PathBuf},
+    ptr,
+    sync::{
+        atomic::{fence, AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard, OnceLock,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+use tracing::{debug, info, warn};
+
+use crate::crc32::Crc32;
+use crate::sha1::Sha1;
+
+/// Largest hunk a slot holds; larger hunks are not shared.
+const SLOT_BYTES: usize = 32 << 10;
+/// Slot header words: sequence, content id, hunk, length, CRC-32.
+const HEADER_WORDS: usize = 5;
+const HEADER_BYTES: usize = HEADER_WORDS * size_of::<u64>();
+/// How long to decode locally after losing the owner before trying again.
+const RETRY: Duration = Duration::from_secs(30);
+/// How long a slot stays reserved for a writer that has not said it is done.
+const ABANDONED: Duration = Duration::from_secs(10);
+
+static SHARED: OnceLock<SharedCache> = OnceLock::new();
+static HITS: AtomicU64 = AtomicU64::new(0);
+static STORES: AtomicU64 = AtomicU64::new(0);
+
+/// (content id, hunk)
+type Key = (u64, u32);
+
+/// Share decoded hunks through the socket at `path`, creating a segment of
+/// `mib` MiB if no instance serves one there yet.
+pub fn configure(path: &Path, mib: u64) -> Result<()> {
+    let bytes = mib.saturating_mul(1 << 20);
+    let link = attach(path, bytes).context("shared cache")?;
+    let _ = SHARED.set(SharedCache {
+        path: path.to_path_buf(),
+        bytes,
+        link: Mutex::new(link),
+    });
+    Ok(())
+}
+
+/// The id under which a CHD's hunks are shared, from the SHA-1 of its data
+/// and the hunk and unit sizes that say which bytes hunk N holds.
+pub fn content_id(data_sha1: Option<[u8; 20]>, hunk_bytes: u32, unit_bytes: u32) -> Option<u64> {
+    let data_sha1 = data_sha1?;
+    let mut h = Sha1::default();
+    h.update(&data_sha1);
+    h.update(&hunk_bytes.to_le_bytes());
+    h.update(&unit_bytes.to_le_bytes());
+    let digest = h.finish();
+    Some(u64::from_le_bytes(digest[..8].try_into().expect("8 bytes")))
+}
+
+/// Hunk `hunk` of the CHD shared as `id`: from the shared cache if an
+/// instance decoded it already, else from `decode` and then shared.
+pub fn hunk(
+    id: Option<u64>,
+    hunk: u32,
+    decode: impl FnOnce() -> Result<Vec<u8>>,
+) -> Result<Vec<u8>> {
+    let (Some(shared), Some(id)) = (SHARED.get(), id) else {
+        return decode();
+    };
+    if let Some(data) = shared.with(|link| link.get((id, hunk))).flatten() {
+        HITS.fetch_add(1, Ordering::Relaxed);
+        return Ok(data);
+    }
+    let data = decode()?;
+    if shared.with(|link| link.put((id, hunk), &data)) == Some(true) {
+        STORES.fetch_add(1, Ordering::Relaxed);
+    }
+    Ok(data)
+}
+
+/// (hunks read from the shared cache, hunks stored in it), if enabled.
+pub fn counts() -> Option<(u64, u64)> {
+    SHARED
+        .get()
+        .map(|_| (HITS.load(Ordering::Relaxed), STORES.load(Ordering::Relaxed)))
+}
+
+struct SharedCache {
+    path: PathBuf,
+    bytes: u64,
+    link: Mutex<Link>,
+}
+
+impl SharedCache {
+    fn link(&self) -> MutexGuard<'_, Link> {
+        self.link.lock().expect("shared cache lock poisoned")
+    }
+
+    /// Run `op` on the link, reattaching after `RETRY` if it is down and
+    /// marking it down if `op` fails. None while down. The lock is not held
+    /// while talking to the socket.
+    fn with<T>(&self, op: impl FnOnce(&Link) -> io::Result<T>) -> Option<T> {
+        let up = {
+            let mut link = self.link();
+            if let Link::Down(since) = *link {
+                if since.elapsed() < RETRY {
+                    return None;
+                }
+                // Still down to the others while this one reattaches.
+                *link = Link::Down(Instant::now());
+                None
+            } else {
+                Some(link.clone())
+            }
+        };
+        let link = match up {
+            Some(link) => link,
+            None => match attach(&self.path, self.bytes) {
+                Ok(link) => {
+                    *self.link() = link.clone();
+                    link
+                }
+                Err(e) => {
+                    debug!("shared cache: {:?}: {:#}", self.path, e);
+                    *self.link() = Link::Down(Instant::now());
+                    return None;
+                }
+            },
+        };
+        match op(&link) {
+            Ok(v) => Some(v),
+            Err(e) => {
+                warn!(
+                    "shared cache: lost {:?}: {}; decoding locally",
+                    self.path, e
+                );
+                let mut current = self.link();
+                if current.same(&link) {
+                    *current = Link::Down(Instant::now());
+                }
+                None
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+enum Link {
+    Owner(Arc<Owner>),
+    Client(Arc<Client>),
+    /// Since when
+    Down(Instant),
+}
+
+impl Link {
+    fn same(&self, other: &Link) -> bool {
+        match (self, other) {
+            (Link::Owner(a), Link::Owner(b)) => Arc::ptr_eq(a, b),
+            (Link::Client(a), Link::Client(b)) => Arc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+
+    fn get(&self, key: Key) -> io::Result<Option<Vec<u8>>> {
+        match self {
+            Link::Owner(owner) => Ok(owner.get(key)),
+            Link::Client(client) => client.get(key),
+            Link::Down(_) => Ok(None),
+        }
+    }
+
+    /// Whether `data` was stored.
+    fn put(&self, key: Key, data: &[u8]) -> io::Result<bool> {
+        match self {
+            Link::Owner(owner) => Ok(owner.put(key, data)),
+            Link::Client(client) => client.put(key, data),
+            Link::Down(_) => Ok(false),
+        }
+    }
+}
+
+/// A mapped segment: `slots` headers, then their data.
+struct Segment {
+    base: *mut u8,
+    len: usize,
+    slots: usize,
+}
+
+// Headers are only reached as atomics, and data copies are bracketed by
+// the sequence checks.
+unsafe impl Send for Segment {}
+unsafe impl Sync for Segment {}
+
+impl Segment {
+    fn create(bytes: u64) -> Result<(OwnedFd, Segment)> {
+        let slots = usize::try_from(bytes).unwrap_or(usize::MAX) / (HEADER_BYTES + SLOT_BYTES);
+        if slots == 0 {
+            return Err(anyhow!("--shared-cache-mib is too small for one hunk"));
+        }
+        let fd = unsafe { libc::memfd_create(c"chd2iso-fuse-hunks".as_ptr(), libc::MFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error()).context("memfd_create");
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        fs::File::from(fd.try_clone()?).set_len((slots * (HEADER_BYTES + SLOT_BYTES)) as u64)?;
+        let segment = Segment::map(&fd, slots)?;
+        Ok((fd, segment))
+    }
+
+    fn map(fd: &OwnedFd, slots: usize) -> Result<Segment> {
+        let len = slots
+            .checked_mul(HEADER_BYTES + SLOT_BYTES)
+            .ok_or_else(|| anyhow!("{slots} slots do not fit in memory"))?;
+        // A short file would fault on access rather than fail here.
+        if fs::File::from(fd.try_clone()?).metadata()?.len() < len as u64 {
+            return Err(anyhow!("segment is smaller than {slots} slots"));
+        }
+        let base = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                fd.as_raw_fd(),
+                0,
+            )
+        };
+        if base == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error()).context("mapping the segment");
+        }
+        Ok(Segment {
+            base: base.cast(),
+            len,
+            slots,
+        })
+    }
+
+    fn header(&self, slot: usize) -> &[AtomicU64; HEADER_WORDS] {
+        assert!(slot < self.slots, "slot {slot} out of range");
+        unsafe { &*self.base.add(slot * HEADER_BYTES).cast() }
+    }
+
+    fn data(&self, slot: usize) -> *mut u8 {
+        assert!(slot < self.slots, "slot {slot} out of range");
+        unsafe { self.base.add(self.slots * HEADER_BYTES + slot * SLOT_BYTES) }
+    }
+
+    /// The data in `slot`, if it is still at `seq` and matches its CRC.
+    fn read(&self, slot: usize, seq: u64) -> Option<Vec<u8>> {
+        let header = self.header(slot);
+        if seq % 2 == 1 || header[0].load(Ordering::Acquire) != seq {
+            return None;
+        }
+        let len = usize::try_from(header[3].load(Ordering::Relaxed))
+            .map_or(SLOT_BYTES, |n| n.min(SLOT_BYTES));
+        let crc = header[4].load(Ordering::Relaxed);
+        let mut out = vec![0; len];
+        unsafe { ptr::copy_nonoverlapping(self.data(slot), out.as_mut_ptr(), len) };
+        fence(Ordering::Acquire);
+        if header[0].load(Ordering::Relaxed) != seq || u64::from(crc32(&out)) != crc {
+            return None;
+        }
+        Some(out)
+    }
+
+    /// Fill `slot`, reserved for `data` by the owner.
+    fn write(&self, slot: usize, data: &[u8]) {
+        let len = data.len().min(SLOT_BYTES);
+        unsafe { ptr::copy_nonoverlapping(data.as_ptr(), self.data(slot), len) };
+        fence(Ordering::Release);
+    }
+}
+
+impl Drop for Segment {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.base.cast(), self.len) };
+    }
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = Crc32::default();
+    crc.update(data);
+    crc.finish()
+}
+
+/// The owner's index of the segment; slots are reused oldest first.
+struct Index {
+    at: HashMap<Key, usize>,
+    /// What each slot holds or is being written with
+    held: Vec<Option<Key>>,
+    /// When each slot being written was reserved
+    busy: Vec<Option<Instant>>,
+    next: usize,
+}
+
+impl Index {
+    fn new(slots: usize) -> Self {
+        Self {
+            at: HashMap::new(),
+            held: vec![None; slots],
+            busy: vec![None; slots],
+            next: 0,
+        }
+    }
+
+    /// The slot holding `key` and its sequence number.
+    fn find(&self, segment: &Segment, key: Key) -> Option<(usize, u64)> {
+        let &slot = self.at.get(&key)?;
+        Some((slot, segment.header(slot)[0].load(Ordering::Acquire)))
+    }
+
+    /// A slot to write `len` bytes of `key` into, marked busy; none if
+    /// `key` is held already or too large, or every slot is being written.
+    fn reserve(&mut self, segment: &Segment, key: Key, len: usize) -> Option<(usize, u64)> {
+        if len > SLOT_BYTES || self.at.contains_key(&key) {
+            return None;
+        }
+        let slots = self.held.len();
+        // A writer that has been at it this long is taken to be gone; the
+        // CRC catches it if it still writes.
+        let slot = (0..slots)
+            .map(|n| (self.next + n) % slots)
+            .find(|&slot| self.busy[slot].is_none_or(|at| at.elapsed() >= ABANDONED))?;
+        self.next = (slot + 1) % slots;
+        if let Some(old) = self.held[slot].take() {
+            if self.at.get(&old) == Some(&slot) {
+                self.at.remove(&old);
+            }
+        }
+        let header = segment.header(slot);
+        // Odd, and different from before even if a write was abandoned.
+        let seq = (header[0].load(Ordering::Relaxed) + 1) | 1;
+        header[0].store(seq, Ordering::Release);
+        header[1].store(key.0, Ordering::Relaxed);
+        header[2].store(key.1.into(), Ordering::Relaxed);
+        header[3].store(len as u64, Ordering::Relaxed);
+        self.held[slot] = Some(key);
+        self.busy[slot] = Some(Instant::now());
+        Some((slot, seq))
+    }
+
+    /// Publish the write into `slot` reserved at `seq`, of data with CRC
+    /// `crc`; false if the slot was reused meanwhile.
+    fn done(&mut self, segment: &Segment, slot: usize, seq: u64, crc: u32) -> bool {
+        let Some(&Some(key)) = self.held.get(slot) else {
+            return false;
+        };
+        let header = segment.header(slot);
+        if header[0].load(Ordering::Relaxed) != seq {
+            return false;
+        }
+        header[4].store(crc.into(), Ordering::Relaxed);
+        header[0].store(seq + 1, Ordering::Release);
+        self.busy[slot] = None;
+        self.at.insert(key, slot);
+        true
+    }
+
+    /// Free `slot`, reserved at `seq` by a writer that went away.
+    fn abandon(&mut self, segment: &Segment, slot: usize, seq: u64) {
+        if self.busy.get(slot).is_some_and(Option::is_some)
+            && segment.header(slot)[0].load(Ordering::Relaxed) == seq
+        {
+            self.busy[slot] = None;
+            self.held[slot] = None;
+        }
+    }
+}
+
+struct Owner {
+    segment: Segment,
+    index: Mutex<Index>,
+    fd: OwnedFd,
+}
+
+impl Owner {
+    fn index(&self) -> MutexGuard<'_, Index> {
+        self.index.lock().expect("shared cache index poisoned")
+    }
+
+    fn get(&self, key: Key) -> Option<Vec<u8>> {
+        let (slot, seq) = self.index().find(&self.segment, key)?;
+        self.segment.read(slot, seq)
+    }
+
+    fn put(&self, key: Key, data: &[u8]) -> bool {
+        let Some((slot, seq)) = self.index().reserve(&self.segment, key, data.len()) else {
+            return false;
+        };
+        self.segment.write(slot, data);
+        self.index().done(&self.segment, slot, seq, crc32(data))
+    }
+
+    /// Answer one instance: the segment, then a line for each `get`, `put`
+    /// and `done` request, `at SLOT SEQ` or `none`. Slots it leaves
+    /// reserved are freed when it goes.
+    fn peer(&self, stream: UnixStream) -> io::Result<()> {
+        let mut reserved = Vec::new();
+        let served = self.answer(stream, &mut reserved);
+        let mut index = self.index();
+        for (slot, seq) in reserved {
+            index.abandon(&self.segment, slot, seq);
+        }
+        served
+    }
+
+    fn answer(&self, stream: UnixStream, reserved: &mut Vec<(usize, u64)>) -> io::Result<()> {
+        let hello = format!("slots {}\n", self.segment.slots);
+        send_fd(&stream, hello.as_bytes(), self.fd.as_raw_fd())?;
+        let mut out = stream.try_clone()?;
+        for line in BufReader::new(stream).lines() {
+            let line = line?;
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let at = match words.as_slice() {
+                ["get", id, hunk] => {
+                    key(id, hunk).and_then(|key| self.index().find(&self.segment, key))
+                }
+                ["put", id, hunk, len] => {
+                    let at = key(id, hunk)
+                        .zip(len.parse().ok())
+                        .and_then(|(key, len)| self.index().reserve(&self.segment, key, len));
+                    reserved.extend(at);
+                    at
+                }
+                ["done", slot, seq, crc] => match (slot.parse(), seq.parse(), crc.parse()) {
+                    (Ok(slot), Ok(seq), Ok(crc)) => {
+                        reserved.retain(|&r| r != (slot, seq));
+                        self.index()
+                            .done(&self.segment, slot, seq, crc)
+                            .then_some((slot, seq + 1))
+                    }
+                    _ => None,
+                },
+                _ => None,
+            };
+            let reply = match at {
+                Some((slot, seq)) => format!("at {slot} {seq}\n"),
+                None => "none\n".into(),
+            };
+            out.write_all(reply.as_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+fn key(id: &str, hunk: &str) -> Option<Key> {
+    Some((id.parse().ok()?, hunk.parse().ok()?))
+}
+
+/// An instance using another's segment.
+struct Client {
+    segment: Segment,
+    stream: Mutex<BufReader<UnixStream>>,
+}
+
+impl Client {
+    fn ask(&self, request: &str) -> io::Result<Option<(usize, u64)>> {
+        let mut stream = self.stream.lock().expect("shared cache stream poisoned");
+        stream.get_mut().write_all(request.as_bytes())?;
+        let mut reply = String::new();
+        if stream.read_line(&mut reply)? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let mut words = reply.split_whitespace();
+        if words.next() != Some("at") {
+            return Ok(None);
+        }
+        let slot = words.next().and_then(|w| w.parse().ok());
+        let seq = words.next().and_then(|w| w.parse().ok());
+        Ok(slot.zip(seq).filter(|&(slot, _)| slot < self.segment.slots))
+    }
+
+    fn get(&self, (id, hunk): Key) -> io::Result<Option<Vec<u8>>> {
+        let at = self.ask(&format!("get {id} {hunk}\n"))?;
+        Ok(at.and_then(|(slot, seq)| self.segment.read(slot, seq)))
+    }
+
+    fn put(&self, (id, hunk): Key, data: &[u8]) -> io::Result<bool> {
+        let Some((slot, seq)) = self.ask(&format!("put {id} {hunk} {}\n", data.len()))? else {
+            return Ok(false);
+        };
+        self.segment.write(slot, data);
+        let crc = crc32(data);
+        Ok(self.ask(&format!("done {slot} {seq} {crc}\n"))?.is_some())
+    }
+}
+
+/// Use the segment of the instance serving `path`, or serve one there.
+fn attach(path: &Path, bytes: u64) -> Result<Link> {
+    // Held until bound, so two instances finding a stale socket do not
+    // both remove it and serve segments of their own.
+    let lock = lock_beside(path)?;
+    match UnixStream::connect(path) {
+        Ok(stream) => {
+            drop(lock);
+            return join(path, stream);
+        }
+        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+            fs::remove_file(path).with_context(|| format!("removing stale socket {path:?}"))?;
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e).with_context(|| format!("connecting to {path:?}")),
+    }
+    let listener = match UnixListener::bind(path) {
+        Ok(listener) => listener,
+        // Another instance got there first.
+        Err(e) if e.kind() == io::ErrorKind::AddrInUse => {
+            drop(lock);
+            return join(path, UnixStream::connect(path)?);
+        }
+        Err(e) => return Err(e).with_context(|| format!("binding {path:?}")),
+    };
+    drop(lock);
+    fs::set_permissions(path, fs::Permissions::from_mode(0o660))?;
+    let (fd, segment) = Segment::create(bytes)?;
+    info!(
+        "shared cache: serving {} hunk slots on {:?}",
+        segment.slots, path
+    );
+
+    let owner = Arc::new(Owner {
+        index: Mutex::new(Index::new(segment.slots)),
+        segment,
+        fd,
+    });
+    let serving = Arc::clone(&owner);
+    thread::Builder::new()
+        .name("shared-cache".into())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let owner = Arc::clone(&serving);
+                        let _ = thread::Builder::new()
+                            .name("shared-cache-peer".into())
+                            .spawn(move || {
+                                if let Err(e) = owner.peer(stream) {
+                                    debug!("shared cache: peer: {}", e);
+                                }
+                            });
+                    }
+                    Err(e) => warn!("shared cache: accept failed: {}", e),
+                }
+            }
+        })?;
+    Ok(Link::Owner(owner))
+}
+
+fn join(path: &Path, stream: UnixStream) -> Result<Link> {
+    let (hello, fd) = recv_fd(&stream).with_context(|| format!("joining {path:?}"))?;
+    let slots = hello
+        .strip_prefix("slots ")
+        .and_then(|n| n.trim().parse().ok())
+        .ok_or_else(|| anyhow!("{path:?}: unexpected greeting {hello:?}"))?;
+    let segment = Segment::map(&fd, slots)?;
+    info!(
+        "shared cache: using {} hunk slots served on {:?}",
+        slots, path
+    );
+    Ok(Link::Client(Arc::new(Client {
+        segment,
+        stream: Mutex::new(BufReader::new(stream)),
+    })))
+}
+
+/// `PATH.lock`, created group-writable like the socket, locked exclusively.
+fn lock_beside(path: &Path) -> Result<File> {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".lock");
+    let file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .mode(0o660)
+        .open(&name)
+        .with_context(|| format!("opening {name:?}"))?;
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+        return Err(io::Error::last_os_error()).with_context(|| format!("locking {name:?}"));
+    }
+    Ok(file)
+}
+
+/// Send `msg` with `fd` attached (SCM_RIGHTS).
+fn send_fd(stream: &UnixStream, msg: &[u8], fd: RawFd) -> io::Result<()> {
+    let mut iov = libc::iovec {
+        iov_base: msg.as_ptr() as *mut libc::c_void,
+        iov_len: msg.len(),
+    };
+    let mut control = [0u64; 8];
+    unsafe {
+        let mut hdr: libc::msghdr = std::mem::zeroed();
+        hdr.msg_iov = &mut iov;
+        hdr.msg_iovlen = 1;
+        hdr.msg_control = control.as_mut_ptr().cast();
+        hdr.msg_controllen = libc::CMSG_SPACE(size_of::<RawFd>() as u32) as _;
+        let cmsg = libc::CMSG_FIRSTHDR(&hdr);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(size_of::<RawFd>() as u32) as _;
+        ptr::write_unaligned(libc::CMSG_DATA(cmsg).cast::<RawFd>(), fd);
+        if libc::sendmsg(stream.as_raw_fd(), &hdr, 0) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Receive a line sent by `send_fd` and its descriptor.
+fn recv_fd(stream: &UnixStream) -> io::Result<(String, OwnedFd)> {
+    let mut buf = [0u8; 64];
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr().cast(),
+        iov_len: buf.len(),
+    };
+    let mut control = [0u64; 8];
+    let (n, fd) = unsafe {
+        let mut hdr: libc::msghdr = std::mem::zeroed();
+        hdr.msg_iov = &mut iov;
+        hdr.msg_iovlen = 1;
+        hdr.msg_control = control.as_mut_ptr().cast();
+        hdr.msg_controllen = std::mem::size_of_val(&control) as _;
+        let n = libc::recvmsg(stream.as_raw_fd(), &mut hdr, 0);
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let cmsg = libc::CMSG_FIRSTHDR(&hdr);
+        if cmsg.is_null()
+            || (*cmsg).cmsg_level != libc::SOL_SOCKET
+            || (*cmsg).cmsg_type != libc::SCM_RIGHTS
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "no segment in greeting",
+            ));
+        }
+        let fd = ptr::read_unaligned(libc::CMSG_DATA(cmsg).cast::<RawFd>());
+        (n as usize, OwnedFd::from_raw_fd(fd))
+    };
+    Ok((String::from_utf8_lossy(&buf[..n]).into_owned(), fd))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn instances_share_hunks_through_the_owner() {
+        let path = std::env::temp_dir().join(format!("chd2iso-shared-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let owner = attach(&path, 1 << 20).unwrap();
+        let client = attach(&path, 1 << 20).unwrap();
+        assert!(matches!(owner, Link::Owner(_)));
+        assert!(matches!(client, Link::Client(_)));
+
+        let hunk = vec![7u8; 19584];
+        assert!(client.get((1, 3)).unwrap().is_none());
+        assert!(client.put((1, 3), &hunk).unwrap());
+        assert_eq!(owner.get((1, 3)).unwrap(), Some(hunk.clone()));
+        // Held already, and too large for a slot.
+        assert!(!owner.put((1, 3), &hunk).unwrap());
+        assert!(!owner.put((1, 4), &vec![0; SLOT_BYTES + 1]).unwrap());
+
+        // Reusing every slot drops the first hunk.
+        let Link::Owner(o) = &owner else {
+            unreachable!()
+        };
+        for n in 0..o.segment.slots as u32 {
+            assert!(owner.put((2, n), &[1, 2, 3]).unwrap());
+        }
+        assert!(client.get((1, 3)).unwrap().is_none());
+        assert_eq!(client.get((2, 1)).unwrap(), Some(vec![1, 2, 3]));
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(path.with_extension("lock")).unwrap();
+    }
+
+    #[test]
+    fn one_instance_takes_over_a_stale_socket() {
+        let path = std::env::temp_dir().join(format!("chd2iso-stale-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        drop(UnixListener::bind(&path).unwrap());
+
+        let links: Vec<Link> = (0..4)
+            .map(|_| {
+                let path = path.clone();
+                thread::spawn(move || attach(&path, 1 << 20).unwrap())
+            })
+            .collect::<Vec<_>>()
+            .into_iter()
+            .map(|t| t.join().unwrap())
+            .collect();
+        let owners = links.iter().filter(|l| matches!(l, Link::Owner(_))).count();
+        assert_eq!(owners, 1);
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(path.with_extension("lock")).unwrap();
+    }
+
+    #[test]
+    fn slots_being_written_are_not_handed_out() {
+        let (_fd, segment) = Segment::create(2 * (HEADER_BYTES + SLOT_BYTES) as u64).unwrap();
+        let mut index = Index::new(segment.slots);
+        let (a, a_seq) = index.reserve(&segment, (1, 0), 3).unwrap();
+        let (b, b_seq) = index.reserve(&segment, (1, 1), 3).unwrap();
+        assert_ne!(a, b);
+        // Both writers are still copying.
+        assert!(index.reserve(&segment, (1, 2), 3).is_none());
+
+        segment.write(a, &[1, 2, 3]);
+        assert!(index.done(&segment, a, a_seq, crc32(&[1, 2, 3])));
+        assert_eq!(segment.read(a, a_seq + 1), Some(vec![1, 2, 3]));
+        assert_eq!(index.reserve(&segment, (1, 2), 3).unwrap().0, a);
+        assert!(index.reserve(&segment, (1, 3), 3).is_none());
+        index.abandon(&segment, b, b_seq);
+        assert_eq!(index.reserve(&segment, (1, 3), 3).unwrap().0, b);
+    }
+
+    #[test]
+    fn torn_slots_are_not_read() {
+        let (_fd, segment) = Segment::create((HEADER_BYTES + SLOT_BYTES) as u64).unwrap();
+        let mut index = Index::new(segment.slots);
+        let (slot, seq) = index.reserve(&segment, (1, 0), 3).unwrap();
+        segment.write(slot, &[1, 2, 4]);
+        assert!(index.done(&segment, slot, seq, crc32(&[1, 2, 3])));
+        assert!(index.find(&segment, (1, 0)).is_some());
+        assert_eq!(segment.read(slot, seq + 1), None);
+    }
+}
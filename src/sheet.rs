@@ -0,0 +1,192 @@
+//! Cue sheets and cdrdao TOC files for audio-only CDs exposed with
+//! `--audio-cd bin|wav`. Both are written from the same [`SheetTrack`] model.
+//! Also the single-file cue sheet of `--full-disc-image`.
+
+use std::fmt::Write;
+
+use crate::chd_image::{TrackInfo, TrackKind};
+
+/// CD frames per second, the unit of sheet timestamps.
+const FRAMES_PER_SEC: u32 = 75;
+
+/// `mm:ss:ff` for a frame count.
+fn msf(frames: u32) -> String {
+    let ff = frames % FRAMES_PER_SEC;
+    let secs = frames / FRAMES_PER_SEC;
+    format!("{:02}:{:02}:{:02}", secs / 60, secs % 60, ff)
+}
+
+/// One track file and the gaps around its audio, in frames.
+pub struct SheetTrack<'a> {
+    pub number: u32,
+    pub file: &'a str,
+    /// Pregap held at the start of `file`
+    pub stored_pregap: u32,
+    /// Pregap to be generated as silence
+    pub silent_pregap: u32,
+    /// Postgap to be generated as silence
+    pub postgap: u32,
+}
+
+/// Pair tracks with their files (in track order). A pregap stored in the
+/// CHD is part of the track file; one that is not stored is silence, except
+/// track 1's standard two seconds, which players imply.
+pub fn layout<'a>(tracks: &[TrackInfo], files: &'a [String]) -> Vec<SheetTrack<'a>> {
+    tracks
+        .iter()
+        .zip(files)
+        .enumerate()
+        .map(|(i, (t, file))| {
+            let stored_pregap = t.stored_pregap();
+            SheetTrack {
+                number: t.number,
+                file,
+                stored_pregap,
+                silent_pregap: if stored_pregap == 0 && i > 0 {
+                    t.pregap
+                } else {
+                    0
+                },
+                postgap: t.postgap,
+            }
+        })
+        .collect()
+}
+
+/// Cue sheet: a stored pregap is marked with `INDEX 00`.
+pub fn cue(tracks: &[SheetTrack], wave: bool) -> String {
+    let mut out = String::new();
+    let file_type = if wave { "WAVE" } else { "BINARY" };
+
+    for t in tracks {
+        let _ = writeln!(out, "FILE \"{}\" {file_type}", t.file);
+        let _ = writeln!(out, "  TRACK {:02} AUDIO", t.number);
+
+        if t.stored_pregap > 0 {
+            let _ = writeln!(out, "    INDEX 00 00:00:00");
+        } else if t.silent_pregap > 0 {
+            let _ = writeln!(out, "    PREGAP {}", msf(t.silent_pregap));
+        }
+        let _ = writeln!(out, "    INDEX 01 {}", msf(t.stored_pregap));
+
+        if t.postgap > 0 {
+            let _ = writeln!(out, "    POSTGAP {}", msf(t.postgap));
+        }
+    }
+
+    out
+}
+
+/// Cue sheet of every track in one raw `file`, as `chdman extractcd` writes
+/// it: tracks back to back from the first frame, a stored pregap marked with
+/// `INDEX 00`.
+pub fn disc_cue(file: &str, tracks: &[TrackInfo]) -> String {
+    let mut out = format!("FILE \"{file}\" BINARY\n");
+    let mut pos = 0u32;
+
+    for (i, t) in tracks.iter().enumerate() {
+        let mode = match t.kind {
+            TrackKind::Audio => "AUDIO",
+            TrackKind::Mode1 => "MODE1/2352",
+            TrackKind::Mode2Form1 | TrackKind::Mode2Form2 | TrackKind::Mode2Raw => "MODE2/2352",
+        };
+        let _ = writeln!(out, "  TRACK {:02} {mode}", t.number);
+
+        let pregap = t.stored_pregap();
+        if pregap > 0 {
+            let _ = writeln!(out, "    INDEX 00 {}", msf(pos));
+        } else if t.pregap > 0 && i > 0 {
+            let _ = writeln!(out, "    PREGAP {}", msf(t.pregap));
+        }
+        let _ = writeln!(out, "    INDEX 01 {}", msf(pos + pregap));
+
+        if t.postgap > 0 {
+            let _ = writeln!(out, "    POSTGAP {}", msf(t.postgap));
+        }
+        pos += t.frames;
+    }
+
+    out
+}
+
+/// cdrdao TOC: a stored pregap ends at `START`, a postgap is `SILENCE`.
+/// cdrdao reads raw (non-WAVE) files as big-endian samples.
+pub fn toc(tracks: &[SheetTrack]) -> String {
+    let mut out = String::from("CD_DA\n");
+
+    for t in tracks {
+        let _ = writeln!(out, "\n// Track {}\nTRACK AUDIO", t.number);
+
+        if t.silent_pregap > 0 {
+            let _ = writeln!(out, "PREGAP {}", msf(t.silent_pregap));
+        }
+        let _ = writeln!(out, "FILE \"{}\" 0", t.file);
+        if t.stored_pregap > 0 {
+            let _ = writeln!(out, "START {}", msf(t.stored_pregap));
+        }
+        if t.postgap > 0 {
+            let _ = writeln!(out, "SILENCE {}", msf(t.postgap));
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chd_image::parse_track_line;
+
+    #[test]
+    fn one_file_per_track() {
+        let tracks: Vec<TrackInfo> = [
+            "TRACK:1 TYPE:AUDIO FRAMES:1000 PREGAP:150 PGTYPE:AUDIO",
+            "TRACK:2 TYPE:AUDIO FRAMES:2000 PREGAP:152 PGTYPE:VAUDIO",
+            "TRACK:3 TYPE:AUDIO FRAMES:500 PREGAP:75 PGTYPE:AUDIO POSTGAP:150",
+        ]
+        .iter()
+        .map(|l| parse_track_line(l).unwrap())
+        .collect();
+        let files = [
+            "A (Track 01).wav".to_string(),
+            "A (Track 02).wav".to_string(),
+            "A (Track 03).wav".to_string(),
+        ];
+        let sheet = layout(&tracks, &files);
+
+        assert_eq!(
+            cue(&sheet, true),
+            "FILE \"A (Track 01).wav\" WAVE\n  TRACK 01 AUDIO\n    INDEX 01 00:00:00\n\
+             FILE \"A (Track 02).wav\" WAVE\n  TRACK 02 AUDIO\n    INDEX 00 00:00:00\n    INDEX 01 00:02:02\n\
+             FILE \"A (Track 03).wav\" WAVE\n  TRACK 03 AUDIO\n    PREGAP 00:01:00\n    INDEX 01 00:00:00\n    POSTGAP 00:02:00\n"
+        );
+        assert_eq!(
+            toc(&sheet),
+            "CD_DA\n\
+             \n// Track 1\nTRACK AUDIO\nFILE \"A (Track 01).wav\" 0\n\
+             \n// Track 2\nTRACK AUDIO\nFILE \"A (Track 02).wav\" 0\nSTART 00:02:02\n\
+             \n// Track 3\nTRACK AUDIO\nPREGAP 00:01:00\nFILE \"A (Track 03).wav\" 0\nSILENCE 00:02:00\n"
+        );
+    }
+
+    #[test]
+    fn one_file_for_the_disc() {
+        let tracks: Vec<TrackInfo> = [
+            "TRACK:1 TYPE:MODE2_RAW FRAMES:1000 PREGAP:150 PGTYPE:MODE2_RAW",
+            "TRACK:2 TYPE:AUDIO FRAMES:2000 PREGAP:152 PGTYPE:VAUDIO",
+            "TRACK:3 TYPE:AUDIO FRAMES:500 PREGAP:75 PGTYPE:AUDIO POSTGAP:150",
+        ]
+        .iter()
+        .map(|l| parse_track_line(l).unwrap())
+        .collect();
+
+        // Track 2 starts at frame 1000, its audio 152 frames later.
+        assert_eq!(
+            disc_cue("A (Full).bin", &tracks),
+            "FILE \"A (Full).bin\" BINARY\n\
+             \x20 TRACK 01 MODE2/2352\n    INDEX 01 00:00:00\n\
+             \x20 TRACK 02 AUDIO\n    INDEX 00 00:13:25\n    INDEX 01 00:15:27\n\
+             \x20 TRACK 03 AUDIO\n    PREGAP 00:01:00\n    INDEX 01 00:40:00\n    POSTGAP 00:02:00\n"
+        );
+    }
+}
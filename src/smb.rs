@@ -0,0 +1,1329 @@
+//! `--serve smb` (experimental): a minimal read-only SMB2 server over the
+//! mounted trees, for players and consoles on networks without Samba. Each
+//! mount is a share named after its mountpoint directory. It speaks SMB
+//! 2.0.2 and 2.1 with anonymous or guest NTLM logons and neither signing
+//! nor encryption: enough to browse and stream, nothing more. Reads go
+//! through the same handles, readahead, throttles and frame cache as FUSE
+//! reads.
+
+use anyhow::{Context, Result};
+use std::{
+    collections::{HashMap, HashSet},
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::Arc,
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tracing::{debug, info, warn};
+
+use crate::{FsState, IndexEntry};
+
+/// Clients are anonymous; audit lines and `--visible-to` see them as nobody.
+const SMB_UID: u32 = 65534;
+
+const SUCCESS: u32 = 0;
+const BUFFER_OVERFLOW: u32 = 0x8000_0005;
+const NO_MORE_FILES: u32 = 0x8000_0006;
+const INVALID_INFO_CLASS: u32 = 0xC000_0003;
+const INFO_LENGTH_MISMATCH: u32 = 0xC000_0004;
+const INVALID_PARAMETER: u32 = 0xC000_000D;
+const NO_SUCH_FILE: u32 = 0xC000_000F;
+const INVALID_DEVICE_REQUEST: u32 = 0xC000_0010;
+const END_OF_FILE: u32 = 0xC000_0011;
+const MORE_PROCESSING_REQUIRED: u32 = 0xC000_0016;
+const ACCESS_DENIED: u32 = 0xC000_0022;
+const OBJECT_NAME_NOT_FOUND: u32 = 0xC000_0034;
+const OBJECT_PATH_NOT_FOUND: u32 = 0xC000_003A;
+const LOGON_FAILURE: u32 = 0xC000_006D;
+const FILE_IS_A_DIRECTORY: u32 = 0xC000_00BA;
+const NOT_SUPPORTED: u32 = 0xC000_00BB;
+const NETWORK_NAME_DELETED: u32 = 0xC000_00C9;
+const BAD_NETWORK_NAME: u32 = 0xC000_00CC;
+const UNEXPECTED_IO_ERROR: u32 = 0xC000_00E9;
+const NOT_A_DIRECTORY: u32 = 0xC000_0103;
+const FILE_CLOSED: u32 = 0xC000_0128;
+const NO_MEDIA_IN_DEVICE: u32 = 0xC000_0178;
+const FS_DRIVER_REQUIRED: u32 = 0xC000_019C;
+const USER_SESSION_DELETED: u32 = 0xC000_0203;
+
+const NEGOTIATE: u16 = 0x00;
+const SESSION_SETUP: u16 = 0x01;
+const LOGOFF: u16 = 0x02;
+const TREE_CONNECT: u16 = 0x03;
+const TREE_DISCONNECT: u16 = 0x04;
+const CREATE: u16 = 0x05;
+const CLOSE: u16 = 0x06;
+const FLUSH: u16 = 0x07;
+const READ: u16 = 0x08;
+const IOCTL: u16 = 0x0B;
+const CANCEL: u16 = 0x0C;
+const ECHO: u16 = 0x0D;
+const QUERY_DIRECTORY: u16 = 0x0E;
+const QUERY_INFO: u16 = 0x10;
+
+const FLAG_RESPONSE: u32 = 0x1;
+const FLAG_RELATED: u32 = 0x4;
+
+/// Largest message from a client that has not logged on: a negotiate or an
+/// NTLM session setup.
+const MAX_LOGON_MESSAGE: usize = 16 << 10;
+/// Room for the headers of a (compound) request beyond its buffer.
+const MESSAGE_HEADERS: usize = 4 << 10;
+
+const DIALECT_202: u16 = 0x0202;
+const DIALECT_210: u16 = 0x0210;
+/// Answer to an SMB1 negotiate offering "SMB 2.???": ask again in SMB2.
+const DIALECT_WILDCARD: u16 = 0x02FF;
+
+const ATTR_READONLY: u32 = 0x01;
+const ATTR_DIRECTORY: u32 = 0x10;
+/// What a read-only share grants: read data, EAs and attributes, execute,
+/// read the security descriptor, synchronize.
+const READ_ACCESS: u32 = 0x0012_00A9;
+/// Access bits that would modify the file or its metadata.
+const WRITE_ACCESS: u32 = 0x000D_0156 | 0x5000_0000;
+
+/// How the related (compounded) requests of a message inherit ids.
+const ANY_FILE: u64 = u64::MAX;
+
+/// Bind `addr` and serve `mounts` from a thread per connection.
+pub fn serve(addr: SocketAddr, mounts: &[Arc<FsState>]) -> Result<()> {
+    let mut shares: Vec<(String, Arc<FsState>)> = Vec::new();
+    for fs in mounts {
+        let name = fs
+            .args()
+            .mountpoint()
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        if name.is_empty() || shares.iter().any(|(n, _)| n.eq_ignore_ascii_case(&name)) {
+            warn!(
+                "SMB: not sharing {:?}; its name is empty or already taken",
+                fs.args().mountpoint()
+            );
+            continue;
+        }
+        info!("SMB: sharing {:?} as \\\\{}", fs.args().mountpoint(), name);
+        shares.push((name, Arc::clone(fs)));
+    }
+    let shares = Arc::new(shares);
+
+    let listener = TcpListener::bind(addr).with_context(|| format!("binding SMB on {addr}"))?;
+    info!("SMB: listening on {} (experimental, read-only)", addr);
+    thread::Builder::new()
+        .name("smb".into())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(s) => s,
+                    Err(e) => {
+                        debug!("SMB accept: {}", e);
+                        continue;
+                    }
+                };
+                let shares = Arc::clone(&shares);
+                let spawned = thread::Builder::new()
+                    .name("smb-conn".into())
+                    .spawn(move || {
+                        let peer = stream.peer_addr().ok();
+                        if let Err(e) = Conn::new(shares).run(stream) {
+                            debug!("SMB {:?}: {}", peer, e);
+                        }
+                    });
+                if let Err(e) = spawned {
+                    warn!("SMB connection thread: {}", e);
+                }
+            }
+        })
+        .context("SMB listener thread")?;
+    Ok(())
+}
+
+/// An open file or directory.
+struct Open {
+    tree: u32,
+    ino: u64,
+    dir: bool,
+    /// Read handle, for files
+    fh: Option<u64>,
+    /// QUERY_DIRECTORY state: the matching names and how many were returned
+    search: Option<(Vec<(String, u64)>, usize)>,
+}
+
+/// Ids a compounded request may refer back to.
+#[derive(Clone, Copy)]
+struct Chain {
+    session: u64,
+    tree: u32,
+    file: u64,
+    status: u32,
+}
+
+struct Conn {
+    shares: Arc<Vec<(String, Arc<FsState>)>>,
+    dialect: u16,
+    next_id: u64,
+    /// Sessions that completed the logon
+    sessions: HashSet<u64>,
+    trees: HashMap<u32, usize>,
+    opens: HashMap<u64, Open>,
+}
+
+impl Conn {
+    fn new(shares: Arc<Vec<(String, Arc<FsState>)>>) -> Self {
+        Self {
+            shares,
+            dialect: 0,
+            next_id: 1,
+            sessions: HashSet::new(),
+            trees: HashMap::new(),
+            opens: HashMap::new(),
+        }
+    }
+
+    fn run(mut self, mut stream: TcpStream) -> io::Result<()> {
+        stream.set_nodelay(true)?;
+        let result = loop {
+            // Direct TCP transport: a zero byte and a 24-bit length.
+            let mut head = [0u8; 4];
+            if let Err(e) = stream.read_exact(&mut head) {
+                break if e.kind() == io::ErrorKind::UnexpectedEof {
+                    Ok(())
+                } else {
+                    Err(e)
+                };
+            }
+            let len = u32::from_be_bytes(head) as usize & 0x00FF_FFFF;
+            if len > self.max_message() {
+                break Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{len}-byte message"),
+                ));
+            }
+            let mut msg = vec![0u8; len];
+            if let Err(e) = stream.read_exact(&mut msg) {
+                break Err(e);
+            }
+
+            let Some(reply) = self.message(&msg) else {
+                break Ok(());
+            };
+            if reply.is_empty() {
+                continue;
+            }
+            let mut framed = (reply.len() as u32).to_be_bytes().to_vec();
+            framed.extend_from_slice(&reply);
+            if let Err(e) = stream.write_all(&framed) {
+                break Err(e);
+            }
+        };
+        self.close_all();
+        result
+    }
+
+    /// The largest message to read: the MaxTransactSize negotiated plus
+    /// headers, and less until the client has logged on.
+    fn max_message(&self) -> usize {
+        if self.sessions.is_empty() {
+            MAX_LOGON_MESSAGE
+        } else {
+            max_read(self.dialect) as usize + MESSAGE_HEADERS
+        }
+    }
+
+    fn id(&mut self) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        id
+    }
+
+    fn close_all(&mut self) {
+        for (_, open) in self.opens.drain() {
+            if let (Some(fh), Some(fs)) = (open.fh, self.trees.get(&open.tree)) {
+                self.shares[*fs].1.release_handle(fh);
+            }
+        }
+    }
+
+    /// Replies to one transport message, which may compound several
+    /// requests. None drops the connection; an empty reply sends nothing.
+    fn message(&mut self, msg: &[u8]) -> Option<Vec<u8>> {
+        if msg.starts_with(b"\xffSMB") {
+            let dialect = smb1_dialect(msg)?;
+            // No SMB2 header to echo: reply as message 0, granting one credit.
+            let mut out = header(&[], SUCCESS, 0, 0, FLAG_RESPONSE);
+            out.extend(negotiate_body(dialect));
+            return Some(out);
+        }
+
+        let mut out = Vec::new();
+        let mut last_start = None::<usize>;
+        let mut chain = Chain {
+            session: 0,
+            tree: 0,
+            file: ANY_FILE,
+            status: SUCCESS,
+        };
+        let mut offset = 0;
+        loop {
+            let rest = &msg[offset..];
+            if rest.len() < 64 || !rest.starts_with(b"\xfeSMB") {
+                return None;
+            }
+            let next = le32(rest, 20).ok()? as usize;
+            let req = if next == 0 { rest } else { rest.get(..next)? };
+            let flags = le32(req, 16).ok()?;
+            let command = le16(req, 12).ok()?;
+            if flags & FLAG_RELATED == 0 {
+                chain.session = le64(req, 40).ok()?;
+                chain.tree = le32(req, 36).ok()?;
+                chain.file = ANY_FILE;
+                chain.status = SUCCESS;
+            }
+
+            let result = if chain.status != SUCCESS {
+                Err(chain.status)
+            } else {
+                self.dispatch(command, req, &mut chain)
+            };
+            let (status, body) = match result {
+                Ok(ok) => ok,
+                Err(status) => {
+                    if flags & FLAG_RELATED != 0 || next != 0 {
+                        chain.status = status;
+                    }
+                    (status, error_body())
+                }
+            };
+
+            if command != CANCEL {
+                if let Some(start) = last_start {
+                    // 8-byte align the previous reply and point it here.
+                    while out.len() % 8 != 0 {
+                        out.push(0);
+                    }
+                    let len = (out.len() - start) as u32;
+                    out[start + 20..start + 24].copy_from_slice(&len.to_le_bytes());
+                }
+                last_start = Some(out.len());
+                let mut reply = header(
+                    req,
+                    status,
+                    chain.tree,
+                    chain.session,
+                    FLAG_RESPONSE | (flags & FLAG_RELATED),
+                );
+                reply.extend(body);
+                out.extend(reply);
+            }
+
+            if next == 0 {
+                break;
+            }
+            offset += next;
+        }
+        Some(out)
+    }
+
+    fn dispatch(&mut self, command: u16, req: &[u8], chain: &mut Chain) -> Reply {
+        match command {
+            NEGOTIATE => self.negotiate(req),
+            SESSION_SETUP => self.session_setup(req, chain),
+            ECHO => Ok((SUCCESS, vec![4, 0, 0, 0])),
+            _ if !self.sessions.contains(&chain.session) => Err(USER_SESSION_DELETED),
+            LOGOFF => {
+                self.sessions.remove(&chain.session);
+                Ok((SUCCESS, vec![4, 0, 0, 0]))
+            }
+            TREE_CONNECT => self.tree_connect(req, chain),
+            _ if !self.trees.contains_key(&chain.tree) => Err(NETWORK_NAME_DELETED),
+            TREE_DISCONNECT => {
+                let tree = chain.tree;
+                let fs = self.trees[&tree];
+                self.opens.retain(|_, o| {
+                    if let (true, Some(fh)) = (o.tree == tree, o.fh) {
+                        self.shares[fs].1.release_handle(fh);
+                    }
+                    o.tree != tree
+                });
+                self.trees.remove(&tree);
+                Ok((SUCCESS, vec![4, 0, 0, 0]))
+            }
+            CREATE => self.create(req, chain),
+            CLOSE => self.close(req, chain),
+            FLUSH => Ok((SUCCESS, vec![4, 0, 0, 0])),
+            READ => self.read(req, chain),
+            QUERY_DIRECTORY => self.query_directory(req, chain),
+            QUERY_INFO => self.query_info(req, chain),
+            // Not a DFS server; clients fall back to plain paths.
+            IOCTL if le32(req, 64 + 4)? == 0x0006_0194 => Err(FS_DRIVER_REQUIRED),
+            IOCTL => Err(NOT_SUPPORTED),
+            // Write, set-info, lock, notify: a read-only share has none.
+            0x09 | 0x11 => Err(ACCESS_DENIED),
+            _ => Err(NOT_SUPPORTED),
+        }
+    }
+
+    fn negotiate(&mut self, req: &[u8]) -> Reply {
+        let count = le16(req, 64 + 2)? as usize;
+        let dialects: Vec<u16> = (0..count)
+            .map(|i| le16(req, 64 + 36 + 2 * i))
+            .collect::<Result<_, _>>()?;
+        let dialect = pick_dialect(&dialects).ok_or(NOT_SUPPORTED)?;
+        self.dialect = dialect;
+        Ok((SUCCESS, negotiate_body(dialect)))
+    }
+
+    fn session_setup(&mut self, req: &[u8], chain: &mut Chain) -> Reply {
+        let token = buffer(req, le16(req, 64 + 12)?, le16(req, 64 + 14)? as u32)?;
+        let spnego = matches!(token.first(), Some(0x60 | 0xa1));
+        let ntlm = token
+            .windows(8)
+            .position(|w| w == b"NTLMSSP\0")
+            .map(|at| &token[at..])
+            .ok_or(LOGON_FAILURE)?;
+
+        let (status, flags, reply) = match le32(ntlm, 8)? {
+            1 => {
+                if chain.session == 0 {
+                    chain.session = self.id();
+                }
+                let challenge = ntlm_challenge(now_filetime());
+                let reply = if spnego {
+                    neg_token_resp(1, Some(&challenge))
+                } else {
+                    challenge
+                };
+                (MORE_PROCESSING_REQUIRED, 0u16, reply)
+            }
+            3 => {
+                // Any credentials will do: the share is public and read-only.
+                let user_len = le16(ntlm, 36)?;
+                self.sessions.insert(chain.session);
+                let flags = if user_len == 0 { 0x2 } else { 0x1 };
+                let reply = if spnego {
+                    neg_token_resp(0, None)
+                } else {
+                    Vec::new()
+                };
+                (SUCCESS, flags, reply)
+            }
+            _ => return Err(LOGON_FAILURE),
+        };
+
+        let mut body = Vec::new();
+        body.u16(9);
+        body.u16(flags);
+        body.u16(64 + 8);
+        body.u16(reply.len() as u16);
+        body.extend(reply);
+        Ok((status, body))
+    }
+
+    fn tree_connect(&mut self, req: &[u8], chain: &mut Chain) -> Reply {
+        let path = utf16(buffer(req, le16(req, 64 + 4)?, le16(req, 64 + 6)? as u32)?);
+        let share = path.rsplit('\\').next().unwrap_or_default();
+        let idx = self
+            .shares
+            .iter()
+            .position(|(name, _)| name.eq_ignore_ascii_case(share))
+            .ok_or(BAD_NETWORK_NAME)?;
+        self.shares[idx]
+            .1
+            .visible_to(SMB_UID)
+            .map_err(|_| ACCESS_DENIED)?;
+
+        let tree = self.id() as u32;
+        self.trees.insert(tree, idx);
+        chain.tree = tree;
+
+        let mut body = Vec::new();
+        body.u16(16);
+        body.push(0x01); // disk
+        body.push(0);
+        body.u32(0);
+        body.u32(0);
+        body.u32(READ_ACCESS);
+        Ok((SUCCESS, body))
+    }
+
+    fn fs(&self, tree: u32) -> &Arc<FsState> {
+        &self.shares[self.trees[&tree]].1
+    }
+
+    fn open(&self, req: &[u8], at: usize, chain: &Chain) -> Result<(u64, &Open), u32> {
+        let mut id = le64(req, at + 8)?;
+        if id == ANY_FILE {
+            id = chain.file;
+        }
+        let open = self.opens.get(&id).ok_or(FILE_CLOSED)?;
+        Ok((id, open))
+    }
+
+    fn create(&mut self, req: &[u8], chain: &mut Chain) -> Reply {
+        let access = le32(req, 64 + 24)?;
+        let disposition = le32(req, 64 + 36)?;
+        let options = le32(req, 64 + 40)?;
+        let name = utf16(buffer(
+            req,
+            le16(req, 64 + 44)?,
+            le16(req, 64 + 46)? as u32,
+        )?);
+        let name = name.strip_suffix("::$DATA").unwrap_or(&name);
+        if name.contains(':') {
+            return Err(OBJECT_NAME_NOT_FOUND);
+        }
+
+        let fs = Arc::clone(self.fs(chain.tree));
+        let ino = match resolve(&fs, name) {
+            Ok(ino) => ino,
+            // FILE_OPEN and FILE_OVERWRITE need it to exist; the rest would create it.
+            Err(e) if matches!(disposition, 1 | 4) => return Err(e),
+            Err(_) => return Err(ACCESS_DENIED),
+        };
+        // Only FILE_OPEN and FILE_OPEN_IF leave an existing file as it is.
+        if !matches!(disposition, 1 | 3) || access & WRITE_ACCESS != 0 || options & 0x1000 != 0 {
+            return Err(ACCESS_DENIED);
+        }
+
+        let info = info(&fs, ino)?;
+        if info.dir && options & 0x40 != 0 {
+            return Err(FILE_IS_A_DIRECTORY);
+        }
+        if !info.dir && options & 0x1 != 0 {
+            return Err(NOT_A_DIRECTORY);
+        }
+
+        let fh = if info.dir {
+            None
+        } else {
+            let e = fs.entry_by_ino(ino).ok_or(OBJECT_NAME_NOT_FOUND)?;
+            if !fs.visible(&e) {
+                return Err(OBJECT_NAME_NOT_FOUND);
+            }
+            Some(fs.open_handle(&e, libc::O_RDONLY, SMB_UID, 0))
+        };
+        let id = self.id();
+        self.opens.insert(
+            id,
+            Open {
+                tree: chain.tree,
+                ino,
+                dir: info.dir,
+                fh,
+                search: None,
+            },
+        );
+        chain.file = id;
+
+        let mut body = Vec::new();
+        body.u16(89);
+        body.push(0); // no oplock
+        body.push(0);
+        body.u32(1); // FILE_OPENED
+        info.times(&mut body);
+        body.u64(info.alloc);
+        body.u64(info.size);
+        body.u32(info.attributes());
+        body.u32(0);
+        body.u64(id);
+        body.u64(id);
+        body.u32(0);
+        body.u32(0);
+        body.push(0);
+        Ok((SUCCESS, body))
+    }
+
+    fn close(&mut self, req: &[u8], chain: &Chain) -> Reply {
+        let flags = le16(req, 64 + 2)?;
+        let (id, _) = self.open(req, 64 + 8, chain)?;
+        let open = self.opens.remove(&id).expect("open just found");
+        let fs = Arc::clone(self.fs(open.tree));
+        if let Some(fh) = open.fh {
+            fs.release_handle(fh);
+        }
+
+        let mut body = Vec::new();
+        body.u16(60);
+        body.u16(flags & 1);
+        body.u32(0);
+        match info(&fs, open.ino) {
+            Ok(info) if flags & 1 != 0 => {
+                info.times(&mut body);
+                body.u64(info.alloc);
+                body.u64(info.size);
+                body.u32(info.attributes());
+            }
+            _ => body.resize(body.len() + 52, 0),
+        }
+        Ok((SUCCESS, body))
+    }
+
+    fn read(&self, req: &[u8], chain: &Chain) -> Reply {
+        let len = le32(req, 64 + 4)?;
+        let offset = le64(req, 64 + 8)?;
+        let (_, open) = self.open(req, 64 + 16, chain)?;
+        let fh = open.fh.ok_or(INVALID_DEVICE_REQUEST)?;
+        let fs = Arc::clone(self.fs(open.tree));
+        fs.online().map_err(errno_status)?;
+        let e = fs.entry_by_ino(open.ino).ok_or(FILE_CLOSED)?;
+        let handle = fs.handles.get(fh, open.ino).map_err(errno_status)?;
+        if fs.check_quarantine(&handle).map_err(errno_status)? {
+            return Err(END_OF_FILE);
+        }
+
+        // A hot copy or a reload may have replaced the entry's provider.
+        let provider = fs.hot.provider(open.ino).unwrap_or(e.provider);
+        let size = provider.size();
+        if offset >= size {
+            return Err(END_OF_FILE);
+        }
+        let len = (len.min(max_read(self.dialect)) as u64).min(size - offset);
+        let mut data = vec![0u8; len as usize];
+        let n = match handle.read(&provider, offset, &mut data) {
+            Ok(n) => n,
+            Err(err) => {
+                fs.read_failed(&handle, &err);
+                return Err(UNEXPECTED_IO_ERROR);
+            }
+        };
+        data.truncate(n);
+        fs.count_read(n);
+        let delay = fs.throttle_delay(handle.throttle.as_deref(), n);
+        if !delay.is_zero() {
+            thread::sleep(delay);
+        }
+
+        let mut body = Vec::new();
+        body.u16(17);
+        body.push(64 + 16);
+        body.push(0);
+        body.u32(n as u32);
+        body.u32(0);
+        body.u32(0);
+        body.extend(data);
+        Ok((SUCCESS, body))
+    }
+
+    fn query_directory(&mut self, req: &[u8], chain: &Chain) -> Reply {
+        let class = *req.get(64 + 2).ok_or(INVALID_PARAMETER)?;
+        let flags = *req.get(64 + 3).ok_or(INVALID_PARAMETER)?;
+        let pattern = utf16(buffer(
+            req,
+            le16(req, 64 + 24)?,
+            le16(req, 64 + 26)? as u32,
+        )?);
+        let max = le32(req, 64 + 28)? as usize;
+        let (id, open) = self.open(req, 64 + 8, chain)?;
+        if !open.dir {
+            return Err(INVALID_PARAMETER);
+        }
+        let fs = Arc::clone(self.fs(open.tree));
+        let ino = open.ino;
+
+        let open = self.opens.get_mut(&id).expect("open just found");
+        // Restart, reopen, index-specified or a first call: search anew.
+        let fresh = open.search.is_none() || flags & 0x15 != 0;
+        if fresh {
+            let parent = fs.index().dir(ino).map_or(1, |d| d.parent);
+            let names = [(".".to_string(), ino), ("..".to_string(), parent)]
+                .into_iter()
+                .chain(children(&fs, ino))
+                .filter(|(name, _)| pattern.is_empty() || wildcard(&pattern, name))
+                .collect();
+            open.search = Some((names, 0));
+        }
+        let (names, pos) = open.search.as_mut().expect("search just set");
+        if *pos >= names.len() {
+            return Err(if fresh { NO_SUCH_FILE } else { NO_MORE_FILES });
+        }
+
+        let mut out = Vec::new();
+        let mut last = None::<usize>;
+        while let Some((name, child)) = names.get(*pos) {
+            let Ok(info) = info(&fs, *child) else {
+                *pos += 1;
+                continue;
+            };
+            let entry = dir_entry(class, name, &info, *pos as u32).ok_or(INVALID_INFO_CLASS)?;
+            let start = (out.len() + 7) & !7;
+            if start + entry.len() > max {
+                if last.is_none() {
+                    return Err(INFO_LENGTH_MISMATCH);
+                }
+                break;
+            }
+            out.resize(start, 0);
+            if let Some(prev) = last {
+                let next = (start - prev) as u32;
+                out[prev..prev + 4].copy_from_slice(&next.to_le_bytes());
+            }
+            out.extend(entry);
+            last = Some(start);
+            *pos += 1;
+            if flags & 0x2 != 0 {
+                break;
+            }
+        }
+
+        let mut body = Vec::new();
+        body.u16(9);
+        body.u16(64 + 8);
+        body.u32(out.len() as u32);
+        body.extend(out);
+        Ok((SUCCESS, body))
+    }
+
+    fn query_info(&mut self, req: &[u8], chain: &Chain) -> Reply {
+        let kind = *req.get(64 + 2).ok_or(INVALID_PARAMETER)?;
+        let class = *req.get(64 + 3).ok_or(INVALID_PARAMETER)?;
+        let max = le32(req, 64 + 4)? as usize;
+        let (_, open) = self.open(req, 64 + 24, chain)?;
+        let fs = self.fs(open.tree);
+        let info = info(fs, open.ino)?;
+
+        let mut out = match kind {
+            1 => file_info(class, &info, &name_of(fs, open.ino))?,
+            2 => fs_info(class, &self.shares[self.trees[&open.tree]].0)?,
+            3 => security_descriptor(),
+            _ => return Err(INVALID_PARAMETER),
+        };
+        let status = if out.len() > max {
+            out.truncate(max);
+            BUFFER_OVERFLOW
+        } else {
+            SUCCESS
+        };
+
+        let mut body = Vec::new();
+        body.u16(9);
+        body.u16(64 + 8);
+        body.u32(out.len() as u32);
+        body.extend(out);
+        Ok((status, body))
+    }
+}
+
+/// Ok: status and body. Err: status of an error reply.
+type Reply = Result<(u32, Vec<u8>), u32>;
+
+trait Put {
+    fn u16(&mut self, v: u16);
+    fn u32(&mut self, v: u32);
+    fn u64(&mut self, v: u64);
+}
+
+impl Put for Vec<u8> {
+    fn u16(&mut self, v: u16) {
+        self.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn u32(&mut self, v: u32) {
+        self.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn u64(&mut self, v: u64) {
+        self.extend_from_slice(&v.to_le_bytes());
+    }
+}
+
+fn le16(b: &[u8], at: usize) -> Result<u16, u32> {
+    b.get(at..at + 2)
+        .map(|s| u16::from_le_bytes(s.try_into().expect("2 bytes")))
+        .ok_or(INVALID_PARAMETER)
+}
+
+fn le32(b: &[u8], at: usize) -> Result<u32, u32> {
+    b.get(at..at + 4)
+        .map(|s| u32::from_le_bytes(s.try_into().expect("4 bytes")))
+        .ok_or(INVALID_PARAMETER)
+}
+
+fn le64(b: &[u8], at: usize) -> Result<u64, u32> {
+    b.get(at..at + 8)
+        .map(|s| u64::from_le_bytes(s.try_into().expect("8 bytes")))
+        .ok_or(INVALID_PARAMETER)
+}
+
+/// A variable-length field, its offset counted from the SMB2 header.
+fn buffer(req: &[u8], offset: u16, len: u32) -> Result<&[u8], u32> {
+    if len == 0 {
+        return Ok(&[]);
+    }
+    let start = offset as usize;
+    req.get(start..start + len as usize)
+        .ok_or(INVALID_PARAMETER)
+}
+
+fn utf16(b: &[u8]) -> String {
+    let units: Vec<u16> = b
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .collect();
+    String::from_utf16_lossy(&units)
+}
+
+fn to_utf16(s: &str) -> Vec<u8> {
+    s.encode_utf16().flat_map(u16::to_le_bytes).collect()
+}
+
+/// Reply header for `req`, echoing its command, message id and credits.
+fn header(req: &[u8], status: u32, tree: u32, session: u64, flags: u32) -> Vec<u8> {
+    let mut h = Vec::with_capacity(64);
+    h.extend_from_slice(b"\xfeSMB");
+    h.u16(64);
+    h.u16(le16(req, 6).unwrap_or(0));
+    h.u32(status);
+    h.u16(le16(req, 12).unwrap_or(0));
+    // Grant what is asked: the server keeps no credit window of its own.
+    h.u16(le16(req, 14).unwrap_or(1).clamp(1, 512));
+    h.u32(flags);
+    h.u32(0);
+    h.u64(le64(req, 24).unwrap_or(0));
+    h.u32(le32(req, 32).unwrap_or(0));
+    h.u32(tree);
+    h.u64(session);
+    h.extend_from_slice(&[0; 16]);
+    h
+}
+
+fn error_body() -> Vec<u8> {
+    vec![9, 0, 0, 0, 0, 0, 0, 0, 0]
+}
+
+/// The highest dialect both sides speak.
+fn pick_dialect(offered: &[u16]) -> Option<u16> {
+    [DIALECT_210, DIALECT_202]
+        .into_iter()
+        .find(|d| offered.contains(d))
+}
+
+/// The SMB2 dialect to answer an SMB1 negotiate with; None if the client
+/// only speaks SMB1.
+fn smb1_dialect(msg: &[u8]) -> Option<u16> {
+    if msg.get(4) != Some(&0x72) {
+        return None;
+    }
+    let words = *msg.get(32)? as usize;
+    let data = msg.get(32 + 1 + 2 * words + 2..)?;
+    let names: Vec<&[u8]> = data
+        .split(|&b| b == 0)
+        .filter_map(|d| d.strip_prefix(&[0x02]))
+        .collect();
+    if names.contains(&&b"SMB 2.???"[..]) {
+        Some(DIALECT_WILDCARD)
+    } else if names.contains(&&b"SMB 2.002"[..]) {
+        Some(DIALECT_202)
+    } else {
+        None
+    }
+}
+
+fn max_read(dialect: u16) -> u32 {
+    if dialect == DIALECT_210 {
+        1 << 20
+    } else {
+        64 << 10
+    }
+}
+
+fn negotiate_body(dialect: u16) -> Vec<u8> {
+    let token = neg_token_init();
+    let mut body = Vec::new();
+    body.u16(65);
+    body.u16(0x1); // signing enabled, not required
+    body.u16(dialect);
+    body.u16(0);
+    body.extend_from_slice(&server_guid());
+    // Large MTU with 2.1, so one request can read up to 1 MiB.
+    body.u32(if dialect == DIALECT_210 { 0x4 } else { 0 });
+    body.u32(max_read(dialect));
+    body.u32(max_read(dialect));
+    body.u32(64 << 10);
+    body.u64(now_filetime());
+    body.u64(0);
+    body.u16(64 + 64);
+    body.u16(token.len() as u16);
+    body.u32(0);
+    body.extend(token);
+    body
+}
+
+fn server_guid() -> [u8; 16] {
+    let mut guid = [0u8; 16];
+    guid[..8].copy_from_slice(b"chd2iso\0");
+    guid[8..12].copy_from_slice(&std::process::id().to_le_bytes());
+    guid
+}
+
+fn der(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut v = vec![tag];
+    let n = content.len();
+    if n < 0x80 {
+        v.push(n as u8);
+    } else if n < 0x100 {
+        v.extend([0x81, n as u8]);
+    } else {
+        v.extend([0x82, (n >> 8) as u8, n as u8]);
+    }
+    v.extend_from_slice(content);
+    v
+}
+
+const SPNEGO_OID: &[u8] = &[0x06, 0x06, 0x2b, 0x06, 0x01, 0x05, 0x05, 0x02];
+const NTLMSSP_OID: &[u8] = &[
+    0x06, 0x0a, 0x2b, 0x06, 0x01, 0x04, 0x01, 0x82, 0x37, 0x02, 0x02, 0x0a,
+];
+
+/// SPNEGO offer of NTLM only, for the negotiate reply.
+fn neg_token_init() -> Vec<u8> {
+    let mechs = der(0xa0, &der(0x30, NTLMSSP_OID));
+    let init = der(0xa0, &der(0x30, &mechs));
+    der(0x60, &[SPNEGO_OID, &init].concat())
+}
+
+/// SPNEGO reply: `state` 0 accepts, 1 carries the NTLM challenge.
+fn neg_token_resp(state: u8, token: Option<&[u8]>) -> Vec<u8> {
+    let mut seq = der(0xa0, &[0x0a, 0x01, state]);
+    if let Some(token) = token {
+        seq.extend(der(0xa1, NTLMSSP_OID));
+        seq.extend(der(0xa2, &der(0x04, token)));
+    }
+    der(0xa1, &der(0x30, &seq))
+}
+
+/// An NTLM CHALLENGE message. The answer is never checked, so the
+/// challenge only needs to look like one.
+fn ntlm_challenge(now: u64) -> Vec<u8> {
+    const HEAD: u32 = 56;
+    let name = to_utf16("CHD2ISO");
+    let mut target = Vec::new();
+    for id in [2u16, 1, 4, 3] {
+        target.u16(id);
+        target.u16(name.len() as u16);
+        target.extend_from_slice(&name);
+    }
+    target.u16(7);
+    target.u16(8);
+    target.u64(now);
+    target.u32(0);
+
+    // Unicode, request target, NTLM, always sign, server target, extended
+    // session security, target info, version, 128- and 56-bit.
+    let flags: u32 = 0xA28A_8205;
+    let mut v = b"NTLMSSP\0".to_vec();
+    v.u32(2);
+    v.u16(name.len() as u16);
+    v.u16(name.len() as u16);
+    v.u32(HEAD);
+    v.u32(flags);
+    v.extend_from_slice(&now.to_le_bytes());
+    v.extend_from_slice(&[0; 8]);
+    v.u16(target.len() as u16);
+    v.u16(target.len() as u16);
+    v.u32(HEAD + name.len() as u32);
+    v.extend_from_slice(&[6, 1, 0xb1, 0x1d, 0, 0, 0, 15]);
+    v.extend(name);
+    v.extend(target);
+    v
+}
+
+fn filetime(t: SystemTime) -> u64 {
+    // 100 ns ticks since 1601.
+    const EPOCH_DIFF: u64 = 11_644_473_600;
+    match t.duration_since(UNIX_EPOCH) {
+        Ok(d) => (d.as_secs() + EPOCH_DIFF) * 10_000_000 + u64::from(d.subsec_nanos() / 100),
+        Err(_) => EPOCH_DIFF * 10_000_000,
+    }
+}
+
+fn now_filetime() -> u64 {
+    filetime(SystemTime::now())
+}
+
+fn errno_status(errno: i32) -> u32 {
+    match errno {
+        libc::ENOENT => OBJECT_NAME_NOT_FOUND,
+        libc::EACCES | libc::EPERM => ACCESS_DENIED,
+        libc::ENODEV => NO_MEDIA_IN_DEVICE,
+        libc::ENOTDIR => NOT_A_DIRECTORY,
+        libc::EBADF => FILE_CLOSED,
+        _ => UNEXPECTED_IO_ERROR,
+    }
+}
+
+/// What the protocol needs to know of an inode.
+struct Info {
+    ino: u64,
+    dir: bool,
+    size: u64,
+    alloc: u64,
+    atime: u64,
+    mtime: u64,
+}
+
+impl Info {
+    fn attributes(&self) -> u32 {
+        if self.dir {
+            ATTR_DIRECTORY
+        } else {
+            ATTR_READONLY
+        }
+    }
+
+    /// Creation, access, write and change times; creation is the mtime.
+    fn times(&self, out: &mut Vec<u8>) {
+        out.u64(self.mtime);
+        out.u64(self.atime);
+        out.u64(self.mtime);
+        out.u64(self.mtime);
+    }
+}
+
+fn info(fs: &FsState, ino: u64) -> Result<Info, u32> {
+    let attr = fs.attr(ino).map_err(errno_status)?;
+    let dir = attr.kind == fuser::FileType::Directory;
+    Ok(Info {
+        ino,
+        dir,
+        size: if dir { 0 } else { attr.size },
+        alloc: if dir { 0 } else { attr.blocks * 512 },
+        atime: filetime(attr.atime),
+        mtime: filetime(attr.mtime),
+    })
+}
+
+/// Directories and visible images under `ino`; no overlay or control files.
+fn children(fs: &FsState, ino: u64) -> Vec<(String, u64)> {
+    let index = fs.index();
+    index
+        .dirs
+        .iter()
+        .filter(|d| d.parent == ino)
+        .map(|d| (d.name.clone(), d.ino))
+        .chain(
+            index
+                .entries
+                .iter()
+                .filter(|e| e.parent == ino && fs.visible(e))
+                .map(|e: &IndexEntry| (e.name.clone(), e.ino)),
+        )
+        .collect()
+}
+
+/// Inode of a share-relative path; names match case-insensitively, as
+/// SMB clients expect, preferring an exact match.
+fn resolve(fs: &FsState, path: &str) -> Result<u64, u32> {
+    fs.online().map_err(errno_status)?;
+    let parts: Vec<&str> = path.split('\\').filter(|p| !p.is_empty()).collect();
+    let mut ino = 1;
+    for (i, part) in parts.iter().enumerate() {
+        let children = children(fs, ino);
+        let found = children
+            .iter()
+            .find(|(n, _)| n == part)
+            .or_else(|| children.iter().find(|(n, _)| n.eq_ignore_ascii_case(part)));
+        match found {
+            Some((_, child)) => ino = *child,
+            None if i + 1 == parts.len() => return Err(OBJECT_NAME_NOT_FOUND),
+            None => return Err(OBJECT_PATH_NOT_FOUND),
+        }
+    }
+    Ok(ino)
+}
+
+fn name_of(fs: &FsState, ino: u64) -> String {
+    let index = fs.index();
+    let (dir, name) = match (index.entry(ino), index.dir(ino)) {
+        (Some(e), _) => (e.dir.as_str(), e.name.as_str()),
+        (None, Some(d)) => (d.path.as_str(), ""),
+        (None, None) => ("", ""),
+    };
+    let path = [dir, name]
+        .iter()
+        .filter(|p| !p.is_empty())
+        .copied()
+        .collect::<Vec<_>>()
+        .join("/");
+    format!("\\{}", path.replace('/', "\\"))
+}
+
+/// `*`, `?` and their DOS forms (`<`, `>`, `"`), case-insensitively. One
+/// pass over the name tracking which pattern positions match so far, since
+/// patterns come off the network and backtracking on `*` is exponential.
+fn wildcard(pattern: &str, name: &str) -> bool {
+    /// `*` and `"` also match nothing.
+    fn skip_empty(p: &[char], at: &mut [bool]) {
+        for (i, c) in p.iter().enumerate() {
+            if at[i] && matches!(c, '*' | '<' | '"') {
+                at[i + 1] = true;
+            }
+        }
+    }
+    let p: Vec<char> = pattern.chars().collect();
+    let mut at = vec![false; p.len() + 1];
+    at[0] = true;
+    skip_empty(&p, &mut at);
+    for c in name.chars() {
+        let mut next = vec![false; p.len() + 1];
+        for i in (0..p.len()).filter(|&i| at[i]) {
+            match p[i] {
+                '*' | '<' => next[i] = true,
+                '?' | '>' => next[i + 1] = true,
+                '"' => next[i + 1] |= c == '.',
+                l => next[i + 1] |= l.eq_ignore_ascii_case(&c),
+            }
+        }
+        skip_empty(&p, &mut next);
+        at = next;
+    }
+    at[p.len()]
+}
+
+/// One QUERY_DIRECTORY entry of information class `class`, without the
+/// padding before the next.
+fn dir_entry(class: u8, name: &str, info: &Info, index: u32) -> Option<Vec<u8>> {
+    let name = to_utf16(name);
+    let mut e = Vec::new();
+    e.u32(0);
+    e.u32(index);
+    if class == 0x0C {
+        // FileNamesInformation
+        e.u32(name.len() as u32);
+        e.extend(name);
+        return Some(e);
+    }
+    info.times(&mut e);
+    e.u64(info.size);
+    e.u64(info.alloc);
+    e.u32(info.attributes());
+    e.u32(name.len() as u32);
+    match class {
+        // FileDirectoryInformation
+        0x01 => {}
+        // FileFullDirectoryInformation
+        0x02 => e.u32(0),
+        // FileIdFullDirectoryInformation
+        0x26 => {
+            e.u32(0);
+            e.u32(0);
+            e.u64(info.ino);
+        }
+        // FileBothDirectoryInformation, FileIdBothDirectoryInformation
+        0x03 | 0x25 => {
+            e.u32(0);
+            e.extend_from_slice(&[0; 26]);
+            if class == 0x25 {
+                e.u16(0);
+                e.u64(info.ino);
+            }
+        }
+        _ => return None,
+    }
+    e.extend(name);
+    Some(e)
+}
+
+fn file_info(class: u8, info: &Info, path: &str) -> Result<Vec<u8>, u32> {
+    let mut v = Vec::new();
+    let basic = |v: &mut Vec<u8>| {
+        info.times(v);
+        v.u32(info.attributes());
+        v.u32(0);
+    };
+    let standard = |v: &mut Vec<u8>| {
+        v.u64(info.alloc);
+        v.u64(info.size);
+        v.u32(1);
+        v.push(0);
+        v.push(u8::from(info.dir));
+        v.u16(0);
+    };
+    let name = to_utf16(path);
+    match class {
+        // FileBasicInformation
+        4 => basic(&mut v),
+        // FileStandardInformation
+        5 => standard(&mut v),
+        // FileInternalInformation
+        6 => v.u64(info.ino),
+        // FileEaInformation, FileModeInformation, FileAlignmentInformation
+        7 | 16 | 17 => v.u32(0),
+        // FileAccessInformation
+        8 => v.u32(READ_ACCESS),
+        // FileNameInformation
+        9 => {
+            v.u32(name.len() as u32);
+            v.extend(name);
+        }
+        // FilePositionInformation
+        14 => v.u64(0),
+        // FileAllInformation
+        18 => {
+            basic(&mut v);
+            standard(&mut v);
+            v.u64(info.ino);
+            v.u32(0);
+            v.u32(READ_ACCESS);
+            v.u64(0);
+            v.u32(0);
+            v.u32(0);
+            v.u32(name.len() as u32);
+            v.extend(name);
+        }
+        // FileStreamInformation: files have their one unnamed stream
+        22 if !info.dir => {
+            let stream = to_utf16("::$DATA");
+            v.u32(0);
+            v.u32(stream.len() as u32);
+            v.u64(info.size);
+            v.u64(info.alloc);
+            v.extend(stream);
+        }
+        22 => {}
+        // FileNetworkOpenInformation
+        34 => {
+            info.times(&mut v);
+            v.u64(info.alloc);
+            v.u64(info.size);
+            v.u32(info.attributes());
+            v.u32(0);
+        }
+        // FileAttributeTagInformation
+        35 => {
+            v.u32(info.attributes());
+            v.u32(0);
+        }
+        _ => return Err(INVALID_INFO_CLASS),
+    }
+    Ok(v)
+}
+
+fn fs_info(class: u8, share: &str) -> Result<Vec<u8>, u32> {
+    let mut v = Vec::new();
+    match class {
+        // FileFsVolumeInformation
+        1 => {
+            let label = to_utf16(share);
+            v.u64(0);
+            v.u32(share.bytes().fold(0x811C_9DC5u32, |h, b| {
+                (h ^ u32::from(b)).wrapping_mul(0x0100_0193)
+            }));
+            v.u32(label.len() as u32);
+            v.push(0);
+            v.push(0);
+            v.extend(label);
+        }
+        // FileFsSizeInformation: a full volume
+        3 => {
+            v.u64(1);
+            v.u64(0);
+            v.u32(1);
+            v.u32(2048);
+        }
+        // FileFsDeviceInformation: a read-only disk
+        4 => {
+            v.u32(0x07);
+            v.u32(0x02);
+        }
+        // FileFsAttributeInformation: case-preserving, Unicode, read-only
+        5 => {
+            let name = to_utf16("NTFS");
+            v.u32(0x0008_0006);
+            v.u32(255);
+            v.u32(name.len() as u32);
+            v.extend(name);
+        }
+        // FileFsFullSizeInformation
+        7 => {
+            v.u64(1);
+            v.u64(0);
+            v.u64(0);
+            v.u32(1);
+            v.u32(2048);
+        }
+        _ => return Err(INVALID_INFO_CLASS),
+    }
+    Ok(v)
+}
+
+/// Self-relative descriptor: owned by Everyone, who may read.
+fn security_descriptor() -> Vec<u8> {
+    const EVERYONE: [u8; 12] = [1, 1, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0];
+    let mut v = Vec::new();
+    v.push(1);
+    v.push(0);
+    v.u16(0x8004); // self-relative, DACL present
+    v.u32(20);
+    v.u32(32);
+    v.u32(0);
+    v.u32(44);
+    v.extend_from_slice(&EVERYONE);
+    v.extend_from_slice(&EVERYONE);
+    v.push(2);
+    v.push(0);
+    v.u16(8 + 20);
+    v.u16(1);
+    v.u16(0);
+    v.push(0); // access allowed
+    v.push(0);
+    v.u16(20);
+    v.u32(READ_ACCESS);
+    v.extend_from_slice(&EVERYONE);
+    v
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiates_smb2_dialects() {
+        assert_eq!(
+            pick_dialect(&[0x0202, 0x0210, 0x0300, 0x0311]),
+            Some(0x0210)
+        );
+        assert_eq!(pick_dialect(&[0x0202]), Some(0x0202));
+        assert_eq!(pick_dialect(&[0x0300, 0x0311]), None);
+
+        // An SMB1 negotiate from a client that also speaks SMB2.
+        let mut msg = b"\xffSMB\x72".to_vec();
+        msg.resize(32, 0);
+        msg.push(0);
+        let dialects = b"\x02NT LM 0.12\0\x02SMB 2.002\0\x02SMB 2.???\0";
+        msg.extend((dialects.len() as u16).to_le_bytes());
+        msg.extend(dialects);
+        assert_eq!(smb1_dialect(&msg), Some(DIALECT_WILDCARD));
+        let len = msg.len();
+        msg.truncate(len - b"\x02SMB 2.???\0".len());
+        assert_eq!(smb1_dialect(&msg), Some(DIALECT_202));
+    }
+
+    #[test]
+    fn caps_messages_until_logon() {
+        let mut conn = Conn::new(Arc::new(Vec::new()));
+        assert_eq!(conn.max_message(), MAX_LOGON_MESSAGE);
+        conn.dialect = DIALECT_210;
+        conn.sessions.insert(1);
+        assert_eq!(conn.max_message(), (1 << 20) + MESSAGE_HEADERS);
+    }
+
+    #[test]
+    fn wildcards_match_dos_style() {
+        assert!(wildcard("*", "Ico (USA).iso"));
+        assert!(wildcard("ico*.ISO", "Ico (USA).iso"));
+        assert!(wildcard("?co (USA).iso", "Ico (USA).iso"));
+        assert!(wildcard("<.iso", "Game.iso"));
+        assert!(wildcard("Game\"*", "Game"));
+        assert!(!wildcard("*.cue", "Game.iso"));
+        assert!(!wildcard("Game", "Game.iso"));
+    }
+
+    #[test]
+    fn wildcards_do_not_backtrack() {
+        let name = "a".repeat(4096);
+        assert!(!wildcard("*a*a*a*a*a*a*a*a*a*a*a*a*b", &name));
+        assert!(wildcard("*a*a*a*a*a*a*a*a*a*a*a*a*", &name));
+    }
+}
@@ -0,0 +1,297 @@
+//! `--export-index` / `--import-index`: the probe results of an index saved
+//! as JSON, so a library probed on a fast machine mounts at once on a slow
+//! one. Imported sources are not opened until their first read; a source
+//! whose size or mtime changed since the export is probed as usual.
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    path::{Path, PathBuf},
+    sync::{Arc, OnceLock},
+};
+
+use crate::iso::Console;
+use crate::provider::{BackingProvider, ImageKind, Probed};
+use crate::sha1;
+use crate::vfs;
+
+const FORMAT_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+pub struct Snapshot {
+    version: u32,
+    /// `ProbeContext::fingerprint` of the build; an import made with other
+    /// settings would expose other files, so it is ignored
+    settings: String,
+    sources: Vec<SourceRecord>,
+}
+
+/// One probed source file.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SourceRecord {
+    /// Relative to the source directory, if under it
+    path: PathBuf,
+    size: u64,
+    mtime: i64,
+    /// The main file first, then its extras
+    pub entries: Vec<EntryRecord>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct EntryRecord {
+    pub name: String,
+    pub kind: ImageKind,
+    pub size: u64,
+    /// Hex CHD header SHA1
+    sha1: Option<String>,
+    pub layer_break: Option<u64>,
+    /// Disc serial, read with `--title-db`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub serial: Option<String>,
+    /// ISO9660 volume label
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    /// Console, read with `--console-dirs`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub console: Option<Console>,
+}
+
+impl EntryRecord {
+    pub fn new(
+        name: &str,
+        provider: &dyn BackingProvider,
+        sha1: Option<[u8; 20]>,
+        layer_break: Option<u64>,
+        serial: Option<String>,
+        label: Option<String>,
+    ) -> Self {
+        Self {
+            name: name.to_string(),
+            kind: provider.kind(),
+            size: provider.size(),
+            sha1: sha1.as_ref().map(sha1::hex),
+            layer_break,
+            serial,
+            label,
+            console: None,
+        }
+    }
+
+    pub fn sha1(&self) -> Option<[u8; 20]> {
+        let hex = self.sha1.as_deref()?;
+        let mut out = [0u8; 20];
+        if hex.len() != 40 {
+            return None;
+        }
+        for (o, i) in out.iter_mut().zip((0..40).step_by(2)) {
+            *o = u8::from_str_radix(hex.get(i..i + 2)?, 16).ok()?;
+        }
+        Some(out)
+    }
+}
+
+/// (size, mtime) of `path`, to tell whether a record still describes it.
+fn stamp(path: &Path) -> Option<(u64, i64)> {
+    let meta = vfs::stat(path).ok()?;
+    Some((meta.size, meta.mtime.unwrap_or(0)))
+}
+
+impl SourceRecord {
+    /// Record for `path` (stored relative to `root`), if it can be stat'ed.
+    pub fn new(path: &Path, root: Option<&Path>, entries: Vec<EntryRecord>) -> Option<Self> {
+        let (size, mtime) = stamp(path)?;
+        let rel = root.and_then(|r| path.strip_prefix(r).ok()).unwrap_or(path);
+        Some(Self {
+            path: rel.to_path_buf(),
+            size,
+            mtime,
+            entries,
+        })
+    }
+}
+
+impl Snapshot {
+    pub fn new(settings: String, sources: Vec<SourceRecord>) -> Self {
+        Self {
+            version: FORMAT_VERSION,
+            settings,
+            sources,
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let data = fs::read(path).with_context(|| format!("reading {path:?}"))?;
+        let snap: Self =
+            serde_json::from_slice(&data).with_context(|| format!("parsing {path:?}"))?;
+        if snap.version != FORMAT_VERSION {
+            bail!("{path:?}: unsupported index version {}", snap.version);
+        }
+        Ok(snap)
+    }
+
+    /// Write to `path` via a temporary file, so a reader never sees half.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec_pretty(self)?)
+            .with_context(|| format!("writing {tmp:?}"))?;
+        fs::rename(&tmp, path).with_context(|| format!("renaming {tmp:?} to {path:?}"))?;
+        Ok(())
+    }
+
+    /// Records still matching their file on disk, by full path; none if
+    /// the snapshot was made with other `settings`.
+    pub fn fresh(
+        self,
+        root: Option<&Path>,
+        settings: &str,
+    ) -> Result<HashMap<PathBuf, SourceRecord>> {
+        if self.settings != settings {
+            return Err(anyhow!(
+                "made with other probe settings ({}), not {}",
+                self.settings,
+                settings
+            ));
+        }
+
+        Ok(self
+            .sources
+            .into_iter()
+            .filter_map(|r| {
+                let path = root.map_or_else(|| r.path.clone(), |root| root.join(&r.path));
+                (stamp(&path) == Some((r.size, r.mtime))).then_some((path, r))
+            })
+            .collect())
+    }
+}
+
+type ProbeFn = Box<dyn Fn() -> Result<Option<Probed>> + Send + Sync>;
+
+/// An imported source, probed on first use.
+pub struct DeferredSource {
+    path: PathBuf,
+    probe: ProbeFn,
+    probed: OnceLock<Vec<(String, Arc<dyn BackingProvider>)>>,
+}
+
+impl DeferredSource {
+    pub fn new(path: PathBuf, probe: ProbeFn) -> Arc<Self> {
+        Arc::new(Self {
+            path,
+            probe,
+            probed: OnceLock::new(),
+        })
+    }
+
+    fn provider(&self, name: &str) -> Result<Arc<dyn BackingProvider>> {
+        let all = match self.probed.get() {
+            Some(all) => all,
+            None => {
+                let p = (self.probe)()?
+                    .ok_or_else(|| anyhow!("{:?} no longer has anything to expose", self.path))?;
+                let all = std::iter::once((p.name, p.provider))
+                    .chain(p.extras)
+                    .collect();
+                self.probed.get_or_init(|| all)
+            }
+        };
+
+        all.iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, p)| Arc::clone(p))
+            .ok_or_else(|| anyhow!("{:?} no longer exposes {:?}", self.path, name))
+    }
+}
+
+/// An entry of an imported index: size and kind as exported, data from the
+/// source once it is probed.
+pub struct DeferredProvider {
+    source: Arc<DeferredSource>,
+    name: String,
+    kind: ImageKind,
+    size: u64,
+}
+
+impl DeferredProvider {
+    pub fn new(source: &Arc<DeferredSource>, record: &EntryRecord) -> Self {
+        Self {
+            source: Arc::clone(source),
+            name: record.name.clone(),
+            kind: record.kind,
+            size: record.size,
+        }
+    }
+}
+
+impl fmt::Debug for DeferredProvider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DeferredProvider")
+            .field("path", &self.source.path)
+            .field("name", &self.name)
+            .field("probed", &self.source.probed.get().is_some())
+            .finish()
+    }
+}
+
+impl BackingProvider for DeferredProvider {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn kind(&self) -> ImageKind {
+        self.kind
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        let p = self.source.provider(&self.name)?;
+        if p.size() != self.size {
+            bail!(
+                "{:?} changed since the index was exported; re-index",
+                self.source.path
+            );
+        }
+        p.read_at(offset, buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_records_of_unchanged_sources() {
+        let root = std::env::temp_dir().join(format!("chd2iso-snapshot-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("A.chd"), b"a").unwrap();
+        fs::write(root.join("B.chd"), b"b").unwrap();
+
+        let entry = EntryRecord {
+            name: "A.iso".into(),
+            kind: ImageKind::Dvd,
+            size: 2048,
+            sha1: Some(sha1::hex(&[0xab; 20])),
+            layer_break: None,
+            serial: None,
+            label: None,
+            console: None,
+        };
+        assert_eq!(entry.sha1(), Some([0xab; 20]));
+
+        let a = SourceRecord::new(&root.join("A.chd"), Some(&root), vec![entry]).unwrap();
+        let b = SourceRecord::new(&root.join("B.chd"), Some(&root), Vec::new()).unwrap();
+        assert_eq!(a.path, Path::new("A.chd"));
+        let snap = Snapshot::new("s".into(), vec![a, b]);
+
+        fs::write(root.join("B.chd"), b"bigger").unwrap();
+        let fresh = snap.fresh(Some(&root), "s").unwrap();
+        assert_eq!(fresh.len(), 1);
+        assert!(fresh.contains_key(&root.join("A.chd")));
+
+        assert!(Snapshot::new("s".into(), Vec::new())
+            .fresh(Some(&root), "other")
+            .is_err());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,68 @@
+//! Token-bucket bandwidth limiting for the read path.
+
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Refills at `rate` bytes/s up to one second's worth. Reads may take the
+/// bucket negative; the caller then waits until the debt is repaid, so a
+/// large read is delayed rather than rejected.
+pub struct TokenBucket {
+    rate: f64,
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        let rate = bytes_per_sec as f64;
+        Self {
+            rate,
+            state: Mutex::new((rate, Instant::now())),
+        }
+    }
+
+    /// From a `--max-*-throughput` value in MB/s; 0 disables limiting.
+    pub fn from_mbps(mbps: f64) -> Option<Self> {
+        (mbps > 0.0).then(|| Self::new((mbps * 1_000_000.0) as u64))
+    }
+
+    /// Charge `bytes` and return how long the caller should wait.
+    pub fn take(&self, bytes: usize) -> Duration {
+        self.take_at(bytes, Instant::now())
+    }
+
+    fn take_at(&self, bytes: usize, now: Instant) -> Duration {
+        let mut state = self.state.lock().expect("token bucket mutex poisoned");
+        let (tokens, last) = &mut *state;
+
+        let elapsed = now.saturating_duration_since(*last).as_secs_f64();
+        *tokens = (*tokens + elapsed * self.rate).min(self.rate);
+        *last = now;
+        *tokens -= bytes as f64;
+
+        if *tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-*tokens / self.rate)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn burst_then_paced() {
+        let b = TokenBucket::new(1_000_000);
+        let t0 = Instant::now();
+
+        assert_eq!(b.take_at(1_000_000, t0), Duration::ZERO);
+        assert_eq!(b.take_at(500_000, t0), Duration::from_millis(500));
+
+        // Half a second later the debt is repaid.
+        let t1 = t0 + Duration::from_millis(500);
+        assert_eq!(b.take_at(0, t1), Duration::ZERO);
+    }
+}
@@ -0,0 +1,181 @@
+//! Title database (`--title-db`): canonical names, and regions, by disc
+//! serial. Read from a CSV of `serial,title[,region]` lines or a
+//! logiqx-style XML DAT whose games carry a `serial` (as a `<serial>`
+//! element or an attribute of their `<rom>`), and reloaded on every index.
+
+use anyhow::{Context, Result};
+use std::{collections::HashMap, fs, path::Path};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Title {
+    pub name: String,
+    pub region: Option<String>,
+}
+
+#[derive(Debug, Default)]
+pub struct TitleDb(HashMap<String, Title>);
+
+/// Serials compare by their letters and digits alone: `SLUS-20062`,
+/// `SLUS_200.62` and `slus20062` are the same disc.
+fn key(serial: &str) -> String {
+    serial
+        .chars()
+        .filter(char::is_ascii_alphanumeric)
+        .map(|c| c.to_ascii_uppercase())
+        .collect()
+}
+
+impl TitleDb {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path).with_context(|| format!("reading {path:?}"))?;
+        let db = match text.trim_start().starts_with('<') {
+            true => Self::from_dat(&text),
+            false => Self::from_csv(&text),
+        };
+        Ok(db)
+    }
+
+    pub fn get(&self, serial: &str) -> Option<&Title> {
+        self.0.get(&key(serial))
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn insert(&mut self, serial: &str, name: &str, region: Option<&str>) {
+        let (serial, name) = (key(serial), name.trim());
+        if serial.is_empty() || name.is_empty() {
+            return;
+        }
+        self.0.entry(serial).or_insert_with(|| Title {
+            name: name.to_string(),
+            region: region
+                .map(str::trim)
+                .filter(|r| !r.is_empty())
+                .map(str::to_string),
+        });
+    }
+
+    /// Blank lines, `#` comments and a `serial,...` header are skipped.
+    fn from_csv(text: &str) -> Self {
+        let mut db = Self::default();
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields = csv_fields(line);
+            if fields[0].eq_ignore_ascii_case("serial") {
+                continue;
+            }
+            if let [serial, name, rest @ ..] = fields.as_slice() {
+                db.insert(serial, name, rest.first().map(String::as_str));
+            }
+        }
+        db
+    }
+
+    fn from_dat(text: &str) -> Self {
+        let mut db = Self::default();
+        for game in text.split("<game").skip(1) {
+            let game = game.split("</game>").next().unwrap_or(game);
+            let Some(name) = attr(game, "name") else {
+                continue;
+            };
+            let region = element(game, "region");
+            let serials = element(game, "serial").or_else(|| attr_of(game, "rom", "serial"));
+            // Multi-disc sets list several serials, comma-separated.
+            for serial in serials.iter().flat_map(|s| s.split(',')) {
+                db.insert(serial, &name, region.as_deref());
+            }
+        }
+        db
+    }
+}
+
+/// Fields of one CSV line; double quotes group commas and `""` is a quote.
+fn csv_fields(line: &str) -> Vec<String> {
+    let mut fields = vec![String::new()];
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        let cur = fields.last_mut().expect("never empty");
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                cur.push('"');
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(String::new()),
+            c => cur.push(c),
+        }
+    }
+    fields.iter().map(|f| f.trim().to_string()).collect()
+}
+
+fn unescape(s: &str) -> String {
+    s.replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+/// `name="..."` of the tag `game` starts with.
+fn attr(game: &str, name: &str) -> Option<String> {
+    let tag = &game[..game.find('>')?];
+    let pat = format!("{name}=\"");
+    let start = tag.find(&pat)? + pat.len();
+    let len = tag[start..].find('"')?;
+    Some(unescape(&tag[start..start + len]))
+}
+
+/// `name="..."` of the first `<tag>` in `game`.
+fn attr_of(game: &str, tag: &str, name: &str) -> Option<String> {
+    let start = game.find(&format!("<{tag} "))?;
+    attr(&game[start + 1..], name)
+}
+
+/// Text of the first `<tag>` element in `game`.
+fn element(game: &str, tag: &str) -> Option<String> {
+    let open = format!("<{tag}>");
+    let start = game.find(&open)? + open.len();
+    let len = game[start..].find(&format!("</{tag}>"))?;
+    Some(unescape(&game[start..start + len]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_csv_and_dat() {
+        let db = TitleDb::from_csv(
+            "serial,title,region\n# comment\nSLUS-20062,\"Grand Theft Auto, Vice City\",USA\nSLES_500.03,Fantavision\n",
+        );
+        assert_eq!(
+            db.get("SLUS_200.62"),
+            Some(&Title {
+                name: "Grand Theft Auto, Vice City".into(),
+                region: Some("USA".into())
+            })
+        );
+        assert_eq!(db.get("sles50003").map(|t| t.region.clone()), Some(None));
+
+        let db = TitleDb::from_dat(
+            r#"<?xml version="1.0"?><datafile>
+            <game name="Ico (USA)"><region>USA</region><serial>SCUS-97113</serial>
+              <rom name="Ico (USA).iso" size="1"/></game>
+            <game name="Rez &amp; Co (Japan)"><rom name="Rez.iso" serial="SLPM-65051, SLPM-65052"/></game>
+            </datafile>"#,
+        );
+        assert_eq!(db.len(), 3);
+        assert_eq!(db.get("SCUS97113").unwrap().region.as_deref(), Some("USA"));
+        assert_eq!(db.get("SLPM-65052").unwrap().name, "Rez & Co (Japan)");
+    }
+}
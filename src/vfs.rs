@@ -0,0 +1,761 @@
+//! Where sources live. A [`SourceVfs`] lists a library, stats its files and
+//! opens them for positional reads; [`vfs_for`] picks one by path: the local
+//! filesystem, with the `http` or `sftp` feature a remote server, and with
+//! the `archive` feature the inside of a zip. Nothing else opens or lists
+//! sources directly, so another kind of library is one more implementation
+//! here.
+
+use anyhow::{Context, Result};
+use std::{
+    collections::HashSet,
+    ffi::OsStr,
+    fmt,
+    fs::{self, File},
+    io::{self, BufReader, Read, Seek, SeekFrom},
+    os::unix::fs::{FileExt, MetadataExt, OpenOptionsExt},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering},
+        mpsc::{self, RecvTimeoutError},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+use tracing::{error, info, warn};
+
+use crate::latency;
+use crate::otel::{self, Attr};
+
+/// How [`SourceVfs::list`] walks a library.
+pub struct ScanOptions<'a> {
+    /// Follow symlinks to files and directories, and descend into
+    /// directories (local only)
+    pub follow_symlinks: bool,
+    /// Skip names starting with `.`
+    pub skip_hidden: bool,
+    /// Whether a file belongs in the listing, by path
+    pub wanted: &'a dyn Fn(&Path) -> bool,
+    /// Canonical directories the scan does not descend into, such as a
+    /// mountpoint inside the library (local only)
+    pub exclude: &'a [PathBuf],
+}
+
+/// What a stat of a source says.
+#[derive(Clone, Copy, Debug)]
+pub struct SourceMeta {
+    pub is_dir: bool,
+    pub size: u64,
+    /// Seconds since the epoch, where the backend knows it
+    pub mtime: Option<i64>,
+    /// Inode details, for local files only
+    pub local: Option<LocalMeta>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct LocalMeta {
+    pub dev: u64,
+    pub ino: u64,
+    pub ctime: i64,
+    pub uid: u32,
+    pub gid: u32,
+    /// Allocated 512-byte blocks
+    pub blocks: u64,
+}
+
+impl From<&fs::Metadata> for SourceMeta {
+    fn from(meta: &fs::Metadata) -> Self {
+        Self {
+            is_dir: meta.is_dir(),
+            size: meta.size(),
+            mtime: Some(meta.mtime()),
+            local: Some(LocalMeta {
+                dev: meta.dev(),
+                ino: meta.ino(),
+                ctime: meta.ctime(),
+                uid: meta.uid(),
+                gid: meta.gid(),
+                blocks: meta.blocks(),
+            }),
+        }
+    }
+}
+
+/// An open source, read by offset from any thread.
+pub trait RangedRead: Send + Sync + fmt::Debug {
+    fn size(&self) -> u64;
+
+    /// Read into `buf` from `offset`; short only at the end of the file.
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize>;
+
+    fn read_exact_at(&self, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+        while !buf.is_empty() {
+            match self.read_at(buf, offset)? {
+                0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+                n => {
+                    buf = &mut buf[n..];
+                    offset += n as u64;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+pub trait SourceVfs: Send + Sync {
+    /// Files under `root` that `opts.wanted`.
+    fn list(&self, root: &Path, opts: &ScanOptions) -> Result<Vec<PathBuf>>;
+
+    fn stat(&self, path: &Path) -> Result<SourceMeta>;
+
+    fn open(&self, path: &Path) -> Result<Box<dyn RangedRead>>;
+}
+
+/// Whether `path` is a remote URL (http, https or sftp) rather than a
+/// local file.
+pub fn is_url(path: &Path) -> bool {
+    path.to_str().is_some_and(|s| {
+        ["http://", "https://", "sftp://"]
+            .iter()
+            .any(|scheme| s.starts_with(scheme))
+    })
+}
+
+pub fn vfs_for(path: &Path) -> &'static dyn SourceVfs {
+    #[cfg(feature = "archive")]
+    if crate::archive::split(path).is_some() {
+        return &crate::archive::ArchiveVfs;
+    }
+    if is_url(path) {
+        #[cfg(any(feature = "http", feature = "sftp"))]
+        return &crate::remote::RemoteVfs;
+        #[cfg(not(any(feature = "http", feature = "sftp")))]
+        return &NoRemote;
+    }
+    &LocalVfs
+}
+
+pub fn list(root: &Path, opts: &ScanOptions) -> Result<Vec<PathBuf>> {
+    vfs_for(root).list(root, opts)
+}
+
+pub fn stat(path: &Path) -> Result<SourceMeta> {
+    vfs_for(path).stat(path)
+}
+
+pub fn open(path: &Path) -> Result<Box<dyn RangedRead>> {
+    vfs_for(path).open(path)
+}
+
+/// Pause before the second try of [`check_open`].
+const REOPEN_DELAY: Duration = Duration::from_millis(100);
+
+/// Make sure the source at `path` can be opened, trying twice: a file
+/// replaced in place (deleted, then written anew) is briefly missing, and
+/// other errors can be as passing. A `NotFound` error means it is still
+/// gone. A remote one is only found missing on its first read.
+pub fn check_open(path: &Path) -> io::Result<()> {
+    if is_url(path) {
+        return Ok(());
+    }
+    let try_open = || {
+        open(path).map(drop).map_err(|e| {
+            let kind = e
+                .downcast_ref::<io::Error>()
+                .map_or(io::ErrorKind::Other, io::Error::kind);
+            io::Error::new(kind, format!("{e:#}"))
+        })
+    };
+    let file = || vec![("file", Attr::Str(path.display().to_string()))];
+    otel::timed("open", file, || {
+        try_open().or_else(|e| {
+            warn!("opening {:?} failed ({}), trying again", path, e);
+            thread::sleep(REOPEN_DELAY);
+            try_open()
+        })
+    })
+}
+
+/// A source open for sequential reading, for parsers that want `Read +
+/// Seek`.
+pub type SourceReader = BufReader<Cursor>;
+
+pub fn reader(path: &Path) -> Result<SourceReader> {
+    Ok(BufReader::new(Cursor {
+        inner: open(path)?,
+        pos: 0,
+    }))
+}
+
+/// `Read + Seek` over a [`RangedRead`].
+#[derive(Debug)]
+pub struct Cursor {
+    inner: Box<dyn RangedRead>,
+    pos: u64,
+}
+
+impl Read for Cursor {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.inner.size() || buf.is_empty() {
+            return Ok(0);
+        }
+        let start = Instant::now();
+        let n = self.inner.read_at(buf, self.pos)?;
+        latency::source_read(start.elapsed());
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for Cursor {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new = match pos {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::End(d) => self.inner.size().checked_add_signed(d),
+            SeekFrom::Current(d) => self.pos.checked_add_signed(d),
+        };
+        self.pos = new.ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?;
+        Ok(self.pos)
+    }
+}
+
+#[derive(Debug)]
+struct LocalFile {
+    path: PathBuf,
+    file: Arc<File>,
+    size: u64,
+    /// With `--source-timeout`, where reads go
+    io: Option<Mutex<SourceIo>>,
+}
+
+impl RangedRead for LocalFile {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let Some(worker) = &self.io else {
+            return FileExt::read_at(&*self.file, buf, offset);
+        };
+        let mut worker = worker.lock().expect("source I/O mutex poisoned");
+        check_blocked(&self.path)?;
+        let mut data = std::mem::take(&mut worker.spare);
+        data.resize(buf.len(), 0);
+        let ticket = Arc::new(Ticket::default());
+        let (tx, rx) = mpsc::channel();
+        worker
+            .jobs
+            .send((offset, data, Arc::clone(&ticket), tx))
+            .map_err(|_| io::Error::other("source I/O thread died"))?;
+        let data = wait(&self.path, worker.timeout, &ticket, rx)?;
+        buf[..data.len()].copy_from_slice(&data);
+        let n = data.len();
+        worker.spare = data;
+        Ok(n)
+    }
+}
+
+type ReadJob = (u64, Vec<u8>, Arc<Ticket>, mpsc::Sender<io::Result<Vec<u8>>>);
+
+/// The one thread a local source's reads run on under `--source-timeout`,
+/// and the buffer handed back and forth with it. The thread ends with the
+/// file.
+#[derive(Debug)]
+struct SourceIo {
+    jobs: mpsc::Sender<ReadJob>,
+    spare: Vec<u8>,
+    timeout: Duration,
+}
+
+impl SourceIo {
+    fn spawn(path: &Path, file: Arc<File>, timeout: Duration) -> io::Result<Self> {
+        let (jobs, rx) = mpsc::channel::<ReadJob>();
+        let path = path.to_path_buf();
+        thread::Builder::new()
+            .name("source-io".into())
+            .spawn(move || {
+                for (offset, mut data, ticket, reply) in rx {
+                    let res = FileExt::read_at(&*file, &mut data, offset).map(|n| {
+                        data.truncate(n);
+                        data
+                    });
+                    ticket.done(&path);
+                    let _ = reply.send(res);
+                }
+            })?;
+        Ok(Self {
+            jobs,
+            spare: Vec::new(),
+            timeout,
+        })
+    }
+}
+
+/// `--source-timeout` in milliseconds, 0 for none; see [`set_source_timeout`].
+static SOURCE_TIMEOUT_MS: AtomicU64 = AtomicU64::new(0);
+
+/// Local sources with an open or read that timed out, until taken.
+static TIMED_OUT: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+/// Local sources, once per I/O, with an open or read still blocked after
+/// its caller gave up. New I/O on them fails at once rather than tie up
+/// another thread.
+static BLOCKED: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+/// Fail local opens and reads that get no answer within `timeout` (zero:
+/// wait forever), so a hung NFS server fails requests instead of wedging
+/// them. Call before the first index.
+pub fn set_source_timeout(timeout: Duration) {
+    SOURCE_TIMEOUT_MS.store(timeout.as_millis() as u64, Ordering::Relaxed);
+}
+
+fn source_timeout() -> Option<Duration> {
+    match SOURCE_TIMEOUT_MS.load(Ordering::Relaxed) {
+        0 => None,
+        ms => Some(Duration::from_millis(ms)),
+    }
+}
+
+/// Whether a timed I/O's caller is still waiting on it: settled once, by
+/// whichever of the caller giving up and the I/O returning comes first.
+#[derive(Debug, Default)]
+struct Ticket(AtomicU8);
+
+const WAITING: u8 = 0;
+const RETURNED: u8 = 1;
+const ABANDONED: u8 = 2;
+
+impl Ticket {
+    /// The I/O returned; unblocks `path` if its caller already gave up.
+    fn done(&self, path: &Path) {
+        if self.0.swap(RETURNED, Ordering::SeqCst) == ABANDONED {
+            unblock(path);
+        }
+    }
+
+    /// The caller gives up; false if the I/O returned in the meantime.
+    fn abandon(&self, path: &Path) -> bool {
+        // Blocked before settling, so `done` cannot unblock first.
+        BLOCKED
+            .lock()
+            .expect("blocked mutex poisoned")
+            .push(path.to_path_buf());
+        let gave_up = self
+            .0
+            .compare_exchange(WAITING, ABANDONED, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok();
+        if !gave_up {
+            unblock(path);
+        }
+        gave_up
+    }
+}
+
+fn unblock(path: &Path) {
+    let mut blocked = BLOCKED.lock().expect("blocked mutex poisoned");
+    if let Some(i) = blocked.iter().position(|p| p == path) {
+        blocked.swap_remove(i);
+    }
+}
+
+/// `EIO` while an earlier open or read of `path` is still blocked.
+fn check_blocked(path: &Path) -> io::Result<()> {
+    match BLOCKED
+        .lock()
+        .expect("blocked mutex poisoned")
+        .iter()
+        .any(|p| p == path)
+    {
+        true => Err(io::Error::from_raw_os_error(libc::EIO)),
+        false => Ok(()),
+    }
+}
+
+/// Wait up to `timeout` for the I/O behind `ticket`, then give up with
+/// `TimedOut`, leaving `path` blocked until the I/O returns.
+fn wait<T>(
+    path: &Path,
+    timeout: Duration,
+    ticket: &Ticket,
+    rx: mpsc::Receiver<io::Result<T>>,
+) -> io::Result<T> {
+    match rx.recv_timeout(timeout) {
+        Ok(res) => res,
+        Err(RecvTimeoutError::Timeout) if ticket.abandon(path) => {
+            TIMED_OUT
+                .lock()
+                .expect("timed-out mutex poisoned")
+                .push(path.to_path_buf());
+            Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("{path:?}: no answer from the source within {timeout:?}"),
+            ))
+        }
+        Err(RecvTimeoutError::Timeout) => rx
+            .recv()
+            .unwrap_or_else(|_| Err(io::Error::other("source I/O thread died"))),
+        Err(RecvTimeoutError::Disconnected) => Err(io::Error::other("source I/O thread died")),
+    }
+}
+
+/// Run `io` on `path` on a helper thread and give up after `timeout` with
+/// `TimedOut`. The helper stays blocked until the I/O returns, and until
+/// then further I/O on `path` fails with `EIO`.
+fn timed<T: Send + 'static>(
+    path: &Path,
+    timeout: Duration,
+    io: impl FnOnce() -> io::Result<T> + Send + 'static,
+) -> io::Result<T> {
+    check_blocked(path)?;
+    let ticket = Arc::new(Ticket::default());
+    let (tx, rx) = mpsc::channel();
+    {
+        let (ticket, path) = (Arc::clone(&ticket), path.to_path_buf());
+        thread::Builder::new()
+            .name("source-io".into())
+            .spawn(move || {
+                let res = io();
+                ticket.done(&path);
+                let _ = tx.send(res);
+            })?;
+    }
+    wait(path, timeout, &ticket, rx)
+}
+
+/// Whether an open or read of `path` timed out since last asked.
+pub fn take_timed_out(path: &Path) -> bool {
+    let mut timed_out = TIMED_OUT.lock().expect("timed-out mutex poisoned");
+    let before = timed_out.len();
+    timed_out.retain(|p| p != path);
+    timed_out.len() != before
+}
+
+/// Open local sources with `O_NOATIME`, see [`set_noatime`].
+static NOATIME: AtomicBool = AtomicBool::new(true);
+
+/// Whether local sources are opened with `O_NOATIME`, so reads do not
+/// update their atime (on by default). Call before the first index.
+pub fn set_noatime(on: bool) {
+    NOATIME.store(on, Ordering::Relaxed);
+}
+
+/// Open a local file for reading, without updating its atime where allowed.
+/// The kernel only permits `O_NOATIME` to the file's owner (or with
+/// `CAP_FOWNER`); otherwise this falls back to a plain open.
+pub fn open_local(path: &Path) -> io::Result<File> {
+    if NOATIME.load(Ordering::Relaxed) {
+        match fs::OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NOATIME)
+            .open(path)
+        {
+            Err(e) if e.raw_os_error() == Some(libc::EPERM) => {}
+            other => return other,
+        }
+    }
+    File::open(path)
+}
+
+/// The local filesystem.
+pub struct LocalVfs;
+
+impl SourceVfs for LocalVfs {
+    /// Only `root` itself is listed, unless following symlinks: then every
+    /// directory below it, real or symlinked, is spliced in.
+    fn list(&self, root: &Path, opts: &ScanOptions) -> Result<Vec<PathBuf>> {
+        let meta = fs::metadata(root).with_context(|| format!("reading {root:?}"))?;
+
+        let mut seen = HashSet::new();
+        seen.insert((meta.dev(), meta.ino()));
+
+        let mut out = Vec::new();
+        scan_dir_into(root, opts, &mut seen, &mut out)?;
+        Ok(out)
+    }
+
+    fn stat(&self, path: &Path) -> Result<SourceMeta> {
+        let meta = fs::metadata(path).with_context(|| format!("stat {path:?}"))?;
+        Ok(SourceMeta::from(&meta))
+    }
+
+    fn open(&self, path: &Path) -> Result<Box<dyn RangedRead>> {
+        let open = {
+            let path = path.to_path_buf();
+            move || {
+                let file = open_local(&path)?;
+                let size = file.metadata()?.len();
+                Ok((file, size))
+            }
+        };
+        let timeout = source_timeout();
+        let (file, size) = match timeout {
+            Some(timeout) => timed(path, timeout, open),
+            None => open(),
+        }
+        .with_context(|| format!("opening {path:?}"))?;
+        let file = Arc::new(file);
+        let io = match timeout {
+            Some(timeout) => Some(Mutex::new(
+                SourceIo::spawn(path, Arc::clone(&file), timeout)
+                    .with_context(|| format!("opening {path:?}"))?,
+            )),
+            None => None,
+        };
+        Ok(Box::new(LocalFile {
+            path: path.to_path_buf(),
+            file,
+            size,
+            io,
+        }))
+    }
+}
+
+/// Scan one directory, and when following symlinks every directory below
+/// it, real or linked, the same way. `seen` holds (dev, ino) pairs of every
+/// directory and file already visited so loops and repeated targets are
+/// skipped. Entries that cannot be read are skipped with a warning.
+fn scan_dir_into(
+    dir: &Path,
+    opts: &ScanOptions,
+    seen: &mut HashSet<(u64, u64)>,
+    out: &mut Vec<PathBuf>,
+) -> Result<()> {
+    for ent in fs::read_dir(dir).with_context(|| format!("reading {dir:?}"))? {
+        let ent = match ent {
+            Ok(ent) => ent,
+            Err(e) => {
+                warn!("Skipping an entry of {:?}: {}", dir, e);
+                continue;
+            }
+        };
+        let path = ent.path();
+
+        if opts.skip_hidden && is_hidden(&ent.file_name()) {
+            continue;
+        }
+
+        let ft = match ent.file_type() {
+            Ok(ft) => ft,
+            Err(e) => {
+                warn!("Skipping {:?}: {}", path, e);
+                continue;
+            }
+        };
+
+        if !opts.follow_symlinks {
+            if (ft.is_file() || ft.is_symlink()) && (opts.wanted)(&path) {
+                out.push(path);
+            }
+            continue;
+        }
+
+        if ft.is_symlink() || ft.is_dir() {
+            if let Ok(target) = fs::canonicalize(&path) {
+                if let Some(x) = opts.exclude.iter().find(|x| target.starts_with(x)) {
+                    warn!("Skipping {:?}: it leads into mountpoint {:?}", path, x);
+                    continue;
+                }
+            }
+        }
+
+        let meta = match fs::metadata(&path) {
+            Ok(m) => m,
+            Err(e) if ft.is_symlink() => {
+                warn!("Skipping dangling symlink {:?}: {}", path, e);
+                continue;
+            }
+            Err(e) => {
+                warn!("Skipping {:?}: {}", path, e);
+                continue;
+            }
+        };
+
+        if meta.is_dir() {
+            if !seen.insert((meta.dev(), meta.ino())) {
+                warn!("Skipping {:?}: directory already scanned (a loop?)", path);
+                continue;
+            }
+
+            // Entries of a linked directory are listed under its target.
+            let target = if ft.is_symlink() {
+                match fs::canonicalize(&path) {
+                    Ok(target) => {
+                        info!("following symlinked directory {:?} -> {:?}", path, target);
+                        target
+                    }
+                    Err(e) => {
+                        warn!("Skipping {:?}: {}", path, e);
+                        continue;
+                    }
+                }
+            } else {
+                path
+            };
+
+            if let Err(e) = scan_dir_into(&target, opts, seen, out) {
+                error!("Skipping {:?}: {:#}", target, e);
+            }
+        } else if meta.is_file() && (opts.wanted)(&path) {
+            if !seen.insert((meta.dev(), meta.ino())) {
+                info!("Skipping {:?}: target already indexed", path);
+                continue;
+            }
+
+            out.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+fn is_hidden(name: &OsStr) -> bool {
+    name.as_encoded_bytes().first() == Some(&b'.')
+}
+
+/// URLs in a build without the `http` and `sftp` features.
+#[cfg(not(any(feature = "http", feature = "sftp")))]
+struct NoRemote;
+
+#[cfg(not(any(feature = "http", feature = "sftp")))]
+impl NoRemote {
+    fn unsupported(path: &Path) -> anyhow::Error {
+        anyhow::anyhow!(
+            "{path:?}: remote sources require a build with the `http` or `sftp` feature"
+        )
+    }
+}
+
+#[cfg(not(any(feature = "http", feature = "sftp")))]
+impl SourceVfs for NoRemote {
+    fn list(&self, root: &Path, _opts: &ScanOptions) -> Result<Vec<PathBuf>> {
+        Err(Self::unsupported(root))
+    }
+
+    fn stat(&self, path: &Path) -> Result<SourceMeta> {
+        Err(Self::unsupported(path))
+    }
+
+    fn open(&self, path: &Path) -> Result<Box<dyn RangedRead>> {
+        Err(Self::unsupported(path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn local_listing_and_ranged_reads() {
+        let dir = std::env::temp_dir().join(format!("chd2iso-vfs-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let data: Vec<u8> = (0..5000u32).map(|i| i as u8).collect();
+        fs::write(dir.join("a.chd"), &data).unwrap();
+        fs::write(dir.join(".b.chd"), b"").unwrap();
+        fs::write(dir.join("c.txt"), b"").unwrap();
+
+        let wanted = |p: &Path| p.extension().is_some_and(|e| e == "chd");
+        let opts = ScanOptions {
+            follow_symlinks: false,
+            skip_hidden: true,
+            wanted: &wanted,
+            exclude: &[],
+        };
+        assert_eq!(list(&dir, &opts).unwrap(), vec![dir.join("a.chd")]);
+
+        let meta = stat(&dir.join("a.chd")).unwrap();
+        assert_eq!((meta.is_dir, meta.size), (false, 5000));
+
+        let mut r = reader(&dir.join("a.chd")).unwrap();
+        let mut buf = [0u8; 10];
+        r.seek(SeekFrom::End(-10)).unwrap();
+        r.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, data[4990..]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn following_descends_into_real_and_linked_dirs() {
+        let dir = std::env::temp_dir().join(format!("chd2iso-follow-{}", std::process::id()));
+        let lib = dir.join("lib");
+        fs::create_dir_all(lib.join("real/deeper")).unwrap();
+        fs::create_dir_all(dir.join("elsewhere")).unwrap();
+        fs::write(lib.join("a.chd"), b"a").unwrap();
+        fs::write(lib.join("real/deeper/b.chd"), b"b").unwrap();
+        fs::write(dir.join("elsewhere/c.chd"), b"c").unwrap();
+        std::os::unix::fs::symlink(dir.join("elsewhere"), lib.join("linked")).unwrap();
+        std::os::unix::fs::symlink(&lib, lib.join("real/loop")).unwrap();
+        std::os::unix::fs::symlink(dir.join("gone"), lib.join("dangling.chd")).unwrap();
+
+        let wanted = |p: &Path| p.extension().is_some_and(|e| e == "chd");
+        let mut opts = ScanOptions {
+            follow_symlinks: true,
+            skip_hidden: true,
+            wanted: &wanted,
+            exclude: &[],
+        };
+        let mut found = list(&lib, &opts).unwrap();
+        found.sort();
+        let elsewhere = fs::canonicalize(dir.join("elsewhere")).unwrap();
+        let mut expected = vec![
+            lib.join("a.chd"),
+            lib.join("real/deeper/b.chd"),
+            elsewhere.join("c.chd"),
+        ];
+        expected.sort();
+        assert_eq!(found, expected);
+
+        // Without following, only the top level, links included as named.
+        opts.follow_symlinks = false;
+        let mut found = list(&lib, &opts).unwrap();
+        found.sort();
+        assert_eq!(found, vec![lib.join("a.chd"), lib.join("dangling.chd")]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn open_is_retried_once() {
+        let dir = std::env::temp_dir().join(format!("chd2iso-reopen-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("a.chd");
+
+        let err = check_open(&path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+
+        // Written back while the first try is waiting, as by `cp` over it.
+        let writer = {
+            let path = path.clone();
+            thread::spawn(move || {
+                thread::sleep(REOPEN_DELAY / 5);
+                fs::write(path, b"chd").unwrap();
+            })
+        };
+        check_open(&path).unwrap();
+        writer.join().unwrap();
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn hung_io_times_out() {
+        let path = Path::new("/srv/nfs/hung.chd");
+        let quick = timed(path, Duration::from_secs(5), || Ok(7));
+        assert_eq!(quick.unwrap(), 7);
+        assert!(!take_timed_out(path));
+
+        let hung = timed(path, Duration::from_millis(10), || {
+            thread::sleep(Duration::from_millis(500));
+            Ok(7)
+        });
+        assert_eq!(hung.unwrap_err().kind(), io::ErrorKind::TimedOut);
+        assert!(take_timed_out(path));
+        assert!(!take_timed_out(path));
+
+        // No second thread while the first is still stuck.
+        let again = timed(path, Duration::from_secs(5), || Ok(7));
+        assert_eq!(again.unwrap_err().raw_os_error(), Some(libc::EIO));
+        thread::sleep(Duration::from_millis(700));
+        assert_eq!(timed(path, Duration::from_secs(5), || Ok(7)).unwrap(), 7);
+    }
+}
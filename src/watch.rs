@@ -0,0 +1,157 @@
+//! inotify watches on the directories holding indexed sources, so cached
+//! attributes are dropped as soon as a source file changes locally. Changes
+//! made on another host of a network filesystem are not reported; that is
+//! what `--attr-refresh` is for.
+
+use std::{
+    collections::HashMap,
+    ffi::{CString, OsStr, OsString},
+    fs::File,
+    io::{self, Read},
+    os::unix::ffi::OsStrExt,
+    os::unix::io::{AsRawFd, FromRawFd},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+const MASK: u32 = libc::IN_ATTRIB
+    | libc::IN_CLOSE_WRITE
+    | libc::IN_MOVED_FROM
+    | libc::IN_MOVED_TO
+    | libc::IN_CREATE
+    | libc::IN_DELETE
+    | libc::IN_ONLYDIR;
+
+/// Size of `struct inotify_event` before its name.
+const EVENT_HEADER: usize = 16;
+
+pub enum Change {
+    File(PathBuf),
+    /// The kernel queue overflowed; anything may have changed
+    Overflow,
+    /// The filesystem holding a watched directory was unmounted
+    Unmount,
+}
+
+pub struct Watcher {
+    fd: File,
+    dirs: Mutex<HashMap<i32, PathBuf>>,
+}
+
+impl Watcher {
+    pub fn new() -> io::Result<Self> {
+        let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Self {
+            fd: unsafe { File::from_raw_fd(fd) },
+            dirs: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Watch `dir`; watching it again is harmless.
+    pub fn add(&self, dir: &Path) -> io::Result<()> {
+        let c = CString::new(dir.as_os_str().as_bytes())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let wd = unsafe { libc::inotify_add_watch(self.fd.as_raw_fd(), c.as_ptr(), MASK) };
+        if wd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        self.dirs
+            .lock()
+            .expect("watch dirs mutex poisoned")
+            .insert(wd, dir.to_path_buf());
+        Ok(())
+    }
+
+    /// Block for events and pass each change to `f`; returns only on a
+    /// read error.
+    pub fn run(&self, mut f: impl FnMut(Change)) -> io::Result<()> {
+        let mut buf = vec![0u8; 64 * 1024];
+        loop {
+            let n = (&self.fd).read(&mut buf)?;
+            for (wd, mask, name) in parse_events(&buf[..n]) {
+                if mask & libc::IN_Q_OVERFLOW != 0 {
+                    f(Change::Overflow);
+                    continue;
+                }
+                if mask & libc::IN_UNMOUNT != 0 {
+                    f(Change::Unmount);
+                    continue;
+                }
+
+                let dir = self
+                    .dirs
+                    .lock()
+                    .expect("watch dirs mutex poisoned")
+                    .get(&wd)
+                    .cloned();
+                if let Some(dir) = dir {
+                    f(Change::File(dir.join(name)));
+                }
+            }
+        }
+    }
+}
+
+/// (watch descriptor, mask, name) of each event in `buf`.
+fn parse_events(buf: &[u8]) -> Vec<(i32, u32, OsString)> {
+    let mut out = Vec::new();
+    let mut at = 0;
+
+    while at + EVENT_HEADER <= buf.len() {
+        let word = |i: usize| buf[at + i..at + i + 4].try_into().expect("4 bytes");
+        let wd = i32::from_ne_bytes(word(0));
+        let mask = u32::from_ne_bytes(word(4));
+        let len = u32::from_ne_bytes(word(12)) as usize;
+
+        let name = buf
+            .get(at + EVENT_HEADER..at + EVENT_HEADER + len)
+            .unwrap_or_default();
+        let name = name.split(|&b| b == 0).next().unwrap_or_default();
+        out.push((wd, mask, OsStr::from_bytes(name).to_os_string()));
+
+        at += EVENT_HEADER + len;
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::ffi::OsStringExt;
+
+    fn event(wd: i32, mask: u32, name: &[u8], padded: usize) -> Vec<u8> {
+        let mut e = Vec::new();
+        e.extend_from_slice(&wd.to_ne_bytes());
+        e.extend_from_slice(&mask.to_ne_bytes());
+        e.extend_from_slice(&0u32.to_ne_bytes());
+        e.extend_from_slice(&(padded as u32).to_ne_bytes());
+        e.extend_from_slice(name);
+        e.resize(EVENT_HEADER + padded, 0);
+        e
+    }
+
+    #[test]
+    fn parses_padded_event_names() {
+        let mut buf = event(1, libc::IN_CLOSE_WRITE, b"Game.chd", 16);
+        buf.extend(event(2, libc::IN_Q_OVERFLOW, b"", 0));
+
+        let events = parse_events(&buf);
+        assert_eq!(
+            events,
+            vec![
+                (
+                    1,
+                    libc::IN_CLOSE_WRITE,
+                    OsString::from_vec(b"Game.chd".to_vec())
+                ),
+                (2, libc::IN_Q_OVERFLOW, OsString::new()),
+            ]
+        );
+    }
+}
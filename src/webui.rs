@@ -0,0 +1,250 @@
+//! `--web-ui ADDR`: a status page over HTTP, for NAS users without a
+//! metrics stack. `GET /` is a single page (`webui.html`) listing each
+//! mount's titles with their sizes and kinds, the counters, and sources with
+//! failed reads, with buttons to re-index and empty the frame cache. The
+//! page talks to `POST /rpc`, which takes the same JSON-RPC requests as
+//! `--control-socket` but only for the read-only methods, `reload` and
+//! `evict`.
+//!
+//! There is no authentication: bind to localhost or a trusted network.
+//! `/rpc` insists on `Content-Type: application/json`, which browsers only
+//! send cross-origin after a CORS preflight this server never answers, so
+//! other sites cannot drive it from a visitor's browser. Requests must also
+//! name this server in `Host` (localhost or the address they came in on),
+//! so a site cannot rebind its own name to it either.
+
+use anyhow::{Context, Result};
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{IpAddr, SocketAddr, TcpListener, TcpStream},
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+use tracing::{info, warn};
+
+use crate::{control, FsState};
+
+const PAGE: &str = include_str!("webui.html");
+/// Largest `/rpc` body accepted.
+const MAX_BODY: usize = 64 << 10;
+/// Most bytes read of a request line and headers together.
+const MAX_HEAD: usize = 16 << 10;
+/// Most header lines read.
+const MAX_HEADERS: usize = 64;
+/// What `/rpc` may call: the read-only methods and the page's two buttons.
+/// Degrading, pinning and tracing stay with the control socket's owner.
+const WEB_METHODS: &[&str] = &[
+    "list_mounts",
+    "list_entries",
+    "entries",
+    "entry",
+    "stats",
+    "degraded_sources",
+    "errors",
+    "read_trace",
+    "hot",
+    "reload",
+    "evict",
+];
+/// A client that stalls this long mid-request is dropped.
+const IDLE: Duration = Duration::from_secs(10);
+
+/// An HTTP response: status line, content type and body.
+type Reply = (&'static str, &'static str, Vec<u8>);
+
+/// Listen on `addr` and serve `mounts` from a background thread, a thread
+/// per connection, one request per connection.
+pub fn serve(addr: SocketAddr, mounts: Vec<Arc<FsState>>) -> Result<()> {
+    let listener =
+        TcpListener::bind(addr).with_context(|| format!("binding the web UI on {addr}"))?;
+    info!("web UI: listening on http://{}/", addr);
+
+    let mounts = Arc::new(mounts);
+    thread::Builder::new().name("webui".into()).spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let mounts = Arc::clone(&mounts);
+                    let _ = thread::Builder::new()
+                        .name("webui-client".into())
+                        .spawn(move || {
+                            if let Err(e) = client(stream, &mounts) {
+                                warn!("web UI: {}", e);
+                            }
+                        });
+                }
+                Err(e) => warn!("web UI: accept failed: {}", e),
+            }
+        }
+    })?;
+    Ok(())
+}
+
+fn client(mut stream: TcpStream, mounts: &[Arc<FsState>]) -> io::Result<()> {
+    stream.set_read_timeout(Some(IDLE))?;
+    let local = stream.local_addr()?;
+    // Nothing a client sends grows past what one request may hold.
+    let mut reader = BufReader::new(stream.try_clone()?.take((MAX_HEAD + MAX_BODY) as u64));
+
+    let mut line = String::new();
+    let mut head = reader.read_line(&mut line)?;
+    let mut parts = line.split_whitespace();
+    let (method, target) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+    let path = target.split('?').next().unwrap_or("");
+
+    let (mut length, mut json, mut host_ok, mut headers) = (0, false, false, 0);
+    loop {
+        let mut header = String::new();
+        let n = reader.read_line(&mut header)?;
+        if n == 0 || header.trim().is_empty() {
+            break;
+        }
+        head += n;
+        headers += 1;
+        if head > MAX_HEAD || headers > MAX_HEADERS {
+            break;
+        }
+        let Some((name, value)) = header.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("content-length") {
+            length = value.parse().unwrap_or(usize::MAX);
+        } else if name.eq_ignore_ascii_case("content-type") {
+            json = value.split(';').next() == Some("application/json");
+        } else if name.eq_ignore_ascii_case("host") {
+            host_ok = names_this_server(value, local);
+        }
+    }
+
+    let (status, kind, body) = if head > MAX_HEAD || headers > MAX_HEADERS {
+        (
+            "431 Request Header Fields Too Large",
+            "text/plain",
+            b"too many headers\n".to_vec(),
+        )
+    } else if !host_ok {
+        (
+            "403 Forbidden",
+            "text/plain",
+            b"Host must be localhost or this server's address\n".to_vec(),
+        )
+    } else if length > MAX_BODY {
+        (
+            "413 Payload Too Large",
+            "text/plain",
+            b"too large\n".to_vec(),
+        )
+    } else {
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body)?;
+        route(method, path, json, &body, mounts)
+    };
+
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Type: {kind}\r\nContent-Length: {}\r\n\
+         Cache-Control: no-store\r\nConnection: close\r\n\r\n",
+        body.len()
+    )?;
+    stream.write_all(&body)?;
+    stream.flush()
+}
+
+/// Whether `host`, a `Host` header, is localhost or `local`, the address
+/// the request came in on, at its port. Names other than localhost are
+/// refused: resolving them says nothing about who chose them.
+fn names_this_server(host: &str, local: SocketAddr) -> bool {
+    let (name, port) = match host.rsplit_once(':') {
+        Some((name, port)) if !port.contains(']') => (name, port.parse().ok()),
+        _ => (host, Some(80)),
+    };
+    let ip = name
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse::<IpAddr>();
+    port == Some(local.port())
+        && (name.eq_ignore_ascii_case("localhost")
+            || ip.is_ok_and(|ip| ip == local.ip().to_canonical() || ip.is_loopback()))
+}
+
+fn route(method: &str, path: &str, json: bool, body: &[u8], mounts: &[Arc<FsState>]) -> Reply {
+    match (method, path) {
+        ("GET", "/") => ("200 OK", "text/html; charset=utf-8", PAGE.into()),
+        ("POST", "/rpc") if !json => (
+            "415 Unsupported Media Type",
+            "text/plain",
+            b"Content-Type must be application/json\n".to_vec(),
+        ),
+        ("POST", "/rpc") => {
+            let request = String::from_utf8_lossy(body);
+            match control::respond(mounts, &request, &|m| WEB_METHODS.contains(&m)) {
+                Some(response) => ("200 OK", "application/json", response),
+                None => ("204 No Content", "text/plain", Vec::new()),
+            }
+        }
+        (_, "/" | "/rpc") => (
+            "405 Method Not Allowed",
+            "text/plain",
+            b"method not allowed\n".to_vec(),
+        ),
+        _ => ("404 Not Found", "text/plain", b"not found\n".to_vec()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn routes_page_and_guards_rpc() {
+        let status = |method, path, json| route(method, path, json, b"{}", &[]).0;
+        assert_eq!(status("GET", "/", false), "200 OK");
+        assert_eq!(status("POST", "/rpc", false), "415 Unsupported Media Type");
+        assert_eq!(status("GET", "/rpc", true), "405 Method Not Allowed");
+        assert_eq!(status("GET", "/metrics", false), "404 Not Found");
+
+        let (_, kind, page) = route("GET", "/", false, b"", &[]);
+        assert!(kind.starts_with("text/html"));
+        assert!(String::from_utf8(page).unwrap().contains("/rpc"));
+    }
+
+    #[test]
+    fn rpc_is_limited_to_status_reload_and_evict() {
+        let call = |method: &str| {
+            let request = format!(
+                r#"{{"jsonrpc":"2.0","id":1,"method":"{method}","params":{{"path":"Game.iso"}}}}"#
+            );
+            let (status, _, body) = route("POST", "/rpc", true, request.as_bytes(), &[]);
+            assert_eq!(status, "200 OK");
+            String::from_utf8(body).unwrap()
+        };
+        assert!(call("evict").contains(r#""result":0"#));
+        for method in ["degrade", "pin", "trace_reads", "handles"] {
+            assert!(call(method).contains("-32001"), "{method}");
+        }
+    }
+
+    #[test]
+    fn host_must_name_this_server() {
+        let local: SocketAddr = "192.168.1.5:8080".parse().unwrap();
+        for host in [
+            "localhost:8080",
+            "127.0.0.1:8080",
+            "[::1]:8080",
+            "192.168.1.5:8080",
+        ] {
+            assert!(names_this_server(host, local), "{host}");
+        }
+        for host in [
+            "attacker.example:8080",
+            "localhost",
+            "192.168.1.6:8080",
+            "[::1]",
+        ] {
+            assert!(!names_this_server(host, local), "{host}");
+        }
+        assert!(names_this_server("[::1]", "[::1]:80".parse().unwrap()));
+    }
+}